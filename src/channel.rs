@@ -0,0 +1,2283 @@
+//! Channel protocol: encrypted packetization of streams (spec section 4).
+//!
+//! A channel is one secure association between two hosts, resulting from a
+//! run of the negotiation protocol. It multiplexes streams into encrypted
+//! packets, acknowledges received packets, retransmits lost data and runs a
+//! congestion control strategy.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use crypto_box::SalsaBox;
+use tokio::sync::Notify;
+
+use crate::crypto::{self, ShortTermKey};
+use crate::decongestion::{self, CongestionAlg, CongestionSnapshot, Decongestion};
+use crate::error::{Error, Result};
+use crate::frame::{AckFrame, Frame, FrameCounters, FrameType, Setting, StreamFrame};
+use crate::host::HostInner;
+use crate::mtu::MtuState;
+use crate::negotiation::{NONCE_PREFIX_CLIENT_MESSAGE, NONCE_PREFIX_SERVER_MESSAGE};
+use crate::packet::{Packet, PacketHeader, MAGIC_MESSAGE};
+use crate::packetizer::Packetizer;
+use crate::pool::BufferPool;
+use crate::stream::{service, Chunk, Stream, StreamIdAllocator, StreamShared, Usid, ROOT_LSID};
+
+/// Delayed-ack timer: how long a received packet may wait for its ack.
+pub(crate) const ACK_DELAY: Duration = Duration::from_millis(25);
+/// Retransmission timeout bounds.
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(10);
+/// Probe timeout floor, comfortably above the peer's delayed-ack timer so
+/// a tail probe does not race the ack it is waiting for.
+const MIN_PTO: Duration = Duration::from_millis(50);
+/// NACK reports before a packet is declared lost.
+const NACK_THRESHOLD: u8 = 3;
+
+/// Unreliable datagrams queued per direction before the oldest is shed.
+const DGRAM_QUEUE: usize = 64;
+
+/// Recently seen datagram ids kept to suppress network duplicates.
+const DGRAM_DEDUP_WINDOW: usize = 128;
+/// Time-threshold loss: a packet NACKed this long after it was sent is lost
+/// without waiting for more reports (RFC 9002 section 6.1.2). This is what
+/// lets a tail-loss probe's ack confirm the loss it probed for.
+const LOSS_GRANULARITY: Duration = Duration::from_millis(1);
+/// Most NACK runs reported in one ACK frame.
+const MAX_NACK_RUNS: usize = 100;
+/// Receive gaps older than this many packets are abandoned.
+const RX_GAP_HORIZON: u64 = 256;
+/// Worst-case STREAM frame header: type, flags, LSID, parent, offset, length.
+const STREAM_FRAME_MAX_HEADER: usize = 21;
+
+/// Cap on queued substream opens waiting for a free slot.
+const OPEN_QUEUE_LIMIT: usize = 128;
+/// Before a peer's address is validated, send at most this multiple of the
+/// bytes received from it (anti-amplification, RFC 9000 section 8).
+const AMPLIFICATION_FACTOR: u64 = 3;
+/// Datagrams held back by the amplification limit; older ones are dropped
+/// since retransmission will regenerate them after validation.
+const HELD_LIMIT: usize = 8;
+
+/// What moved the congestion window in a [`CwndEvent`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CwndCause {
+    /// New data was acknowledged.
+    Ack,
+    /// A packet was declared lost by the peer's NACK reports.
+    Loss,
+    /// A packet was declared lost by the retransmission timeout.
+    Timeout,
+    /// Reserved: ECN congestion signals are not wired up yet.
+    Ecn,
+}
+
+/// One congestion-window change on a channel, reported through
+/// [`HostBuilder::on_cwnd_change`](crate::HostBuilder::on_cwnd_change).
+#[derive(Debug, Clone, Copy)]
+pub struct CwndEvent {
+    /// Window before the change, in bytes.
+    pub old: usize,
+    /// Window after the change, in bytes.
+    pub new: usize,
+    /// What moved the window.
+    pub cause: CwndCause,
+    /// When the change was observed.
+    pub at: Instant,
+}
+
+/// The congestion-window observer installed on the host, if any.
+pub(crate) type CwndHook = Arc<dyn Fn(CwndEvent) + Send + Sync>;
+
+/// Which side of the negotiation this host played for this channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    Initiator,
+    Responder,
+}
+
+impl Role {
+    /// Convert an LSID from the peer's ID space into ours.
+    ///
+    /// Stream LSIDs come in pairs: the initiator is always on the odd side
+    /// and the responder on the even side (spec section 5).
+    pub(crate) fn near_lsid(self, far: u32) -> u32 {
+        match (self, far) {
+            (_, ROOT_LSID) => ROOT_LSID,
+            (Role::Initiator, far) => far - 1,
+            (Role::Responder, far) => far + 1,
+        }
+    }
+}
+
+/// A packet handed to the socket and awaiting acknowledgement.
+struct SentPacket {
+    sent_at: Instant,
+    /// Payload bytes charged against the congestion window.
+    bytes: usize,
+    /// Stream data carried, for retransmission on loss.
+    chunks: Vec<(Arc<StreamShared>, Chunk)>,
+    /// Reliable control frames carried, requeued verbatim on loss.
+    ctrl: Vec<Frame>,
+    /// LSIDs whose multipath attach INIT rode in this packet.
+    inits: Vec<u32>,
+    /// Times this packet has been reported missing.
+    nacks: u8,
+    /// An MTU probe; its loss is expected and not a congestion signal.
+    is_probe: bool,
+}
+
+pub(crate) struct ChannelCore {
+    pub(crate) remote_addr: SocketAddr,
+    /// Next packet sequence number in our direction.
+    pub(crate) next_seq: u64,
+    sent: BTreeMap<u64, SentPacket>,
+    /// Reliable control frames awaiting transmission.
+    ctrl: VecDeque<Frame>,
+    /// Attached streams by LSID in our ID space.
+    pub(crate) streams: HashMap<u32, Arc<StreamShared>>,
+    lsids: StreamIdAllocator,
+    next_usid: u64,
+    tx_half_channel: [u8; 16],
+    rx_half_channel: [u8; 16],
+    // Receive-side packet tracking for ack generation.
+    /// All sequence numbers below this have been received (or abandoned).
+    rx_contiguous: u64,
+    /// Received sequence numbers at or above `rx_contiguous`.
+    rx_above: BTreeSet<u64>,
+    rx_largest: u64,
+    rx_largest_at: Instant,
+    ack_deadline: Option<Instant>,
+    pub(crate) cc: Box<dyn Decongestion>,
+    /// Estimates inherited from a predecessor channel (migration); re-applied
+    /// if SETTINGS negotiation swaps the strategy out after warm-start.
+    warm_start: Option<CongestionSnapshot>,
+    pub(crate) packetizer: Packetizer,
+    pub(crate) mtu: MtuState,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    /// Most recent RTT sample, before smoothing.
+    latest_rtt: Option<Duration>,
+    /// Smallest RTT sample seen on this channel, approximating the
+    /// path's propagation delay.
+    min_rtt: Option<Duration>,
+    /// Wire scale of our outgoing ack delays (units of two-to-the-
+    /// exponent microseconds), advertised in SETTINGS.
+    ack_delay_exponent: u8,
+    /// Longest we sit on an acknowledgement: the delayed-ack timer,
+    /// advertised in SETTINGS so the peer can bound its RTT math.
+    max_ack_delay: Duration,
+    /// Scale the peer advertised for its ack delay field; zero until
+    /// its SETTINGS arrive, matching plain microseconds.
+    peer_ack_delay_exponent: u8,
+    /// Cap on the ack delay the peer may report: bounds the delay
+    /// subtracted from RTT samples and pads the probe timeout.
+    peer_max_ack_delay: Option<Duration>,
+    rto_backoff: u32,
+    /// Consecutive retransmission timeouts without an acknowledgement;
+    /// past `max_retransmits` the peer is declared unresponsive.
+    rto_strikes: u32,
+    /// Give up after this many strikes; `None` retransmits forever.
+    max_retransmits: Option<u32>,
+    /// Declare the channel dead when packets are outstanding and nothing
+    /// has been heard from the peer for this long.
+    unresponsive_timeout: Option<Duration>,
+    /// Cap on out-of-order reassembly bytes across this channel's streams.
+    reassembly_limit: Option<usize>,
+    /// Whether the soft half of the reassembly cap has been crossed and
+    /// shrunken windows are already advertised.
+    reassembly_pressure: bool,
+    /// When the last authenticated packet arrived from the peer.
+    last_heard: Instant,
+    /// Both ends agreed to stream compression on this channel.
+    pub(crate) compression: bool,
+    /// Bytes the header-authentication tag takes out of every packet.
+    header_tag_len: usize,
+    /// Both ends support unreliable datagrams on this channel.
+    pub(crate) datagrams: bool,
+    /// The peer's latest advertised headroom for new top-level streams;
+    /// `None` until it has sent one. Zero blocks connects on this channel.
+    pub(crate) peer_stream_limit: Option<u32>,
+    /// The peer's advertised idle timeout, from its SETTINGS; `None`
+    /// until one arrives (an old peer never sends it).
+    pub(crate) peer_idle_timeout: Option<Duration>,
+    /// Backoff multiplier for the probe timeout, doubled per firing.
+    pto_backoff: u32,
+    idle_timeout: Duration,
+    idle_deadline: Instant,
+    /// Raw INITIATE datagram, resent until the responder is heard from.
+    handshake: Option<HandshakeResend>,
+    /// Raw datagrams queued by timer handling (handshake resends).
+    raw_out: Vec<Vec<u8>>,
+    /// A CLOSE frame has been queued or received.
+    closing: bool,
+    /// Total payload bytes handed to the socket, for channel stats.
+    pub(crate) bytes_sent: u64,
+    /// Total fresh stream payload bytes sent, for the connection-level
+    /// flow-control limit; retransmissions are not re-counted.
+    conn_bytes_sent: u64,
+    /// Connection-level absolute cap on `conn_bytes_sent`, raised
+    /// monotonically by peer WINDOW_UPDATE frames with LSID 0.
+    conn_send_limit: Option<u64>,
+    /// Local bound on unconsumed inbound payload across the channel; the
+    /// pump grants the peer credit against it as applications consume.
+    conn_recv_window: Option<usize>,
+    /// Total inbound payload bytes applications have consumed.
+    conn_consumed: u64,
+    /// Highest connection-level grant sent to the peer.
+    conn_granted: u64,
+    /// Unreliable datagrams awaiting a packet, with their dedup ids.
+    dgram_out: VecDeque<(u64, Bytes)>,
+    /// Next outbound datagram dedup id.
+    next_dgram_id: u64,
+    /// Received datagrams awaiting [`Stream::recv_datagram`] callers.
+    ///
+    /// [`Stream::recv_datagram`]: crate::Stream::recv_datagram
+    dgram_in: VecDeque<Bytes>,
+    /// Ids of recently delivered datagrams, for duplicate suppression.
+    dgram_seen: VecDeque<u64>,
+    /// Tasks blocked receiving a datagram.
+    dgram_wakers: Vec<Waker>,
+    /// The remote address has proven it can receive our packets. Always
+    /// true for the initiator; the responder flips it on the first MESSAGE
+    /// packet that decrypts, since only the real peer holds the channel key.
+    validated: bool,
+    /// Bytes received from / sent to the address while unvalidated.
+    pre_rx: u64,
+    pre_tx: u64,
+    /// Datagrams held back by the amplification limit.
+    held: VecDeque<Vec<u8>>,
+    /// Teardown should hibernate the streams instead of closing them.
+    hibernate: bool,
+    /// Why the channel is being aborted; streams surface this as a
+    /// connection reset instead of a plain close.
+    abort: Option<(u32, String)>,
+    /// The peer announced GOAWAY: refuse new local stream opens, but let
+    /// in-flight streams run to completion.
+    pub(crate) going_away: bool,
+    /// Streams attached to this channel as a secondary multipath path whose
+    /// INIT (with USID) has not been acknowledged yet.
+    pending_init: BTreeSet<u32>,
+    /// Window changes recorded under the core lock, fired to the host's
+    /// observer once it is released. Stays empty when no observer is set.
+    cwnd_events: Vec<CwndEvent>,
+    /// Whether the host installed a congestion-window observer.
+    cwnd_observed: bool,
+    /// Locally opened substreams counted against the concurrency cap.
+    open_local: usize,
+    /// Opens waiting for a free substream slot, granted in ticket order.
+    open_queue: VecDeque<(u64, Option<Waker>)>,
+    next_open_ticket: u64,
+    pub(crate) pump_done: bool,
+}
+
+struct HandshakeResend {
+    datagram: Vec<u8>,
+    next_at: Instant,
+    interval: Duration,
+}
+
+/// Removes a queued substream open from the wait queue when its future is
+/// dropped before being granted, so later tickets are not starved.
+struct OpenTicket<'a> {
+    chan: &'a Arc<ChannelShared>,
+    id: u64,
+    granted: bool,
+}
+
+impl Drop for OpenTicket<'_> {
+    fn drop(&mut self) {
+        if self.granted {
+            return;
+        }
+        let waker = {
+            let mut core = self.chan.lock();
+            if let Some(pos) = core.open_queue.iter().position(|(t, _)| *t == self.id) {
+                core.open_queue.remove(pos);
+            }
+            core.open_queue.front_mut().and_then(|(_, w)| w.take())
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Shared state for one channel.
+pub(crate) struct ChannelShared {
+    pub(crate) core: Mutex<ChannelCore>,
+    pub(crate) notify: Notify,
+    pub(crate) socket: Arc<crate::socket::Socket>,
+    pub(crate) host: Weak<HostInner>,
+    pub(crate) role: Role,
+    /// Our short-term public key, identifying our packets to the peer.
+    pub(crate) local_key: [u8; 32],
+    /// The peer's short-term public key, identifying its packets to us.
+    pub(crate) remote_key: [u8; 32],
+    /// The peer's long-term identity: known up front as initiator, learned
+    /// from the INITIATE as responder.
+    pub(crate) remote_identity: Mutex<Option<crate::crypto::PublicKey>>,
+    /// Detach streams (rather than close them) when the channel goes away.
+    detach_on_idle: bool,
+    /// This host accepts stream compression, from the host config.
+    compression_enabled: bool,
+    datagrams_enabled: bool,
+    /// Congestion-window observer, from the host config.
+    cwnd_hook: Option<CwndHook>,
+    /// Smoothed RTT in microseconds (0 while unsampled), readable without
+    /// the core lock for multipath path selection.
+    srtt_hint: std::sync::atomic::AtomicU64,
+    /// Host buffer pool handed to every stream on this channel.
+    pool: Arc<BufferPool>,
+    /// Host-wide frame counters, bumped as frames are placed in and taken
+    /// out of packets.
+    stats: Arc<FrameCounters>,
+    /// Concurrent locally-opened substream cap, from the host config.
+    pub(crate) max_substreams: usize,
+    /// Whether new streams start with Nagle coalescing, from the host
+    /// config.
+    default_nagle: bool,
+    /// Scheduling priority new streams start with, from the host config.
+    default_priority: u32,
+    /// Checksum-only message integrity instead of encryption.
+    #[cfg(feature = "insecure-loopback")]
+    insecure: bool,
+    cbox: SalsaBox,
+    /// Header-protection key masking the cleartext sequence field.
+    hp_key: [u8; 32],
+    /// Header-authentication key when this host opted into authenticated
+    /// headers; `None` leaves the cleartext prefix unauthenticated.
+    header_auth: Option<[u8; 32]>,
+    /// The deadline currently armed in the host's timer wheel, so the pump
+    /// re-arms only when it needs an earlier wakeup.
+    armed: Mutex<Option<Instant>>,
+}
+
+impl ChannelShared {
+    pub(crate) fn new(
+        host: &Arc<HostInner>,
+        socket: Arc<crate::socket::Socket>,
+        role: Role,
+        local_short: &ShortTermKey,
+        remote_key: [u8; 32],
+        remote_addr: SocketAddr,
+    ) -> Arc<Self> {
+        let idle_timeout = host.cfg.idle_timeout;
+        let packet_size = host.cfg.packet_size;
+        let local_key = local_short.public();
+        let (initiator_key, responder_key) = match role {
+            Role::Initiator => (local_key, remote_key),
+            Role::Responder => (remote_key, local_key),
+        };
+        let cbox = SalsaBox::new(&crypto_box::PublicKey::from(remote_key), local_short.secret());
+        let i2r = crypto::half_channel_id(&initiator_key, &responder_key, b'I');
+        let r2i = crypto::half_channel_id(&initiator_key, &responder_key, b'R');
+        let (tx_half_channel, rx_half_channel) = match role {
+            Role::Initiator => (i2r, r2i),
+            Role::Responder => (r2i, i2r),
+        };
+        let now = Instant::now();
+        // The header-authentication tag rides outside the message box, so
+        // it comes out of the packet size budget up front.
+        let header_tag_len = if host.cfg.authenticate_headers {
+            crypto::BOX_OVERHEAD
+        } else {
+            0
+        };
+        let mut packetizer = Packetizer::new(packet_size - header_tag_len);
+        packetizer.set_pad_sizes(host.cfg.pad_sizes.clone());
+        Arc::new(ChannelShared {
+            core: Mutex::new(ChannelCore {
+                remote_addr,
+                next_seq: 0,
+                sent: BTreeMap::new(),
+                ctrl: VecDeque::new(),
+                streams: HashMap::new(),
+                lsids: StreamIdAllocator::new(role),
+                next_usid: 0,
+                tx_half_channel,
+                rx_half_channel,
+                rx_contiguous: 0,
+                rx_above: BTreeSet::new(),
+                rx_largest: 0,
+                rx_largest_at: now,
+                ack_deadline: None,
+                cc: decongestion::make(CongestionAlg::TcpCubic),
+                warm_start: None,
+                packetizer,
+                mtu: if host.cfg.mtu_probing {
+                    MtuState::new(packet_size, host.cfg.packet_ceiling)
+                } else {
+                    MtuState::fixed(packet_size)
+                },
+                srtt: None,
+                rttvar: Duration::ZERO,
+                latest_rtt: None,
+                min_rtt: None,
+                ack_delay_exponent: host.cfg.ack_delay_exponent,
+                max_ack_delay: host.cfg.max_ack_delay,
+                peer_ack_delay_exponent: 0,
+                peer_max_ack_delay: None,
+                rto_backoff: 1,
+                rto_strikes: 0,
+                max_retransmits: host.cfg.max_retransmits,
+                unresponsive_timeout: host.cfg.unresponsive_timeout,
+                reassembly_limit: host.cfg.reassembly_limit,
+                reassembly_pressure: false,
+                last_heard: now,
+                compression: false,
+                header_tag_len,
+                datagrams: host.cfg.datagrams,
+                peer_stream_limit: None,
+                peer_idle_timeout: None,
+                pto_backoff: 1,
+                idle_timeout,
+                idle_deadline: now + idle_timeout,
+                handshake: None,
+                raw_out: Vec::new(),
+                closing: false,
+                bytes_sent: 0,
+                conn_bytes_sent: 0,
+                conn_send_limit: None,
+                conn_recv_window: None,
+                conn_consumed: 0,
+                conn_granted: 0,
+                dgram_out: VecDeque::new(),
+                next_dgram_id: 1,
+                dgram_in: VecDeque::new(),
+                dgram_seen: VecDeque::new(),
+                dgram_wakers: Vec::new(),
+                validated: matches!(role, Role::Initiator),
+                pre_rx: 0,
+                pre_tx: 0,
+                held: VecDeque::new(),
+                hibernate: false,
+                abort: None,
+                going_away: false,
+                pending_init: BTreeSet::new(),
+                cwnd_events: Vec::new(),
+                cwnd_observed: host.cfg.on_cwnd_change.is_some(),
+                open_local: 0,
+                open_queue: VecDeque::new(),
+                next_open_ticket: 0,
+                pump_done: false,
+            }),
+            notify: Notify::new(),
+            socket,
+            host: Arc::downgrade(host),
+            role,
+            local_key,
+            remote_key,
+            remote_identity: Mutex::new(None),
+            detach_on_idle: host.cfg.detach_on_idle,
+            compression_enabled: host.cfg.compression,
+            datagrams_enabled: host.cfg.datagrams,
+            cwnd_hook: host.cfg.on_cwnd_change.clone(),
+            srtt_hint: std::sync::atomic::AtomicU64::new(0),
+            pool: host.pool.clone(),
+            stats: host.frame_counters.clone(),
+            max_substreams: host.cfg.max_substreams,
+            default_nagle: host.cfg.default_nagle,
+            default_priority: host.cfg.default_priority,
+            #[cfg(feature = "insecure-loopback")]
+            insecure: host.cfg.insecure_loopback,
+            hp_key: crypto::header_protection_key(&cbox),
+            header_auth: host
+                .cfg
+                .authenticate_headers
+                .then(|| crypto::header_auth_key(&cbox)),
+            cbox,
+            armed: Mutex::new(None),
+        })
+    }
+
+    /// Invoke the host's congestion-window observer outside the core lock,
+    /// so the callback is free to call back into the host.
+    fn fire_cwnd_events(&self, events: Vec<CwndEvent>) {
+        if let Some(hook) = &self.cwnd_hook {
+            for event in events {
+                hook(event);
+            }
+        }
+    }
+
+    /// Smoothed RTT estimate in microseconds; 0 while unsampled.
+    pub(crate) fn srtt_hint(&self) -> u64 {
+        self.srtt_hint.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, ChannelCore> {
+        self.core.lock().unwrap()
+    }
+
+    /// The host's timer wheel reached this channel's armed deadline: clear
+    /// the arm record and wake the pump.
+    pub(crate) fn timer_fired(&self) {
+        *self.armed.lock().unwrap() = None;
+        self.notify.notify_one();
+    }
+
+    fn tx_nonce_prefix(&self) -> &'static [u8; 16] {
+        match self.role {
+            Role::Initiator => NONCE_PREFIX_CLIENT_MESSAGE,
+            Role::Responder => NONCE_PREFIX_SERVER_MESSAGE,
+        }
+    }
+
+    fn rx_nonce_prefix(&self) -> &'static [u8; 16] {
+        match self.role {
+            Role::Initiator => NONCE_PREFIX_SERVER_MESSAGE,
+            Role::Responder => NONCE_PREFIX_CLIENT_MESSAGE,
+        }
+    }
+
+    fn seal_message(&self, nonce: &[u8; crypto::NONCE_SIZE], payload: &[u8]) -> Vec<u8> {
+        #[cfg(feature = "insecure-loopback")]
+        if self.insecure {
+            return crypto::seal_insecure(nonce, payload);
+        }
+        crypto::seal(&self.cbox, nonce, payload)
+    }
+
+    fn open_message(&self, nonce: &[u8; crypto::NONCE_SIZE], boxed: &[u8]) -> Result<Vec<u8>> {
+        #[cfg(feature = "insecure-loopback")]
+        if self.insecure {
+            return crypto::open_insecure(nonce, boxed);
+        }
+        crypto::open(&self.cbox, nonce, boxed)
+    }
+
+    /// Seal a message payload into a full MESSAGE datagram. The sequence
+    /// field is masked with the header-protection key after sealing, so the
+    /// nonce uses the real value but the wire carries an obfuscated one.
+    pub(crate) fn seal_packet(&self, seq: u64, payload: &[u8]) -> Vec<u8> {
+        let mut compressed = seq.to_be_bytes();
+        let nonce = crypto::nonce(self.tx_nonce_prefix(), &compressed);
+        let boxed = self.seal_message(&nonce, payload);
+        let mask = crypto::header_mask(&self.hp_key, &boxed[..crypto::BOX_OVERHEAD]);
+        for (byte, m) in compressed.iter_mut().zip(mask) {
+            *byte ^= m;
+        }
+        let mut datagram = Vec::with_capacity(64 + boxed.len());
+        datagram.extend_from_slice(MAGIC_MESSAGE);
+        datagram.extend_from_slice(&self.local_key);
+        datagram.extend_from_slice(&compressed);
+        if let Some(key) = &self.header_auth {
+            let tag = crypto::header_tag(key, &datagram);
+            datagram.extend_from_slice(&tag);
+        }
+        datagram.extend_from_slice(&boxed);
+        datagram
+    }
+
+    /// Open a new stream as a substream of `parent_lsid` (our ID space).
+    pub(crate) fn open_stream(self: &Arc<Self>, parent_lsid: u32) -> Arc<StreamShared> {
+        let mut core = self.lock();
+        let lsid = core.alloc_lsid();
+        let usid = Usid {
+            half_channel: core.tx_half_channel,
+            counter: core.next_usid,
+        };
+        core.next_usid += 1;
+        let stream = StreamShared::new(lsid, parent_lsid, usid, Arc::downgrade(self), self.pool.clone());
+        {
+            let mut s = stream.lock();
+            s.nagle = self.default_nagle;
+            s.priority = self.default_priority;
+        }
+        core.streams.insert(lsid, stream.clone());
+        drop(core);
+        self.register_usid(&stream);
+        stream
+    }
+
+    /// Credit bytes received from the (possibly spoofed) remote address
+    /// against the pre-validation amplification budget.
+    pub(crate) fn credit_unvalidated(&self, len: usize) {
+        let mut core = self.lock();
+        if !core.validated {
+            core.pre_rx += len as u64;
+            if !core.held.is_empty() {
+                drop(core);
+                self.notify.notify_one();
+            }
+        }
+    }
+
+    /// Record a stream in the host's USID index so a multipath attach from
+    /// the peer can find it. Stale entries are pruned as we go.
+    fn register_usid(&self, stream: &Arc<StreamShared>) {
+        if let Some(host) = self.host.upgrade() {
+            let mut index = host.usid_index.lock().unwrap();
+            index.retain(|_, w| w.strong_count() > 0);
+            index.insert(stream.usid, Arc::downgrade(stream));
+        }
+    }
+
+    /// Open a locally initiated substream, subject to the concurrency cap.
+    pub(crate) fn open_substream(self: &Arc<Self>, parent_lsid: u32) -> Result<Arc<StreamShared>> {
+        {
+            let mut core = self.lock();
+            if core.going_away {
+                return Err(Error::GoingAway);
+            }
+            if core.open_local >= self.max_substreams || !core.open_queue.is_empty() {
+                return Err(Error::SubstreamLimit);
+            }
+            core.open_local += 1;
+        }
+        let stream = self.open_stream(parent_lsid);
+        stream.lock().open_slot = true;
+        Ok(stream)
+    }
+
+    /// Open a substream, waiting for a free slot when the cap is reached.
+    /// Queued opens are granted in order as earlier substreams close.
+    pub(crate) async fn open_substream_queued(
+        self: &Arc<Self>,
+        parent_lsid: u32,
+    ) -> Result<Arc<StreamShared>> {
+        let ticket = {
+            let mut core = self.lock();
+            if core.going_away {
+                return Err(Error::GoingAway);
+            }
+            if core.open_local < self.max_substreams && core.open_queue.is_empty() {
+                core.open_local += 1;
+                None
+            } else {
+                if core.open_queue.len() >= OPEN_QUEUE_LIMIT {
+                    return Err(Error::SubstreamLimit);
+                }
+                let id = core.next_open_ticket;
+                core.next_open_ticket += 1;
+                core.open_queue.push_back((id, None));
+                Some(id)
+            }
+        };
+        if let Some(id) = ticket {
+            let mut guard = OpenTicket {
+                chan: self,
+                id,
+                granted: false,
+            };
+            std::future::poll_fn(|cx| {
+                let mut core = guard.chan.lock();
+                if core.pump_done || core.closing {
+                    return Poll::Ready(Err(Error::ConnectionClosed));
+                }
+                let front = core.open_queue.front().map(|(t, _)| *t);
+                if front == Some(guard.id) && core.open_local < guard.chan.max_substreams {
+                    core.open_queue.pop_front();
+                    core.open_local += 1;
+                    return Poll::Ready(Ok(()));
+                }
+                if let Some(entry) = core.open_queue.iter_mut().find(|(t, _)| *t == guard.id) {
+                    entry.1 = Some(cx.waker().clone());
+                }
+                Poll::Pending
+            })
+            .await?;
+            guard.granted = true;
+        }
+        let stream = self.open_stream(parent_lsid);
+        stream.lock().open_slot = true;
+        Ok(stream)
+    }
+
+    /// Attach `stream` to this channel as an additional multipath path.
+    /// The INIT (carrying the stream's USID) is repeated until acknowledged
+    /// so the peer can merge the paths.
+    pub(crate) fn attach_path(self: &Arc<Self>, stream: &Arc<StreamShared>) -> u32 {
+        let mut core = self.lock();
+        let lsid = core.alloc_lsid();
+        core.streams.insert(lsid, stream.clone());
+        core.pending_init.insert(lsid);
+        drop(core);
+        self.notify.notify_one();
+        lsid
+    }
+
+    /// Return a substream slot and hand it to the next queued open.
+    pub(crate) fn release_open_slot(&self) {
+        let waker = {
+            let mut core = self.lock();
+            core.open_local = core.open_local.saturating_sub(1);
+            core.open_queue.front_mut().and_then(|(_, w)| w.take())
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Like [`ChannelShared::open_stream`], with metadata attached to the
+    /// opening STREAM frame. Rejects metadata that cannot share a packet
+    /// with the frame header.
+    pub(crate) fn open_stream_with_metadata(
+        self: &Arc<Self>,
+        parent_lsid: u32,
+        meta: &[u8],
+    ) -> Result<Arc<StreamShared>> {
+        let budget = self.lock().packetizer.payload_budget();
+        // Header, metadata length prefix and room for at least one data byte.
+        if STREAM_FRAME_MAX_HEADER + 2 + meta.len() >= budget {
+            return Err(Error::Protocol(format!(
+                "open metadata of {} bytes does not fit in one packet",
+                meta.len()
+            )));
+        }
+        let stream = self.open_substream(parent_lsid)?;
+        stream.lock().metadata_out = Some(Bytes::copy_from_slice(meta));
+        Ok(stream)
+    }
+
+    pub(crate) fn queue_reset(
+        &self,
+        lsid: u32,
+        error_code: u32,
+        reason: &str,
+        final_offset: Option<u64>,
+    ) {
+        let mut core = self.lock();
+        core.ctrl.push_back(Frame::Reset {
+            lsid,
+            error_code,
+            reason: reason.to_string(),
+            final_offset,
+        });
+        drop(core);
+        self.notify.notify_one();
+    }
+
+    /// Whether both ends of this channel agreed to stream compression.
+    pub(crate) fn compression_negotiated(&self) -> bool {
+        self.lock().compression
+    }
+
+    /// Drop an aborted stream from the channel so the scheduler stops
+    /// visiting it; late frames for its LSID are ignored.
+    pub(crate) fn forget_stream(&self, lsid: u32) {
+        let mut core = self.lock();
+        if let Some(stream) = core.streams.remove(&lsid) {
+            // Keep the consumed-credit ledger whole across removal.
+            core.conn_consumed += std::mem::take(&mut stream.lock().conn_consumed_pending);
+            drop(core);
+            stream.clear_channel();
+        }
+    }
+
+    pub(crate) fn queue_stop_sending(&self, lsid: u32, error_code: u32) {
+        let mut core = self.lock();
+        core.ctrl.push_back(Frame::StopSending { lsid, error_code });
+        drop(core);
+        self.notify.notify_one();
+    }
+
+    /// Queue a bare STREAM frame advertising `window` for `lsid`, so an
+    /// override reaches the peer without waiting for data to ride on.
+    pub(crate) fn queue_window_advert(&self, lsid: u32, window: u32) {
+        let mut core = self.lock();
+        core.ctrl.push_back(Frame::Stream(StreamFrame {
+            lsid,
+            parent_lsid: None,
+            usid: None,
+            offset: 0,
+            fin: false,
+            no_ack: false,
+            record: false,
+            metadata: None,
+            window: Some(window),
+            data: Bytes::new(),
+        }));
+        drop(core);
+        self.notify.notify_one();
+    }
+
+    /// Bound unconsumed inbound payload across the whole channel; see
+    /// [`Stream::set_connection_window`].
+    pub(crate) fn set_conn_window(&self, bytes: usize) {
+        let mut core = self.lock();
+        core.conn_recv_window = Some(bytes);
+        drop(core);
+        self.notify.notify_one();
+    }
+
+    /// Queue an unreliable datagram; when the queue is full the oldest
+    /// one is shed rather than blocking. See [`Stream::send_datagram`].
+    ///
+    /// [`Stream::send_datagram`]: crate::Stream::send_datagram
+    pub(crate) fn send_datagram(&self, data: Bytes) -> Result<()> {
+        let mut core = self.lock();
+        if !core.datagrams {
+            return Err(Error::DatagramUnsupported);
+        }
+        // Header seq plus the frame's type, id and length prefix.
+        if data.len() + 8 + 11 > core.packetizer.payload_budget() {
+            return Err(Error::DatagramTooLarge);
+        }
+        if core.closing || core.pump_done {
+            return Err(Error::ConnectionClosed);
+        }
+        if core.dgram_out.len() >= DGRAM_QUEUE {
+            core.dgram_out.pop_front();
+        }
+        let id = core.next_dgram_id;
+        core.next_dgram_id += 1;
+        core.dgram_out.push_back((id, data));
+        drop(core);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Poll for the next received datagram, in arrival order.
+    pub(crate) fn poll_recv_datagram(&self, cx: &mut std::task::Context<'_>) -> Poll<Result<Bytes>> {
+        let mut core = self.lock();
+        if let Some(data) = core.dgram_in.pop_front() {
+            return Poll::Ready(Ok(data));
+        }
+        if core.closing || core.pump_done {
+            return Poll::Ready(Err(Error::ConnectionClosed));
+        }
+        core.dgram_wakers.push(cx.waker().clone());
+        Poll::Pending
+    }
+
+    pub(crate) fn queue_window_update(&self, lsid: u32, max_offset: u64) {
+        let mut core = self.lock();
+        core.ctrl.push_back(Frame::WindowUpdate { lsid, max_offset });
+        drop(core);
+        self.notify.notify_one();
+    }
+
+    /// Advertise how many more top-level streams this end will accept on
+    /// the channel, as accept-queue backpressure the peer honors on its
+    /// next connect.
+    pub(crate) fn advertise_stream_limit(&self, limit: u32) {
+        let mut core = self.lock();
+        core.ctrl.push_back(Frame::Settings(vec![Setting::StreamLimit(limit)]));
+        drop(core);
+        self.notify.notify_one();
+    }
+
+    /// Clamp this channel's packet size down to `size`; see
+    /// [`crate::Host::set_mtu`].
+    pub(crate) fn clamp_mtu(&self, size: usize) {
+        let mut core = self.lock();
+        core.mtu.clamp_to(size);
+        let packet_size = core.mtu.current() - core.header_tag_len;
+        core.packetizer.set_packet_size(packet_size);
+        drop(core);
+        self.notify.notify_one();
+    }
+
+    pub(crate) fn queue_priority(&self, lsid: u32, priority: u32) {
+        let mut core = self.lock();
+        core.ctrl.push_back(Frame::Priority { lsid, priority });
+        drop(core);
+        self.notify.notify_one();
+    }
+
+    /// Queue the initial SETTINGS frame, tracked as packet `seq` which was
+    /// carried inside the INITIATE packet.
+    pub(crate) fn track_initiate(&self, seq: u64, payload_len: usize, settings: Vec<Setting>, datagram: Vec<u8>) {
+        let mut core = self.lock();
+        core.sent.insert(
+            seq,
+            SentPacket {
+                sent_at: Instant::now(),
+                bytes: payload_len,
+                chunks: Vec::new(),
+                ctrl: vec![Frame::Settings(settings)],
+                inits: Vec::new(),
+                nacks: 0,
+                is_probe: false,
+            },
+        );
+        core.cc.on_sent(payload_len);
+        core.next_seq = seq + 1;
+        core.handshake = Some(HandshakeResend {
+            datagram,
+            next_at: Instant::now() + MIN_RTO,
+            interval: MIN_RTO,
+        });
+    }
+
+    /// Announce GOAWAY: the peer must stop opening new streams on this
+    /// channel, while streams already in flight run to completion.
+    pub(crate) fn go_away(&self) {
+        let mut core = self.lock();
+        core.ctrl.push_back(Frame::Settings(vec![Setting::GoAway]));
+        drop(core);
+        self.notify.notify_one();
+    }
+
+    /// Warm-start congestion control from a predecessor channel's exported
+    /// estimates, instead of probing up from the cold-start initial window.
+    /// The snapshot is kept so a later SETTINGS strategy swap re-applies it.
+    pub(crate) fn warm_start(&self, snapshot: &CongestionSnapshot) {
+        let mut core = self.lock();
+        core.warm_start = Some(*snapshot);
+        core.cc.import(snapshot);
+        if core.srtt.is_none() {
+            core.srtt = snapshot.srtt;
+            core.rttvar = snapshot.rttvar;
+        }
+    }
+
+    /// Abort the channel: a CLOSE with `error_code` goes out at once and
+    /// every stream on the channel is torn down immediately, surfacing the
+    /// connection-reset reason, with nothing drained or hibernated.
+    pub(crate) fn reset(&self, error_code: u32, reason: &str) {
+        let mut core = self.lock();
+        if core.closing {
+            return;
+        }
+        core.abort = Some((error_code, reason.to_string()));
+        core.hibernate = false;
+        drop(core);
+        self.close(error_code, reason);
+    }
+
+    /// Initiate a graceful channel close.
+    pub(crate) fn close(&self, error_code: u32, reason: &str) {
+        let mut core = self.lock();
+        if core.closing {
+            return;
+        }
+        core.closing = true;
+        let final_ack = core.build_ack(Instant::now());
+        core.ctrl.push_back(Frame::Close {
+            error_code,
+            reason: reason.to_string(),
+            final_ack,
+        });
+        drop(core);
+        self.notify.notify_one();
+    }
+
+    /// Best-effort synchronous close: seal a final CLOSE and push it out
+    /// without going through the pump, so a Drop or signal handler can tell
+    /// the peer before the process exits. The datagram may be lost like any
+    /// other; the peer's idle timeout remains the backstop.
+    pub(crate) fn close_now(&self, error_code: u32, reason: &str) {
+        let mut core = self.lock();
+        if core.pump_done {
+            return;
+        }
+        core.closing = true;
+        core.pump_done = true;
+        let seq = core.next_seq;
+        core.next_seq += 1;
+        let mut payload = Vec::new();
+        PacketHeader::new(seq).encode(&mut payload);
+        let final_ack = core.build_ack(Instant::now());
+        Frame::Close {
+            error_code,
+            reason: reason.to_string(),
+            final_ack,
+        }
+        .encode(&mut payload);
+        let (padding, empty) = core.packetizer.pad_payload(&mut payload);
+        self.stats.count_sent(FrameType::Close);
+        self.stats.add_sent(FrameType::Padding, padding);
+        self.stats.add_sent(FrameType::Empty, empty);
+        let remote_addr = core.remote_addr;
+        drop(core);
+        let _ = self
+            .socket
+            .try_send_to(&self.seal_packet(seq, &payload), remote_addr);
+        self.notify.notify_one();
+    }
+
+    /// Process a received MESSAGE datagram body (after magic and key).
+    pub(crate) fn process_message(self: &Arc<Self>, rest: &[u8], from: SocketAddr) -> Result<()> {
+        let tag_len = if self.header_auth.is_some() {
+            crypto::BOX_OVERHEAD
+        } else {
+            0
+        };
+        if rest.len() < 8 + tag_len + crypto::BOX_OVERHEAD {
+            return Err(Error::protocol("short message packet"));
+        }
+        let (protected, boxed) = rest.split_at(8);
+        let boxed = if let Some(key) = &self.header_auth {
+            let (tag, boxed) = boxed.split_at(crypto::BOX_OVERHEAD);
+            let mut prefix = Vec::with_capacity(48);
+            prefix.extend_from_slice(MAGIC_MESSAGE);
+            prefix.extend_from_slice(&self.remote_key);
+            prefix.extend_from_slice(protected);
+            if crypto::header_tag(key, &prefix) != tag[..] {
+                return Err(Error::Crypto);
+            }
+            boxed
+        } else {
+            boxed
+        };
+        self.credit_unvalidated(40 + rest.len());
+        let mask = crypto::header_mask(&self.hp_key, &boxed[..crypto::BOX_OVERHEAD]);
+        let mut compressed = [0u8; 8];
+        for (i, byte) in compressed.iter_mut().enumerate() {
+            *byte = protected[i] ^ mask[i];
+        }
+        let nonce = crypto::nonce(self.rx_nonce_prefix(), &compressed);
+        let payload = self.open_message(&nonce, boxed)?;
+        {
+            let mut core = self.lock();
+            if !core.validated {
+                core.validated = true;
+                drop(core);
+                self.notify.notify_one();
+            }
+        }
+        self.process_plaintext(&payload, from)
+    }
+
+    /// Process a decrypted packet payload: header, dedup, then frames.
+    pub(crate) fn process_plaintext(self: &Arc<Self>, payload: &[u8], _from: SocketAddr) -> Result<()> {
+        let now = Instant::now();
+        let Packet { header, frames } = Packet::decode(payload)?;
+
+        let mut core = self.lock();
+        core.idle_deadline = now + core.idle_timeout;
+        core.last_heard = now;
+        core.handshake = None;
+        if !core.record_rx(header.seq, now) {
+            // Duplicate: re-ack immediately in case our ack was lost.
+            core.ack_deadline = Some(now);
+            drop(core);
+            self.notify.notify_one();
+            return Ok(());
+        }
+        let mut ack_eliciting = false;
+        for frame in frames {
+            self.stats.count_received(frame.frame_type());
+            ack_eliciting |= is_eliciting(&frame);
+            self.handle_frame(&mut core, frame, now);
+        }
+        if ack_eliciting {
+            let deadline = now + core.max_ack_delay;
+            core.ack_deadline = Some(core.ack_deadline.map_or(deadline, |d| d.min(deadline)));
+        }
+        let cwnd_events = std::mem::take(&mut core.cwnd_events);
+        drop(core);
+        self.fire_cwnd_events(cwnd_events);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    fn handle_frame(self: &Arc<Self>, core: &mut ChannelCore, frame: Frame, now: Instant) {
+        match frame {
+            Frame::Empty | Frame::Padding(_) => {}
+            Frame::Stream(sf) => self.handle_stream_frame(core, sf),
+            Frame::Ack(ack) => {
+                core.on_ack_frame(&ack, now);
+                if let Some(srtt) = core.srtt {
+                    self.srtt_hint.store(
+                        srtt.as_micros() as u64,
+                        std::sync::atomic::Ordering::Relaxed,
+                    );
+                }
+            }
+            Frame::Reset {
+                lsid,
+                error_code,
+                reason,
+                final_offset,
+            } => {
+                let near = self.role.near_lsid(lsid);
+                if let Some(stream) = core.streams.get(&near) {
+                    let mut s = stream.lock();
+                    s.peer_final_offset = final_offset;
+                    s.apply_reset(error_code, reason);
+                    // Inline slot release: the channel core is already locked.
+                    if std::mem::take(&mut s.open_slot) {
+                        drop(s);
+                        core.open_local = core.open_local.saturating_sub(1);
+                        if let Some(w) = core.open_queue.front_mut().and_then(|(_, w)| w.take()) {
+                            w.wake();
+                        }
+                    }
+                }
+            }
+            Frame::StopSending { lsid, error_code } => {
+                let near = self.role.near_lsid(lsid);
+                if let Some(stream) = core.streams.get(&near) {
+                    stream.lock().apply_stop_sending(error_code);
+                }
+            }
+            Frame::WindowUpdate { lsid, max_offset } => {
+                if lsid == ROOT_LSID {
+                    // Connection-level: caps total fresh payload across
+                    // the channel, monotonically.
+                    if core.conn_send_limit.is_none_or(|cur| cur < max_offset) {
+                        core.conn_send_limit = Some(max_offset);
+                    }
+                } else {
+                    let near = self.role.near_lsid(lsid);
+                    if let Some(stream) = core.streams.get(&near) {
+                        stream.lock().apply_window_update(max_offset);
+                    }
+                }
+            }
+            Frame::Priority { lsid, priority } => {
+                let near = self.role.near_lsid(lsid);
+                if let Some(stream) = core.streams.get(&near) {
+                    stream.lock().priority = priority;
+                }
+            }
+            Frame::Datagram { id, data } => {
+                // Network copies of a recent datagram are suppressed; a
+                // full receive queue sheds the oldest, as loss would.
+                if !core.dgram_seen.contains(&id) {
+                    if core.dgram_seen.len() >= DGRAM_DEDUP_WINDOW {
+                        core.dgram_seen.pop_front();
+                    }
+                    core.dgram_seen.push_back(id);
+                    if core.dgram_in.len() >= DGRAM_QUEUE {
+                        core.dgram_in.pop_front();
+                    }
+                    core.dgram_in.push_back(data);
+                    for waker in core.dgram_wakers.drain(..) {
+                        waker.wake();
+                    }
+                }
+            }
+            Frame::Detach { lsid } => {
+                let near = self.role.near_lsid(lsid);
+                if !self.detach_on_idle {
+                    tracing::debug!(lsid, "ignoring DETACH frame (detach_on_idle disabled)");
+                } else if let Some(stream) = core.streams.remove(&near) {
+                    tracing::debug!(lsid, "stream detached by peer");
+                    stream.clear_channel();
+                    if let Some(host) = self.host.upgrade() {
+                        let peer = *self.remote_identity.lock().unwrap();
+                        let snapshot = core.congestion_snapshot();
+                        host.hibernate_streams(
+                            self.role,
+                            peer,
+                            core.remote_addr,
+                            vec![stream],
+                            snapshot,
+                        );
+                    }
+                }
+            }
+            Frame::Decongestion(_) => {}
+            Frame::Settings(settings) => {
+                for setting in settings {
+                    match setting {
+                        Setting::CongestionControl(alg) => {
+                            if let Some(alg) = CongestionAlg::from_wire(alg) {
+                                core.cc = decongestion::make(alg);
+                                if let Some(snapshot) = core.warm_start {
+                                    core.cc.import(&snapshot);
+                                }
+                            }
+                        }
+                        Setting::GoAway => {
+                            tracing::debug!("peer is going away");
+                            core.going_away = true;
+                        }
+                        Setting::Fec(_) => {}
+                        Setting::Compression(on) => match self.role {
+                            // The initiator offered; agree only if this
+                            // host opted in too, and confirm either way.
+                            Role::Responder => {
+                                let agree = on && self.compression_enabled;
+                                core.compression = agree;
+                                core.ctrl
+                                    .push_back(Frame::Settings(vec![Setting::Compression(agree)]));
+                            }
+                            Role::Initiator => core.compression = on,
+                        },
+                        Setting::StreamLimit(n) => core.peer_stream_limit = Some(n),
+                        Setting::Datagram(on) => match self.role {
+                            // The effective feature set is the intersection
+                            // of what the two ends advertise.
+                            Role::Responder => {
+                                let agree = on && self.datagrams_enabled;
+                                core.datagrams = agree;
+                                core.ctrl
+                                    .push_back(Frame::Settings(vec![Setting::Datagram(agree)]));
+                            }
+                            Role::Initiator => core.datagrams = on,
+                        },
+                        Setting::IdleTimeout(ms) => {
+                            core.peer_idle_timeout = Some(Duration::from_millis(ms.into()));
+                            // Timeouts are exchanged, not intersected: each
+                            // end just declares how long it will sit idle.
+                            if let Role::Responder = self.role {
+                                let ours = core.idle_timeout.as_millis() as u32;
+                                core.ctrl
+                                    .push_back(Frame::Settings(vec![Setting::IdleTimeout(ours)]));
+                            }
+                        }
+                        Setting::AckDelayExponent(exp) => {
+                            core.peer_ack_delay_exponent = exp;
+                            if let Role::Responder = self.role {
+                                let ours = core.ack_delay_exponent;
+                                core.ctrl.push_back(Frame::Settings(vec![
+                                    Setting::AckDelayExponent(ours),
+                                ]));
+                            }
+                        }
+                        Setting::MaxAckDelay(ms) => {
+                            core.peer_max_ack_delay = Some(Duration::from_millis(ms.into()));
+                            if let Role::Responder = self.role {
+                                let ours = core.max_ack_delay.as_millis() as u32;
+                                core.ctrl
+                                    .push_back(Frame::Settings(vec![Setting::MaxAckDelay(ours)]));
+                            }
+                        }
+                    }
+                }
+            }
+            Frame::Close {
+                error_code,
+                reason,
+                final_ack,
+            } => {
+                tracing::debug!(error_code, %reason, "channel closed by peer");
+                core.on_ack_frame(&final_ack, now);
+                if error_code != 0 {
+                    core.abort = Some((error_code, reason));
+                    core.hibernate = false;
+                }
+                core.closing = true;
+                core.pump_done = true;
+            }
+        }
+    }
+
+    fn handle_stream_frame(self: &Arc<Self>, core: &mut ChannelCore, sf: StreamFrame) {
+        let near = self.role.near_lsid(sf.lsid);
+        let stream = match core.streams.get(&near) {
+            Some(stream) => stream.clone(),
+            None => {
+                let Some(parent_far) = sf.parent_lsid else {
+                    tracing::debug!(lsid = sf.lsid, "frame for unknown stream without INIT");
+                    return;
+                };
+                // A peer may only initiate streams on its own parity side.
+                let peer_initiates = matches!(self.role, Role::Responder);
+                if StreamIdAllocator::initiator(sf.lsid) != peer_initiates {
+                    tracing::debug!(lsid = sf.lsid, "INIT with the wrong LSID parity");
+                    return;
+                }
+                self.accept_stream(
+                    core,
+                    near,
+                    self.role.near_lsid(parent_far),
+                    sf.usid,
+                    sf.metadata.clone(),
+                )
+            }
+        };
+        let mut s = stream.lock();
+        if let Some(window) = sf.window {
+            // The peer's receive window caps what we keep in flight there.
+            s.send_window = window as usize;
+            s.wake_writers();
+        }
+        s.receive(sf.offset, sf.data, sf.fin, sf.record);
+        if s.pending_service {
+            if let Some(record) = s.recv.read_record() {
+                s.pending_service = false;
+                s.release_read(record.len());
+                self.dispatch_service(core, &mut s, &stream, &record);
+            }
+        }
+        drop(s);
+        self.enforce_reassembly_limit(core);
+    }
+
+    /// Keep out-of-order reassembly memory across the channel's streams
+    /// under the configured cap; see [crate::HostBuilder::reassembly_limit].
+    fn enforce_reassembly_limit(&self, core: &mut ChannelCore) {
+        let Some(limit) = core.reassembly_limit else {
+            return;
+        };
+        let total: usize = core
+            .streams
+            .values()
+            .map(|s| s.lock().recv.reorder_depth())
+            .sum();
+        if total <= limit / 2 {
+            core.reassembly_pressure = false;
+            return;
+        }
+        if !core.reassembly_pressure {
+            core.reassembly_pressure = true;
+            // Backpressure first: gapped streams advertise a quarter of
+            // their window so the peer slows before anything is dropped.
+            let mut adverts = Vec::new();
+            for (&lsid, stream) in &core.streams {
+                let s = stream.lock();
+                if s.recv.reorder_depth() > 0 && s.window_override.is_none() {
+                    adverts.push((lsid, (s.recv_window / 4) as u32));
+                }
+            }
+            for (lsid, window) in adverts {
+                core.ctrl.push_back(Frame::Stream(StreamFrame {
+                    lsid,
+                    parent_lsid: None,
+                    usid: None,
+                    offset: 0,
+                    fin: false,
+                    no_ack: false,
+                    record: false,
+                    metadata: None,
+                    window: Some(window),
+                    data: Bytes::new(),
+                }));
+            }
+        }
+        if total <= limit {
+            return;
+        }
+        // Last resort: reset the gapped stream whose reassembly advanced
+        // least recently, reclaiming its buffers like [Stream::abort].
+        let victim = core
+            .streams
+            .iter()
+            .filter(|(_, stream)| {
+                let s = stream.lock();
+                s.recv.reorder_depth() > 0 && s.reset.is_none()
+            })
+            .min_by_key(|(_, stream)| stream.lock().last_progress)
+            .map(|(&lsid, stream)| (lsid, stream.clone()));
+        let Some((lsid, stream)) = victim else {
+            return;
+        };
+        tracing::debug!(lsid, total, limit, "reassembly cap hit, resetting the stalest stream");
+        let mut s = stream.lock();
+        s.apply_reset(0, "reassembly memory limit exceeded".to_string());
+        s.aborted = true;
+        let held = s.outstanding + s.recv.readable_len();
+        s.outstanding = 0;
+        s.recv = crate::stream::Reassembly::new();
+        s.pool.discharge(held);
+        if std::mem::take(&mut s.open_slot) {
+            core.open_local = core.open_local.saturating_sub(1);
+            if let Some(w) = core.open_queue.front_mut().and_then(|(_, w)| w.take()) {
+                w.wake();
+            }
+        }
+        drop(s);
+        // Address the peer by this channel's map key: a multipath-merged
+        // stream keeps its original channel's id in `stream.lsid`.
+        core.ctrl.push_back(Frame::Reset {
+            lsid,
+            error_code: 0,
+            reason: "reassembly memory limit exceeded".to_string(),
+            final_offset: None,
+        });
+    }
+
+    /// Register a stream the peer initiated.
+    fn accept_stream(
+        self: &Arc<Self>,
+        core: &mut ChannelCore,
+        near: u32,
+        parent_near: u32,
+        usid: Option<Usid>,
+        metadata: Option<Bytes>,
+    ) -> Arc<StreamShared> {
+        let usid = usid.unwrap_or(Usid {
+            half_channel: core.rx_half_channel,
+            counter: u64::from(near),
+        });
+        // A known USID is a multipath attach: route this channel's frames
+        // into the existing stream instead of creating a second one.
+        if let Some(host) = self.host.upgrade() {
+            let existing = host
+                .usid_index
+                .lock()
+                .unwrap()
+                .get(&usid)
+                .and_then(std::sync::Weak::upgrade);
+            if let Some(existing) = existing {
+                if existing.channel().is_none() {
+                    // A hibernated stream coming back over a new channel.
+                    existing.set_channel(self);
+                    host.unhibernate(&existing);
+                }
+                core.streams.insert(near, existing.clone());
+                core.lsids.reserve(near);
+                return existing;
+            }
+        }
+        let stream = StreamShared::new(near, parent_near, usid, Arc::downgrade(self), self.pool.clone());
+        {
+            let mut s = stream.lock();
+            s.open_metadata = metadata;
+            s.nagle = self.default_nagle;
+            s.priority = self.default_priority;
+        }
+        core.streams.insert(near, stream.clone());
+        self.register_usid(&stream);
+        // Inbound pairs land on our parity; never re-allocate their LSID.
+        core.lsids.reserve(near);
+        if parent_near == ROOT_LSID {
+            stream.lock().pending_service = true;
+        } else if let Some(parent) = core.streams.get(&parent_near) {
+            let mut p = parent.lock();
+            p.incoming_subs.push_back(Stream::new(stream.clone()));
+            p.wake_readers();
+        }
+        stream
+    }
+
+    /// Handle the service request record opening a new top-level stream.
+    fn dispatch_service(
+        self: &Arc<Self>,
+        core: &mut ChannelCore,
+        s: &mut crate::stream::StreamCore,
+        stream: &Arc<StreamShared>,
+        record: &[u8],
+    ) {
+        let reply = match service::decode_connect_request(record) {
+            Ok((svc, proto)) => {
+                let listener = self
+                    .host
+                    .upgrade()
+                    .and_then(|host| host.listeners.lock().unwrap().get(&(svc.clone(), proto.clone())).cloned());
+                match listener {
+                    Some(tx) => {
+                        let reply = match tx.try_send(Stream::new(stream.clone())) {
+                            Ok(()) => {
+                                s.service = Some((svc, proto));
+                                service::encode_connect_reply(service::STATUS_OK, "ok")
+                            }
+                            Err(_) => {
+                                service::encode_connect_reply(service::STATUS_NOT_FOUND, "accept queue full")
+                            }
+                        };
+                        // Tell the peer how much accept headroom is left,
+                        // so it slows connects before they are refused.
+                        core.ctrl.push_back(Frame::Settings(vec![Setting::StreamLimit(
+                            tx.capacity() as u32,
+                        )]));
+                        reply
+                    }
+                    None => service::encode_connect_reply(
+                        service::STATUS_NOT_FOUND,
+                        "no such service",
+                    ),
+                }
+            }
+            Err(_) => service::encode_connect_reply(service::STATUS_NOT_FOUND, "malformed request"),
+        };
+        s.queue_record(&reply);
+        self.notify.notify_one();
+    }
+
+    /// Assemble and encrypt as many packets as current state allows.
+    fn assemble(&self, core: &mut ChannelCore, now: Instant) -> Vec<Vec<u8>> {
+        let mut out = std::mem::take(&mut core.raw_out);
+        if core.pump_done {
+            return out;
+        }
+
+        // Connection-level flow control: collect the credit applications
+        // consumed and grant the peer more once a quarter of the window
+        // has accrued.
+        if let Some(window) = core.conn_recv_window {
+            let mut fresh = 0;
+            for stream in core.streams.values() {
+                fresh += std::mem::take(&mut stream.lock().conn_consumed_pending);
+            }
+            core.conn_consumed += fresh;
+            let grant = core.conn_consumed + window as u64;
+            if grant >= core.conn_granted + (window as u64 / 4).max(1) {
+                core.ctrl.push_back(Frame::WindowUpdate {
+                    lsid: ROOT_LSID,
+                    max_offset: grant,
+                });
+                core.conn_granted = grant;
+            }
+        }
+
+        // MTU probe: a padded packet of the candidate size.
+        if let Some(size) = core.mtu.probe_due(now) {
+            let seq = core.next_seq;
+            core.next_seq += 1;
+            let mut payload = Vec::new();
+            PacketHeader::new(seq).encode(&mut payload);
+            let (padding, empty) =
+                Packetizer::pad_to(&mut payload, size - crate::packetizer::PACKET_OVERHEAD);
+            self.stats.add_sent(FrameType::Padding, padding);
+            self.stats.add_sent(FrameType::Empty, empty);
+            core.sent.insert(
+                seq,
+                SentPacket {
+                    sent_at: now,
+                    bytes: payload.len(),
+                    chunks: Vec::new(),
+                    ctrl: Vec::new(),
+                    inits: Vec::new(),
+                    nacks: 0,
+                    is_probe: true,
+                },
+            );
+            core.cc.on_sent(payload.len());
+            core.mtu.probe_sent(seq, size);
+            out.push(self.seal_packet(seq, &payload));
+        }
+
+        loop {
+            let budget = core.packetizer.payload_budget();
+            let mut payload = Vec::with_capacity(budget);
+            let seq = core.next_seq;
+            PacketHeader::new(seq).encode(&mut payload);
+            let header_len = payload.len();
+            let full_frame = budget - header_len;
+
+            let want_ack = core.ack_deadline.is_some();
+            let ack_due = core.ack_deadline.is_some_and(|d| d <= now);
+            let mut ack_included = false;
+            if want_ack {
+                let ack = core.build_ack(now);
+                if payload.len() + 1 + ack.wire_size() <= budget {
+                    Frame::Ack(ack).encode(&mut payload);
+                    ack_included = true;
+                }
+            }
+
+            let mut ctrl_sent = Vec::new();
+            let mut eliciting = false;
+            while let Some(frame) = core.ctrl.pop_front() {
+                let mut tmp = Vec::new();
+                frame.encode(&mut tmp);
+                if payload.len() + tmp.len() > budget {
+                    core.ctrl.push_front(frame);
+                    break;
+                }
+                payload.extend_from_slice(&tmp);
+                eliciting = true;
+                if let Frame::Close { .. } = frame {
+                    core.pump_done = true;
+                }
+                self.stats.count_sent(frame.frame_type());
+                ctrl_sent.push(frame);
+            }
+
+            // Datagrams ride once and are never recorded for
+            // retransmission: a lost packet simply loses them.
+            while let Some((id, data)) = core.dgram_out.pop_front() {
+                let frame = Frame::Datagram { id, data };
+                let mut tmp = Vec::new();
+                frame.encode(&mut tmp);
+                if payload.len() + tmp.len() > budget {
+                    let Frame::Datagram { id, data } = frame else {
+                        unreachable!()
+                    };
+                    core.dgram_out.push_front((id, data));
+                    break;
+                }
+                payload.extend_from_slice(&tmp);
+                eliciting = true;
+                self.stats.count_sent(FrameType::Datagram);
+            }
+
+            let mut chunks: Vec<(Arc<StreamShared>, Chunk)> = Vec::new();
+            let mut stream_bytes = 0usize;
+            let mut inits_sent = Vec::new();
+            if !core.closing && core.cc.may_send(full_frame) {
+                let mut floor = u64::MAX;
+                let ready: Vec<(u32, u32, Arc<StreamShared>)> = core
+                    .streams
+                    .iter()
+                    .filter_map(|(&lsid, stream)| {
+                        let mut s = stream.lock();
+                        if !s.sendable(full_frame) {
+                            s.sched_backlogged = false;
+                            return None;
+                        }
+                        if s.sched_backlogged {
+                            floor = floor.min(s.sched_debt);
+                        }
+                        Some((s.priority, lsid, stream.clone()))
+                    })
+                    .collect();
+                let mut order: Vec<(u32, u64, u32, Arc<StreamShared>)> = ready
+                    .into_iter()
+                    .map(|(priority, lsid, stream)| {
+                        // A stream joining the backlog starts level with the
+                        // least-served backlogged one, so time spent idle
+                        // earns no catch-up burst.
+                        let mut s = stream.lock();
+                        if !s.sched_backlogged {
+                            s.sched_backlogged = true;
+                            if floor != u64::MAX {
+                                s.sched_debt = s.sched_debt.max(floor);
+                            }
+                        }
+                        let debt = s.sched_debt;
+                        drop(s);
+                        (priority, debt, lsid, stream)
+                    })
+                    .collect();
+                // The service order is total and documented: priority,
+                // then weighted debt, then LSID -- and LSIDs grow in
+                // creation order. No key depends on hash-map iteration,
+                // so the same inputs produce the same wire bytes; golden
+                // packet tests rely on this.
+                order.sort_by_key(|&(priority, debt, lsid, _)| (priority, debt, lsid));
+                'streams: for (_, _, lsid, stream) in order {
+                    if !stream.path_allows(self) {
+                        continue;
+                    }
+                    loop {
+                        let remaining = budget - payload.len();
+                        if remaining <= STREAM_FRAME_MAX_HEADER {
+                            break 'streams;
+                        }
+                        // A multipath attach sends INIT with the USID until
+                        // acknowledged, so the peer can merge the paths.
+                        let attach_init = core.pending_init.contains(&lsid);
+                        let mut s = stream.lock();
+                        // The stream's own channel repeats INIT (and any open
+                        // metadata) on every frame until one is acknowledged,
+                        // so a lost attach cannot strand the stream.
+                        let primary_init = !s.init_acked
+                            && lsid == stream.lsid
+                            && stream.is_primary(self);
+                        let metadata = primary_init.then(|| s.metadata_out.clone()).flatten();
+                        let mut head_cost = metadata.as_ref().map_or(0, |m| 2 + m.len());
+                        if attach_init || primary_init {
+                            head_cost += Usid::WIRE_SIZE;
+                        }
+                        if s.window_override.is_some() {
+                            head_cost += 4;
+                        }
+                        if remaining <= STREAM_FRAME_MAX_HEADER + head_cost {
+                            break;
+                        }
+                        let allowed = s.rate_allowance(now);
+                        if allowed == 0 {
+                            break;
+                        }
+                        // The connection-level limit binds fresh data
+                        // only; retransmissions re-cover offsets that
+                        // were already counted.
+                        let conn_left = match (s.has_rtx(), core.conn_send_limit) {
+                            (false, Some(limit)) => {
+                                usize::try_from(limit.saturating_sub(core.conn_bytes_sent))
+                                    .unwrap_or(usize::MAX)
+                            }
+                            _ => usize::MAX,
+                        };
+                        if conn_left == 0 {
+                            break;
+                        }
+                        let fresh_before = s.payload_bytes;
+                        let chunk = if s.sendable(full_frame) {
+                            s.next_chunk(
+                                (remaining - STREAM_FRAME_MAX_HEADER - head_cost)
+                                    .min(allowed)
+                                    .min(conn_left),
+                            )
+                        } else {
+                            None
+                        };
+                        let Some(chunk) = chunk else { break };
+                        core.conn_bytes_sent += s.payload_bytes - fresh_before;
+                        // Charge the weighted scheduler: debt grows with the
+                        // bytes sent, scaled down by the stream's weight.
+                        s.sched_debt +=
+                            ((chunk.data.len() as u64) << 16) / u64::from(s.weight.max(1));
+                        s.rate_consume(chunk.data.len());
+                        let frame = StreamFrame {
+                            lsid,
+                            parent_lsid: (attach_init || primary_init)
+                                .then(|| if attach_init { ROOT_LSID } else { s.parent_lsid }),
+                            usid: (attach_init || primary_init).then_some(stream.usid),
+                            offset: chunk.offset,
+                            fin: chunk.fin,
+                            no_ack: false,
+                            record: chunk.record,
+                            metadata,
+                            window: s.window_override.map(|w| w as u32),
+                            data: chunk.data.clone(),
+                        };
+                        drop(s);
+                        if attach_init {
+                            inits_sent.push(lsid);
+                        }
+                        let frame_start = payload.len();
+                        Frame::Stream(frame).encode(&mut payload);
+                        stream_bytes += payload.len() - frame_start;
+                        stream.lock().overhead_bytes +=
+                            (payload.len() - frame_start - chunk.data.len()) as u64;
+                        self.stats.count_sent(FrameType::Stream);
+                        chunks.push((stream.clone(), chunk));
+                        stream.note_path_used();
+                        eliciting = true;
+                    }
+                }
+            }
+
+            // Nothing worth a packet: only send a bare ack when it is due.
+            if !(eliciting || (want_ack && ack_due)) {
+                break;
+            }
+            if payload.len() == header_len {
+                break;
+            }
+            core.next_seq += 1;
+            core.ack_deadline = None;
+            if ack_included {
+                self.stats.count_sent(FrameType::Ack);
+            }
+            let (padding, empty) = core.packetizer.pad_payload(&mut payload);
+            self.stats.add_sent(FrameType::Padding, padding);
+            self.stats.add_sent(FrameType::Empty, empty);
+            // Attribute the packet's fixed costs -- header, acks, control
+            // frames, padding and the crypto envelope -- to the streams
+            // aboard, proportional to their payload bytes.
+            if !chunks.is_empty() {
+                let fixed = payload.len() + crate::packetizer::PACKET_OVERHEAD - stream_bytes;
+                let data_total: usize = chunks.iter().map(|(_, c)| c.data.len()).sum();
+                for (stream, chunk) in &chunks {
+                    let share = fixed * chunk.data.len() / data_total.max(1);
+                    stream.lock().overhead_bytes += share as u64;
+                }
+            }
+            if eliciting {
+                core.cc.on_sent(payload.len());
+                core.bytes_sent += payload.len() as u64;
+                core.sent.insert(
+                    seq,
+                    SentPacket {
+                        sent_at: now,
+                        bytes: payload.len(),
+                        chunks,
+                        ctrl: ctrl_sent,
+                        inits: inits_sent,
+                        nacks: 0,
+                        is_probe: false,
+                    },
+                );
+            }
+            out.push(self.seal_packet(seq, &payload));
+            if core.pump_done {
+                break;
+            }
+        }
+        out
+    }
+
+    fn handle_timers(&self, core: &mut ChannelCore, now: Instant) {
+        if now >= core.idle_deadline {
+            if self.detach_on_idle && !core.closing {
+                // Detach every stream, tell the peer, and let teardown
+                // hibernate them once the CLOSE goes out.
+                tracing::debug!("channel idle timeout, detaching streams");
+                core.hibernate = true;
+                core.closing = true;
+                let lsids: Vec<u32> = core.streams.keys().copied().collect();
+                for lsid in lsids {
+                    core.ctrl.push_back(Frame::Detach { lsid });
+                }
+                let final_ack = core.build_ack(now);
+                core.ctrl.push_back(Frame::Close {
+                    error_code: 0,
+                    reason: "idle".to_string(),
+                    final_ack,
+                });
+                return;
+            }
+            tracing::debug!("channel idle timeout");
+            core.pump_done = true;
+            return;
+        }
+        if let Some(hs) = &mut core.handshake {
+            if now >= hs.next_at {
+                let datagram = hs.datagram.clone();
+                hs.interval = (hs.interval * 2).min(MAX_RTO);
+                hs.next_at = now + hs.interval;
+                core.raw_out.push(datagram);
+            }
+        }
+        // Half-open detection: packets outstanding, peer silent past the
+        // configured window -- the peer is gone, not just slow.
+        if let Some(limit) = core.unresponsive_timeout {
+            if !core.sent.is_empty() && now.duration_since(core.last_heard) >= limit {
+                tracing::debug!("unresponsive timeout, declaring the peer dead");
+                core.abort = Some((0, "unresponsive timeout: peer silent".to_string()));
+                core.hibernate = false;
+                core.pump_done = true;
+                return;
+            }
+        }
+        // Probe timeout: fire a tail-loss probe well before the RTO. The
+        // handshake phase has its own resend timer above.
+        if core.handshake.is_none() {
+            let newest = core
+                .sent
+                .values()
+                .filter(|p| !p.is_probe)
+                .map(|p| p.sent_at)
+                .max();
+            if newest.is_some_and(|at| now.duration_since(at) >= core.pto()) {
+                core.on_probe_timeout();
+            }
+        }
+        // Retransmission timeout: declare overdue packets lost.
+        let rto = core.rto();
+        let overdue: Vec<u64> = core
+            .sent
+            .iter()
+            .filter(|(_, p)| now.duration_since(p.sent_at) >= rto)
+            .map(|(&seq, _)| seq)
+            .collect();
+        if !overdue.is_empty() {
+            core.rto_strikes += 1;
+            if core
+                .max_retransmits
+                .is_some_and(|max| core.rto_strikes > max)
+            {
+                tracing::debug!(
+                    strikes = core.rto_strikes,
+                    "retransmit limit reached, declaring the peer unresponsive"
+                );
+                core.abort = Some((0, "retransmit timeout: peer unresponsive".to_string()));
+                core.hibernate = false;
+                core.pump_done = true;
+                return;
+            }
+            core.rto_backoff = (core.rto_backoff * 2).min(32);
+            for seq in overdue {
+                core.on_lost(seq, CwndCause::Timeout, now);
+            }
+        }
+    }
+
+    fn next_deadline(&self, core: &ChannelCore, now: Instant) -> Instant {
+        let mut deadline = core.idle_deadline;
+        if let Some(d) = core.ack_deadline {
+            deadline = deadline.min(d);
+        }
+        if let Some(hs) = &core.handshake {
+            deadline = deadline.min(hs.next_at);
+        }
+        if let Some(p) = core.sent.values().map(|p| p.sent_at).min() {
+            deadline = deadline.min(p + core.rto());
+        }
+        if core.handshake.is_none() {
+            let newest = core
+                .sent
+                .values()
+                .filter(|p| !p.is_probe)
+                .map(|p| p.sent_at)
+                .max();
+            if let Some(at) = newest {
+                deadline = deadline.min(at + core.pto());
+            }
+        }
+        if let Some(at) = core.mtu.next_probe_at() {
+            deadline = deadline.min(at);
+        }
+        if let Some(limit) = core.unresponsive_timeout {
+            if !core.sent.is_empty() {
+                deadline = deadline.min(core.last_heard + limit);
+            }
+        }
+        // A stream held back only by its rate limiter wakes the pump once
+        // a packet's worth of tokens has accrued.
+        let full_frame = core.packetizer.payload_budget();
+        for stream in core.streams.values() {
+            if let Some(at) = stream.lock().rate_ready_at(full_frame) {
+                deadline = deadline.min(at);
+            }
+        }
+        deadline.max(now)
+    }
+
+    /// Tear the channel down: detach streams and unregister from the host.
+    /// With detach-on-idle the streams hibernate instead of closing, and
+    /// their unacknowledged chunks go back to the retransmission queues.
+    pub(crate) fn teardown(self: &Arc<Self>) {
+        let (streams, queued, hibernate, sent, remote_addr, abort, snapshot) = {
+            let mut core = self.lock();
+            core.pump_done = true;
+            let streams: Vec<Arc<StreamShared>> = core.streams.drain().map(|(_, s)| s).collect();
+            let mut queued: Vec<Waker> = core.open_queue.drain(..).filter_map(|(_, w)| w).collect();
+            queued.append(&mut core.dgram_wakers);
+            let sent = std::mem::take(&mut core.sent);
+            (
+                streams,
+                queued,
+                core.hibernate,
+                sent,
+                core.remote_addr,
+                core.abort.take(),
+                core.congestion_snapshot(),
+            )
+        };
+        for waker in queued {
+            waker.wake();
+        }
+        let host = self.host.upgrade();
+        if let Some(host) = &host {
+            host.channels.lock().unwrap().remove(&self.remote_key);
+        }
+        if hibernate {
+            if let Some(host) = &host {
+                // One stream can sit under several LSIDs (multipath); keep
+                // each once, and leave streams primarily owned elsewhere.
+                let mut unique: Vec<Arc<StreamShared>> = Vec::new();
+                for stream in streams {
+                    let foreign = stream.channel().is_some_and(|c| !std::ptr::eq(Arc::as_ptr(&c), Arc::as_ptr(self)));
+                    if foreign || unique.iter().any(|u| Arc::ptr_eq(u, &stream)) {
+                        continue;
+                    }
+                    stream.clear_channel();
+                    unique.push(stream);
+                }
+                let peer = *self.remote_identity.lock().unwrap();
+                host.hibernate_streams(self.role, peer, remote_addr, unique, snapshot);
+                // In-flight chunks will never be acknowledged; requeue them
+                // so the reattached stream retransmits.
+                for packet in sent.into_values() {
+                    for (stream, chunk) in packet.chunks {
+                        stream.chunk_lost(chunk);
+                    }
+                }
+                return;
+            }
+        }
+        for stream in streams {
+            tracing::trace!(lsid = stream.lsid, usid = ?stream.usid, "stream detached");
+            match &abort {
+                Some((code, reason)) => stream.connection_reset(*code, reason),
+                None => stream.connection_closed(),
+            }
+        }
+    }
+}
+
+impl ChannelCore {
+    fn alloc_lsid(&mut self) -> u32 {
+        self.lsids.alloc(&self.streams)
+    }
+
+    /// Apply the amplification limit to outgoing datagrams: pass through
+    /// what the budget covers and hold the rest until the address validates
+    /// or more bytes arrive from it.
+    fn gate_unvalidated(&mut self, datagrams: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        self.held.extend(datagrams);
+        let mut out = Vec::new();
+        while let Some(d) = self.held.front() {
+            if self.pre_tx + d.len() as u64 > AMPLIFICATION_FACTOR * self.pre_rx {
+                break;
+            }
+            let d = self.held.pop_front().unwrap();
+            self.pre_tx += d.len() as u64;
+            out.push(d);
+        }
+        while self.held.len() > HELD_LIMIT {
+            self.held.pop_front();
+        }
+        out
+    }
+
+    /// The congestion and RTT estimates a replacement channel would want
+    /// to warm-start from.
+    fn congestion_snapshot(&self) -> CongestionSnapshot {
+        let mut snapshot = self.cc.export();
+        snapshot.srtt = self.srtt;
+        snapshot.rttvar = self.rttvar;
+        snapshot
+    }
+
+    /// RTT estimator state, for observers: (smoothed, latest, minimum).
+    pub(crate) fn rtt_estimates(&self) -> (Option<Duration>, Option<Duration>, Option<Duration>) {
+        (self.srtt, self.latest_rtt, self.min_rtt)
+    }
+
+    fn rto(&self) -> Duration {
+        let base = match self.srtt {
+            Some(srtt) => srtt + 4 * self.rttvar,
+            None => Duration::from_millis(500),
+        };
+        (base * self.rto_backoff).clamp(MIN_RTO, MAX_RTO)
+    }
+
+    /// Probe timeout: long enough for the newest packet's ack to arrive,
+    /// including the peer's delayed-ack budget, and well short of the RTO.
+    fn pto(&self) -> Duration {
+        let base = match self.srtt {
+            Some(srtt) => {
+                srtt + 4 * self.rttvar + self.peer_max_ack_delay.unwrap_or(ACK_DELAY)
+            }
+            None => Duration::from_millis(500),
+        };
+        (base * self.pto_backoff).clamp(MIN_PTO, MAX_RTO)
+    }
+
+    /// Probe timeout expired: the tail of a burst may be lost and no later
+    /// acks are coming to report it. Duplicate the oldest unacked packet's
+    /// contents to elicit an ack. The packet itself stays tracked and the
+    /// probe is not a loss signal: if data really was lost, the probe's ack
+    /// reports the gap and loss is declared through the usual NACK
+    /// threshold; if not, the shared acked flags make the duplicates no-ops.
+    fn on_probe_timeout(&mut self) {
+        let Some((seq, ctrl, chunks)) = self
+            .sent
+            .iter()
+            .find(|(_, p)| !p.is_probe)
+            .map(|(&seq, p)| (seq, p.ctrl.clone(), p.chunks.clone()))
+        else {
+            return;
+        };
+        tracing::debug!(seq, "probe timeout, retransmitting oldest unacked packet");
+        self.pto_backoff = (self.pto_backoff * 2).min(32);
+        for frame in ctrl.into_iter().rev() {
+            self.ctrl.push_front(frame);
+        }
+        for (stream, chunk) in chunks {
+            stream.chunk_lost(chunk);
+        }
+    }
+
+    /// Record a received sequence number; false if it is a duplicate.
+    fn record_rx(&mut self, seq: u64, now: Instant) -> bool {
+        if seq < self.rx_contiguous || self.rx_above.contains(&seq) {
+            return false;
+        }
+        if seq == self.rx_contiguous {
+            self.rx_contiguous += 1;
+            while self.rx_above.remove(&self.rx_contiguous) {
+                self.rx_contiguous += 1;
+            }
+        } else {
+            self.rx_above.insert(seq);
+        }
+        if seq >= self.rx_largest {
+            self.rx_largest = seq;
+            self.rx_largest_at = now;
+        }
+        true
+    }
+
+    fn build_ack(&mut self, now: Instant) -> AckFrame {
+        // Abandon ancient gaps (e.g. lost ack-only packets that will never
+        // be retransmitted) so the NACK list stays bounded.
+        if self.rx_largest.saturating_sub(self.rx_contiguous) > RX_GAP_HORIZON {
+            let floor = self.rx_largest - RX_GAP_HORIZON;
+            self.rx_above = self.rx_above.split_off(&floor);
+            self.rx_contiguous = floor;
+            while self.rx_above.remove(&self.rx_contiguous) {
+                self.rx_contiguous += 1;
+            }
+        }
+        let mut missing = Vec::new();
+        let mut expected = self.rx_contiguous;
+        for &seq in &self.rx_above {
+            if seq > expected && missing.len() < MAX_NACK_RUNS {
+                missing.push((expected, (seq - expected) as u16));
+            }
+            expected = seq + 1;
+        }
+        AckFrame {
+            sent_entropy: 0,
+            received_entropy: 0,
+            least_unacked: self.sent.keys().next().copied().unwrap_or(self.next_seq),
+            largest_observed: self.rx_largest,
+            delta_time: AckFrame::encode_delay(
+                now.duration_since(self.rx_largest_at),
+                self.ack_delay_exponent,
+            ),
+            missing,
+        }
+    }
+
+    fn on_ack_frame(&mut self, ack: &AckFrame, now: Instant) {
+        let in_missing = |seq: u64| {
+            ack.missing
+                .iter()
+                .any(|&(start, run)| seq >= start && seq < start + u64::from(run))
+        };
+        let loss_delay = std::cmp::max(
+            self.srtt.unwrap_or(Duration::ZERO) * 9 / 8,
+            LOSS_GRANULARITY,
+        );
+        let outstanding: Vec<u64> = self
+            .sent
+            .range(..=ack.largest_observed)
+            .map(|(&seq, _)| seq)
+            .collect();
+        for seq in outstanding {
+            if in_missing(seq) {
+                let lost = {
+                    let p = self.sent.get_mut(&seq).unwrap();
+                    p.nacks += 1;
+                    p.nacks >= NACK_THRESHOLD || now.duration_since(p.sent_at) >= loss_delay
+                };
+                if lost {
+                    self.on_lost(seq, CwndCause::Loss, now);
+                }
+            } else {
+                self.on_acked(seq, seq == ack.largest_observed, ack.delta_time, now);
+            }
+        }
+    }
+
+    fn on_acked(&mut self, seq: u64, is_largest: bool, delta_micros: u32, now: Instant) {
+        let Some(p) = self.sent.remove(&seq) else {
+            return;
+        };
+        self.mtu.probe_acked(seq);
+        self.packetizer
+            .set_packet_size(self.mtu.current() - self.header_tag_len);
+        let rtt = if is_largest {
+            let mut delta = AckFrame::decode_delay(delta_micros, self.peer_ack_delay_exponent);
+            if let Some(max) = self.peer_max_ack_delay {
+                delta = delta.min(max);
+            }
+            now.duration_since(p.sent_at).checked_sub(delta)
+        } else {
+            None
+        };
+        if let Some(rtt) = rtt {
+            self.latest_rtt = Some(rtt);
+            self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+            match self.srtt {
+                Some(srtt) => {
+                    let diff = srtt.abs_diff(rtt);
+                    self.rttvar = (3 * self.rttvar + diff) / 4;
+                    self.srtt = Some((7 * srtt + rtt) / 8);
+                }
+                None => {
+                    self.srtt = Some(rtt);
+                    self.rttvar = rtt / 2;
+                }
+            }
+            self.rto_backoff = 1;
+            self.rto_strikes = 0;
+            self.pto_backoff = 1;
+        }
+        let before = self.cc.window();
+        self.cc.on_ack(p.bytes, rtt);
+        self.note_cwnd(before, CwndCause::Ack, now);
+        for lsid in &p.inits {
+            self.pending_init.remove(lsid);
+        }
+        for (stream, chunk) in &p.chunks {
+            stream.chunk_acked(chunk);
+        }
+    }
+
+    fn on_lost(&mut self, seq: u64, cause: CwndCause, now: Instant) {
+        let Some(p) = self.sent.remove(&seq) else {
+            return;
+        };
+        let before = self.cc.window();
+        if p.is_probe {
+            self.mtu.probe_lost(seq);
+            self.cc.on_ack(p.bytes, None); // probes do not signal congestion
+            self.note_cwnd(before, CwndCause::Ack, now);
+            return;
+        }
+        tracing::debug!(seq, "packet lost, retransmitting contents");
+        self.cc.on_loss(p.bytes);
+        self.note_cwnd(before, cause, now);
+        for frame in p.ctrl.into_iter().rev() {
+            self.ctrl.push_front(frame);
+        }
+        for (stream, chunk) in p.chunks {
+            stream.chunk_lost(chunk);
+        }
+    }
+
+    /// Record a window change for the host's observer; a no-op unless an
+    /// observer is installed and the window actually moved.
+    fn note_cwnd(&mut self, old: usize, cause: CwndCause, at: Instant) {
+        let new = self.cc.window();
+        if !self.cwnd_observed || new == old {
+            return;
+        }
+        self.cwnd_events.push(CwndEvent {
+            old,
+            new,
+            cause,
+            at,
+        });
+    }
+}
+
+fn is_eliciting(frame: &Frame) -> bool {
+    !matches!(
+        frame,
+        Frame::Ack(_) | Frame::Padding(_) | Frame::Empty | Frame::Decongestion(_)
+    )
+}
+
+/// The channel pump: drives sending, retransmission and timers.
+pub(crate) async fn run(shared: Arc<ChannelShared>) {
+    loop {
+        let now = Instant::now();
+        let (datagrams, remote, deadline, done, cwnd_events) = {
+            let mut core = shared.lock();
+            shared.handle_timers(&mut core, now);
+            let mut datagrams = shared.assemble(&mut core, now);
+            if !core.validated {
+                datagrams = core.gate_unvalidated(datagrams);
+            } else if !core.held.is_empty() {
+                let mut flushed: Vec<Vec<u8>> = core.held.drain(..).collect();
+                flushed.append(&mut datagrams);
+                datagrams = flushed;
+            }
+            (
+                datagrams,
+                core.remote_addr,
+                shared.next_deadline(&core, now),
+                core.pump_done,
+                std::mem::take(&mut core.cwnd_events),
+            )
+        };
+        shared.fire_cwnd_events(cwnd_events);
+        for datagram in datagrams {
+            if shared.socket.send_to(&datagram, remote).await.is_err() {
+                shared.teardown();
+                return;
+            }
+        }
+        if done {
+            shared.teardown();
+            return;
+        }
+        // Wakeups come from the host's timer wheel, one runtime timer for
+        // every channel; re-arm only when no earlier deadline is armed.
+        // Without a host (it was dropped), fall back to a local sleep.
+        let arm = {
+            let mut armed = shared.armed.lock().unwrap();
+            if armed.is_some_and(|at| at <= deadline && at > now) {
+                false
+            } else {
+                *armed = Some(deadline);
+                true
+            }
+        };
+        match shared.host.upgrade() {
+            Some(host) => {
+                if arm {
+                    host.arm_channel_timer(deadline, Arc::downgrade(&shared));
+                }
+                shared.notify.notified().await;
+            }
+            None => {
+                tokio::select! {
+                    _ = shared.notify.notified() => {}
+                    _ = tokio::time::sleep_until(deadline.into()) => {}
+                }
+            }
+        }
+    }
+}
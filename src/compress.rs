@@ -0,0 +1,320 @@
+//! Transparent stream compression.
+//!
+//! [`Stream::into_compressed`] wraps a byte stream in a block-oriented
+//! LZ77 codec: every write is compressed into a self-contained block with
+//! a tiny header, and blocks are decompressed again below the reader's
+//! [`AsyncRead`] surface. The codec is only available once both hosts
+//! opted in ([`crate::HostBuilder::compression`]) and the channel's
+//! SETTINGS exchange agreed, so compressed bytes never surprise a peer.
+//!
+//! Incompressible input costs three bytes per block: blocks that do not
+//! shrink are stored raw.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use std::collections::HashMap;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::error::{Error, Result};
+use crate::stream::Stream;
+
+/// Input bytes compressed into one block; bounds the decompression buffer
+/// a hostile length field can demand.
+const MAX_BLOCK: usize = 16 * 1024;
+
+/// How many bytes to pull from the stream per read while decoding.
+const READ_CHUNK: usize = 16 * 1024;
+
+/// Block header: a raw/compressed flag and a big-endian payload length.
+const BLOCK_HEADER: usize = 3;
+
+/// Match distances are capped by the 12-bit field.
+const MAX_DISTANCE: usize = 4095;
+/// Match lengths occupy the remaining 4 bits, biased by the minimum.
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = MIN_MATCH + 15;
+
+/// Compress `input` with byte-oriented LZ77: groups of eight tokens share
+/// a control byte whose set bits mark back-references (12-bit distance,
+/// 4-bit length) among literals.
+fn compress_block(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut recent: HashMap<[u8; 3], usize> = HashMap::new();
+    let mut control_at = usize::MAX;
+    let mut control_bit = 8;
+    let mut pos = 0;
+    while pos < input.len() {
+        if control_bit == 8 {
+            control_at = out.len();
+            out.push(0);
+            control_bit = 0;
+        }
+        let mut matched = 0;
+        let mut distance = 0;
+        if pos + MIN_MATCH <= input.len() {
+            let key = [input[pos], input[pos + 1], input[pos + 2]];
+            if let Some(&at) = recent.get(&key) {
+                let back = pos - at;
+                if back <= MAX_DISTANCE {
+                    let limit = (input.len() - pos).min(MAX_MATCH);
+                    while matched < limit && input[at + matched] == input[pos + matched] {
+                        matched += 1;
+                    }
+                    distance = back;
+                }
+            }
+            recent.insert(key, pos);
+        }
+        if matched >= MIN_MATCH {
+            out[control_at] |= 1 << control_bit;
+            let token = ((distance as u16) << 4) | (matched - MIN_MATCH) as u16;
+            out.extend_from_slice(&token.to_be_bytes());
+            pos += matched;
+        } else {
+            out.push(input[pos]);
+            pos += 1;
+        }
+        control_bit += 1;
+    }
+    out
+}
+
+/// Reverse [`compress_block`]. Fails on truncated tokens or distances
+/// reaching before the start of the block.
+fn decompress_block(mut input: &[u8]) -> Result<Vec<u8>> {
+    let corrupt = || Error::protocol("corrupt compressed block");
+    let mut out = Vec::with_capacity(input.len() * 2);
+    while let Some((&control, rest)) = input.split_first() {
+        input = rest;
+        for bit in 0..8 {
+            if input.is_empty() {
+                break;
+            }
+            if control & (1 << bit) == 0 {
+                out.push(input[0]);
+                input = &input[1..];
+            } else {
+                if input.len() < 2 {
+                    return Err(corrupt());
+                }
+                let token = u16::from_be_bytes([input[0], input[1]]);
+                input = &input[2..];
+                let distance = (token >> 4) as usize;
+                let length = (token & 0xf) as usize + MIN_MATCH;
+                if distance == 0 || distance > out.len() || out.len() + length > MAX_BLOCK {
+                    return Err(corrupt());
+                }
+                for _ in 0..length {
+                    out.push(out[out.len() - distance]);
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Encode one block of `input` (raw if compression does not pay) onto
+/// `out`.
+fn encode_block(input: &[u8], out: &mut Vec<u8>) {
+    let compressed = compress_block(input);
+    let (flag, payload) = if compressed.len() < input.len() {
+        (1u8, compressed.as_slice())
+    } else {
+        (0u8, input)
+    };
+    out.push(flag);
+    out.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// A stream with transparent compression; see [`Stream::into_compressed`].
+pub struct Compressed {
+    stream: Stream,
+    /// Inbound wire bytes not yet forming a complete block.
+    read_buf: Vec<u8>,
+    /// Decompressed bytes the reader has not consumed yet.
+    out_buf: Vec<u8>,
+    /// Encoded outbound bytes the stream has not yet accepted.
+    write_buf: Vec<u8>,
+    /// The byte stream ended; only buffered data remains.
+    read_done: bool,
+}
+
+impl Stream {
+    /// Wrap this stream in transparent compression below the
+    /// [`AsyncRead`]/[`AsyncWrite`] surface. Both peers must wrap their
+    /// end of the stream.
+    ///
+    /// Fails with a protocol error unless both hosts opted in with
+    /// [`crate::HostBuilder::compression`] and the channel's SETTINGS
+    /// exchange agreed.
+    pub fn into_compressed(self) -> Result<Compressed> {
+        let negotiated = self
+            .shared
+            .channel()
+            .is_some_and(|chan| chan.compression_negotiated());
+        if !negotiated {
+            return Err(Error::protocol("compression was not negotiated"));
+        }
+        Ok(Compressed {
+            stream: self,
+            read_buf: Vec::new(),
+            out_buf: Vec::new(),
+            write_buf: Vec::new(),
+            read_done: false,
+        })
+    }
+}
+
+impl Compressed {
+    /// The underlying stream, dropping any partially received block.
+    pub fn into_inner(self) -> Stream {
+        self.stream
+    }
+
+    /// Push buffered outbound bytes into the stream until it stops
+    /// accepting them or none remain.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            match Pin::new(&mut self.stream).poll_write(cx, &self.write_buf) {
+                Poll::Ready(Ok(n)) => {
+                    self.write_buf.drain(..n);
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for Compressed {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.out_buf.is_empty() {
+                let n = this.out_buf.len().min(buf.remaining());
+                buf.put_slice(&this.out_buf[..n]);
+                this.out_buf.drain(..n);
+                return Poll::Ready(Ok(()));
+            }
+            // A complete block may already be buffered.
+            if this.read_buf.len() >= BLOCK_HEADER {
+                let len = usize::from(u16::from_be_bytes([this.read_buf[1], this.read_buf[2]]));
+                if this.read_buf.len() >= BLOCK_HEADER + len {
+                    let flag = this.read_buf[0];
+                    let payload = &this.read_buf[BLOCK_HEADER..BLOCK_HEADER + len];
+                    this.out_buf = match flag {
+                        0 => payload.to_vec(),
+                        1 => decompress_block(payload).map_err(io::Error::from)?,
+                        _ => {
+                            return Poll::Ready(Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "unknown compression block flag",
+                            )))
+                        }
+                    };
+                    this.read_buf.drain(..BLOCK_HEADER + len);
+                    continue;
+                }
+            }
+            if this.read_done {
+                return if this.read_buf.is_empty() {
+                    Poll::Ready(Ok(()))
+                } else {
+                    // The peer closed mid-block; surface it once.
+                    this.read_buf.clear();
+                    Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "stream ended inside a compressed block",
+                    )))
+                };
+            }
+            let mut chunk = [0u8; READ_CHUNK];
+            let mut inner = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.stream).poll_read(cx, &mut inner) {
+                Poll::Ready(Ok(())) if inner.filled().is_empty() => this.read_done = true,
+                Poll::Ready(Ok(())) => this.read_buf.extend_from_slice(inner.filled()),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Compressed {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other.map_ok(|()| 0),
+        }
+        let n = buf.len().min(MAX_BLOCK);
+        encode_block(&buf[..n], &mut this.write_buf);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repetitive_input_round_trips_and_shrinks() {
+        let input: Vec<u8> = b"the rain in spain stays mainly in the plain "
+            .iter()
+            .copied()
+            .cycle()
+            .take(8 * 1024)
+            .collect();
+        let compressed = compress_block(&input);
+        assert!(compressed.len() < input.len() / 4);
+        assert_eq!(decompress_block(&compressed).unwrap(), input);
+    }
+
+    #[test]
+    fn incompressible_input_is_stored_raw() {
+        let input: Vec<u8> = (0..=255).collect();
+        let mut block = Vec::new();
+        encode_block(&input, &mut block);
+        assert_eq!(block[0], 0);
+        assert_eq!(block.len(), BLOCK_HEADER + input.len());
+    }
+
+    #[test]
+    fn truncated_tokens_are_rejected() {
+        // A control byte claiming a match, with only one token byte left.
+        assert!(decompress_block(&[0x01, 0x12]).is_err());
+        // A match reaching before the start of the output.
+        assert!(decompress_block(&[0x01, 0x00, 0x10]).is_err());
+    }
+}
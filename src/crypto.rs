@@ -0,0 +1,369 @@
+//! Key material and cryptographic boxes.
+//!
+//! SSS follows the CurveCP key schedule (spec section 3.1.3): each host owns a
+//! long-term key pair, and every channel uses fresh short-term key pairs on
+//! both sides. All boxes are NaCl `crypto_box` (Curve25519/XSalsa20/Poly1305)
+//! with 24-byte nonces assembled from a fixed ASCII prefix and a compressed
+//! nonce carried on the wire.
+
+use std::time::{Duration, Instant};
+
+use crypto_box::aead::{Aead, OsRng};
+use crypto_box::SalsaBox;
+use crypto_secretbox::{KeyInit, XSalsa20Poly1305};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+
+/// Size of public and secret keys, in bytes.
+pub const KEY_SIZE: usize = 32;
+/// Poly1305 authenticator overhead added by every box.
+pub(crate) const BOX_OVERHEAD: usize = 16;
+/// Full nonce size used by all boxes.
+pub(crate) const NONCE_SIZE: usize = 24;
+
+/// How long a minute key stays current before rotation.
+const MINUTE_KEY_LIFETIME: Duration = Duration::from_secs(60);
+
+/// A host's long-term key pair, identifying it to its peers.
+#[derive(Clone)]
+pub struct Identity {
+    secret: crypto_box::SecretKey,
+}
+
+impl Identity {
+    /// Generate a fresh long-term key pair.
+    pub fn generate() -> Self {
+        Identity {
+            secret: crypto_box::SecretKey::generate(&mut OsRng),
+        }
+    }
+
+    /// Generate a long-term key pair from a host's random source.
+    pub(crate) fn generate_with(rng: &HostRng) -> Self {
+        Identity {
+            secret: crypto_box::SecretKey::from(rng.bytes::<KEY_SIZE>()),
+        }
+    }
+
+    /// Reconstruct an identity from raw secret key bytes.
+    pub fn from_secret_bytes(bytes: [u8; KEY_SIZE]) -> Self {
+        Identity {
+            secret: crypto_box::SecretKey::from(bytes),
+        }
+    }
+
+    /// The public half of this identity, shared with peers out of band.
+    pub fn public(&self) -> PublicKey {
+        PublicKey(*self.secret.public_key().as_bytes())
+    }
+
+    pub(crate) fn secret(&self) -> &crypto_box::SecretKey {
+        &self.secret
+    }
+}
+
+impl std::fmt::Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Identity")
+            .field("public", &self.public())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A 32-byte Curve25519 public key.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PublicKey(pub(crate) [u8; KEY_SIZE]);
+
+impl PublicKey {
+    /// Construct from raw bytes.
+    pub fn from_bytes(bytes: [u8; KEY_SIZE]) -> Self {
+        PublicKey(bytes)
+    }
+
+    /// The raw key bytes.
+    pub fn as_bytes(&self) -> &[u8; KEY_SIZE] {
+        &self.0
+    }
+
+    pub(crate) fn inner(&self) -> crypto_box::PublicKey {
+        crypto_box::PublicKey::from(self.0)
+    }
+}
+
+impl std::fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for b in &self.0[..4] {
+            write!(f, "{b:02x}")?;
+        }
+        write!(f, "…")
+    }
+}
+
+/// A short-term key pair, generated per channel and destroyed with it.
+pub(crate) struct ShortTermKey {
+    secret: crypto_box::SecretKey,
+}
+
+impl ShortTermKey {
+    pub(crate) fn generate(rng: &HostRng) -> Self {
+        ShortTermKey {
+            secret: crypto_box::SecretKey::from(rng.bytes::<KEY_SIZE>()),
+        }
+    }
+
+    pub(crate) fn from_secret(secret: crypto_box::SecretKey) -> Self {
+        ShortTermKey { secret }
+    }
+
+    pub(crate) fn public(&self) -> [u8; KEY_SIZE] {
+        *self.secret.public_key().as_bytes()
+    }
+
+    pub(crate) fn secret(&self) -> &crypto_box::SecretKey {
+        &self.secret
+    }
+}
+
+/// Assemble a 24-byte nonce from an ASCII prefix and a compressed nonce.
+pub(crate) fn nonce(prefix: &[u8], compressed: &[u8]) -> [u8; NONCE_SIZE] {
+    debug_assert_eq!(prefix.len() + compressed.len(), NONCE_SIZE);
+    let mut out = [0u8; NONCE_SIZE];
+    out[..prefix.len()].copy_from_slice(prefix);
+    out[prefix.len()..].copy_from_slice(compressed);
+    out
+}
+
+/// Seal `plaintext` into a box, appending the 16-byte authenticator.
+pub(crate) fn seal(cbox: &SalsaBox, nonce: &[u8; NONCE_SIZE], plaintext: &[u8]) -> Vec<u8> {
+    cbox.encrypt(nonce.as_ref().into(), plaintext)
+        .expect("box encryption is infallible")
+}
+
+/// Open a box sealed by [`seal`].
+pub(crate) fn open(cbox: &SalsaBox, nonce: &[u8; NONCE_SIZE], boxed: &[u8]) -> Result<Vec<u8>> {
+    cbox.decrypt(nonce.as_ref().into(), boxed)
+        .map_err(|_| Error::Crypto)
+}
+
+/// Derive a channel's header-protection key, used to obfuscate the cleartext
+/// sequence field of MESSAGE packets so observers cannot link a connection
+/// across paths. Both ends seal the same constant under a fixed nonce with
+/// the shared short-term box, so they derive the same key.
+pub(crate) fn header_protection_key(cbox: &SalsaBox) -> [u8; 32] {
+    let nonce = [b'H'; NONCE_SIZE];
+    let sealed = seal(cbox, &nonce, b"sss header protection");
+    Sha256::digest(&sealed).into()
+}
+
+/// Compute the XOR mask for one packet's sequence field from the channel's
+/// header-protection key and a sample of the sealed payload. The receiver
+/// sees the same ciphertext sample, so it can strip the mask before using
+/// the sequence number as the nonce suffix.
+pub(crate) fn header_mask(key: &[u8; 32], sample: &[u8]) -> [u8; 8] {
+    let mut hasher = Sha256::new();
+    hasher.update(key);
+    hasher.update(sample);
+    let digest = hasher.finalize();
+    let mut mask = [0u8; 8];
+    mask.copy_from_slice(&digest[..8]);
+    mask
+}
+
+/// Derive a channel's header-authentication key, under a distinct label
+/// from the header-protection key; used when both ends opt into
+/// authenticated headers (see [`crate::HostBuilder::authenticate_headers`]).
+pub(crate) fn header_auth_key(cbox: &SalsaBox) -> [u8; 32] {
+    let nonce = [b'A'; NONCE_SIZE];
+    let sealed = seal(cbox, &nonce, b"sss header authentication");
+    Sha256::digest(&sealed).into()
+}
+
+/// Authenticate a MESSAGE packet's cleartext prefix — magic, channel id
+/// and masked sequence field — with a truncated HMAC, so tampering is
+/// detected and the packet dropped before decryption is attempted.
+pub(crate) fn header_tag(key: &[u8; 32], prefix: &[u8]) -> [u8; BOX_OVERHEAD] {
+    let mut hmac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("any key length");
+    hmac.update(prefix);
+    let digest = hmac.finalize().into_bytes();
+    let mut tag = [0u8; BOX_OVERHEAD];
+    tag.copy_from_slice(&digest[..BOX_OVERHEAD]);
+    tag
+}
+
+/// Seal `plaintext` with a checksum in place of the box authenticator: the
+/// truncated SHA-256 of nonce and payload, preserving the 16-byte overhead.
+/// No confidentiality or authenticity; for loopback benchmarking only.
+#[cfg(feature = "insecure-loopback")]
+pub(crate) fn seal_insecure(nonce: &[u8; NONCE_SIZE], plaintext: &[u8]) -> Vec<u8> {
+    let mut out = insecure_checksum(nonce, plaintext).to_vec();
+    out.extend_from_slice(plaintext);
+    out
+}
+
+/// Open a checksum "box" sealed by [`seal_insecure`].
+#[cfg(feature = "insecure-loopback")]
+pub(crate) fn open_insecure(nonce: &[u8; NONCE_SIZE], boxed: &[u8]) -> Result<Vec<u8>> {
+    if boxed.len() < BOX_OVERHEAD {
+        return Err(Error::Crypto);
+    }
+    let (tag, payload) = boxed.split_at(BOX_OVERHEAD);
+    if insecure_checksum(nonce, payload) != tag[..] {
+        return Err(Error::Crypto);
+    }
+    Ok(payload.to_vec())
+}
+
+#[cfg(feature = "insecure-loopback")]
+fn insecure_checksum(nonce: &[u8; NONCE_SIZE], payload: &[u8]) -> [u8; BOX_OVERHEAD] {
+    let mut hasher = Sha256::new();
+    hasher.update(nonce);
+    hasher.update(payload);
+    let digest = hasher.finalize();
+    let mut tag = [0u8; BOX_OVERHEAD];
+    tag.copy_from_slice(&digest[..BOX_OVERHEAD]);
+    tag
+}
+
+/// The source of every random byte a host draws: nonces, short-term keys,
+/// minute keys and (when not provided) the long-term identity. Defaults to
+/// the OS generator; tests may seed it via [`HostBuilder::rng`] to make
+/// handshakes reproducible byte for byte.
+///
+/// [`HostBuilder::rng`]: crate::HostBuilder::rng
+pub(crate) struct HostRng(std::sync::Mutex<Box<dyn rand::RngCore + Send>>);
+
+impl HostRng {
+    /// The production source: the operating system's generator.
+    pub(crate) fn os() -> Self {
+        HostRng::new(Box::new(rand::rngs::OsRng))
+    }
+
+    pub(crate) fn new(rng: Box<dyn rand::RngCore + Send>) -> Self {
+        HostRng(std::sync::Mutex::new(rng))
+    }
+
+    /// Draw `N` random bytes, e.g. for a compressed nonce.
+    pub(crate) fn bytes<const N: usize>(&self) -> [u8; N] {
+        let mut out = [0u8; N];
+        self.0.lock().unwrap().fill_bytes(&mut out);
+        out
+    }
+}
+
+/// Responder minute keys used to seal cookies (spec section 3.2.1).
+///
+/// The current key rotates every minute; the previous key is kept so cookies
+/// sealed just before rotation remain valid for one more interval.
+pub(crate) struct MinuteKeys {
+    current: XSalsa20Poly1305,
+    previous: XSalsa20Poly1305,
+    rotated_at: Instant,
+}
+
+fn fresh_secretbox(rng: &HostRng) -> XSalsa20Poly1305 {
+    XSalsa20Poly1305::new(&rng.bytes::<32>().into())
+}
+
+impl MinuteKeys {
+    pub(crate) fn new(rng: &HostRng) -> Self {
+        let key = fresh_secretbox(rng);
+        MinuteKeys {
+            previous: key.clone(),
+            current: key,
+            rotated_at: Instant::now(),
+        }
+    }
+
+    fn rotate_if_due(&mut self, rng: &HostRng) {
+        if self.rotated_at.elapsed() >= MINUTE_KEY_LIFETIME {
+            self.previous = self.current.clone();
+            self.current = fresh_secretbox(rng);
+            self.rotated_at = Instant::now();
+        }
+    }
+
+    /// Seal a cookie payload under the current minute key.
+    pub(crate) fn seal(&mut self, rng: &HostRng, nonce: &[u8; NONCE_SIZE], plaintext: &[u8]) -> Vec<u8> {
+        self.rotate_if_due(rng);
+        self.current
+            .encrypt(nonce.as_ref().into(), plaintext)
+            .expect("secretbox encryption is infallible")
+    }
+
+    /// Open a cookie with the current key, falling back to the previous one.
+    pub(crate) fn open(&mut self, rng: &HostRng, nonce: &[u8; NONCE_SIZE], boxed: &[u8]) -> Result<Vec<u8>> {
+        self.rotate_if_due(rng);
+        self.current
+            .decrypt(nonce.as_ref().into(), boxed)
+            .or_else(|_| self.previous.decrypt(nonce.as_ref().into(), boxed))
+            .map_err(|_| Error::Crypto)
+    }
+}
+
+/// Derive a half-channel identifier (spec section 5, "Stream IDs - USID").
+///
+/// Follows the SST `calc_key` construction: HMAC-SHA256 keyed with the hash of
+/// the master material over the two parties' short-term public keys plus a
+/// direction byte, truncated to 16 bytes.
+pub(crate) fn half_channel_id(
+    initiator_key: &[u8; KEY_SIZE],
+    responder_key: &[u8; KEY_SIZE],
+    which: u8,
+) -> [u8; 16] {
+    let mut master = Sha256::new();
+    master.update(initiator_key);
+    master.update(responder_key);
+    let master_hash = master.finalize();
+
+    let mut hmac = <Hmac<Sha256> as Mac>::new_from_slice(&master_hash).expect("any key length");
+    hmac.update(initiator_key);
+    hmac.update(responder_key);
+    hmac.update(&[which]);
+    let digest = hmac.finalize().into_bytes();
+
+    let mut id = [0u8; 16];
+    id.copy_from_slice(&digest[..16]);
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_protection_hides_the_sequence_and_strips_off() {
+        let rng = HostRng::os();
+        let ours = ShortTermKey::generate(&rng);
+        let theirs = ShortTermKey::generate(&rng);
+        let tx_box = SalsaBox::new(&theirs.secret().public_key(), ours.secret());
+        let rx_box = SalsaBox::new(&ours.secret().public_key(), theirs.secret());
+
+        // The derivation is symmetric: both ends get the same key.
+        let tx_key = header_protection_key(&tx_box);
+        assert_eq!(tx_key, header_protection_key(&rx_box));
+
+        let seq: u64 = 7;
+        let compressed = seq.to_be_bytes();
+        let boxed = seal(&tx_box, &nonce(b"sss-test-prefix!", &compressed), b"payload");
+
+        // Mask the sequence as the sender would after sealing.
+        let mask = header_mask(&tx_key, &boxed[..BOX_OVERHEAD]);
+        let mut wire = compressed;
+        for (byte, m) in wire.iter_mut().zip(mask) {
+            *byte ^= m;
+        }
+        assert_ne!(wire, compressed, "sequence must not appear in cleartext");
+
+        // The receiver strips the mask from the same ciphertext sample and
+        // recovers a nonce that opens the box.
+        let mask = header_mask(&header_protection_key(&rx_box), &boxed[..BOX_OVERHEAD]);
+        for (byte, m) in wire.iter_mut().zip(mask) {
+            *byte ^= m;
+        }
+        assert_eq!(u64::from_be_bytes(wire), seq);
+        let payload = open(&rx_box, &nonce(b"sss-test-prefix!", &wire), &boxed).unwrap();
+        assert_eq!(payload, b"payload");
+    }
+}
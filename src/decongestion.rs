@@ -0,0 +1,560 @@
+//! Pluggable congestion control strategies (spec section 4.2.7).
+//!
+//! A channel owns one `Decongestion` instance which gates how much data may
+//! be in flight at once. Strategies are negotiated through the SETTINGS frame
+//! during session establishment.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Congestion control algorithm identifiers used in SETTINGS negotiation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CongestionAlg {
+    None = 0,
+    TcpCubic = 1,
+    Chicago = 2,
+    Ledbat = 3,
+    InterArrival = 4,
+}
+
+/// Congestion control algorithm selectable by the application through
+/// [`Stream::set_congestion_algorithm`](crate::Stream::set_congestion_algorithm).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CongestionAlgorithm {
+    /// NewReno-style AIMD: slow start, then additive increase and
+    /// multiplicative decrease on loss.
+    NewReno,
+    /// Vegas-style delay-based control: backs off when the RTT rises above
+    /// its observed baseline, before loss occurs.
+    Vegas,
+    /// BBR-style model-based control: estimates the bottleneck bandwidth
+    /// and the round-trip propagation time, and sizes the window from the
+    /// measured bandwidth-delay product instead of reacting to loss.
+    Bbr,
+}
+
+impl CongestionAlg {
+    pub(crate) fn from_wire(value: u16) -> Option<Self> {
+        match value {
+            0 => Some(CongestionAlg::None),
+            1 => Some(CongestionAlg::TcpCubic),
+            2 => Some(CongestionAlg::Chicago),
+            3 => Some(CongestionAlg::Ledbat),
+            4 => Some(CongestionAlg::InterArrival),
+            _ => None,
+        }
+    }
+}
+
+/// Congestion estimates carried from a torn-down channel to its
+/// replacement, so a migrated connection warm-starts from the old path's
+/// measurements instead of re-probing from the initial window.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CongestionSnapshot {
+    /// Congestion window at export time, in bytes.
+    pub(crate) cwnd: usize,
+    /// Slow-start threshold, when the strategy had one.
+    pub(crate) ssthresh: Option<usize>,
+    /// Smoothed RTT estimate, when one had been taken.
+    pub(crate) srtt: Option<Duration>,
+    /// RTT variance matching `srtt`.
+    pub(crate) rttvar: Duration,
+}
+
+/// A congestion control strategy attached to one channel.
+///
+/// All quantities are in bytes of packet payload. The channel reports send,
+/// ack and loss events; the strategy answers how much more may be sent.
+pub(crate) trait Decongestion: Send {
+    /// A packet of `bytes` was handed to the socket.
+    fn on_sent(&mut self, bytes: usize);
+
+    /// A packet of `bytes` was acknowledged; `rtt` is a fresh sample when the
+    /// ack timing allowed one to be taken.
+    fn on_ack(&mut self, bytes: usize, rtt: Option<Duration>);
+
+    /// A packet of `bytes` was declared lost.
+    fn on_loss(&mut self, bytes: usize);
+
+    /// Current congestion window, in bytes.
+    fn window(&self) -> usize;
+
+    /// Slow-start threshold in bytes; `None` while the strategy has none
+    /// (no loss seen yet, or no such notion at all).
+    fn ssthresh(&self) -> Option<usize> {
+        None
+    }
+
+    /// Bytes currently in flight (sent but neither acked nor lost).
+    fn in_flight(&self) -> usize;
+
+    /// The selectable algorithm this strategy implements, if any.
+    fn algorithm(&self) -> Option<CongestionAlgorithm> {
+        None
+    }
+
+    /// Whether another packet of `bytes` may be sent now.
+    fn may_send(&self, bytes: usize) -> bool {
+        self.in_flight() + bytes <= self.window()
+    }
+
+    /// Export the strategy's estimates for a replacement channel. The RTT
+    /// fields are filled in by the channel, which owns the estimator.
+    fn export(&self) -> CongestionSnapshot {
+        CongestionSnapshot {
+            cwnd: self.window(),
+            ssthresh: self.ssthresh(),
+            srtt: None,
+            rttvar: Duration::ZERO,
+        }
+    }
+
+    /// Warm-start from another channel's exported estimates, where the
+    /// strategy has state worth seeding; the default keeps cold-start.
+    fn import(&mut self, snapshot: &CongestionSnapshot) {
+        let _ = snapshot;
+    }
+}
+
+/// Default sender maximum payload used to size windows.
+pub(crate) const MSS: usize = 1168;
+const INITIAL_WINDOW: usize = 10 * MSS;
+const MIN_WINDOW: usize = 2 * MSS;
+
+/// TCP-style AIMD congestion control: slow start up to `ssthresh`, then one
+/// MSS of growth per window acked, halving on loss.
+pub(crate) struct TcpDecongestion {
+    cwnd: usize,
+    ssthresh: usize,
+    in_flight: usize,
+}
+
+impl TcpDecongestion {
+    pub(crate) fn new() -> Self {
+        TcpDecongestion {
+            cwnd: INITIAL_WINDOW,
+            ssthresh: usize::MAX,
+            in_flight: 0,
+        }
+    }
+}
+
+impl Decongestion for TcpDecongestion {
+    fn on_sent(&mut self, bytes: usize) {
+        self.in_flight += bytes;
+    }
+
+    fn on_ack(&mut self, bytes: usize, _rtt: Option<Duration>) {
+        self.in_flight = self.in_flight.saturating_sub(bytes);
+        if self.cwnd < self.ssthresh {
+            // Slow start: grow by the acked amount.
+            self.cwnd += bytes;
+        } else {
+            // Congestion avoidance: one MSS per window's worth of acks.
+            self.cwnd += MSS * bytes / self.cwnd;
+        }
+    }
+
+    fn on_loss(&mut self, bytes: usize) {
+        self.in_flight = self.in_flight.saturating_sub(bytes);
+        self.ssthresh = std::cmp::max(self.cwnd / 2, MIN_WINDOW);
+        self.cwnd = self.ssthresh;
+    }
+
+    fn window(&self) -> usize {
+        self.cwnd
+    }
+
+    fn ssthresh(&self) -> Option<usize> {
+        (self.ssthresh != usize::MAX).then_some(self.ssthresh)
+    }
+
+    fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    fn algorithm(&self) -> Option<CongestionAlgorithm> {
+        Some(CongestionAlgorithm::NewReno)
+    }
+
+    fn import(&mut self, snapshot: &CongestionSnapshot) {
+        self.cwnd = snapshot.cwnd.max(MIN_WINDOW);
+        self.ssthresh = snapshot.ssthresh.unwrap_or(usize::MAX);
+    }
+}
+
+/// Extra queued bytes Vegas aims to keep in the network: below this it
+/// grows, above `VEGAS_BETA` it shrinks.
+const VEGAS_ALPHA: usize = 2 * MSS;
+const VEGAS_BETA: usize = 4 * MSS;
+
+/// Vegas-style delay-based congestion control: the lowest RTT seen is taken
+/// as the uncongested baseline, and the window is steered so that only a few
+/// packets' worth of extra data queue in the network.
+pub(crate) struct VegasDecongestion {
+    cwnd: usize,
+    base_rtt: Option<Duration>,
+    in_flight: usize,
+}
+
+impl VegasDecongestion {
+    pub(crate) fn new() -> Self {
+        VegasDecongestion {
+            cwnd: INITIAL_WINDOW,
+            base_rtt: None,
+            in_flight: 0,
+        }
+    }
+}
+
+impl Decongestion for VegasDecongestion {
+    fn on_sent(&mut self, bytes: usize) {
+        self.in_flight += bytes;
+    }
+
+    fn on_ack(&mut self, bytes: usize, rtt: Option<Duration>) {
+        self.in_flight = self.in_flight.saturating_sub(bytes);
+        let Some(rtt) = rtt else { return };
+        let base = match self.base_rtt {
+            Some(base) if base <= rtt => base,
+            _ => {
+                self.base_rtt = Some(rtt);
+                rtt
+            }
+        };
+        // Bytes queued in the network beyond what the base RTT would carry.
+        let carried = self.cwnd as u128 * base.as_nanos() / rtt.as_nanos().max(1);
+        let queued = self.cwnd.saturating_sub(carried as usize);
+        if queued < VEGAS_ALPHA {
+            self.cwnd += MSS * bytes / self.cwnd;
+        } else if queued > VEGAS_BETA {
+            self.cwnd = std::cmp::max(self.cwnd - MSS * bytes / self.cwnd, MIN_WINDOW);
+        }
+    }
+
+    fn on_loss(&mut self, bytes: usize) {
+        self.in_flight = self.in_flight.saturating_sub(bytes);
+        // Loss still halves: delay avoidance failed, so react like Reno.
+        self.cwnd = std::cmp::max(self.cwnd / 2, MIN_WINDOW);
+    }
+
+    fn window(&self) -> usize {
+        self.cwnd
+    }
+
+    fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    fn algorithm(&self) -> Option<CongestionAlgorithm> {
+        Some(CongestionAlgorithm::Vegas)
+    }
+
+    fn import(&mut self, snapshot: &CongestionSnapshot) {
+        self.cwnd = snapshot.cwnd.max(MIN_WINDOW);
+        // The old path's smoothed RTT is the best available baseline until
+        // the new path produces its own samples.
+        self.base_rtt = snapshot.srtt;
+    }
+}
+
+/// Length of the bandwidth max-filter, in rate-sample epochs.
+const BBR_BW_WINDOW: usize = 10;
+/// Gain cycle applied to the bandwidth-delay product while probing, in
+/// eighths: one epoch probing above the estimate, one draining the queue
+/// that built, six cruising at the estimate.
+const BBR_CYCLE: [u64; 8] = [10, 6, 8, 8, 8, 8, 8, 8];
+/// Startup gain in eighths (roughly 2/ln 2), doubling the delivery rate
+/// every round until growth stalls.
+const BBR_STARTUP_GAIN: u64 = 23;
+/// Rate-sample epoch length floor, when no RTT estimate exists yet.
+const BBR_MIN_EPOCH: Duration = Duration::from_millis(10);
+
+/// BBR-style model-based congestion control: a max-filter over measured
+/// delivery rates estimates the bottleneck bandwidth, the lowest RTT seen
+/// estimates the propagation delay, and the window tracks their product
+/// through a cyclic probing gain. Loss is not a control signal; a flow
+/// backs off only when the model says the pipe is full.
+pub(crate) struct BbrDecongestion {
+    in_flight: usize,
+    /// Bytes acknowledged in the current rate-sample epoch.
+    epoch_delivered: u64,
+    epoch_start: Instant,
+    /// Recent delivery-rate samples, in bytes per second; the window is
+    /// their maximum.
+    bw_samples: VecDeque<u64>,
+    min_rtt: Option<Duration>,
+    /// Still in startup: bandwidth has kept growing every epoch.
+    startup: bool,
+    /// Consecutive epochs without meaningful bandwidth growth.
+    stalled: u32,
+    last_bw: u64,
+    /// Position in [`BBR_CYCLE`] once startup ends.
+    phase: usize,
+}
+
+impl BbrDecongestion {
+    pub(crate) fn new() -> Self {
+        BbrDecongestion {
+            in_flight: 0,
+            epoch_delivered: 0,
+            epoch_start: Instant::now(),
+            bw_samples: VecDeque::new(),
+            min_rtt: None,
+            startup: true,
+            stalled: 0,
+            last_bw: 0,
+            phase: 0,
+        }
+    }
+
+    /// The bottleneck bandwidth estimate, in bytes per second.
+    fn btl_bw(&self) -> u64 {
+        self.bw_samples.iter().copied().max().unwrap_or(0)
+    }
+
+    /// Close the current rate-sample epoch if it has run a full RTT.
+    fn advance_epoch(&mut self, now: Instant) {
+        let epoch = self.min_rtt.unwrap_or(BBR_MIN_EPOCH).max(BBR_MIN_EPOCH);
+        let elapsed = now.duration_since(self.epoch_start);
+        if elapsed < epoch {
+            return;
+        }
+        let sample =
+            (self.epoch_delivered as u128 * 1_000_000_000 / elapsed.as_nanos().max(1)) as u64;
+        if self.bw_samples.len() == BBR_BW_WINDOW {
+            self.bw_samples.pop_front();
+        }
+        self.bw_samples.push_back(sample);
+        self.epoch_delivered = 0;
+        self.epoch_start = now;
+        let bw = self.btl_bw();
+        if self.startup {
+            // Startup ends when three epochs in a row fail to grow the
+            // bandwidth estimate by at least a quarter: the pipe is full.
+            if bw > self.last_bw + self.last_bw / 4 {
+                self.stalled = 0;
+            } else {
+                self.stalled += 1;
+                if self.stalled >= 3 {
+                    self.startup = false;
+                }
+            }
+            self.last_bw = bw;
+        } else {
+            self.phase = (self.phase + 1) % BBR_CYCLE.len();
+        }
+    }
+}
+
+impl Decongestion for BbrDecongestion {
+    fn on_sent(&mut self, bytes: usize) {
+        self.in_flight += bytes;
+    }
+
+    fn on_ack(&mut self, bytes: usize, rtt: Option<Duration>) {
+        self.in_flight = self.in_flight.saturating_sub(bytes);
+        self.epoch_delivered += bytes as u64;
+        if let Some(rtt) = rtt {
+            if self.min_rtt.is_none_or(|min| rtt < min) {
+                self.min_rtt = Some(rtt);
+            }
+        }
+        self.advance_epoch(Instant::now());
+    }
+
+    fn on_loss(&mut self, bytes: usize) {
+        // Loss is noise to the model, not a signal: the bandwidth filter
+        // already reflects what the bottleneck actually delivered.
+        self.in_flight = self.in_flight.saturating_sub(bytes);
+    }
+
+    fn window(&self) -> usize {
+        let (Some(min_rtt), bw) = (self.min_rtt, self.btl_bw()) else {
+            return INITIAL_WINDOW;
+        };
+        if bw == 0 {
+            return INITIAL_WINDOW;
+        }
+        let bdp = (bw as u128 * min_rtt.as_nanos() / 1_000_000_000) as u64;
+        let gain = if self.startup {
+            BBR_STARTUP_GAIN
+        } else {
+            BBR_CYCLE[self.phase]
+        };
+        ((bdp * gain / 8) as usize).max(MIN_WINDOW)
+    }
+
+    fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    fn algorithm(&self) -> Option<CongestionAlgorithm> {
+        Some(CongestionAlgorithm::Bbr)
+    }
+
+    fn import(&mut self, snapshot: &CongestionSnapshot) {
+        // The model is path-specific; only the RTT baseline carries over.
+        self.min_rtt = snapshot.srtt;
+    }
+}
+
+/// No congestion control: a large fixed window. Useful for loopback tests.
+pub(crate) struct FixedWindow {
+    window: usize,
+    in_flight: usize,
+}
+
+impl FixedWindow {
+    pub(crate) fn new(window: usize) -> Self {
+        FixedWindow {
+            window,
+            in_flight: 0,
+        }
+    }
+}
+
+impl Decongestion for FixedWindow {
+    fn on_sent(&mut self, bytes: usize) {
+        self.in_flight += bytes;
+    }
+
+    fn on_ack(&mut self, bytes: usize, _rtt: Option<Duration>) {
+        self.in_flight = self.in_flight.saturating_sub(bytes);
+    }
+
+    fn on_loss(&mut self, bytes: usize) {
+        self.in_flight = self.in_flight.saturating_sub(bytes);
+    }
+
+    fn window(&self) -> usize {
+        self.window
+    }
+
+    fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+}
+
+/// Instantiate the strategy negotiated for a channel.
+pub(crate) fn make(alg: CongestionAlg) -> Box<dyn Decongestion> {
+    match alg {
+        CongestionAlg::None => Box::new(FixedWindow::new(usize::MAX / 2)),
+        // Only the TCP-style strategy is implemented so far; the others fall
+        // back to it rather than failing negotiation.
+        _ => Box::new(TcpDecongestion::new()),
+    }
+}
+
+/// Instantiate an algorithm selected by the application.
+pub(crate) fn make_selected(algo: CongestionAlgorithm) -> Box<dyn Decongestion> {
+    match algo {
+        CongestionAlgorithm::NewReno => Box::new(TcpDecongestion::new()),
+        CongestionAlgorithm::Vegas => Box::new(VegasDecongestion::new()),
+        CongestionAlgorithm::Bbr => Box::new(BbrDecongestion::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slow_start_doubles_per_window() {
+        let mut cc = TcpDecongestion::new();
+        let start = cc.window();
+        cc.on_sent(start);
+        cc.on_ack(start, None);
+        assert_eq!(cc.window(), 2 * start);
+    }
+
+    #[test]
+    fn loss_halves_window() {
+        let mut cc = TcpDecongestion::new();
+        let start = cc.window();
+        cc.on_sent(start);
+        cc.on_loss(MSS);
+        assert_eq!(cc.window(), start / 2);
+    }
+
+    #[test]
+    fn import_seeds_the_window_and_threshold() {
+        let mut old = TcpDecongestion::new();
+        old.on_sent(old.window());
+        old.on_ack(old.window(), None);
+        old.on_loss(MSS);
+        let snapshot = old.export();
+        let mut new = TcpDecongestion::new();
+        new.import(&snapshot);
+        assert_eq!(new.window(), old.window());
+        assert_eq!(new.ssthresh(), old.ssthresh());
+    }
+
+    #[test]
+    fn vegas_import_seeds_the_rtt_baseline() {
+        let mut snapshot = TcpDecongestion::new().export();
+        snapshot.cwnd = 20 * MSS;
+        snapshot.srtt = Some(Duration::from_millis(30));
+        let mut cc = VegasDecongestion::new();
+        cc.import(&snapshot);
+        assert_eq!(cc.window(), 20 * MSS);
+        assert_eq!(cc.base_rtt, Some(Duration::from_millis(30)));
+    }
+
+    #[test]
+    fn vegas_grows_while_the_queue_is_empty() {
+        let mut cc = VegasDecongestion::new();
+        let start = cc.window();
+        cc.on_sent(start);
+        // RTT holds at the baseline: nothing is queued, keep growing.
+        cc.on_ack(start, Some(Duration::from_millis(10)));
+        cc.on_ack(0, Some(Duration::from_millis(10)));
+        assert!(cc.window() > start);
+    }
+
+    #[test]
+    fn vegas_backs_off_when_rtt_rises() {
+        let mut cc = VegasDecongestion::new();
+        cc.on_sent(2 * INITIAL_WINDOW);
+        cc.on_ack(MSS, Some(Duration::from_millis(10)));
+        let grown = cc.window();
+        // Doubled RTT means half the window is queued in the network.
+        cc.on_ack(grown, Some(Duration::from_millis(20)));
+        assert!(cc.window() < grown);
+    }
+
+    #[test]
+    fn bbr_startup_raises_the_window_with_measured_bandwidth() {
+        let mut cc = BbrDecongestion::new();
+        let start = cc.window();
+        // Ack a window per ~12ms against a 10ms RTT: the measured rate
+        // implies a BDP near the window, and the startup gain overshoots.
+        for _ in 0..4 {
+            cc.on_sent(start);
+            std::thread::sleep(Duration::from_millis(12));
+            cc.on_ack(start, Some(Duration::from_millis(10)));
+        }
+        assert!(cc.window() > start, "window {} never grew", cc.window());
+    }
+
+    #[test]
+    fn bbr_ignores_loss() {
+        let mut cc = BbrDecongestion::new();
+        cc.on_sent(cc.window());
+        std::thread::sleep(Duration::from_millis(12));
+        cc.on_ack(cc.window(), Some(Duration::from_millis(10)));
+        let before = cc.window();
+        cc.on_loss(MSS);
+        assert_eq!(cc.window(), before, "loss is not a model input");
+    }
+
+    #[test]
+    fn may_send_respects_window() {
+        let mut cc = FixedWindow::new(3 * MSS);
+        assert!(cc.may_send(MSS));
+        cc.on_sent(3 * MSS);
+        assert!(!cc.may_send(1));
+        cc.on_ack(MSS, None);
+        assert!(cc.may_send(MSS));
+    }
+}
@@ -0,0 +1,133 @@
+//! Error types for the SSS transport.
+
+use std::io;
+
+/// Convenience alias used throughout the crate.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors surfaced by the SSS transport.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Underlying socket I/O failed.
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+
+    /// A cryptographic box failed to open or seal.
+    #[error("cryptographic failure")]
+    Crypto,
+
+    /// The peer sent something that violates the wire protocol.
+    #[error("protocol violation: {0}")]
+    Protocol(String),
+
+    /// Negotiation did not complete within the allotted time.
+    #[error("connection timed out")]
+    Timeout,
+
+    /// The channel carrying this stream was torn down.
+    #[error("connection closed")]
+    ConnectionClosed,
+
+    /// The channel carrying this stream was aborted with an error code.
+    #[error("connection reset (code {code}): {reason}")]
+    ConnectionReset {
+        /// Application error code from the connection CLOSE.
+        code: u32,
+        /// Optional human-readable reason phrase.
+        reason: String,
+    },
+
+    /// The peer reset the stream.
+    #[error("stream reset by peer (code {code}): {reason}")]
+    StreamReset {
+        /// Application error code from the RESET frame.
+        code: u32,
+        /// Optional human-readable reason phrase.
+        reason: String,
+    },
+
+    /// The peer refused the service connection request.
+    #[error("service refused (status {status}): {reason}")]
+    ServiceRefused {
+        /// Status code from the service reply.
+        status: u32,
+        /// Reply string from the responder.
+        reason: String,
+    },
+
+    /// The stream ended before a [`crate::Stream::read_exact`] filled its
+    /// buffer; the bytes read so far remain in the buffer's prefix.
+    #[error("stream ended after {read} of {expected} bytes")]
+    UnexpectedEof {
+        /// Bytes already read before the stream ended.
+        read: usize,
+        /// Bytes the call needed in total.
+        expected: usize,
+    },
+
+    /// The stream was closed for sending.
+    #[error("stream is closed for sending")]
+    StreamClosed,
+
+    /// The peer shut down its read side and will not accept more data.
+    #[error("peer stopped reading (code {code})")]
+    PeerStoppedReading {
+        /// Application reason code from the STOP_SENDING frame.
+        code: u32,
+    },
+
+    /// The concurrent substream limit was reached and the open was not
+    /// allowed to queue (or the queue itself was full).
+    #[error("substream limit reached")]
+    SubstreamLimit,
+
+    /// The datagram exceeds what one packet can carry; see
+    /// [`crate::Stream::send_datagram`].
+    #[error("datagram too large")]
+    DatagramTooLarge,
+
+    /// The negotiated feature set for this channel does not include
+    /// unreliable datagrams; see [`crate::Stream::features`].
+    #[error("peer does not support datagrams")]
+    DatagramUnsupported,
+
+    /// The host's concurrent channel limit was reached; see
+    /// [`crate::HostBuilder::max_channels`].
+    #[error("channel limit reached")]
+    ChannelLimit,
+
+    /// The operation needs exclusive use of the channel, but other streams
+    /// share it.
+    #[error("channel is shared with other streams")]
+    ChannelShared,
+
+    /// The peer announced it is shutting down and accepts no new streams
+    /// on this channel.
+    #[error("peer is going away")]
+    GoingAway,
+}
+
+impl Error {
+    pub(crate) fn protocol(msg: impl Into<String>) -> Self {
+        Error::Protocol(msg.into())
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            Error::Timeout => io::Error::new(io::ErrorKind::TimedOut, e.to_string()),
+            Error::ConnectionClosed | Error::StreamClosed | Error::PeerStoppedReading { .. } => {
+                io::Error::new(io::ErrorKind::BrokenPipe, e.to_string())
+            }
+            Error::StreamReset { .. } => {
+                io::Error::new(io::ErrorKind::ConnectionReset, e.to_string())
+            }
+            Error::UnexpectedEof { .. } => {
+                io::Error::new(io::ErrorKind::UnexpectedEof, e.to_string())
+            }
+            other => io::Error::other(other.to_string()),
+        }
+    }
+}
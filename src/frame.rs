@@ -0,0 +1,1187 @@
+//! Frame encoding and decoding (spec section 4.2).
+//!
+//! Frames are the tagged chunks carried inside a channel packet's message
+//! box. Each frame starts with a one-byte type tag followed by a
+//! type-specific layout.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::Bytes;
+
+use crate::error::{Error, Result};
+use crate::packet::{decode_be_uint, take};
+use crate::stream::Usid;
+
+/// The wire frame types (spec section 4.2), as a plain discriminant for
+/// per-type accounting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    Empty,
+    Padding,
+    Stream,
+    Close,
+    Detach,
+    Decongestion,
+    Priority,
+    Reset,
+    Ack,
+    Settings,
+    StopSending,
+    WindowUpdate,
+    Datagram,
+}
+
+/// Number of distinct [`FrameType`] values, sizing the counter arrays.
+const FRAME_TYPE_COUNT: usize = 13;
+
+/// Per-frame-type send/receive counts, from [`Host::frame_stats`].
+///
+/// [`Host::frame_stats`]: crate::Host::frame_stats
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameStats {
+    sent: [u64; FRAME_TYPE_COUNT],
+    received: [u64; FRAME_TYPE_COUNT],
+}
+
+impl FrameStats {
+    /// Frames of type `t` this host has sent.
+    pub fn sent(&self, t: FrameType) -> u64 {
+        self.sent[t as usize]
+    }
+
+    /// Frames of type `t` this host has received.
+    pub fn received(&self, t: FrameType) -> u64 {
+        self.received[t as usize]
+    }
+}
+
+/// Lock-free counters the encode/decode paths increment; snapshot via
+/// [`Host::frame_stats`](crate::Host::frame_stats).
+#[derive(Default)]
+pub(crate) struct FrameCounters {
+    sent: [AtomicU64; FRAME_TYPE_COUNT],
+    received: [AtomicU64; FRAME_TYPE_COUNT],
+}
+
+impl FrameCounters {
+    pub(crate) fn count_sent(&self, t: FrameType) {
+        self.add_sent(t, 1);
+    }
+
+    pub(crate) fn add_sent(&self, t: FrameType, n: u64) {
+        self.sent[t as usize].fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_received(&self, t: FrameType) {
+        self.received[t as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> FrameStats {
+        let mut stats = FrameStats::default();
+        for i in 0..FRAME_TYPE_COUNT {
+            stats.sent[i] = self.sent[i].load(Ordering::Relaxed);
+            stats.received[i] = self.received[i].load(Ordering::Relaxed);
+        }
+        stats
+    }
+}
+
+pub(crate) const FRAME_EMPTY: u8 = 0;
+pub(crate) const FRAME_PADDING: u8 = 1;
+pub(crate) const FRAME_STREAM: u8 = 2;
+pub(crate) const FRAME_CLOSE: u8 = 3;
+pub(crate) const FRAME_DETACH: u8 = 4;
+pub(crate) const FRAME_DECONGESTION: u8 = 5;
+pub(crate) const FRAME_PRIORITY: u8 = 6;
+pub(crate) const FRAME_RESET: u8 = 7;
+pub(crate) const FRAME_ACK: u8 = 8;
+pub(crate) const FRAME_SETTINGS: u8 = 9;
+pub(crate) const FRAME_STOP_SENDING: u8 = 10;
+pub(crate) const FRAME_WINDOW_UPDATE: u8 = 11;
+pub(crate) const FRAME_DATAGRAM: u8 = 12;
+
+const STREAM_FLAG_NOACK: u16 = 0x8000;
+const STREAM_FLAG_INIT: u16 = 0x4000;
+const STREAM_FLAG_USID: u16 = 0x2000;
+const STREAM_OFFSET_SHIFT: u16 = 10;
+const STREAM_OFFSET_MASK: u16 = 0x1c00;
+const STREAM_FLAG_DATA_LENGTH: u16 = 0x0200;
+const STREAM_FLAG_FIN: u16 = 0x0100;
+const STREAM_FLAG_RECORD: u16 = 0x0001;
+const STREAM_FLAG_METADATA: u16 = 0x0002;
+const STREAM_FLAG_WINDOW: u16 = 0x0004;
+
+/// Top bit of the RESET reason-length field: a 64-bit final offset
+/// follows the reason text.
+const RESET_FLAG_FINAL_OFFSET: u16 = 0x8000;
+
+/// SETTINGS tags (spec section 4.2.11).
+pub(crate) const SETTING_FEC: u16 = 1;
+pub(crate) const SETTING_CONGESTION_CONTROL: u16 = 2;
+pub(crate) const SETTING_GO_AWAY: u16 = 3;
+pub(crate) const SETTING_COMPRESSION: u16 = 4;
+pub(crate) const SETTING_DATAGRAM: u16 = 5;
+pub(crate) const SETTING_STREAM_LIMIT: u16 = 6;
+pub(crate) const SETTING_IDLE_TIMEOUT: u16 = 7;
+pub(crate) const SETTING_ACK_DELAY_EXPONENT: u16 = 8;
+pub(crate) const SETTING_MAX_ACK_DELAY: u16 = 9;
+
+/// A single frame within a channel packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Frame {
+    /// Single-byte filler where a PADDING frame does not fit.
+    Empty,
+    /// Explicit padding of the given length.
+    Padding(u16),
+    /// Stream data, also doubling as stream attach (INIT).
+    Stream(StreamFrame),
+    /// Channel close with an error code and a final ACK.
+    Close {
+        error_code: u32,
+        reason: String,
+        final_ack: AckFrame,
+    },
+    /// Detach a stream from this channel without closing it.
+    Detach { lsid: u32 },
+    /// Congestion control feedback, method-specific.
+    Decongestion(DecongestionFeedback),
+    /// Hint about the relative processing priority of a stream.
+    Priority { lsid: u32, priority: u32 },
+    /// Abort a stream.
+    Reset {
+        lsid: u32,
+        error_code: u32,
+        reason: String,
+        /// The absolute offset the sender's data reached before the
+        /// reset, when it chose to report one; lets a receiver of a
+        /// partial transfer record how much is missing.
+        final_offset: Option<u64>,
+    },
+    /// Packet acknowledgement.
+    Ack(AckFrame),
+    /// Ask the peer to stop sending on a stream; its reader is gone.
+    StopSending { lsid: u32, error_code: u32 },
+    /// Connection parameter negotiation.
+    Settings(Vec<Setting>),
+    /// Raise the absolute offset a sender may reach on a stream, or on
+    /// the whole connection when `lsid` is 0. Receivers treat the limit
+    /// as monotonic and ignore stale (lower) updates.
+    WindowUpdate { lsid: u32, max_offset: u64 },
+    /// An unreliable message: delivered whole or not at all, never
+    /// retransmitted. The id deduplicates recent network copies.
+    Datagram { id: u64, data: Bytes },
+}
+
+/// STREAM frame payload (spec section 4.2.4).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct StreamFrame {
+    /// Stream LSID in the sender's ID space.
+    pub lsid: u32,
+    /// Parent stream LSID; present when this frame initiates the stream.
+    pub parent_lsid: Option<u32>,
+    /// Full unique stream ID, for reattachment; requires `parent_lsid`.
+    pub usid: Option<Usid>,
+    /// Byte offset of `data` within the stream.
+    pub offset: u64,
+    /// Last transmission on this stream in this direction.
+    pub fin: bool,
+    /// Sender does not require acknowledgement of this frame.
+    pub no_ack: bool,
+    /// This frame ends a record.
+    pub record: bool,
+    /// Application metadata attached to the stream open; requires
+    /// `parent_lsid`.
+    pub metadata: Option<Bytes>,
+    /// Receive-window advertisement for this stream, in bytes; the peer
+    /// caps its unacknowledged sends toward us at this value.
+    pub window: Option<u32>,
+    /// Stream payload bytes.
+    pub data: Bytes,
+}
+
+/// ACK frame payload (spec section 4.2.10).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AckFrame {
+    pub sent_entropy: u8,
+    pub received_entropy: u8,
+    /// Smallest sequence number the sender still awaits an ack for.
+    pub least_unacked: u64,
+    /// Largest sequence number observed by the sender.
+    pub largest_observed: u64,
+    /// Microseconds from receiving `largest_observed` to sending this frame.
+    pub delta_time: u32,
+    /// RLE-encoded NACK runs: (first missing 48-bit seq, run length).
+    pub missing: Vec<(u64, u16)>,
+}
+
+impl AckFrame {
+    pub(crate) fn wire_size(&self) -> usize {
+        24 + self.missing.len() * 8
+    }
+
+    /// Build an ACK describing a set of received sequence-number ranges:
+    /// the inverse of [AckFrame::ranges]. The ranges may be given in any
+    /// order and may overlap; they are sorted and merged internally. Gaps
+    /// between them (and below the first) become NACK runs, and the
+    /// largest received number becomes `largest_observed`. `ack_delay` is
+    /// the time since that number arrived. The `least_unacked` and
+    /// entropy fields describe the builder's own sending state, which a
+    /// range set says nothing about; they are left zero for the caller.
+    pub fn from_ranges(ranges: &[std::ops::Range<u64>], ack_delay: std::time::Duration) -> Self {
+        let mut sorted: Vec<std::ops::Range<u64>> =
+            ranges.iter().filter(|r| !r.is_empty()).cloned().collect();
+        sorted.sort_by_key(|r| r.start);
+        let mut merged: Vec<std::ops::Range<u64>> = Vec::with_capacity(sorted.len());
+        for range in sorted {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        let delta_time = u32::try_from(ack_delay.as_micros()).unwrap_or(u32::MAX);
+        let Some(last) = merged.last() else {
+            return AckFrame {
+                delta_time,
+                ..AckFrame::default()
+            };
+        };
+        let mut missing = Vec::new();
+        let mut expected = 0;
+        for range in &merged {
+            // A gap longer than one run can hold is split across several.
+            while range.start > expected {
+                let run = (range.start - expected).min(u64::from(u16::MAX));
+                missing.push((expected, run as u16));
+                expected += run;
+            }
+            expected = range.end;
+        }
+        AckFrame {
+            sent_entropy: 0,
+            received_entropy: 0,
+            least_unacked: 0,
+            largest_observed: last.end - 1,
+            delta_time,
+            missing,
+        }
+    }
+
+    /// The received sequence-number ranges this ACK describes: everything
+    /// up to `largest_observed` except the NACK runs, with adjacent runs
+    /// merged. The inverse of [AckFrame::from_ranges].
+    pub fn ranges(&self) -> Vec<std::ops::Range<u64>> {
+        let mut ranges = Vec::new();
+        let mut next = 0;
+        for &(seq, run) in &self.missing {
+            if seq > next {
+                ranges.push(next..seq);
+            }
+            next = next.max(seq + u64::from(run));
+        }
+        if next <= self.largest_observed {
+            ranges.push(next..self.largest_observed + 1);
+        }
+        ranges
+    }
+
+    /// Scale an ack delay into `delta_time` wire units of two-to-the-
+    /// `exponent` microseconds, rounding down; the inverse of
+    /// [`AckFrame::decode_delay`].
+    pub fn encode_delay(delay: std::time::Duration, exponent: u8) -> u32 {
+        u32::try_from(delay.as_micros() >> exponent).unwrap_or(u32::MAX)
+    }
+
+    /// The ack delay a raw `delta_time` value represents, given the
+    /// exponent its sender advertised in SETTINGS.
+    pub fn decode_delay(delta_time: u32, exponent: u8) -> std::time::Duration {
+        std::time::Duration::from_micros(u64::from(delta_time) << exponent)
+    }
+}
+
+/// Congestion control feedback (spec section 4.2.7).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum DecongestionFeedback {
+    /// TCP-style feedback: loss count and receive window.
+    Tcp { lost_packets: u16, window: u16 },
+    /// Chicago feedback: RTT statistics as seen by the far end.
+    Chicago {
+        rtt_high: u32,
+        rtt_low: u32,
+        rtt_average: u32,
+        rtt_deviation: u32,
+    },
+}
+
+/// A single tag/value pair in a SETTINGS frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Setting {
+    /// Whether FEC is in use for this session.
+    Fec(bool),
+    /// Negotiated congestion control algorithm.
+    CongestionControl(u16),
+    /// Sender is shutting down: open no new streams on this channel.
+    GoAway,
+    /// Whether stream compression is offered (initiator) or agreed
+    /// (responder) on this channel.
+    Compression(bool),
+    /// Whether unreliable datagrams are offered (initiator) or agreed
+    /// (responder) on this channel.
+    Datagram(bool),
+    /// How many more top-level streams the sender is prepared to accept
+    /// on this channel right now; an accept-queue backpressure signal.
+    StreamLimit(u32),
+    /// The sender's idle timeout in milliseconds, so the peer knows how
+    /// long this end will keep a quiet channel alive.
+    IdleTimeout(u32),
+    /// The scale of the sender's ACK `delta_time` field: units of
+    /// two-to-the-exponent microseconds. Absent means zero (plain
+    /// microseconds).
+    AckDelayExponent(u8),
+    /// The longest the sender will sit on an acknowledgement, in
+    /// milliseconds; bounds the delay a peer should ever see reported.
+    MaxAckDelay(u32),
+}
+
+impl Setting {
+    pub(crate) fn tag(&self) -> u16 {
+        match self {
+            Setting::Fec(_) => SETTING_FEC,
+            Setting::CongestionControl(_) => SETTING_CONGESTION_CONTROL,
+            Setting::GoAway => SETTING_GO_AWAY,
+            Setting::Compression(_) => SETTING_COMPRESSION,
+            Setting::Datagram(_) => SETTING_DATAGRAM,
+            Setting::StreamLimit(_) => SETTING_STREAM_LIMIT,
+            Setting::IdleTimeout(_) => SETTING_IDLE_TIMEOUT,
+            Setting::AckDelayExponent(_) => SETTING_ACK_DELAY_EXPONENT,
+            Setting::MaxAckDelay(_) => SETTING_MAX_ACK_DELAY,
+        }
+    }
+}
+
+fn offset_width(offset: u64) -> usize {
+    // Encodable widths are 0, 2, 3, 4, 5, 6, 7 or 8 bytes.
+    if offset == 0 {
+        0
+    } else {
+        std::cmp::max(2, 8 - offset.leading_zeros() as usize / 8)
+    }
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+impl Frame {
+    pub(crate) fn frame_type(&self) -> FrameType {
+        match self {
+            Frame::Empty => FrameType::Empty,
+            Frame::Padding(_) => FrameType::Padding,
+            Frame::Stream(_) => FrameType::Stream,
+            Frame::Close { .. } => FrameType::Close,
+            Frame::Detach { .. } => FrameType::Detach,
+            Frame::Decongestion(_) => FrameType::Decongestion,
+            Frame::Priority { .. } => FrameType::Priority,
+            Frame::Reset { .. } => FrameType::Reset,
+            Frame::Ack(_) => FrameType::Ack,
+            Frame::Settings(_) => FrameType::Settings,
+            Frame::StopSending { .. } => FrameType::StopSending,
+            Frame::WindowUpdate { .. } => FrameType::WindowUpdate,
+            Frame::Datagram { .. } => FrameType::Datagram,
+        }
+    }
+
+    /// Encode this frame, appending to `buf`.
+    ///
+    /// STREAM frames are always encoded with an explicit data length; the
+    /// packetizer may rewrite the final frame of a packet to elide it.
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Frame::Empty => buf.push(FRAME_EMPTY),
+            Frame::Padding(len) => {
+                buf.push(FRAME_PADDING);
+                put_u16(buf, *len);
+                buf.resize(buf.len() + *len as usize, 0);
+            }
+            Frame::Stream(sf) => {
+                buf.push(FRAME_STREAM);
+                let mut flags = STREAM_FLAG_DATA_LENGTH;
+                if sf.parent_lsid.is_some() {
+                    flags |= STREAM_FLAG_INIT;
+                }
+                if sf.usid.is_some() {
+                    debug_assert!(sf.parent_lsid.is_some(), "USID requires INIT");
+                    flags |= STREAM_FLAG_USID;
+                }
+                if sf.fin {
+                    flags |= STREAM_FLAG_FIN;
+                }
+                if sf.no_ack {
+                    flags |= STREAM_FLAG_NOACK;
+                }
+                if sf.record {
+                    flags |= STREAM_FLAG_RECORD;
+                }
+                if sf.metadata.is_some() {
+                    debug_assert!(sf.parent_lsid.is_some(), "metadata requires INIT");
+                    flags |= STREAM_FLAG_METADATA;
+                }
+                if sf.window.is_some() {
+                    flags |= STREAM_FLAG_WINDOW;
+                }
+                let owidth = offset_width(sf.offset);
+                flags |= (owidth as u16) << STREAM_OFFSET_SHIFT & STREAM_OFFSET_MASK;
+                // Width 8 encodes as 0b111; widths 0 and 2..=7 as themselves.
+                if owidth == 8 {
+                    flags |= STREAM_OFFSET_MASK;
+                }
+                put_u16(buf, flags);
+                put_u32(buf, sf.lsid);
+                if let Some(parent) = sf.parent_lsid {
+                    put_u32(buf, parent);
+                }
+                if let Some(usid) = &sf.usid {
+                    usid.encode(buf);
+                }
+                if owidth > 0 {
+                    buf.extend_from_slice(&sf.offset.to_be_bytes()[8 - owidth..]);
+                }
+                if let Some(meta) = &sf.metadata {
+                    put_u16(buf, meta.len() as u16);
+                    buf.extend_from_slice(meta);
+                }
+                if let Some(window) = sf.window {
+                    put_u32(buf, window);
+                }
+                put_u16(buf, sf.data.len() as u16);
+                buf.extend_from_slice(&sf.data);
+            }
+            Frame::Close {
+                error_code,
+                reason,
+                final_ack,
+            } => {
+                buf.push(FRAME_CLOSE);
+                put_u32(buf, *error_code);
+                put_u16(buf, reason.len() as u16);
+                buf.extend_from_slice(reason.as_bytes());
+                Frame::Ack(final_ack.clone()).encode(buf);
+            }
+            Frame::Detach { lsid } => {
+                buf.push(FRAME_DETACH);
+                put_u32(buf, *lsid);
+            }
+            Frame::Decongestion(fb) => {
+                buf.push(FRAME_DECONGESTION);
+                match fb {
+                    DecongestionFeedback::Tcp {
+                        lost_packets,
+                        window,
+                    } => {
+                        buf.push(1);
+                        put_u16(buf, *lost_packets);
+                        put_u16(buf, *window);
+                    }
+                    DecongestionFeedback::Chicago {
+                        rtt_high,
+                        rtt_low,
+                        rtt_average,
+                        rtt_deviation,
+                    } => {
+                        buf.push(2);
+                        put_u32(buf, *rtt_high);
+                        put_u32(buf, *rtt_low);
+                        put_u32(buf, *rtt_average);
+                        put_u32(buf, *rtt_deviation);
+                    }
+                }
+            }
+            Frame::Priority { lsid, priority } => {
+                buf.push(FRAME_PRIORITY);
+                put_u32(buf, *lsid);
+                put_u32(buf, *priority);
+            }
+            Frame::Reset {
+                lsid,
+                error_code,
+                reason,
+                final_offset,
+            } => {
+                buf.push(FRAME_RESET);
+                put_u32(buf, *lsid);
+                put_u32(buf, *error_code);
+                let mut rlen = reason.len() as u16;
+                if final_offset.is_some() {
+                    rlen |= RESET_FLAG_FINAL_OFFSET;
+                }
+                put_u16(buf, rlen);
+                buf.extend_from_slice(reason.as_bytes());
+                if let Some(offset) = final_offset {
+                    buf.extend_from_slice(&offset.to_be_bytes());
+                }
+            }
+            Frame::Ack(ack) => {
+                buf.push(FRAME_ACK);
+                buf.push(ack.sent_entropy);
+                buf.push(ack.received_entropy);
+                buf.push(ack.missing.len() as u8);
+                buf.extend_from_slice(&ack.least_unacked.to_be_bytes());
+                buf.extend_from_slice(&ack.largest_observed.to_be_bytes());
+                put_u32(buf, ack.delta_time);
+                for (seq, run) in &ack.missing {
+                    buf.extend_from_slice(&seq.to_be_bytes()[2..]);
+                    put_u16(buf, *run);
+                }
+            }
+            Frame::StopSending { lsid, error_code } => {
+                buf.push(FRAME_STOP_SENDING);
+                put_u32(buf, *lsid);
+                put_u32(buf, *error_code);
+            }
+            Frame::WindowUpdate { lsid, max_offset } => {
+                buf.push(FRAME_WINDOW_UPDATE);
+                put_u32(buf, *lsid);
+                buf.extend_from_slice(&max_offset.to_be_bytes());
+            }
+            Frame::Datagram { id, data } => {
+                buf.push(FRAME_DATAGRAM);
+                buf.extend_from_slice(&id.to_be_bytes());
+                put_u16(buf, data.len() as u16);
+                buf.extend_from_slice(data);
+            }
+            Frame::Settings(settings) => {
+                buf.push(FRAME_SETTINGS);
+                put_u16(buf, settings.len() as u16);
+                for setting in settings {
+                    put_u16(buf, setting.tag());
+                    match setting {
+                        Setting::Fec(on) => buf.push(u8::from(*on)),
+                        Setting::CongestionControl(alg) => put_u16(buf, *alg),
+                        Setting::GoAway => buf.push(1),
+                        Setting::Compression(on) => buf.push(u8::from(*on)),
+                        Setting::Datagram(on) => buf.push(u8::from(*on)),
+                        Setting::StreamLimit(n) => put_u32(buf, *n),
+                        Setting::IdleTimeout(ms) => put_u32(buf, *ms),
+                        Setting::AckDelayExponent(e) => buf.push(*e),
+                        Setting::MaxAckDelay(ms) => put_u32(buf, *ms),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Decode one frame, advancing `buf` past it.
+    pub(crate) fn decode(buf: &mut &[u8]) -> Result<Frame> {
+        let frame_type = take(buf, 1)?[0];
+        match frame_type {
+            // An EMPTY frame is exactly its one type byte, with no
+            // length and no body. The sealed box fixes the payload
+            // length, so there is no such thing as raw trailing
+            // padding: every byte belongs to a frame, and a zero-filled
+            // tail parses as a run of EMPTY frames.
+            FRAME_EMPTY => Ok(Frame::Empty),
+            // PADDING's body is length-prefixed and skipped without
+            // interpretation; zero bytes inside it never read as EMPTY
+            // frames.
+            FRAME_PADDING => {
+                let len = decode_be_uint(take(buf, 2)?) as u16;
+                take(buf, len as usize)?;
+                Ok(Frame::Padding(len))
+            }
+            FRAME_STREAM => {
+                let flags = decode_be_uint(take(buf, 2)?) as u16;
+                let lsid = decode_be_uint(take(buf, 4)?) as u32;
+                let parent_lsid = if flags & STREAM_FLAG_INIT != 0 {
+                    Some(decode_be_uint(take(buf, 4)?) as u32)
+                } else {
+                    None
+                };
+                let usid = if flags & STREAM_FLAG_USID != 0 {
+                    if parent_lsid.is_none() {
+                        return Err(Error::protocol("USID flag without INIT"));
+                    }
+                    Some(Usid::decode(buf)?)
+                } else {
+                    None
+                };
+                let owidth = match (flags & STREAM_OFFSET_MASK) >> STREAM_OFFSET_SHIFT {
+                    0 => 0,
+                    7 => 8,
+                    w => w as usize,
+                };
+                let offset = decode_be_uint(take(buf, owidth)?);
+                let metadata = if flags & STREAM_FLAG_METADATA != 0 {
+                    if parent_lsid.is_none() {
+                        return Err(Error::protocol("metadata flag without INIT"));
+                    }
+                    let len = decode_be_uint(take(buf, 2)?) as usize;
+                    if len > buf.len() {
+                        return Err(Error::Protocol(format!(
+                            "STREAM metadata length {len} exceeds the {} bytes left",
+                            buf.len()
+                        )));
+                    }
+                    Some(Bytes::copy_from_slice(take(buf, len)?))
+                } else {
+                    None
+                };
+                let window = if flags & STREAM_FLAG_WINDOW != 0 {
+                    Some(decode_be_uint(take(buf, 4)?) as u32)
+                } else {
+                    None
+                };
+                let data = if flags & STREAM_FLAG_DATA_LENGTH != 0 {
+                    let len = decode_be_uint(take(buf, 2)?) as usize;
+                    if len > buf.len() {
+                        return Err(Error::Protocol(format!(
+                            "STREAM data length {len} exceeds the {} bytes left",
+                            buf.len()
+                        )));
+                    }
+                    Bytes::copy_from_slice(take(buf, len)?)
+                } else {
+                    // Data extends to the end of the packet.
+                    let data = Bytes::copy_from_slice(buf);
+                    *buf = &[];
+                    data
+                };
+                Ok(Frame::Stream(StreamFrame {
+                    lsid,
+                    parent_lsid,
+                    usid,
+                    offset,
+                    fin: flags & STREAM_FLAG_FIN != 0,
+                    no_ack: flags & STREAM_FLAG_NOACK != 0,
+                    record: flags & STREAM_FLAG_RECORD != 0,
+                    metadata,
+                    window,
+                    data,
+                }))
+            }
+            FRAME_CLOSE => {
+                let error_code = decode_be_uint(take(buf, 4)?) as u32;
+                let rlen = decode_be_uint(take(buf, 2)?) as usize;
+                let reason = String::from_utf8_lossy(take(buf, rlen)?).into_owned();
+                let final_ack = match Frame::decode(buf)? {
+                    Frame::Ack(ack) => ack,
+                    _ => return Err(Error::protocol("CLOSE frame without final ACK")),
+                };
+                Ok(Frame::Close {
+                    error_code,
+                    reason,
+                    final_ack,
+                })
+            }
+            FRAME_DETACH => Ok(Frame::Detach {
+                lsid: decode_be_uint(take(buf, 4)?) as u32,
+            }),
+            FRAME_DECONGESTION => {
+                let subtype = take(buf, 1)?[0];
+                match subtype {
+                    1 => Ok(Frame::Decongestion(DecongestionFeedback::Tcp {
+                        lost_packets: decode_be_uint(take(buf, 2)?) as u16,
+                        window: decode_be_uint(take(buf, 2)?) as u16,
+                    })),
+                    2 => Ok(Frame::Decongestion(DecongestionFeedback::Chicago {
+                        rtt_high: decode_be_uint(take(buf, 4)?) as u32,
+                        rtt_low: decode_be_uint(take(buf, 4)?) as u32,
+                        rtt_average: decode_be_uint(take(buf, 4)?) as u32,
+                        rtt_deviation: decode_be_uint(take(buf, 4)?) as u32,
+                    })),
+                    other => Err(Error::Protocol(format!(
+                        "unknown decongestion feedback subtype {other}"
+                    ))),
+                }
+            }
+            FRAME_PRIORITY => Ok(Frame::Priority {
+                lsid: decode_be_uint(take(buf, 4)?) as u32,
+                priority: decode_be_uint(take(buf, 4)?) as u32,
+            }),
+            FRAME_RESET => {
+                let lsid = decode_be_uint(take(buf, 4)?) as u32;
+                let error_code = decode_be_uint(take(buf, 4)?) as u32;
+                let rlen = decode_be_uint(take(buf, 2)?) as u16;
+                let reason_len = usize::from(rlen & !RESET_FLAG_FINAL_OFFSET);
+                let reason = String::from_utf8_lossy(take(buf, reason_len)?).into_owned();
+                let final_offset = if rlen & RESET_FLAG_FINAL_OFFSET != 0 {
+                    Some(decode_be_uint(take(buf, 8)?))
+                } else {
+                    None
+                };
+                Ok(Frame::Reset {
+                    lsid,
+                    error_code,
+                    reason,
+                    final_offset,
+                })
+            }
+            FRAME_ACK => {
+                let sent_entropy = take(buf, 1)?[0];
+                let received_entropy = take(buf, 1)?[0];
+                let num_missing = take(buf, 1)?[0] as usize;
+                if num_missing * 8 > buf.len().saturating_sub(20) {
+                    return Err(Error::Protocol(format!(
+                        "ACK with {num_missing} NACK runs exceeds the {} bytes left",
+                        buf.len()
+                    )));
+                }
+                let least_unacked = decode_be_uint(take(buf, 8)?);
+                let largest_observed = decode_be_uint(take(buf, 8)?);
+                let delta_time = decode_be_uint(take(buf, 4)?) as u32;
+                let mut missing = Vec::with_capacity(num_missing);
+                for _ in 0..num_missing {
+                    let seq = decode_be_uint(take(buf, 6)?);
+                    let run = decode_be_uint(take(buf, 2)?) as u16;
+                    if run == 0 {
+                        return Err(Error::protocol("empty NACK run"));
+                    }
+                    missing.push((seq, run));
+                }
+                Ok(Frame::Ack(AckFrame {
+                    sent_entropy,
+                    received_entropy,
+                    least_unacked,
+                    largest_observed,
+                    delta_time,
+                    missing,
+                }))
+            }
+            FRAME_SETTINGS => {
+                let count = decode_be_uint(take(buf, 2)?) as usize;
+                // Every entry is a 2-byte tag plus at least one value byte.
+                if count * 3 > buf.len() {
+                    return Err(Error::Protocol(format!(
+                        "SETTINGS with {count} entries exceeds the {} bytes left",
+                        buf.len()
+                    )));
+                }
+                let mut settings = Vec::with_capacity(count);
+                let mut last_tag = 0u16;
+                for _ in 0..count {
+                    let tag = decode_be_uint(take(buf, 2)?) as u16;
+                    if tag <= last_tag {
+                        return Err(Error::protocol("SETTINGS tags not strictly increasing"));
+                    }
+                    last_tag = tag;
+                    let setting = match tag {
+                        SETTING_FEC => Setting::Fec(take(buf, 1)?[0] != 0),
+                        SETTING_CONGESTION_CONTROL => {
+                            Setting::CongestionControl(decode_be_uint(take(buf, 2)?) as u16)
+                        }
+                        SETTING_GO_AWAY => {
+                            take(buf, 1)?;
+                            Setting::GoAway
+                        }
+                        SETTING_COMPRESSION => Setting::Compression(take(buf, 1)?[0] != 0),
+                        SETTING_DATAGRAM => Setting::Datagram(take(buf, 1)?[0] != 0),
+                        SETTING_STREAM_LIMIT => {
+                            Setting::StreamLimit(decode_be_uint(take(buf, 4)?) as u32)
+                        }
+                        SETTING_IDLE_TIMEOUT => {
+                            Setting::IdleTimeout(decode_be_uint(take(buf, 4)?) as u32)
+                        }
+                        SETTING_ACK_DELAY_EXPONENT => Setting::AckDelayExponent(take(buf, 1)?[0]),
+                        SETTING_MAX_ACK_DELAY => {
+                            Setting::MaxAckDelay(decode_be_uint(take(buf, 4)?) as u32)
+                        }
+                        other => {
+                            return Err(Error::Protocol(format!("unknown SETTINGS tag {other}")))
+                        }
+                    };
+                    settings.push(setting);
+                }
+                Ok(Frame::Settings(settings))
+            }
+            FRAME_STOP_SENDING => Ok(Frame::StopSending {
+                lsid: decode_be_uint(take(buf, 4)?) as u32,
+                error_code: decode_be_uint(take(buf, 4)?) as u32,
+            }),
+            FRAME_WINDOW_UPDATE => Ok(Frame::WindowUpdate {
+                lsid: decode_be_uint(take(buf, 4)?) as u32,
+                max_offset: decode_be_uint(take(buf, 8)?),
+            }),
+            FRAME_DATAGRAM => {
+                let id = decode_be_uint(take(buf, 8)?);
+                let len = decode_be_uint(take(buf, 2)?) as usize;
+                if len > buf.len() {
+                    return Err(Error::Protocol(format!(
+                        "DATAGRAM length {len} exceeds the {} bytes left",
+                        buf.len()
+                    )));
+                }
+                Ok(Frame::Datagram {
+                    id,
+                    data: Bytes::copy_from_slice(take(buf, len)?),
+                })
+            }
+            other => Err(Error::Protocol(format!("unknown frame type {other}"))),
+        }
+    }
+
+    /// Decode every frame in a packet payload.
+    pub(crate) fn decode_all(mut buf: &[u8]) -> Result<Vec<Frame>> {
+        let mut frames = Vec::new();
+        while !buf.is_empty() {
+            frames.push(Frame::decode(&mut buf)?);
+        }
+        Ok(frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(frame: Frame) {
+        let mut buf = Vec::new();
+        frame.encode(&mut buf);
+        let decoded = Frame::decode_all(&buf).unwrap();
+        assert_eq!(decoded, vec![frame]);
+    }
+
+    #[test]
+    fn roundtrip_empty_and_padding() {
+        roundtrip(Frame::Empty);
+        roundtrip(Frame::Padding(0));
+        roundtrip(Frame::Padding(100));
+    }
+
+    #[test]
+    fn roundtrip_datagram() {
+        roundtrip(Frame::Datagram {
+            id: 17,
+            data: Bytes::from_static(b"fire and forget"),
+        });
+    }
+
+    #[test]
+    fn roundtrip_window_update() {
+        roundtrip(Frame::WindowUpdate {
+            lsid: 0,
+            max_offset: 1 << 40,
+        });
+        roundtrip(Frame::WindowUpdate {
+            lsid: 7,
+            max_offset: 4096,
+        });
+    }
+
+    #[test]
+    fn mixed_empty_and_padding_frames_parse_unambiguously() {
+        // PADDING's zero-filled body must not be mistaken for EMPTY
+        // frames, and EMPTY frames on either side of it must each
+        // consume exactly one byte.
+        let mut buf = Vec::new();
+        Frame::Empty.encode(&mut buf);
+        Frame::Padding(4).encode(&mut buf);
+        Frame::Empty.encode(&mut buf);
+        Frame::Empty.encode(&mut buf);
+        assert_eq!(buf.len(), 1 + 7 + 1 + 1);
+        let frames = Frame::decode_all(&buf).unwrap();
+        assert_eq!(
+            frames,
+            vec![Frame::Empty, Frame::Padding(4), Frame::Empty, Frame::Empty]
+        );
+    }
+
+    #[test]
+    fn a_zero_filled_tail_is_a_run_of_empty_frames() {
+        let mut buf = Vec::new();
+        Frame::Detach { lsid: 5 }.encode(&mut buf);
+        buf.extend_from_slice(&[0; 3]);
+        let frames = Frame::decode_all(&buf).unwrap();
+        assert_eq!(
+            frames,
+            vec![Frame::Detach { lsid: 5 }, Frame::Empty, Frame::Empty, Frame::Empty]
+        );
+    }
+
+    #[test]
+    fn roundtrip_stream_plain() {
+        roundtrip(Frame::Stream(StreamFrame {
+            lsid: 1,
+            parent_lsid: None,
+            usid: None,
+            offset: 0,
+            fin: false,
+            no_ack: false,
+            record: false,
+            metadata: None,
+            window: None,
+            data: Bytes::from_static(b"hello world"),
+        }));
+    }
+
+    #[test]
+    fn roundtrip_stream_with_a_window_advertisement() {
+        roundtrip(Frame::Stream(StreamFrame {
+            lsid: 3,
+            parent_lsid: None,
+            usid: None,
+            offset: 4096,
+            fin: false,
+            no_ack: false,
+            record: false,
+            metadata: None,
+            window: Some(64 * 1024),
+            data: Bytes::new(),
+        }));
+    }
+
+    #[test]
+    fn stream_frame_with_oversized_length_is_rejected() {
+        let mut buf = Vec::new();
+        Frame::Stream(StreamFrame {
+            lsid: 1,
+            parent_lsid: None,
+            usid: None,
+            offset: 0,
+            fin: false,
+            no_ack: false,
+            record: false,
+            metadata: None,
+            window: None,
+            data: Bytes::from_static(b"short"),
+        })
+        .encode(&mut buf);
+        // Inflate the declared data length past the end of the buffer.
+        let len_at = buf.len() - 5 - 2;
+        buf[len_at..len_at + 2].copy_from_slice(&100u16.to_be_bytes());
+        assert!(Frame::decode_all(&buf).is_err());
+    }
+
+    #[test]
+    fn stream_frame_length_consumes_exactly_declared_bytes() {
+        let mut buf = Vec::new();
+        Frame::Stream(StreamFrame {
+            lsid: 1,
+            parent_lsid: None,
+            usid: None,
+            offset: 0,
+            fin: false,
+            no_ack: false,
+            record: false,
+            metadata: None,
+            window: None,
+            data: Bytes::from_static(b"exactly this"),
+        })
+        .encode(&mut buf);
+        Frame::Detach { lsid: 9 }.encode(&mut buf);
+        let frames = Frame::decode_all(&buf).unwrap();
+        assert_eq!(frames.len(), 2);
+        match &frames[0] {
+            Frame::Stream(sf) => assert_eq!(&sf.data[..], b"exactly this"),
+            other => panic!("unexpected {other:?}"),
+        }
+        assert_eq!(frames[1], Frame::Detach { lsid: 9 });
+    }
+
+    #[test]
+    fn settings_count_exceeding_buffer_is_rejected() {
+        let buf = [FRAME_SETTINGS, 0xff, 0xff, 0, 1, 1];
+        assert!(Frame::decode_all(&buf).is_err());
+    }
+
+    #[test]
+    fn roundtrip_stream_init_with_usid() {
+        roundtrip(Frame::Stream(StreamFrame {
+            lsid: 3,
+            parent_lsid: Some(0),
+            usid: Some(Usid {
+                half_channel: [7; 16],
+                counter: 42,
+            }),
+            offset: 0x1234_5678_9abc,
+            fin: true,
+            no_ack: false,
+            record: true,
+            metadata: None,
+            window: None,
+            data: Bytes::from_static(b"payload"),
+        }));
+    }
+
+    #[test]
+    fn roundtrip_stream_init_with_metadata() {
+        roundtrip(Frame::Stream(StreamFrame {
+            lsid: 5,
+            parent_lsid: Some(1),
+            usid: None,
+            offset: 0,
+            fin: false,
+            no_ack: false,
+            record: false,
+            metadata: Some(Bytes::from_static(b"GET /index")),
+            window: None,
+            data: Bytes::from_static(b"body"),
+        }));
+    }
+
+    #[test]
+    fn stream_metadata_without_init_is_rejected() {
+        let mut buf = Vec::new();
+        Frame::Stream(StreamFrame {
+            lsid: 5,
+            parent_lsid: Some(1),
+            usid: None,
+            offset: 0,
+            fin: false,
+            no_ack: false,
+            record: false,
+            metadata: Some(Bytes::from_static(b"meta")),
+            window: None,
+            data: Bytes::new(),
+        })
+        .encode(&mut buf);
+        // Clear the INIT flag, leaving the metadata flag set.
+        buf[1] &= !0x40;
+        assert!(Frame::decode_all(&buf).is_err());
+    }
+
+    #[test]
+    fn usid_without_init_rejected() {
+        let mut buf = Vec::new();
+        buf.push(FRAME_STREAM);
+        buf.extend_from_slice(&STREAM_FLAG_USID.to_be_bytes());
+        buf.extend_from_slice(&1u32.to_be_bytes());
+        assert!(Frame::decode_all(&buf).is_err());
+    }
+
+    #[test]
+    fn roundtrip_ack_with_nacks() {
+        roundtrip(Frame::Ack(AckFrame {
+            sent_entropy: 1,
+            received_entropy: 2,
+            least_unacked: 5,
+            largest_observed: 900,
+            delta_time: 1000,
+            missing: vec![(7, 3), (100, 1)],
+        }));
+    }
+
+    #[test]
+    fn an_ack_built_from_ranges_decodes_back_to_them() {
+        use std::time::Duration;
+
+        let ranges = [0..5, 7..9];
+        let ack = AckFrame::from_ranges(&ranges, Duration::from_micros(250));
+        assert_eq!(ack.largest_observed, 8);
+        assert_eq!(ack.missing, vec![(5, 2)]);
+        let mut buf = Vec::new();
+        Frame::Ack(ack).encode(&mut buf);
+        let decoded = match Frame::decode_all(&buf).unwrap().pop().unwrap() {
+            Frame::Ack(ack) => ack,
+            other => panic!("decoded {other:?}"),
+        };
+        assert_eq!(decoded.ranges(), ranges);
+        assert_eq!(decoded.delta_time, 250);
+    }
+
+    #[test]
+    fn ack_delay_scaling_roundtrips_with_a_nonzero_exponent() {
+        use std::time::Duration;
+
+        // 10ms in units of 8us survives the wire roundtrip exactly.
+        let raw = AckFrame::encode_delay(Duration::from_millis(10), 3);
+        assert_eq!(raw, 1250);
+        let mut buf = Vec::new();
+        Frame::Ack(AckFrame {
+            delta_time: raw,
+            ..AckFrame::default()
+        })
+        .encode(&mut buf);
+        let decoded = match Frame::decode_all(&buf).unwrap().pop().unwrap() {
+            Frame::Ack(ack) => ack,
+            other => panic!("decoded {other:?}"),
+        };
+        assert_eq!(
+            AckFrame::decode_delay(decoded.delta_time, 3),
+            Duration::from_millis(10)
+        );
+
+        // Encoding rounds down, losing at most one unit of the scale.
+        let lossy = AckFrame::encode_delay(Duration::from_micros(1003), 3);
+        assert_eq!(
+            AckFrame::decode_delay(lossy, 3),
+            Duration::from_micros(1000)
+        );
+    }
+
+    #[test]
+    fn from_ranges_sorts_and_merges_its_input() {
+        use std::time::Duration;
+
+        let ack = AckFrame::from_ranges(&[7..9, 0..3, 2..5, 4..4], Duration::ZERO);
+        assert_eq!(ack.ranges(), vec![0..5, 7..9]);
+        // A leading gap below the first range counts as missing too.
+        let ack = AckFrame::from_ranges(std::slice::from_ref(&(3..4)), Duration::ZERO);
+        assert_eq!(ack.missing, vec![(0, 3)]);
+        assert_eq!(ack.ranges(), vec![3..4]);
+    }
+
+    #[test]
+    fn roundtrip_close_with_final_ack() {
+        roundtrip(Frame::Close {
+            error_code: 2,
+            reason: "going away".into(),
+            final_ack: AckFrame {
+                largest_observed: 17,
+                ..AckFrame::default()
+            },
+        });
+    }
+
+    #[test]
+    fn roundtrip_control_frames() {
+        roundtrip(Frame::Detach { lsid: 9 });
+        roundtrip(Frame::Priority {
+            lsid: 4,
+            priority: 100,
+        });
+        roundtrip(Frame::Reset {
+            lsid: 8,
+            error_code: 404,
+            reason: "not wanted".into(),
+            final_offset: None,
+        });
+        roundtrip(Frame::Reset {
+            lsid: 8,
+            error_code: 404,
+            reason: "interrupted".into(),
+            final_offset: Some(1 << 40),
+        });
+        roundtrip(Frame::Decongestion(DecongestionFeedback::Tcp {
+            lost_packets: 3,
+            window: 1000,
+        }));
+    }
+
+    #[test]
+    fn roundtrip_settings() {
+        roundtrip(Frame::Settings(vec![
+            Setting::Fec(false),
+            Setting::CongestionControl(1),
+            Setting::GoAway,
+        ]));
+    }
+
+    #[test]
+    fn settings_tags_must_increase() {
+        let mut buf = Vec::new();
+        Frame::Settings(vec![Setting::CongestionControl(1), Setting::Fec(true)]).encode(&mut buf);
+        assert!(Frame::decode_all(&buf).is_err());
+    }
+
+    #[test]
+    fn multiple_frames_in_one_payload() {
+        let mut buf = Vec::new();
+        Frame::Detach { lsid: 1 }.encode(&mut buf);
+        Frame::Empty.encode(&mut buf);
+        Frame::Padding(5).encode(&mut buf);
+        let frames = Frame::decode_all(&buf).unwrap();
+        assert_eq!(frames.len(), 3);
+    }
+}
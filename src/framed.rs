@@ -0,0 +1,215 @@
+//! Length-delimited message framing over a stream.
+//!
+//! [`Stream::into_framed`] wraps a byte stream in a codec that prepends a
+//! big-endian length prefix to every outgoing message and strips it again
+//! on the way in, turning the stream into a [`futures_sink::Sink`] of
+//! whole messages and a [`futures_core::Stream`] of the peer's. Partial
+//! frames spanning packet boundaries are reassembled internally.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::error::{Error, Result};
+use crate::stream::Stream;
+
+/// Default cap on a single message, guarding against a hostile or corrupt
+/// length prefix.
+const DEFAULT_MAX_FRAME: usize = 1024 * 1024;
+
+/// How many bytes to pull from the stream per read while reassembling.
+const READ_CHUNK: usize = 16 * 1024;
+
+/// Length-prefix framing parameters for [`Stream::into_framed`].
+#[derive(Debug, Clone, Copy)]
+pub struct LengthDelimitedCodec {
+    length_bytes: usize,
+    max_frame: usize,
+}
+
+impl LengthDelimitedCodec {
+    /// A four-byte big-endian length prefix, frames capped at one MiB.
+    pub fn new() -> Self {
+        LengthDelimitedCodec {
+            length_bytes: 4,
+            max_frame: DEFAULT_MAX_FRAME,
+        }
+    }
+
+    /// Use a length prefix of `n` bytes (1, 2, 4 or 8).
+    ///
+    /// # Panics
+    ///
+    /// Panics on any other width.
+    pub fn length_bytes(mut self, n: usize) -> Self {
+        assert!(
+            matches!(n, 1 | 2 | 4 | 8),
+            "length prefix must be 1, 2, 4 or 8 bytes"
+        );
+        self.length_bytes = n;
+        self
+    }
+
+    /// Refuse to send or accept messages longer than `bytes`.
+    pub fn max_frame(mut self, bytes: usize) -> Self {
+        self.max_frame = bytes;
+        self
+    }
+
+    fn decode_len(&self, prefix: &[u8]) -> u64 {
+        prefix.iter().fold(0u64, |acc, &b| acc << 8 | u64::from(b))
+    }
+}
+
+impl Default for LengthDelimitedCodec {
+    fn default() -> Self {
+        LengthDelimitedCodec::new()
+    }
+}
+
+/// A stream adapted to whole-message semantics; see [`Stream::into_framed`].
+pub struct Framed {
+    stream: Stream,
+    codec: LengthDelimitedCodec,
+    /// Inbound bytes not yet forming a complete frame.
+    read_buf: Vec<u8>,
+    /// Encoded outbound bytes the stream has not yet accepted.
+    write_buf: Vec<u8>,
+    /// The byte stream ended; only buffered frames remain.
+    read_done: bool,
+}
+
+impl Stream {
+    /// Adapt this stream to message semantics: a [`futures_sink::Sink`]
+    /// accepting whole messages and a [`futures_core::Stream`] yielding the
+    /// peer's, delimited by the codec's length prefix.
+    pub fn into_framed(self, codec: LengthDelimitedCodec) -> Framed {
+        Framed {
+            stream: self,
+            codec,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            read_done: false,
+        }
+    }
+}
+
+impl Framed {
+    /// The underlying stream, dropping any partially received frame.
+    pub fn into_inner(self) -> Stream {
+        self.stream
+    }
+
+    /// Push buffered outbound bytes into the stream until it stops
+    /// accepting them or none remain.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        while !self.write_buf.is_empty() {
+            match Pin::new(&mut self.stream).poll_write(cx, &self.write_buf) {
+                Poll::Ready(Ok(n)) => {
+                    self.write_buf.drain(..n);
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl futures_core::Stream for Framed {
+    type Item = Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            // A complete prefix and frame may already be buffered.
+            let prefix = this.codec.length_bytes;
+            if this.read_buf.len() >= prefix {
+                let len = this.codec.decode_len(&this.read_buf[..prefix]);
+                if len > this.codec.max_frame as u64 {
+                    return Poll::Ready(Some(Err(Error::protocol(format!(
+                        "framed message of {len} bytes exceeds the cap"
+                    )))));
+                }
+                let len = len as usize;
+                if this.read_buf.len() >= prefix + len {
+                    let frame = Bytes::copy_from_slice(&this.read_buf[prefix..prefix + len]);
+                    this.read_buf.drain(..prefix + len);
+                    return Poll::Ready(Some(Ok(frame)));
+                }
+            }
+            if this.read_done {
+                return if this.read_buf.is_empty() {
+                    Poll::Ready(None)
+                } else {
+                    // The peer closed mid-frame; surface it once.
+                    this.read_buf.clear();
+                    Poll::Ready(Some(Err(Error::protocol(
+                        "stream ended inside a framed message",
+                    ))))
+                };
+            }
+            let mut chunk = [0u8; READ_CHUNK];
+            let mut buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.stream).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) if buf.filled().is_empty() => this.read_done = true,
+                Poll::Ready(Ok(())) => this.read_buf.extend_from_slice(buf.filled()),
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl futures_sink::Sink<Bytes> for Framed {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_drain(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> Result<()> {
+        let this = self.get_mut();
+        if item.len() > this.codec.max_frame {
+            return Err(Error::protocol(format!(
+                "framed message of {} bytes exceeds the cap",
+                item.len()
+            )));
+        }
+        let prefix = this.codec.length_bytes;
+        if prefix < 8 && item.len() as u128 >= 1 << (8 * prefix as u32) {
+            return Err(Error::protocol(format!(
+                "framed message of {} bytes does not fit a {prefix}-byte prefix",
+                item.len()
+            )));
+        }
+        let len = (item.len() as u64).to_be_bytes();
+        this.write_buf.extend_from_slice(&len[8 - prefix..]);
+        this.write_buf.extend_from_slice(&item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+        Pin::new(&mut this.stream)
+            .poll_flush(cx)
+            .map_err(Error::from)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+        Pin::new(&mut this.stream)
+            .poll_shutdown(cx)
+            .map_err(Error::from)
+    }
+}
@@ -0,0 +1,1869 @@
+//! The SSS host: socket ownership, negotiation and channel demultiplexing.
+//!
+//! A [`Host`] owns a datagram socket and a long-term identity. It accepts
+//! inbound negotiation, demultiplexes MESSAGE packets to channels by
+//! short-term public key, and hands application streams to listeners
+//! registered per (service, protocol) pair.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::task::JoinHandle;
+
+use crate::channel::{self, ChannelShared, CwndEvent, CwndHook, Role};
+use crate::decongestion::CongestionSnapshot;
+use crate::crypto::{HostRng, Identity, MinuteKeys, PublicKey, ShortTermKey, KEY_SIZE};
+use crate::error::{Error, Result};
+use crate::frame::{Frame, FrameCounters, FrameStats, FrameType, Setting};
+use crate::mtu::{DEFAULT_PACKET_SIZE, MAX_PACKET_SIZE, MIN_PACKET_SIZE};
+use crate::negotiation::{self, CookiePayload};
+use crate::packet::{
+    Packet, PacketHeader, MAGIC_COOKIE, MAGIC_HELLO, MAGIC_INITIATE, MAGIC_MESSAGE,
+};
+use crate::packetizer::Packetizer;
+use crate::pool::{BufferPool, DEFAULT_POOL_SIZE};
+use crate::sim::SimSocket;
+use crate::socket::Socket;
+use crate::stream::{service, Stream, StreamShared, ROOT_LSID};
+use crate::wheel::TimerWheel;
+
+/// Default channel lifetime without traffic (spec section 3.1.3).
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// Default overall deadline for connection establishment.
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default cap on concurrently open substreams per channel.
+const DEFAULT_MAX_SUBSTREAMS: usize = 256;
+/// Initial HELLO retransmission interval.
+const HELLO_RETRY: Duration = Duration::from_millis(250);
+
+/// Caps on the early-MESSAGE stash: distinct connection ids, and packets
+/// kept per id.
+const EARLY_MESSAGE_KEYS: usize = 16;
+const EARLY_MESSAGES_PER_KEY: usize = 8;
+/// Capacity of each listener's accept queue.
+const ACCEPT_QUEUE: usize = 16;
+/// How long a handshake slot stays held without its INITIATE arriving
+/// before the limiter reclaims it.
+const HANDSHAKE_SLOT_TTL: Duration = Duration::from_secs(30);
+
+/// How a host treats repeated connects to the same peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChannelPolicy {
+    /// Reuse one established channel per peer; later connects open a new
+    /// top-level stream on it.
+    #[default]
+    Single,
+    /// Give every connect its own channel, for multipath topologies.
+    Multi,
+}
+
+/// Per-connect channel selection for [`Host::connect_with_channel`],
+/// overriding the host-wide [`ChannelPolicy`] for one call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelChoice {
+    /// Ride an established channel to the peer when one exists,
+    /// negotiating one otherwise: the [`ChannelPolicy::Single`] behavior.
+    Reuse,
+    /// Negotiate a fresh channel even when one exists, isolating the
+    /// stream's congestion state and migration from other traffic.
+    New,
+    /// Require an established channel and fail with
+    /// [`Error::ConnectionClosed`] when there is none.
+    Existing,
+}
+
+/// Host-wide configuration, set through [`HostBuilder`].
+pub(crate) struct Config {
+    pub(crate) idle_timeout: Duration,
+    /// Deadline for connection establishment, unless overridden per call.
+    pub(crate) connect_timeout: Duration,
+    /// Initial UDP payload budget for new channels.
+    pub(crate) packet_size: usize,
+    /// Hard cap on the UDP payload size, bounding PMTU discovery.
+    pub(crate) packet_ceiling: usize,
+    /// Whether channels probe for a larger path MTU.
+    pub(crate) mtu_probing: bool,
+    /// Cap on concurrently open substreams per channel.
+    pub(crate) max_substreams: usize,
+    /// Channel reuse policy for repeated connects to one peer.
+    pub(crate) channel_policy: ChannelPolicy,
+    /// Detach and hibernate streams on channel teardown instead of
+    /// closing them.
+    pub(crate) detach_on_idle: bool,
+    /// Observer invoked on every congestion-window change, for tuning.
+    pub(crate) on_cwnd_change: Option<CwndHook>,
+    /// Observer invoked once per newly established channel.
+    pub(crate) on_channel_established: Option<EstablishedHook>,
+    /// Permitted UDP payload sizes, ascending; empty means unrestricted.
+    pub(crate) pad_sizes: Vec<usize>,
+    /// At most this many new connections per source IP per interval.
+    pub(crate) accept_rate_limit: Option<(usize, Duration)>,
+    /// Give a channel up as dead after this many consecutive
+    /// retransmission timeouts without an acknowledgement.
+    pub(crate) max_retransmits: Option<u32>,
+    pub(crate) unresponsive_timeout: Option<Duration>,
+    /// Cap on out-of-order reassembly bytes buffered per channel.
+    pub(crate) reassembly_limit: Option<usize>,
+    /// Whether new streams start with Nagle coalescing enabled.
+    pub(crate) default_nagle: bool,
+    /// Scheduling priority new streams start with; 0 is highest.
+    pub(crate) default_priority: u32,
+    /// Send at most this many HELLOs per connect before giving up.
+    pub(crate) handshake_attempts: Option<u32>,
+    /// Upper bound on a single handshake attempt's wait before resend.
+    pub(crate) handshake_timeout: Option<Duration>,
+    /// Concurrent in-progress handshake cap and HELLO queue depth.
+    pub(crate) handshake_limit: Option<(usize, usize)>,
+    /// Cap on simultaneously open channels, inbound and outbound together.
+    pub(crate) max_channels: Option<usize>,
+    /// Offer (and accept) stream compression on this host's channels.
+    pub(crate) compression: bool,
+    /// Advertise unreliable datagram support on this host's channels.
+    pub(crate) datagrams: bool,
+    /// Authenticate the cleartext MESSAGE header with a per-channel MAC.
+    pub(crate) authenticate_headers: bool,
+    pub(crate) ack_delay_exponent: u8,
+    pub(crate) max_ack_delay: Duration,
+    /// Checksum-only message integrity, between loopback hosts only.
+    #[cfg(feature = "insecure-loopback")]
+    pub(crate) insecure_loopback: bool,
+}
+
+/// Builds a [`Host`].
+pub struct HostBuilder {
+    bind: SocketAddr,
+    dual_stack: bool,
+    reuse_port: bool,
+    sim: Option<SimSocket>,
+    extra_binds: Vec<SocketAddr>,
+    extra_sims: Vec<SimSocket>,
+    identity: Option<Identity>,
+    idle_timeout: Duration,
+    connect_timeout: Duration,
+    max_packet_size: usize,
+    fixed_mtu: Option<usize>,
+    buffer_pool_size: usize,
+    max_substreams: usize,
+    channel_policy: ChannelPolicy,
+    detach_on_idle: bool,
+    on_cwnd_change: Option<CwndHook>,
+    on_channel_established: Option<EstablishedHook>,
+    pad_sizes: Vec<usize>,
+    accept_rate_limit: Option<(usize, Duration)>,
+    max_retransmits: Option<u32>,
+    unresponsive_timeout: Option<Duration>,
+    reassembly_limit: Option<usize>,
+    default_nagle: bool,
+    default_priority: u32,
+    handshake_attempts: Option<u32>,
+    handshake_timeout: Option<Duration>,
+    handshake_limit: Option<(usize, usize)>,
+    max_channels: Option<usize>,
+    compression: bool,
+    datagrams: bool,
+    authenticate_headers: bool,
+    ack_delay_exponent: u8,
+    max_ack_delay: Duration,
+    dscp: Option<u8>,
+    rng: Option<Box<dyn rand::RngCore + Send>>,
+    #[cfg(feature = "insecure-loopback")]
+    insecure_loopback: bool,
+}
+
+impl HostBuilder {
+    pub fn new() -> Self {
+        HostBuilder {
+            bind: "0.0.0.0:0".parse().unwrap(),
+            dual_stack: false,
+            reuse_port: false,
+            sim: None,
+            extra_binds: Vec::new(),
+            extra_sims: Vec::new(),
+            identity: None,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            max_packet_size: MAX_PACKET_SIZE,
+            fixed_mtu: None,
+            buffer_pool_size: DEFAULT_POOL_SIZE,
+            max_substreams: DEFAULT_MAX_SUBSTREAMS,
+            channel_policy: ChannelPolicy::default(),
+            detach_on_idle: false,
+            on_cwnd_change: None,
+            on_channel_established: None,
+            pad_sizes: Vec::new(),
+            accept_rate_limit: None,
+            max_retransmits: None,
+            unresponsive_timeout: None,
+            reassembly_limit: None,
+            default_nagle: true,
+            default_priority: 0,
+            handshake_attempts: None,
+            handshake_timeout: None,
+            handshake_limit: None,
+            max_channels: None,
+            compression: false,
+            datagrams: true,
+            authenticate_headers: false,
+            ack_delay_exponent: 0,
+            max_ack_delay: crate::channel::ACK_DELAY,
+            dscp: None,
+            rng: None,
+            #[cfg(feature = "insecure-loopback")]
+            insecure_loopback: false,
+        }
+    }
+
+    /// Draw all of this host's randomness -- handshake nonces, short-term
+    /// and minute keys, and the identity when none is provided -- from
+    /// `rng` instead of the OS generator. A seeded generator makes the
+    /// host's handshakes reproducible byte for byte; only for tests.
+    pub fn rng(mut self, rng: impl rand::RngCore + Send + 'static) -> Self {
+        self.rng = Some(Box::new(rng));
+        self
+    }
+
+    /// Address to bind the UDP socket to; defaults to an ephemeral port on
+    /// all interfaces. IPv6 addresses work throughout, including link-local
+    /// ones with a scope ID.
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.bind = addr;
+        self
+    }
+
+    /// Accept both IPv4 and IPv6 peers on one socket. The host binds the
+    /// IPv6 wildcard (keeping any port set via [`bind`](Self::bind), or an
+    /// IPv6 bind address given there) with `IPV6_V6ONLY` cleared, so IPv4
+    /// peers appear with v4-mapped addresses (`::ffff:a.b.c.d`). Off by
+    /// default.
+    pub fn dual_stack(mut self) -> Self {
+        self.dual_stack = true;
+        self
+    }
+
+    /// Bind with `SO_REUSEPORT` (unix only), so a replacement process can
+    /// bind the same port while this host drains: packets for established
+    /// channels keep flowing to whichever socket owns them, and the kernel
+    /// spreads new connections across the sharing sockets. Building fails
+    /// on platforms without the option.
+    pub fn reuse_port(mut self) -> Self {
+        self.reuse_port = true;
+        self
+    }
+
+    /// Run over a simulated network socket instead of UDP (for tests).
+    pub fn sim_socket(mut self, socket: SimSocket) -> Self {
+        self.sim = Some(socket);
+        self
+    }
+
+    /// Bind an additional UDP socket at `addr`, on top of the primary
+    /// bind. On a multihomed host this makes a second local interface
+    /// available for [`Host::connect_from`]; traffic not pinned to it
+    /// keeps using the primary socket.
+    pub fn bind_also(mut self, addr: SocketAddr) -> Self {
+        self.extra_binds.push(addr);
+        self
+    }
+
+    /// An additional simulated socket, the [`bind_also`](Self::bind_also)
+    /// counterpart for tests running over a [`crate::sim::SimNetwork`].
+    pub fn sim_socket_also(mut self, socket: SimSocket) -> Self {
+        self.extra_sims.push(socket);
+        self
+    }
+
+    /// Long-term identity; a fresh one is generated if not provided.
+    pub fn identity(mut self, identity: Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// How long an established channel survives without traffic before
+    /// being torn down. Independent of the connect-phase deadlines: see
+    /// [`connect_timeout`](Self::connect_timeout) and
+    /// [`handshake_timeout`](Self::handshake_timeout).
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero(), "the idle timeout must be positive");
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Default deadline for [`Host::connect`]; can be overridden per call
+    /// with [`Host::connect_with_timeout`]. It bounds the whole connect --
+    /// every handshake retry included -- while
+    /// [`handshake_timeout`](Self::handshake_timeout) bounds one attempt.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero(), "the connect timeout must be positive");
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Cap on how long a single handshake attempt waits before the HELLO
+    /// is resent. Attempts normally back off exponentially from 250ms;
+    /// this bounds each wait, so with [`handshake_attempts`]
+    /// (Self::handshake_attempts) set the connect gives up after roughly
+    /// `attempts * handshake_timeout` at worst. The overall
+    /// [`connect_timeout`](Self::connect_timeout) still applies on top.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero(), "the handshake timeout must be positive");
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Cap on concurrently open substreams per channel. Opens past the cap
+    /// fail or queue depending on [`crate::SubstreamOptions`].
+    pub fn max_substreams(mut self, limit: usize) -> Self {
+        self.max_substreams = limit;
+        self
+    }
+
+    /// Channel reuse policy for repeated connects to one peer; defaults to
+    /// [`ChannelPolicy::Single`].
+    pub fn channel_policy(mut self, policy: ChannelPolicy) -> Self {
+        self.channel_policy = policy;
+        self
+    }
+
+    /// When a channel times out idle, detach its streams and hibernate
+    /// them instead of closing them: offsets and buffered data survive,
+    /// and the next activity on a stream reattaches it over a fresh
+    /// channel to the same peer. Off by default.
+    pub fn detach_on_idle(mut self, enabled: bool) -> Self {
+        self.detach_on_idle = enabled;
+        self
+    }
+
+    /// Pad every channel packet up to the next of the given UDP payload
+    /// sizes, so observers cannot infer message sizes from packet lengths.
+    /// This trades bandwidth for traffic-analysis resistance: padding never
+    /// reaches the application byte stream. Channels cap their packets at
+    /// the largest size and skip PMTU probing, whose probe packets would
+    /// leak off-class sizes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sizes` is empty or a size is below the 548-byte minimum
+    /// payload budget.
+    pub fn pad_to(mut self, mut sizes: Vec<usize>) -> Self {
+        assert!(!sizes.is_empty(), "pad_to needs at least one size");
+        sizes.sort_unstable();
+        assert!(
+            sizes[0] >= MIN_PACKET_SIZE,
+            "pad size below the {MIN_PACKET_SIZE}-byte minimum"
+        );
+        self.pad_sizes = sizes;
+        self
+    }
+
+    /// Observe every congestion-window change on this host's channels: the
+    /// callback gets the old and new window, the cause and a timestamp,
+    /// which is enough to plot the window over time while tuning. It runs
+    /// on the channel's control path, so keep it cheap; unset, it costs a
+    /// single flag check.
+    pub fn on_cwnd_change(mut self, hook: impl Fn(CwndEvent) + Send + Sync + 'static) -> Self {
+        self.on_cwnd_change = Some(Arc::new(hook));
+        self
+    }
+
+    /// Observe every channel this host establishes, as initiator or
+    /// responder, the moment its handshake completes -- before any stream
+    /// is accepted on it. The callback gets the peer's long-term identity
+    /// and a [`ChannelInfo`] snapshot; a reconnect that reuses an existing
+    /// channel (see [`ChannelPolicy::Single`]) does not fire it again. It
+    /// runs on the handshake path, so keep it cheap.
+    pub fn on_channel_established(
+        mut self,
+        hook: impl Fn(&PublicKey, ChannelInfo) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_channel_established = Some(Arc::new(hook));
+        self
+    }
+
+    /// Replace message encryption with a bare integrity checksum.
+    ///
+    /// # Security
+    ///
+    /// This sends all stream data in the clear with no authenticity. It is
+    /// only for benchmarking the framing and scheduling code between two
+    /// hosts on the same machine, both built with this flag; message packets
+    /// to or from secure peers fail to open, so mixed connects never
+    /// establish.
+    #[cfg(feature = "insecure-loopback")]
+    pub fn insecure_loopback(mut self) -> Self {
+        self.insecure_loopback = true;
+        self
+    }
+
+    /// Hard cap on the UDP payload size of emitted packets, including the
+    /// channel header and message box overhead. PMTU discovery never probes
+    /// beyond it. Useful for tunnelled paths with a reduced MTU.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is below the 548-byte minimum payload budget.
+    pub fn max_packet_size(mut self, size: usize) -> Self {
+        assert!(
+            size >= MIN_PACKET_SIZE,
+            "max_packet_size below the {MIN_PACKET_SIZE}-byte minimum"
+        );
+        self.max_packet_size = size;
+        self
+    }
+
+    /// Use a static UDP payload size and disable PMTU discovery entirely,
+    /// for constrained environments where probe packets are undesirable.
+    /// Overrides [`max_packet_size`](Self::max_packet_size).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is below the 548-byte minimum payload budget.
+    pub fn fixed_mtu(mut self, size: usize) -> Self {
+        assert!(
+            size >= MIN_PACKET_SIZE,
+            "fixed_mtu below the {MIN_PACKET_SIZE}-byte minimum"
+        );
+        self.fixed_mtu = Some(size);
+        self
+    }
+
+    /// Refuse more than `max` new connections per source IP address in
+    /// each `per` interval. Excess HELLOs are dropped before any cookie is
+    /// computed or channel state allocated, bounding the work a handshake
+    /// flood from one address can force. Off by default.
+    pub fn accept_rate_limit(mut self, max: usize, per: Duration) -> Self {
+        self.accept_rate_limit = Some((max, per));
+        self
+    }
+
+    /// Declare a channel dead after `count` consecutive retransmission
+    /// timeouts pass without a single acknowledgement, closing it with a
+    /// timeout reason instead of backing off against an unresponsive peer
+    /// forever. Each timeout already doubles the retransmission interval,
+    /// so `count` bounds the total wait roughly at `MIN_RTO * 2^count`.
+    /// Off by default: channels retransmit until the idle timeout.
+    pub fn max_retransmits(mut self, count: u32) -> Self {
+        self.max_retransmits = Some(count);
+        self
+    }
+
+    /// Declare a channel dead when it has unacknowledged packets
+    /// outstanding and nothing -- not even a bare ack -- has been heard
+    /// from the peer for `timeout`. This catches a half-open connection
+    /// to a peer that vanished silently (power loss, cable pull) much
+    /// sooner than the idle timeout, and surfaces it as an abort rather
+    /// than the graceful idle close. A quiet channel with nothing in
+    /// flight is left alone. Off by default.
+    pub fn unresponsive_timeout(mut self, timeout: Duration) -> Self {
+        self.unresponsive_timeout = Some(timeout);
+        self
+    }
+
+    /// Cap the out-of-order reassembly bytes buffered across all streams
+    /// of one channel. Past half the cap, streams with gaps advertise a
+    /// quarter of their receive window to slow the peer down; past the
+    /// cap, the stream whose reassembly progressed least recently is
+    /// reset with the reason `"reassembly memory limit exceeded"` and its
+    /// buffers reclaimed. Unlimited by default (each stream is still
+    /// individually bounded by the reorder-gap cap).
+    pub fn reassembly_limit(mut self, bytes: usize) -> Self {
+        assert!(bytes > 0, "the reassembly limit must be positive");
+        self.reassembly_limit = Some(bytes);
+        self
+    }
+
+    /// Start every stream on this host with Nagle-style coalescing
+    /// disabled, as [crate::Stream::set_nagle] would do per stream. Handy
+    /// for servers where every accepted stream wants low latency;
+    /// per-stream `set_nagle` still overrides.
+    pub fn default_nodelay(mut self, nodelay: bool) -> Self {
+        self.default_nagle = !nodelay;
+        self
+    }
+
+    /// Scheduling priority every new stream starts with (0 is highest),
+    /// instead of the usual 0. Per-stream
+    /// [crate::Stream::set_priority] still overrides.
+    pub fn default_priority(mut self, priority: u32) -> Self {
+        self.default_priority = priority;
+        self
+    }
+
+    /// Send at most `attempts` HELLOs per connect, with exponential backoff
+    /// between them, before failing with [`Error::Timeout`]. Every attempt
+    /// resends the same HELLO, so a server that already answered just
+    /// deduplicates. By default the HELLO is retried until the connect
+    /// timeout expires.
+    pub fn handshake_attempts(mut self, attempts: u32) -> Self {
+        assert!(attempts > 0, "at least one handshake attempt is required");
+        self.handshake_attempts = Some(attempts);
+        self
+    }
+
+    /// Answer at most `max` handshakes concurrently, holding up to `queue`
+    /// further HELLOs for the next free slot; HELLOs beyond the queue are
+    /// dropped like rate-limited ones. Handshake crypto is CPU-expensive,
+    /// and this keeps an accept flood from starving the data path. A slot
+    /// is held from a peer's first HELLO until its INITIATE arrives. Off
+    /// by default.
+    pub fn handshake_limit(mut self, max: usize, queue: usize) -> Self {
+        assert!(max > 0, "at least one concurrent handshake is required");
+        self.handshake_limit = Some((max, queue));
+        self
+    }
+
+    /// Cap the number of simultaneously open channels, inbound and
+    /// outbound together, bounding this host's per-channel memory. At the
+    /// cap, inbound handshake packets are dropped -- the peer retries
+    /// until a slot frees or its connect deadline passes -- and a connect
+    /// that would negotiate a fresh channel fails with
+    /// [`Error::ChannelLimit`]. Closing a channel frees its slot at once.
+    pub fn max_channels(mut self, max: usize) -> Self {
+        assert!(max > 0, "a host needs at least one channel");
+        self.max_channels = Some(max);
+        self
+    }
+
+    /// Mark outgoing packets with the given DSCP so managed networks can
+    /// prioritize SSS traffic: `IP_TOS` on IPv4 sockets, `IPV6_TCLASS` on
+    /// IPv6 ones. DSCP is a six-bit field; values above 63 are rejected.
+    /// Ignored over a simulated network.
+    pub fn dscp(mut self, value: u8) -> Self {
+        assert!(value < 64, "DSCP is a six-bit field");
+        self.dscp = Some(value);
+        self
+    }
+
+    /// Offer stream compression when connecting and agree to it when
+    /// accepting. Compression takes effect only on streams explicitly
+    /// wrapped with [`crate::Stream::into_compressed`], and only once both
+    /// ends of the channel have opted in. Off by default.
+    pub fn compression(mut self) -> Self {
+        self.compression = true;
+        self
+    }
+
+    /// Withhold unreliable datagram support from SETTINGS negotiation, so
+    /// [`crate::Stream::send_datagram`] fails on both ends while reliable
+    /// streams work as usual. Advertised by default.
+    pub fn disable_datagrams(mut self) -> Self {
+        self.datagrams = false;
+        self
+    }
+
+    /// Authenticate each MESSAGE packet's cleartext header — magic, channel
+    /// id and masked sequence field — with a per-channel MAC, so tampering
+    /// drops the packet before decryption is attempted. Changes the wire
+    /// format: both ends must enable it or no packets get through. Off by
+    /// default.
+    pub fn authenticate_headers(mut self) -> Self {
+        self.authenticate_headers = true;
+        self
+    }
+
+    /// Scale of the ack delay field this host reports in its ACK frames:
+    /// units of two-to-the-`exponent` microseconds, advertised in
+    /// SETTINGS so the peer can undo the encoding. Zero (plain
+    /// microseconds) by default; larger exponents trade precision for
+    /// range on very slow paths.
+    pub fn ack_delay_exponent(mut self, exponent: u8) -> Self {
+        self.ack_delay_exponent = exponent;
+        self
+    }
+
+    /// Longest this host sits on an acknowledgement before sending it:
+    /// the delayed-ack timer, advertised in SETTINGS so the peer can cap
+    /// the ack delay it subtracts from RTT samples. 25ms by default.
+    pub fn max_ack_delay(mut self, delay: Duration) -> Self {
+        self.max_ack_delay = delay;
+        self
+    }
+
+    /// Capacity of the host-wide buffer pool all stream send queues and
+    /// readable reassembly data draw from. When the pool runs dry, writes
+    /// block and advertised receive windows shrink.
+    pub fn buffer_pool_size(mut self, bytes: usize) -> Self {
+        self.buffer_pool_size = bytes;
+        self
+    }
+
+    pub async fn build(self) -> Result<Host> {
+        let socket = match self.sim {
+            Some(sim) => Socket::Sim(sim),
+            None if self.dual_stack || self.reuse_port => {
+                let addr = match self.bind {
+                    // The IPv4 default (or an explicit v4 bind) cannot carry
+                    // both families; move to the v6 wildcard on the same port.
+                    SocketAddr::V4(v4) if self.dual_stack => {
+                        SocketAddr::new(std::net::Ipv6Addr::UNSPECIFIED.into(), v4.port())
+                    }
+                    addr => addr,
+                };
+                Socket::bind_udp_opts(addr, self.dual_stack, self.reuse_port)?
+            }
+            None => Socket::bind_udp(self.bind).await?,
+        };
+        let mut extra_sockets = Vec::new();
+        for sim in self.extra_sims {
+            extra_sockets.push(Arc::new(Socket::Sim(sim)));
+        }
+        for addr in self.extra_binds {
+            extra_sockets.push(Arc::new(Socket::bind_udp(addr).await?));
+        }
+        if let Some(dscp) = self.dscp {
+            socket.set_dscp(dscp)?;
+            for extra in &extra_sockets {
+                extra.set_dscp(dscp)?;
+            }
+        }
+        let rng = match self.rng {
+            Some(rng) => HostRng::new(rng),
+            None => HostRng::os(),
+        };
+        let inner = Arc::new(HostInner {
+            socket: Arc::new(socket),
+            extra_sockets,
+            identity: self
+                .identity
+                .unwrap_or_else(|| Identity::generate_with(&rng)),
+            cfg: Config {
+                idle_timeout: self.idle_timeout,
+                connect_timeout: self.connect_timeout,
+                packet_size: match (self.pad_sizes.last(), self.fixed_mtu) {
+                    (Some(&largest), _) => largest,
+                    (None, Some(size)) => size,
+                    (None, None) => DEFAULT_PACKET_SIZE.min(self.max_packet_size),
+                },
+                packet_ceiling: self.fixed_mtu.unwrap_or(self.max_packet_size),
+                mtu_probing: self.fixed_mtu.is_none() && self.pad_sizes.is_empty(),
+                pad_sizes: self.pad_sizes,
+                accept_rate_limit: self.accept_rate_limit,
+                max_retransmits: self.max_retransmits,
+                unresponsive_timeout: self.unresponsive_timeout,
+                reassembly_limit: self.reassembly_limit,
+                default_nagle: self.default_nagle,
+                default_priority: self.default_priority,
+                handshake_attempts: self.handshake_attempts,
+                handshake_timeout: self.handshake_timeout,
+                handshake_limit: self.handshake_limit,
+                max_channels: self.max_channels,
+                compression: self.compression,
+                datagrams: self.datagrams,
+                authenticate_headers: self.authenticate_headers,
+                ack_delay_exponent: self.ack_delay_exponent,
+                max_ack_delay: self.max_ack_delay,
+                max_substreams: self.max_substreams,
+                channel_policy: self.channel_policy,
+                detach_on_idle: self.detach_on_idle,
+                on_cwnd_change: self.on_cwnd_change,
+                on_channel_established: self.on_channel_established,
+                #[cfg(feature = "insecure-loopback")]
+                insecure_loopback: self.insecure_loopback,
+            },
+            pool: BufferPool::new(self.buffer_pool_size),
+            frame_counters: Arc::new(FrameCounters::default()),
+            minute_keys: Mutex::new(MinuteKeys::new(&rng)),
+            channels: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            early_messages: Mutex::new(HashMap::new()),
+            hello_rate: Mutex::new(HelloRate {
+                window_start: Instant::now(),
+                counts: HashMap::new(),
+            }),
+            handshakes: Mutex::new(HandshakeLimiter {
+                in_progress: HashMap::new(),
+                queue: VecDeque::new(),
+            }),
+            timers: Mutex::new(TimerWheel::new(Instant::now())),
+            timer_notify: Notify::new(),
+            listeners: Mutex::new(HashMap::new()),
+            usid_index: Mutex::new(HashMap::new()),
+            hibernated: Mutex::new(Vec::new()),
+            #[cfg(unix)]
+            wakeup: WakeupPipe::new()?,
+            rng,
+        });
+        let mut recv_tasks = vec![tokio::spawn(recv_loop(inner.clone(), inner.socket.clone()))];
+        for extra in &inner.extra_sockets {
+            recv_tasks.push(tokio::spawn(recv_loop(inner.clone(), extra.clone())));
+        }
+        let timer_task = tokio::spawn(timer_loop(inner.clone()));
+        Ok(Host {
+            inner,
+            recv_tasks,
+            timer_task,
+        })
+    }
+}
+
+impl Default for HostBuilder {
+    fn default() -> Self {
+        HostBuilder::new()
+    }
+}
+
+/// Fixed-window count of handshake attempts per source IP, for the
+/// accept rate limit.
+struct HelloRate {
+    window_start: Instant,
+    counts: HashMap<std::net::IpAddr, usize>,
+}
+
+/// Responder-side handshake limiter state: who holds a slot (HELLO
+/// answered, INITIATE awaited, keyed by source address) and the HELLOs
+/// queued for the next free slot.
+struct HandshakeLimiter {
+    in_progress: HashMap<SocketAddr, Instant>,
+    /// Queued HELLOs with the socket each one arrived on, so the COOKIE
+    /// goes back out the same interface.
+    queue: VecDeque<(Vec<u8>, SocketAddr, Arc<Socket>)>,
+}
+
+/// The work-signal socketpair backing [`Host::wakeup_fd`]. Both ends are
+/// non-blocking: an undrained pipe drops signals rather than stalling the
+/// receive loop.
+#[cfg(unix)]
+struct WakeupPipe {
+    read: socket2::Socket,
+    write: socket2::Socket,
+}
+
+#[cfg(unix)]
+impl WakeupPipe {
+    fn new() -> std::io::Result<WakeupPipe> {
+        let (read, write) = socket2::Socket::pair(
+            socket2::Domain::UNIX,
+            socket2::Type::STREAM,
+            None,
+        )?;
+        read.set_nonblocking(true)?;
+        write.set_nonblocking(true)?;
+        Ok(WakeupPipe { read, write })
+    }
+
+    fn signal(&self) {
+        let _ = self.write.send(&[1]);
+    }
+}
+
+/// MESSAGE packets held for one not-yet-established channel.
+type EarlyMessages = Vec<(Vec<u8>, SocketAddr)>;
+
+/// A connect awaiting its COOKIE reply.
+struct PendingHello {
+    short_secret: crypto_box::SecretKey,
+    responder: PublicKey,
+    reply: oneshot::Sender<CookiePayload>,
+}
+
+pub(crate) struct HostInner {
+    pub(crate) socket: Arc<Socket>,
+    /// Additional bound sockets, for channels pinned to a specific local
+    /// interface via [`Host::connect_from`].
+    pub(crate) extra_sockets: Vec<Arc<Socket>>,
+    pub(crate) identity: Identity,
+    pub(crate) cfg: Config,
+    pub(crate) pool: Arc<BufferPool>,
+    /// Per-frame-type send/receive counters, shared with every channel.
+    pub(crate) frame_counters: Arc<FrameCounters>,
+    minute_keys: Mutex<MinuteKeys>,
+    pub(crate) channels: Mutex<HashMap<[u8; KEY_SIZE], Arc<ChannelShared>>>,
+    pending: Mutex<HashMap<SocketAddr, PendingHello>>,
+    /// MESSAGE packets that raced ahead of their channel's INITIATE,
+    /// stashed by connection id and replayed once the channel exists.
+    early_messages: Mutex<HashMap<[u8; KEY_SIZE], EarlyMessages>>,
+    /// Handshake attempts per source IP in the current rate-limit window.
+    hello_rate: Mutex<HelloRate>,
+    /// Handshake slots held and HELLOs queued, for the handshake limit.
+    handshakes: Mutex<HandshakeLimiter>,
+    /// Armed channel wakeups, swept by the host's timer loop.
+    timers: Mutex<TimerWheel<Weak<ChannelShared>>>,
+    /// Pokes the timer loop after an earlier deadline is armed.
+    timer_notify: Notify,
+    pub(crate) listeners: Mutex<HashMap<(String, String), mpsc::Sender<Stream>>>,
+    /// Streams by USID, so a multipath attach can find the original stream.
+    pub(crate) usid_index: Mutex<HashMap<crate::stream::Usid, std::sync::Weak<crate::stream::StreamShared>>>,
+    /// Detached streams kept alive until they reattach (detach-on-idle).
+    hibernated: Mutex<Vec<Arc<StreamShared>>>,
+    /// Socketpair whose read end external reactors wait on; the receive
+    /// loop writes a byte per received packet.
+    #[cfg(unix)]
+    wakeup: WakeupPipe,
+    /// Source of all this host's randomness; seedable for tests.
+    pub(crate) rng: HostRng,
+}
+
+impl HostInner {
+    /// Park detached streams. On the side that can dial, also watch for
+    /// activity on any of them and reattach the lot over a fresh channel.
+    pub(crate) fn hibernate_streams(
+        self: &Arc<Self>,
+        role: Role,
+        peer: Option<PublicKey>,
+        addr: SocketAddr,
+        streams: Vec<Arc<StreamShared>>,
+        snapshot: CongestionSnapshot,
+    ) {
+        if streams.is_empty() {
+            return;
+        }
+        self.hibernated.lock().unwrap().extend(streams.iter().cloned());
+        let (Role::Initiator, Some(peer)) = (role, peer) else {
+            return;
+        };
+        let wake = Arc::new(Notify::new());
+        for stream in &streams {
+            *stream.detach_wake.lock().unwrap() = Some(wake.clone());
+        }
+        tokio::spawn(reattach(self.clone(), peer, addr, streams, snapshot, wake));
+    }
+
+    /// Whether a HELLO from `from` fits the accept rate limit; counts the
+    /// attempt. Always true when no limit is configured.
+    fn admit_hello(&self, from: SocketAddr) -> bool {
+        let Some((max, per)) = self.cfg.accept_rate_limit else {
+            return true;
+        };
+        let mut rate = self.hello_rate.lock().unwrap();
+        let now = Instant::now();
+        if now.duration_since(rate.window_start) >= per {
+            rate.window_start = now;
+            rate.counts.clear();
+        }
+        let count = rate.counts.entry(from.ip()).or_insert(0);
+        *count += 1;
+        *count <= max
+    }
+
+    /// Whether the channel cap admits one more channel.
+    fn below_channel_limit(&self) -> bool {
+        self.cfg
+            .max_channels
+            .is_none_or(|max| self.channels.lock().unwrap().len() < max)
+    }
+
+    /// Whether a HELLO from `from` may start its crypto now: `Ok(true)` to
+    /// proceed, `Ok(false)` when queued for a free slot, an error when the
+    /// slots and the queue are both full. Always proceeds when no
+    /// handshake limit is configured.
+    fn admit_handshake(
+        &self,
+        socket: &Arc<Socket>,
+        datagram: &[u8],
+        from: SocketAddr,
+    ) -> Result<bool> {
+        let Some((max, queue_depth)) = self.cfg.handshake_limit else {
+            return Ok(true);
+        };
+        let mut limiter = self.handshakes.lock().unwrap();
+        let now = Instant::now();
+        limiter
+            .in_progress
+            .retain(|_, started| now.duration_since(*started) < HANDSHAKE_SLOT_TTL);
+        if let Some(started) = limiter.in_progress.get_mut(&from) {
+            // A HELLO retransmission keeps its slot; refresh it.
+            *started = now;
+            return Ok(true);
+        }
+        if limiter.in_progress.len() < max {
+            limiter.in_progress.insert(from, now);
+            return Ok(true);
+        }
+        if let Some((queued, ..)) = limiter.queue.iter_mut().find(|(_, addr, _)| *addr == from) {
+            *queued = datagram.to_vec();
+            return Ok(false);
+        }
+        if limiter.queue.len() < queue_depth {
+            limiter.queue.push_back((datagram.to_vec(), from, socket.clone()));
+            return Ok(false);
+        }
+        Err(Error::protocol("handshake limit exceeded"))
+    }
+
+    /// Release `from`'s handshake slot -- its INITIATE arrived -- and
+    /// answer any queued HELLOs that now fit.
+    fn release_handshake(self: &Arc<Self>, from: SocketAddr) {
+        let Some((max, _)) = self.cfg.handshake_limit else {
+            return;
+        };
+        let mut limiter = self.handshakes.lock().unwrap();
+        limiter.in_progress.remove(&from);
+        while limiter.in_progress.len() < max {
+            let Some((datagram, addr, socket)) = limiter.queue.pop_front() else {
+                break;
+            };
+            limiter.in_progress.insert(addr, Instant::now());
+            let inner = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = answer_hello(&inner, &socket, &datagram, addr).await {
+                    tracing::debug!(error = %e, from = %addr, "dropping queued HELLO");
+                }
+            });
+        }
+    }
+
+    /// Arm the timer wheel to wake `chan`'s pump once `deadline` passes.
+    /// Invoke the channel-established observer, if configured. Called
+    /// exactly once per new channel, after its peer identity is known.
+    fn channel_established(&self, chan: &ChannelShared) {
+        if let Some(hook) = &self.cfg.on_channel_established {
+            let peer = chan
+                .remote_identity
+                .lock()
+                .unwrap()
+                .expect("established channel without an identity");
+            hook(&peer, channel_info(chan));
+        }
+    }
+
+    pub(crate) fn arm_channel_timer(&self, deadline: Instant, chan: Weak<ChannelShared>) {
+        self.timers.lock().unwrap().insert(deadline, chan);
+        self.timer_notify.notify_one();
+    }
+
+    /// Stash a MESSAGE that arrived before its channel's INITIATE, so a
+    /// reordered handshake costs no retransmission round trip. Both maps
+    /// are capped: a flood of unknown connection ids buffers nothing.
+    fn stash_early_message(&self, key: [u8; KEY_SIZE], message: &[u8], from: SocketAddr) {
+        let mut early = self.early_messages.lock().unwrap();
+        if early.len() >= EARLY_MESSAGE_KEYS && !early.contains_key(&key) {
+            return;
+        }
+        let stash = early.entry(key).or_default();
+        if stash.len() < EARLY_MESSAGES_PER_KEY {
+            stash.push((message.to_vec(), from));
+        }
+    }
+
+    /// Replay MESSAGE packets stashed for a channel that now exists.
+    fn replay_early_messages(&self, key: &[u8; KEY_SIZE], chan: &Arc<ChannelShared>) {
+        let stashed = self.early_messages.lock().unwrap().remove(key);
+        for (message, from) in stashed.into_iter().flatten() {
+            if let Err(e) = chan.process_message(&message, from) {
+                tracing::debug!(error = %e, %from, "dropping stashed MESSAGE");
+            }
+        }
+    }
+
+    /// Forget a hibernated stream once it is attached (or dead).
+    pub(crate) fn unhibernate(&self, stream: &Arc<StreamShared>) {
+        *stream.detach_wake.lock().unwrap() = None;
+        self.hibernated
+            .lock()
+            .unwrap()
+            .retain(|s| !Arc::ptr_eq(s, stream));
+    }
+}
+
+/// An SSS host endpoint.
+pub struct Host {
+    inner: Arc<HostInner>,
+    recv_tasks: Vec<JoinHandle<()>>,
+    timer_task: JoinHandle<()>,
+}
+
+impl Host {
+    pub fn builder() -> HostBuilder {
+        HostBuilder::new()
+    }
+
+    /// The local socket address this host is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.inner.socket.local_addr()?)
+    }
+
+    /// All socket addresses this host is reachable on, with ephemeral
+    /// ports resolved: the primary bind first, then any extra binds added
+    /// with [`HostBuilder::bind_also`].
+    pub fn local_endpoints(&self) -> Vec<SocketAddr> {
+        self.inner
+            .socket
+            .local_addr()
+            .into_iter()
+            .chain(self.inner.extra_sockets.iter().filter_map(|s| s.local_addr().ok()))
+            .collect()
+    }
+
+    /// This host's long-term public key.
+    pub fn public_key(&self) -> PublicKey {
+        self.inner.identity.public()
+    }
+
+    /// The raw file descriptor of the underlying UDP socket, for external
+    /// event loops that want to watch the transport directly; `None` when
+    /// the host runs over a simulated network. The host's own receive
+    /// loop keeps reading from the socket, so readability here is
+    /// transient -- wait on [`wakeup_fd`](Host::wakeup_fd) instead to
+    /// learn that packets have arrived.
+    #[cfg(unix)]
+    pub fn as_raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        self.inner.socket.as_raw_fd()
+    }
+
+    /// A descriptor that becomes readable when the host has received
+    /// packets, so a C-style reactor can wait on it instead of busy
+    /// polling. The receive loop writes one byte per packet; drain the
+    /// descriptor with small non-blocking reads after waking. Signals are
+    /// dropped, not queued, while the pipe is full.
+    #[cfg(unix)]
+    pub fn wakeup_fd(&self) -> std::os::fd::RawFd {
+        std::os::fd::AsRawFd::as_raw_fd(&self.inner.wakeup.read)
+    }
+
+    /// Clamp every channel's packet size down to `size`, for paths whose
+    /// MTU is learned out of band (a tunnel coming up, a Packet-Too-Big
+    /// signal). Queued and lost data is repacked into frames that fit the
+    /// new size; probing never raises the MTU past it again. Sizes at or
+    /// above a channel's current value leave that channel alone.
+    pub fn set_mtu(&self, size: usize) {
+        assert!(
+            size >= MIN_PACKET_SIZE,
+            "set_mtu below the {MIN_PACKET_SIZE}-byte minimum"
+        );
+        let channels: Vec<_> = self
+            .inner
+            .channels
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        for chan in channels {
+            chan.clamp_mtu(size);
+        }
+    }
+
+    /// The UDP payload size currently validated for this host's channels:
+    /// the smallest across active channels, or the configured initial size
+    /// when none exist. With [`HostBuilder::fixed_mtu`] this is always the
+    /// fixed value.
+    pub fn current_mtu(&self) -> usize {
+        self.inner
+            .channels
+            .lock()
+            .unwrap()
+            .values()
+            .map(|chan| chan.lock().mtu.current())
+            .min()
+            .unwrap_or(self.inner.cfg.packet_size)
+    }
+
+    /// Register a listener for a (service, protocol) pair.
+    pub fn listen(&self, service: &str, protocol: &str) -> Listener {
+        let (tx, rx) = mpsc::channel(ACCEPT_QUEUE);
+        self.inner
+            .listeners
+            .lock()
+            .unwrap()
+            .insert((service.to_string(), protocol.to_string()), tx.clone());
+        Listener { rx, tx }
+    }
+
+    /// Snapshot of the live dispatch table: every (service, protocol)
+    /// pair with a registered listener, sorted for stable output. Useful
+    /// when a server is not receiving connections and the question is
+    /// whether the expected registration is actually in place. Entries
+    /// are the literal strings passed to [`listen`](Host::listen) -- a
+    /// `"*"` registration shows up as `"*"`, not expanded.
+    pub fn listeners(&self) -> Vec<(String, String)> {
+        let mut pairs: Vec<(String, String)> = self
+            .inner
+            .listeners
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        pairs.sort();
+        pairs
+    }
+
+    /// Connect to `service`/`protocol` on the peer at `addr` identified by
+    /// its long-term public key.
+    pub async fn connect(
+        &self,
+        addr: SocketAddr,
+        peer: PublicKey,
+        service: &str,
+        protocol: &str,
+    ) -> Result<Stream> {
+        self.connect_with_timeout(addr, peer, service, protocol, self.inner.cfg.connect_timeout)
+            .await
+    }
+
+    /// Like [`Host::connect`] with an explicit deadline for this attempt.
+    /// On timeout the connect resolves with [`Error::Timeout`] and any
+    /// half-open channel state is discarded.
+    pub async fn connect_with_timeout(
+        &self,
+        addr: SocketAddr,
+        peer: PublicKey,
+        service: &str,
+        protocol: &str,
+        timeout: Duration,
+    ) -> Result<Stream> {
+        let choice = match self.inner.cfg.channel_policy {
+            ChannelPolicy::Single => ChannelChoice::Reuse,
+            ChannelPolicy::Multi => ChannelChoice::New,
+        };
+        self.connect_guarded(addr, peer, service, protocol, choice, timeout)
+            .await
+    }
+
+    /// Like [`Host::connect`], but pin the stream's channel to the local
+    /// socket bound at `local`: on a multihomed host, one of the
+    /// addresses added with [`HostBuilder::bind_also`] (or the primary
+    /// bind). Every packet of the channel leaves from that socket, and an
+    /// established channel is reused only if it is pinned the same way.
+    /// Should the interface go away, the usual reattach machinery redials
+    /// over the primary socket.
+    pub async fn connect_from(
+        &self,
+        local: SocketAddr,
+        addr: SocketAddr,
+        peer: PublicKey,
+        service: &str,
+        protocol: &str,
+    ) -> Result<Stream> {
+        let socket = std::iter::once(&self.inner.socket)
+            .chain(self.inner.extra_sockets.iter())
+            .find(|s| s.local_addr().ok() == Some(local))
+            .cloned()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::AddrNotAvailable,
+                    "no local socket bound to that address",
+                )
+            })?;
+        let choice = match self.inner.cfg.channel_policy {
+            ChannelPolicy::Single => ChannelChoice::Reuse,
+            ChannelPolicy::Multi => ChannelChoice::New,
+        };
+        self.connect_guarded_on(
+            socket,
+            addr,
+            peer,
+            service,
+            protocol,
+            choice,
+            self.inner.cfg.connect_timeout,
+        )
+        .await
+    }
+
+    /// Connect with explicit channel selection, overriding the host's
+    /// [`ChannelPolicy`] for this call. The choice decides whether the
+    /// stream shares congestion state and migration fate with other
+    /// traffic to the peer or gets a channel of its own.
+    pub async fn connect_with_channel(
+        &self,
+        addr: SocketAddr,
+        peer: PublicKey,
+        service: &str,
+        protocol: &str,
+        choice: ChannelChoice,
+    ) -> Result<Stream> {
+        self.connect_guarded(
+            addr,
+            peer,
+            service,
+            protocol,
+            choice,
+            self.inner.cfg.connect_timeout,
+        )
+        .await
+    }
+
+    async fn connect_guarded(
+        &self,
+        addr: SocketAddr,
+        peer: PublicKey,
+        service: &str,
+        protocol: &str,
+        choice: ChannelChoice,
+        timeout: Duration,
+    ) -> Result<Stream> {
+        self.connect_guarded_on(
+            self.inner.socket.clone(),
+            addr,
+            peer,
+            service,
+            protocol,
+            choice,
+            timeout,
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn connect_guarded_on(
+        &self,
+        socket: Arc<Socket>,
+        addr: SocketAddr,
+        peer: PublicKey,
+        service: &str,
+        protocol: &str,
+        choice: ChannelChoice,
+        timeout: Duration,
+    ) -> Result<Stream> {
+        let created = Arc::new(Mutex::new(None));
+        tokio::time::timeout(
+            timeout,
+            self.connect_inner(&socket, addr, peer, service, protocol, choice, &created),
+        )
+        .await
+        .map_err(|_| {
+            self.inner.pending.lock().unwrap().remove(&addr);
+            let half_open = created
+                .lock()
+                .unwrap()
+                .and_then(|key| self.inner.channels.lock().unwrap().get(&key).cloned());
+            if let Some(chan) = half_open {
+                chan.teardown();
+            }
+            Error::Timeout
+        })?
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn connect_inner(
+        &self,
+        socket: &Arc<Socket>,
+        addr: SocketAddr,
+        peer: PublicKey,
+        service: &str,
+        protocol: &str,
+        choice: ChannelChoice,
+        created: &Arc<Mutex<Option<[u8; KEY_SIZE]>>>,
+    ) -> Result<Stream> {
+        let inner = &self.inner;
+        // Ride an established channel to this peer instead of negotiating
+        // another one, unless this connect wants one of its own.
+        if choice != ChannelChoice::New {
+            let existing = inner
+                .channels
+                .lock()
+                .unwrap()
+                .values()
+                .find(|chan| {
+                    *chan.remote_identity.lock().unwrap() == Some(peer)
+                        && chan.lock().remote_addr == addr
+                        && Arc::ptr_eq(&chan.socket, socket)
+                        && !chan.lock().pump_done
+                })
+                .cloned();
+            if let Some(chan) = existing {
+                // Honor the peer's advertised accept headroom before asking.
+                if chan.lock().peer_stream_limit == Some(0) {
+                    return Err(Error::SubstreamLimit);
+                }
+                return request_service(&chan, service, protocol).await;
+            }
+            if choice == ChannelChoice::Existing {
+                return Err(Error::ConnectionClosed);
+            }
+        }
+        if !inner.below_channel_limit() {
+            return Err(Error::ChannelLimit);
+        }
+        let chan = negotiate_channel(inner, socket, addr, peer, created).await?;
+
+        // Open the service request stream and await the connection reply.
+        request_service(&chan, service, protocol).await
+    }
+
+    /// Enumerate the live channels, distinguishing multiple channels to the
+    /// same peer by their channel id (the peer's short-term key).
+    pub fn channels(&self) -> Vec<ChannelInfo> {
+        self.inner
+            .channels
+            .lock()
+            .unwrap()
+            .values()
+            .map(|chan| channel_info(chan))
+            .collect()
+    }
+
+    /// Test orchestration helper: poll `cond` until it holds or `deadline`
+    /// passes, returning whether it was met. The host's channels run on
+    /// their own tasks, so polling just yields to them; under a paused
+    /// clock the short poll interval advances time deterministically.
+    pub async fn run_until(&self, deadline: Duration, cond: impl Fn() -> bool) -> bool {
+        let end = tokio::time::Instant::now() + deadline;
+        while !cond() {
+            if tokio::time::Instant::now() >= end {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        true
+    }
+
+    /// Feed a raw datagram into this host's receive path as if it had just
+    /// arrived from `from` on the primary socket, exercising the same magic
+    /// dispatch, header unprotection, decryption and frame handling as the
+    /// receive loop. Errors the loop would log and drop are returned
+    /// instead, so tests can assert on specific decode failures.
+    #[cfg(feature = "packet-injection")]
+    pub async fn inject_packet(&self, from: SocketAddr, bytes: &[u8]) -> Result<()> {
+        if bytes.len() < 8 {
+            return Err(Error::protocol("short packet"));
+        }
+        dispatch_datagram(&self.inner, &self.inner.socket, bytes, from).await
+    }
+
+    /// Seal `payload` into a MESSAGE datagram exactly as this host's channel
+    /// to `peer` would, carrying packet sequence number `seq`. The channel's
+    /// own send state is untouched, so the result can carry any sequence
+    /// number or payload bytes a test cares to decode; hand it to the peer's
+    /// [`inject_packet`](Self::inject_packet) to drive its receive path.
+    /// Returns `None` when no channel to `peer` is established.
+    #[cfg(feature = "packet-injection")]
+    pub fn craft_message(&self, peer: &PublicKey, seq: u64, payload: &[u8]) -> Option<Vec<u8>> {
+        let chan = self
+            .inner
+            .channels
+            .lock()
+            .unwrap()
+            .values()
+            .find(|chan| *chan.remote_identity.lock().unwrap() == Some(*peer))
+            .cloned()?;
+        Some(chan.seal_packet(seq, payload))
+    }
+
+    /// Per-frame-type counts of frames this host has sent and received,
+    /// across all its channels. Useful for spotting pathologies such as
+    /// retransmission storms (STREAM outpacing the payload) or ack storms.
+    pub fn frame_stats(&self) -> FrameStats {
+        self.inner.frame_counters.snapshot()
+    }
+
+    /// Reset every stream on this host that was connected or accepted under
+    /// a matching (service, protocol) pair, e.g. to take one service
+    /// offline; `None` fields match any. Each matching stream is reset with
+    /// `code`, which the peer sees as [`Error::StreamReset`]; substreams and
+    /// the channels themselves are left alone. Returns how many streams
+    /// were closed.
+    pub fn close_streams_matching(
+        &self,
+        service: Option<&str>,
+        protocol: Option<&str>,
+        code: u32,
+    ) -> usize {
+        let channels: Vec<_> = self
+            .inner
+            .channels
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        let mut closed = 0;
+        for chan in channels {
+            let streams: Vec<_> = chan.lock().streams.values().cloned().collect();
+            for stream in streams {
+                {
+                    let mut core = stream.lock();
+                    let matches = core.service.as_ref().is_some_and(|(svc, proto)| {
+                        service.is_none_or(|s| s == svc) && protocol.is_none_or(|p| p == proto)
+                    });
+                    if !matches || core.reset.is_some() || core.conn_closed {
+                        continue;
+                    }
+                    core.apply_reset(code, "service closed by host".to_string());
+                }
+                stream.release_open_slot();
+                chan.queue_reset(stream.lsid, code, "service closed by host", None);
+                closed += 1;
+            }
+        }
+        closed
+    }
+
+    /// Announce an impending shutdown to every connected peer: new stream
+    /// opens on this host's channels are refused from here on, while
+    /// streams already in flight run to completion. Follow with
+    /// [`close`](Self::close) once they have drained.
+    pub fn go_away(&self) {
+        let channels: Vec<_> = self
+            .inner
+            .channels
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        for chan in channels {
+            chan.go_away();
+        }
+    }
+
+    /// Close all channels, notifying peers.
+    pub fn close(&self) {
+        let channels: Vec<_> = self
+            .inner
+            .channels
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        for chan in channels {
+            chan.close(0, "host shutting down");
+        }
+    }
+
+    /// Close every channel immediately and synchronously: each one sends a
+    /// single best-effort CLOSE datagram without waiting for delivery or
+    /// acknowledgement, so peers learn of the exit at once instead of via
+    /// their idle timeout. Suitable for a signal handler or other
+    /// last-moment path; dropping the host calls this automatically.
+    pub fn close_all(&self) {
+        let channels: Vec<_> = self
+            .inner
+            .channels
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        for chan in channels {
+            chan.close_now(0, "host shutting down");
+        }
+    }
+}
+
+/// Channel-established observer; see [`HostBuilder::on_channel_established`].
+pub(crate) type EstablishedHook = Arc<dyn Fn(&PublicKey, ChannelInfo) + Send + Sync>;
+
+/// One live channel, for diagnostics and enumeration.
+#[derive(Debug, Clone)]
+pub struct ChannelInfo {
+    /// Channel id: the peer's short-term public key.
+    pub id: [u8; KEY_SIZE],
+    /// Peer socket address.
+    pub remote_addr: SocketAddr,
+    /// Peer long-term identity, once known.
+    pub peer: Option<PublicKey>,
+    /// Total MESSAGE payload bytes sent on this channel.
+    pub bytes_sent: u64,
+    /// Chunks declared lost and currently queued for retransmission
+    /// across the channel's streams. A depth that keeps growing means the
+    /// peer stopped acknowledging; see [`HostBuilder::max_retransmits`].
+    pub rtx_queued: usize,
+    /// The peer's advertised headroom for new top-level streams, from its
+    /// latest SETTINGS; `None` until it has advertised one.
+    pub stream_limit: Option<u32>,
+}
+
+/// Snapshot one channel as a [`ChannelInfo`].
+fn channel_info(chan: &ChannelShared) -> ChannelInfo {
+    let core = chan.lock();
+    ChannelInfo {
+        id: chan.remote_key,
+        remote_addr: core.remote_addr,
+        peer: *chan.remote_identity.lock().unwrap(),
+        bytes_sent: core.bytes_sent,
+        rtx_queued: core.streams.values().map(|s| s.lock().rtx.len()).sum(),
+        stream_limit: core.peer_stream_limit,
+    }
+}
+
+/// Open a service request stream on `chan` and await the connection reply.
+async fn request_service(
+    chan: &Arc<ChannelShared>,
+    service: &str,
+    protocol: &str,
+) -> Result<Stream> {
+    let stream = Stream::new(chan.open_stream(ROOT_LSID));
+    {
+        let mut core = stream.shared.lock();
+        core.queue_record(&service::encode_connect_request(service, protocol));
+    }
+    chan.notify.notify_one();
+    match stream.read_record().await? {
+        Some(record) => {
+            let (status, reason) = service::decode_connect_reply(&record)?;
+            if status == service::STATUS_OK {
+                stream.shared.lock().service = Some((service.to_string(), protocol.to_string()));
+                Ok(stream)
+            } else {
+                Err(Error::ServiceRefused { status, reason })
+            }
+        }
+        None => Err(Error::ConnectionClosed),
+    }
+}
+
+/// Negotiate a fresh channel to `peer` at `addr`: HELLO with backoff until a
+/// COOKIE arrives, then INITIATE. `created` records the channel id as soon as
+/// the channel exists, so a caller that gives up early can tear the half-open
+/// channel down.
+async fn negotiate_channel(
+    inner: &Arc<HostInner>,
+    socket: &Arc<Socket>,
+    addr: SocketAddr,
+    peer: PublicKey,
+    created: &Arc<Mutex<Option<[u8; KEY_SIZE]>>>,
+) -> Result<Arc<ChannelShared>> {
+    let short = ShortTermKey::generate(&inner.rng);
+    let hello = negotiation::build_hello(&inner.identity, &short, &peer, &inner.rng);
+
+    // Send HELLO with backoff until a COOKIE arrives.
+    let (reply_tx, mut reply_rx) = oneshot::channel();
+    inner.pending.lock().unwrap().insert(
+        addr,
+        PendingHello {
+            short_secret: short.secret().clone(),
+            responder: peer,
+            reply: reply_tx,
+        },
+    );
+    let mut retry = HELLO_RETRY;
+    let mut attempts = 0u32;
+    let cookie = loop {
+        socket.send_to(&hello, addr).await?;
+        attempts += 1;
+        let wait = match inner.cfg.handshake_timeout {
+            Some(cap) => retry.min(cap),
+            None => retry,
+        };
+        match tokio::time::timeout(wait, &mut reply_rx).await {
+            Ok(Ok(cookie)) => break cookie,
+            Ok(Err(_)) => return Err(Error::ConnectionClosed),
+            Err(_) => {
+                if inner.cfg.handshake_attempts.is_some_and(|max| attempts >= max) {
+                    inner.pending.lock().unwrap().remove(&addr);
+                    return Err(Error::Timeout);
+                }
+                retry *= 2;
+            }
+        }
+    };
+    inner.pending.lock().unwrap().remove(&addr);
+
+    // The channel exists as soon as we can build the INITIATE packet.
+    let chan = ChannelShared::new(
+        inner,
+        socket.clone(),
+        Role::Initiator,
+        &short,
+        cookie.responder_short,
+        addr,
+    );
+    *chan.remote_identity.lock().unwrap() = Some(peer);
+    inner
+        .channels
+        .lock()
+        .unwrap()
+        .insert(cookie.responder_short, chan.clone());
+    *created.lock().unwrap() = Some(cookie.responder_short);
+    inner.replay_early_messages(&cookie.responder_short, &chan);
+
+    // INITIATE carries packet 0: the mandatory SETTINGS frame.
+    let mut settings = vec![Setting::Fec(false), Setting::CongestionControl(1)];
+    if inner.cfg.compression {
+        settings.push(Setting::Compression(true));
+    }
+    settings.push(Setting::Datagram(inner.cfg.datagrams));
+    settings.push(Setting::IdleTimeout(
+        inner.cfg.idle_timeout.as_millis() as u32,
+    ));
+    settings.push(Setting::AckDelayExponent(inner.cfg.ack_delay_exponent));
+    settings.push(Setting::MaxAckDelay(
+        inner.cfg.max_ack_delay.as_millis() as u32,
+    ));
+    let packet = Packet::new(PacketHeader::new(0), vec![Frame::Settings(settings.clone())]);
+    let mut message = vec![0u8; MIN_PACKET_SIZE];
+    let len = packet.encode(&mut message).expect("SETTINGS packet fits");
+    message.truncate(len);
+    let (padding, empty) = Packetizer::pad(&mut message);
+    inner.frame_counters.count_sent(FrameType::Settings);
+    inner.frame_counters.add_sent(FrameType::Padding, padding);
+    inner.frame_counters.add_sent(FrameType::Empty, empty);
+    let initiate =
+        negotiation::build_initiate(&inner.identity, &short, &peer, &cookie, &message, &inner.rng);
+    chan.track_initiate(0, message.len(), settings, initiate.clone());
+    socket.send_to(&initiate, addr).await?;
+    tokio::spawn(channel::run(chan.clone()));
+    inner.channel_established(&chan);
+    Ok(chan)
+}
+
+/// Wait for activity on hibernated streams, then dial their peer again and
+/// attach them to the fresh channel; offsets and buffered data carry over.
+async fn reattach(
+    inner: Arc<HostInner>,
+    peer: PublicKey,
+    addr: SocketAddr,
+    streams: Vec<Arc<StreamShared>>,
+    snapshot: CongestionSnapshot,
+    wake: Arc<Notify>,
+) {
+    wake.notified().await;
+    let created = Arc::new(Mutex::new(None));
+    match negotiate_channel(&inner, &inner.socket.clone(), addr, peer, &created).await {
+        Ok(chan) => {
+            // The replacement dials the same address, so the old path's
+            // congestion estimates remain a reasonable starting point.
+            chan.warm_start(&snapshot);
+            for stream in streams {
+                inner.unhibernate(&stream);
+                stream.set_channel(&chan);
+                chan.attach_path(&stream);
+            }
+        }
+        Err(e) => {
+            tracing::debug!(error = %e, %addr, "reattach failed, closing detached streams");
+            for stream in streams {
+                inner.unhibernate(&stream);
+                stream.connection_closed();
+            }
+        }
+    }
+}
+
+impl Drop for Host {
+    fn drop(&mut self) {
+        // Tell peers we are going before anything stops running.
+        self.close_all();
+        for task in &self.recv_tasks {
+            task.abort();
+        }
+        self.timer_task.abort();
+        let channels: Vec<_> = self
+            .inner
+            .channels
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        for chan in channels {
+            chan.lock().pump_done = true;
+            chan.notify.notify_one();
+        }
+    }
+}
+
+impl std::fmt::Debug for Host {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Host")
+            .field("public_key", &self.public_key())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Accepts inbound streams for one registered (service, protocol) pair.
+pub struct Listener {
+    rx: mpsc::Receiver<Stream>,
+    /// Kept for its capacity view of the accept queue, so draining a slot
+    /// can raise the stream limit advertised to the peer.
+    tx: mpsc::Sender<Stream>,
+}
+
+impl Listener {
+    /// The next inbound stream; `None` once the host is gone.
+    pub async fn accept(&mut self) -> Option<Stream> {
+        let stream = self.rx.recv().await?;
+        if let Some(chan) = stream.shared.channel() {
+            chan.advertise_stream_limit(self.tx.capacity() as u32);
+        }
+        Some(stream)
+    }
+}
+
+/// Socket receive loop: parse magic, dispatch to negotiation or channels.
+/// One runs per bound socket; they share all of the host's state.
+async fn recv_loop(inner: Arc<HostInner>, socket: Arc<Socket>) {
+    let mut buf = vec![0u8; 2048];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(ok) => ok,
+            Err(e) => {
+                tracing::debug!(error = %e, "socket receive failed, stopping host");
+                return;
+            }
+        };
+        #[cfg(unix)]
+        inner.wakeup.signal();
+        if len < 8 {
+            continue;
+        }
+        if let Err(e) = dispatch_datagram(&inner, &socket, &buf[..len], from).await {
+            tracing::debug!(error = %e, %from, "dropping packet");
+        }
+    }
+}
+
+/// Route one datagram, at least 8 bytes long, by its magic to the matching
+/// packet handler. Replies go out on `socket`, the one it arrived on.
+async fn dispatch_datagram(
+    inner: &Arc<HostInner>,
+    socket: &Arc<Socket>,
+    datagram: &[u8],
+    from: SocketAddr,
+) -> Result<()> {
+    let magic: &[u8; 8] = datagram[..8].try_into().unwrap();
+    match magic {
+        m if m == MAGIC_HELLO => handle_hello(inner, socket, datagram, from).await,
+        m if m == MAGIC_COOKIE => handle_cookie(inner, datagram, from),
+        m if m == MAGIC_INITIATE => handle_initiate(inner, socket, datagram, from),
+        m if m == MAGIC_MESSAGE => handle_message(inner, datagram, from),
+        _ => Ok(()), // unknown magic: not for us
+    }
+}
+
+/// Sweep the host's timer wheel: wake every channel whose armed deadline
+/// has passed, then sleep until the next one. One runtime timer serves
+/// all channels, however many deadlines they arm.
+async fn timer_loop(inner: Arc<HostInner>) {
+    loop {
+        let (due, next) = {
+            let mut timers = inner.timers.lock().unwrap();
+            (timers.advance(Instant::now()), timers.next_deadline())
+        };
+        for chan in due {
+            if let Some(chan) = chan.upgrade() {
+                chan.timer_fired();
+            }
+        }
+        match next {
+            Some(deadline) => {
+                tokio::select! {
+                    _ = inner.timer_notify.notified() => {}
+                    _ = tokio::time::sleep_until(deadline.into()) => {}
+                }
+            }
+            None => inner.timer_notify.notified().await,
+        }
+    }
+}
+
+async fn handle_hello(
+    inner: &Arc<HostInner>,
+    socket: &Arc<Socket>,
+    datagram: &[u8],
+    from: SocketAddr,
+) -> Result<()> {
+    if !inner.admit_hello(from) {
+        return Err(Error::protocol("connection rate limit exceeded"));
+    }
+    if !inner.below_channel_limit() {
+        return Err(Error::protocol("channel limit reached"));
+    }
+    if !inner.admit_handshake(socket, datagram, from)? {
+        return Ok(()); // queued until a handshake slot frees
+    }
+    answer_hello(inner, socket, datagram, from).await
+}
+
+/// Parse a HELLO and answer it with a COOKIE; the CPU-expensive half of
+/// accepting, run only once the handshake limiter admits the packet.
+async fn answer_hello(
+    inner: &Arc<HostInner>,
+    socket: &Arc<Socket>,
+    datagram: &[u8],
+    from: SocketAddr,
+) -> Result<()> {
+    let hello = negotiation::parse_hello(&inner.identity, datagram)?;
+    tracing::trace!(initiator = ?PublicKey::from_bytes(hello.initiator_long), %from, "HELLO");
+    let cookie = {
+        let mut minute_keys = inner.minute_keys.lock().unwrap();
+        negotiation::build_cookie(&inner.identity, &mut minute_keys, &hello, &inner.rng)
+    };
+    socket.send_to(&cookie, from).await?;
+    Ok(())
+}
+
+fn handle_cookie(inner: &Arc<HostInner>, datagram: &[u8], from: SocketAddr) -> Result<()> {
+    let mut pending = inner.pending.lock().unwrap();
+    let Some(hello) = pending.get(&from) else {
+        return Err(Error::protocol("unexpected COOKIE"));
+    };
+    let cookie = negotiation::parse_cookie(&hello.short_secret, &hello.responder, datagram)?;
+    let hello = pending.remove(&from).unwrap();
+    let _ = hello.reply.send(cookie);
+    Ok(())
+}
+
+fn handle_initiate(
+    inner: &Arc<HostInner>,
+    socket: &Arc<Socket>,
+    datagram: &[u8],
+    from: SocketAddr,
+) -> Result<()> {
+    let initiate = {
+        let mut minute_keys = inner.minute_keys.lock().unwrap();
+        negotiation::parse_initiate(&inner.identity, &mut minute_keys, datagram, &inner.rng)?
+    };
+    inner.release_handshake(from);
+    let existing = inner
+        .channels
+        .lock()
+        .unwrap()
+        .get(&initiate.initiator_short)
+        .cloned();
+    let chan = match existing {
+        Some(chan) => chan, // INITIATE retransmission; reprocess the message
+        None => {
+            // The cap is rechecked here: HELLOs admitted before other
+            // channels filled the table must not overshoot it.
+            if !inner.below_channel_limit() {
+                return Err(Error::protocol("channel limit reached"));
+            }
+            tracing::debug!(
+                initiator = ?PublicKey::from_bytes(initiate.initiator_long),
+                %from,
+                "accepting channel"
+            );
+            let short = ShortTermKey::from_secret(initiate.responder_short.clone());
+            let chan = ChannelShared::new(
+                inner,
+                socket.clone(),
+                Role::Responder,
+                &short,
+                initiate.initiator_short,
+                from,
+            );
+            *chan.remote_identity.lock().unwrap() =
+                Some(PublicKey::from_bytes(initiate.initiator_long));
+            inner
+                .channels
+                .lock()
+                .unwrap()
+                .insert(initiate.initiator_short, chan.clone());
+            tokio::spawn(channel::run(chan.clone()));
+            inner.channel_established(&chan);
+            chan
+        }
+    };
+    chan.credit_unvalidated(datagram.len());
+    let result = chan.process_plaintext(&initiate.message, from);
+    inner.replay_early_messages(&initiate.initiator_short, &chan);
+    result
+}
+
+/// Route a MESSAGE packet by the short-term key in its channel header --
+/// the connection id -- rather than by source address, so a peer keeps its
+/// channel when its address changes under it (NAT rebinding, migration).
+fn handle_message(inner: &Arc<HostInner>, datagram: &[u8], from: SocketAddr) -> Result<()> {
+    if datagram.len() < 48 {
+        return Err(Error::protocol("short MESSAGE packet"));
+    }
+    let mut key = [0u8; KEY_SIZE];
+    key.copy_from_slice(&datagram[8..40]);
+    let chan = inner.channels.lock().unwrap().get(&key).cloned();
+    match chan {
+        Some(chan) => chan.process_message(&datagram[40..], from),
+        None => {
+            // Likely a MESSAGE outrunning its INITIATE; hold it until the
+            // channel appears instead of forcing a retransmission.
+            tracing::trace!(%from, "stashing MESSAGE for unknown channel");
+            inner.stash_early_message(key, &datagram[40..], from);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Structured Secure Streams.
+//!
+//! SSS is a transport protocol in the spirit of SST, QUIC and CurveCP:
+//! it multiplexes many lightweight, hereditary application streams onto
+//! end-to-end encrypted channels running over UDP.
+//!
+//! The layering follows `doc/spec.md`:
+//!
+//! * [`Host`] owns the socket and long-term keys, runs the negotiation
+//!   protocol and demultiplexes packets to channels.
+//! * The channel protocol (internal) provides encrypted packetization,
+//!   acknowledgement, retransmission and congestion control.
+//! * [`Stream`] is the application-facing reliable byte stream, with
+//!   records, substreams and priorities.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # async fn example() -> sss::Result<()> {
+//! let server = sss::Host::builder().build().await?;
+//! let mut greeter = server.listen("greeter", "v1");
+//!
+//! let client = sss::Host::builder().build().await?;
+//! let stream = client
+//!     .connect(server.local_addr()?, server.public_key(), "greeter", "v1")
+//!     .await?;
+//! stream.write(b"hello").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod channel;
+mod compress;
+mod crypto;
+mod decongestion;
+mod error;
+mod frame;
+mod framed;
+mod host;
+mod mtu;
+mod negotiation;
+mod packet;
+mod packetizer;
+mod pool;
+pub mod sim;
+mod socket;
+mod stream;
+mod wheel;
+
+pub use channel::{CwndCause, CwndEvent};
+pub use compress::Compressed;
+pub use crypto::{Identity, PublicKey};
+pub use decongestion::CongestionAlgorithm;
+pub use error::{Error, Result};
+pub use frame::{AckFrame, FrameStats, FrameType};
+pub use framed::{Framed, LengthDelimitedCodec};
+pub use host::{ChannelChoice, ChannelInfo, ChannelPolicy, Host, HostBuilder, Listener};
+pub use stream::{
+    BlockReason, ConnectionParams, Features, OnLimit, PathPolicy, Stream, StreamSender,
+    SubstreamOptions,
+};
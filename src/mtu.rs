@@ -0,0 +1,158 @@
+//! Path MTU tracking and discovery.
+//!
+//! Datagrams always fit the minimum IPv6 MTU of 1280 bytes (spec section
+//! 2.1.2); after a channel is established we probe upwards with padded
+//! packets to take advantage of larger paths.
+
+use std::time::{Duration, Instant};
+
+/// Default UDP payload budget: 1280 bytes minus 40 bytes of IPv6 header and
+/// 8 bytes of UDP header.
+pub(crate) const DEFAULT_PACKET_SIZE: usize = 1232;
+
+/// Largest UDP payload we will ever probe towards (1500-byte Ethernet MTU
+/// minus IPv6 and UDP headers).
+pub(crate) const MAX_PACKET_SIZE: usize = 1452;
+
+/// Smallest UDP payload budget a host may be configured with: the IPv4
+/// minimum reassembly size of 576 bytes minus 28 bytes of headers.
+pub(crate) const MIN_PACKET_SIZE: usize = 548;
+
+/// Probe step size.
+const PROBE_STEP: usize = 64;
+/// Delay before the first probe and between successful probes.
+const PROBE_INTERVAL: Duration = Duration::from_millis(500);
+/// Give up raising the MTU after this many failed probes at one size.
+const MAX_PROBE_FAILURES: u8 = 3;
+
+/// Per-channel path MTU state.
+pub(crate) struct MtuState {
+    /// Validated UDP payload size for this path.
+    current: usize,
+    /// Upper bound we will not probe beyond.
+    ceiling: usize,
+    /// Sequence number of the outstanding probe packet, if any.
+    pub(crate) probe_seq: Option<u64>,
+    /// Size being probed.
+    probe_size: usize,
+    failures: u8,
+    next_probe_at: Instant,
+    enabled: bool,
+}
+
+impl MtuState {
+    pub(crate) fn new(current: usize, ceiling: usize) -> Self {
+        MtuState {
+            current,
+            ceiling,
+            probe_seq: None,
+            probe_size: 0,
+            failures: 0,
+            next_probe_at: Instant::now() + PROBE_INTERVAL,
+            enabled: true,
+        }
+    }
+
+    /// A static MTU with discovery switched off entirely.
+    pub(crate) fn fixed(size: usize) -> Self {
+        let mut state = MtuState::new(size, size);
+        state.enabled = false;
+        state
+    }
+
+    /// Validated UDP payload size currently in use.
+    pub(crate) fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Clamp the path MTU down to `size`, as after a Packet-Too-Big
+    /// signal: the validated size and probe ceiling drop to it and any
+    /// outstanding probe is abandoned. A `size` at or above the current
+    /// value changes nothing.
+    pub(crate) fn clamp_to(&mut self, size: usize) {
+        if size >= self.current && size >= self.ceiling {
+            return;
+        }
+        self.current = self.current.min(size);
+        self.ceiling = self.ceiling.min(size);
+        self.probe_seq = None;
+    }
+
+    /// Whether a probe should be emitted now; returns the probe size.
+    pub(crate) fn probe_due(&self, now: Instant) -> Option<usize> {
+        if !self.enabled
+            || self.probe_seq.is_some()
+            || self.current >= self.ceiling
+            || now < self.next_probe_at
+        {
+            return None;
+        }
+        Some(std::cmp::min(self.current + PROBE_STEP, self.ceiling))
+    }
+
+    /// When the next probe may fire, if probing is still active.
+    pub(crate) fn next_probe_at(&self) -> Option<Instant> {
+        (self.enabled && self.probe_seq.is_none() && self.current < self.ceiling)
+            .then_some(self.next_probe_at)
+    }
+
+    /// Record that a probe of `size` went out as packet `seq`.
+    pub(crate) fn probe_sent(&mut self, seq: u64, size: usize) {
+        self.probe_seq = Some(seq);
+        self.probe_size = size;
+    }
+
+    /// The outstanding probe was acknowledged: raise the validated MTU.
+    pub(crate) fn probe_acked(&mut self, seq: u64) {
+        if self.probe_seq == Some(seq) {
+            self.probe_seq = None;
+            self.current = self.probe_size;
+            self.failures = 0;
+            self.next_probe_at = Instant::now() + PROBE_INTERVAL;
+        }
+    }
+
+    /// The outstanding probe was declared lost.
+    pub(crate) fn probe_lost(&mut self, seq: u64) {
+        if self.probe_seq == Some(seq) {
+            self.probe_seq = None;
+            self.failures += 1;
+            if self.failures >= MAX_PROBE_FAILURES {
+                self.enabled = false;
+            } else {
+                self.next_probe_at = Instant::now() + PROBE_INTERVAL;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_raises_mtu_on_ack() {
+        let mut mtu = MtuState::new(DEFAULT_PACKET_SIZE, MAX_PACKET_SIZE);
+        let when = Instant::now() + 2 * PROBE_INTERVAL;
+        let size = mtu.probe_due(when).unwrap();
+        assert!(size > mtu.current());
+        mtu.probe_sent(5, size);
+        assert_eq!(mtu.probe_due(when), None, "one probe at a time");
+        mtu.probe_acked(5);
+        assert_eq!(mtu.current(), size);
+    }
+
+    #[test]
+    fn probing_stops_after_repeated_losses() {
+        let mut mtu = MtuState::new(DEFAULT_PACKET_SIZE, MAX_PACKET_SIZE);
+        for seq in 0..u64::from(MAX_PROBE_FAILURES) {
+            let when = Instant::now() + Duration::from_secs(3600);
+            let size = mtu.probe_due(when).expect("probe still enabled");
+            mtu.probe_sent(seq, size);
+            mtu.probe_lost(seq);
+        }
+        let when = Instant::now() + Duration::from_secs(7200);
+        assert_eq!(mtu.probe_due(when), None);
+        assert_eq!(mtu.current(), DEFAULT_PACKET_SIZE);
+    }
+}
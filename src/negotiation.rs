@@ -0,0 +1,304 @@
+//! Negotiation protocol: HELLO, COOKIE and INITIATE packets (spec section 3).
+//!
+//! The negotiation protocol performs short-term key agreement and host
+//! identity verification in a single round trip, with the responder staying
+//! stateless until a valid INITIATE arrives (cookies, spec section 3.2.1).
+
+use crypto_box::SalsaBox;
+
+use crate::crypto::{self, HostRng, Identity, MinuteKeys, PublicKey, ShortTermKey, BOX_OVERHEAD, KEY_SIZE};
+use crate::error::{Error, Result};
+use crate::packet::{MAGIC_COOKIE, MAGIC_HELLO, MAGIC_INITIATE};
+
+pub(crate) const NONCE_PREFIX_HELLO: &[u8; 16] = b"cUVVYcp-CLIENT-h";
+pub(crate) const NONCE_PREFIX_COOKIE: &[u8; 8] = b"cUVVYcpk";
+pub(crate) const NONCE_PREFIX_INITIATE: &[u8; 16] = b"cUVVYcp-CLIENT-i";
+pub(crate) const NONCE_PREFIX_VOUCH: &[u8; 8] = b"cUVVYcpv";
+pub(crate) const NONCE_PREFIX_MINUTE: &[u8; 8] = b"minute-k";
+pub(crate) const NONCE_PREFIX_CLIENT_MESSAGE: &[u8; 16] = b"cUVVYcp-CLIENT-m";
+pub(crate) const NONCE_PREFIX_SERVER_MESSAGE: &[u8; 16] = b"cUVVYcp-SERVER-m";
+
+pub(crate) const HELLO_SIZE: usize = 192;
+pub(crate) const COOKIE_PACKET_SIZE: usize = 168;
+/// Responder cookie: 16-byte compressed nonce plus an 80-byte secretbox.
+pub(crate) const COOKIE_SIZE: usize = 96;
+/// Bounds on the message carried inside an INITIATE packet.
+pub(crate) const INITIATE_MIN_MESSAGE: usize = 16;
+pub(crate) const INITIATE_MAX_MESSAGE: usize = 1024;
+
+/// Build a HELLO packet (spec section 3.2.2).
+///
+/// Padded with zeros to be larger than the COOKIE reply, as an
+/// anti-amplification measure.
+pub(crate) fn build_hello(
+    identity: &Identity,
+    short: &ShortTermKey,
+    responder: &PublicKey,
+    rng: &HostRng,
+) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(HELLO_SIZE);
+    packet.extend_from_slice(MAGIC_HELLO);
+    packet.extend_from_slice(&short.public());
+    packet.extend_from_slice(&[0u8; 64]);
+    let compressed = rng.bytes::<8>();
+    packet.extend_from_slice(&compressed);
+    let nonce = crypto::nonce(NONCE_PREFIX_HELLO, &compressed);
+    let mut plaintext = [0u8; 64];
+    plaintext[..KEY_SIZE].copy_from_slice(identity.public().as_bytes());
+    let cbox = SalsaBox::new(&responder.inner(), short.secret());
+    packet.extend_from_slice(&crypto::seal(&cbox, &nonce, &plaintext));
+    debug_assert_eq!(packet.len(), HELLO_SIZE);
+    packet
+}
+
+/// A parsed HELLO packet, seen by the responder.
+pub(crate) struct HelloPacket {
+    pub(crate) initiator_short: [u8; KEY_SIZE],
+    pub(crate) initiator_long: [u8; KEY_SIZE],
+}
+
+pub(crate) fn parse_hello(identity: &Identity, datagram: &[u8]) -> Result<HelloPacket> {
+    if datagram.len() != HELLO_SIZE {
+        return Err(Error::protocol("bad HELLO size"));
+    }
+    let mut initiator_short = [0u8; KEY_SIZE];
+    initiator_short.copy_from_slice(&datagram[8..40]);
+    let nonce = crypto::nonce(NONCE_PREFIX_HELLO, &datagram[104..112]);
+    let cbox = SalsaBox::new(&crypto_box::PublicKey::from(initiator_short), identity.secret());
+    let plaintext = crypto::open(&cbox, &nonce, &datagram[112..192])?;
+    let mut initiator_long = [0u8; KEY_SIZE];
+    initiator_long.copy_from_slice(&plaintext[..KEY_SIZE]);
+    Ok(HelloPacket {
+        initiator_short,
+        initiator_long,
+    })
+}
+
+/// Build a COOKIE packet in response to a HELLO (spec section 3.2.3).
+///
+/// Generates the responder's short-term key pair, then forgets the secret
+/// half: it travels back to us inside the minute-key cookie.
+pub(crate) fn build_cookie(
+    identity: &Identity,
+    minute_keys: &mut MinuteKeys,
+    hello: &HelloPacket,
+    rng: &HostRng,
+) -> Vec<u8> {
+    let responder_short = ShortTermKey::generate(rng);
+
+    // Responder cookie: I' and r' under the minute key (spec section 3.2.1).
+    let cookie_compressed = rng.bytes::<16>();
+    let cookie_nonce = crypto::nonce(NONCE_PREFIX_MINUTE, &cookie_compressed);
+    let mut cookie_plain = [0u8; 64];
+    cookie_plain[..KEY_SIZE].copy_from_slice(&hello.initiator_short);
+    cookie_plain[KEY_SIZE..].copy_from_slice(&responder_short.secret().to_bytes());
+    let mut cookie = Vec::with_capacity(COOKIE_SIZE);
+    cookie.extend_from_slice(&cookie_compressed);
+    cookie.extend_from_slice(&minute_keys.seal(rng, &cookie_nonce, &cookie_plain));
+
+    let mut packet = Vec::with_capacity(COOKIE_PACKET_SIZE);
+    packet.extend_from_slice(MAGIC_COOKIE);
+    let compressed = rng.bytes::<16>();
+    packet.extend_from_slice(&compressed);
+    let nonce = crypto::nonce(NONCE_PREFIX_COOKIE, &compressed);
+    let mut plaintext = Vec::with_capacity(KEY_SIZE + COOKIE_SIZE);
+    plaintext.extend_from_slice(&responder_short.public());
+    plaintext.extend_from_slice(&cookie);
+    let cbox = SalsaBox::new(
+        &crypto_box::PublicKey::from(hello.initiator_short),
+        identity.secret(),
+    );
+    packet.extend_from_slice(&crypto::seal(&cbox, &nonce, &plaintext));
+    debug_assert_eq!(packet.len(), COOKIE_PACKET_SIZE);
+    packet
+}
+
+/// A parsed COOKIE packet, seen by the initiator.
+pub(crate) struct CookiePayload {
+    pub(crate) responder_short: [u8; KEY_SIZE],
+    pub(crate) cookie: Vec<u8>,
+}
+
+pub(crate) fn parse_cookie(
+    short_secret: &crypto_box::SecretKey,
+    responder: &PublicKey,
+    datagram: &[u8],
+) -> Result<CookiePayload> {
+    if datagram.len() != COOKIE_PACKET_SIZE {
+        return Err(Error::protocol("bad COOKIE size"));
+    }
+    let nonce = crypto::nonce(NONCE_PREFIX_COOKIE, &datagram[8..24]);
+    let cbox = SalsaBox::new(&responder.inner(), short_secret);
+    let plaintext = crypto::open(&cbox, &nonce, &datagram[24..])?;
+    let mut responder_short = [0u8; KEY_SIZE];
+    responder_short.copy_from_slice(&plaintext[..KEY_SIZE]);
+    Ok(CookiePayload {
+        responder_short,
+        cookie: plaintext[KEY_SIZE..].to_vec(),
+    })
+}
+
+/// Build an INITIATE packet carrying an initial message (spec section 3.2.4).
+pub(crate) fn build_initiate(
+    identity: &Identity,
+    short: &ShortTermKey,
+    responder: &PublicKey,
+    cookie: &CookiePayload,
+    message: &[u8],
+    rng: &HostRng,
+) -> Vec<u8> {
+    debug_assert!(
+        message.len().is_multiple_of(16)
+            && (INITIATE_MIN_MESSAGE..=INITIATE_MAX_MESSAGE).contains(&message.len()),
+        "INITIATE message must be a 16-byte multiple between 16 and 1024 bytes"
+    );
+    // Vouch subpacket: our short-term key under our long-term key.
+    let vouch_compressed = rng.bytes::<16>();
+    let vouch_nonce = crypto::nonce(NONCE_PREFIX_VOUCH, &vouch_compressed);
+    let long_box = SalsaBox::new(&responder.inner(), identity.secret());
+    let vouch = crypto::seal(&long_box, &vouch_nonce, &short.public());
+
+    let mut plaintext =
+        Vec::with_capacity(KEY_SIZE + 16 + KEY_SIZE + BOX_OVERHEAD + message.len());
+    plaintext.extend_from_slice(identity.public().as_bytes());
+    plaintext.extend_from_slice(&vouch_compressed);
+    plaintext.extend_from_slice(&vouch);
+    plaintext.extend_from_slice(message);
+
+    let mut packet = Vec::new();
+    packet.extend_from_slice(MAGIC_INITIATE);
+    packet.extend_from_slice(&short.public());
+    packet.extend_from_slice(&cookie.cookie);
+    let compressed = rng.bytes::<8>();
+    packet.extend_from_slice(&compressed);
+    let nonce = crypto::nonce(NONCE_PREFIX_INITIATE, &compressed);
+    let short_box = SalsaBox::new(
+        &crypto_box::PublicKey::from(cookie.responder_short),
+        short.secret(),
+    );
+    packet.extend_from_slice(&crypto::seal(&short_box, &nonce, &plaintext));
+    packet
+}
+
+/// A validated INITIATE packet, seen by the responder.
+pub(crate) struct InitiatePacket {
+    pub(crate) initiator_short: [u8; KEY_SIZE],
+    pub(crate) initiator_long: [u8; KEY_SIZE],
+    /// Our short-term secret key, recovered from the cookie.
+    pub(crate) responder_short: crypto_box::SecretKey,
+    /// The embedded initial message (packet header plus frames).
+    pub(crate) message: Vec<u8>,
+}
+
+pub(crate) fn parse_initiate(
+    identity: &Identity,
+    minute_keys: &mut MinuteKeys,
+    datagram: &[u8],
+    rng: &HostRng,
+) -> Result<InitiatePacket> {
+    if datagram.len() < 8 + KEY_SIZE + COOKIE_SIZE + 8 + BOX_OVERHEAD {
+        return Err(Error::protocol("bad INITIATE size"));
+    }
+    let mut initiator_short = [0u8; KEY_SIZE];
+    initiator_short.copy_from_slice(&datagram[8..40]);
+
+    // Recover our short-term secret key from the cookie.
+    let cookie = &datagram[40..40 + COOKIE_SIZE];
+    let cookie_nonce = crypto::nonce(NONCE_PREFIX_MINUTE, &cookie[..16]);
+    let cookie_plain = minute_keys.open(rng, &cookie_nonce, &cookie[16..])?;
+    if cookie_plain[..KEY_SIZE] != initiator_short {
+        return Err(Error::protocol("INITIATE cookie for a different key"));
+    }
+    let mut short_secret = [0u8; KEY_SIZE];
+    short_secret.copy_from_slice(&cookie_plain[KEY_SIZE..]);
+    let responder_short = crypto_box::SecretKey::from(short_secret);
+
+    let nonce = crypto::nonce(NONCE_PREFIX_INITIATE, &datagram[136..144]);
+    let short_box = SalsaBox::new(&crypto_box::PublicKey::from(initiator_short), &responder_short);
+    let plaintext = crypto::open(&short_box, &nonce, &datagram[144..])?;
+    if plaintext.len() < KEY_SIZE + 16 + KEY_SIZE + BOX_OVERHEAD {
+        return Err(Error::protocol("short INITIATE payload"));
+    }
+    let mut initiator_long = [0u8; KEY_SIZE];
+    initiator_long.copy_from_slice(&plaintext[..KEY_SIZE]);
+
+    // Verify the vouch: the long-term key must vouch for the short-term one.
+    let vouch_nonce = crypto::nonce(NONCE_PREFIX_VOUCH, &plaintext[KEY_SIZE..KEY_SIZE + 16]);
+    let long_box = SalsaBox::new(&crypto_box::PublicKey::from(initiator_long), identity.secret());
+    let vouch_end = KEY_SIZE + 16 + KEY_SIZE + BOX_OVERHEAD;
+    let vouched = crypto::open(&long_box, &vouch_nonce, &plaintext[KEY_SIZE + 16..vouch_end])?;
+    if vouched != initiator_short {
+        return Err(Error::protocol("INITIATE vouch mismatch"));
+    }
+
+    Ok(InitiatePacket {
+        initiator_short,
+        initiator_long,
+        responder_short,
+        message: plaintext[vouch_end..].to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation_roundtrip() {
+        let rng = HostRng::os();
+        let initiator = Identity::generate();
+        let responder = Identity::generate();
+        let mut minute_keys = MinuteKeys::new(&rng);
+        let short = ShortTermKey::generate(&rng);
+
+        let hello = build_hello(&initiator, &short, &responder.public(), &rng);
+        assert_eq!(hello.len(), HELLO_SIZE);
+        assert!(hello.len() > COOKIE_PACKET_SIZE, "anti-amplification");
+
+        let parsed = parse_hello(&responder, &hello).unwrap();
+        assert_eq!(parsed.initiator_short, short.public());
+        assert_eq!(&parsed.initiator_long, initiator.public().as_bytes());
+
+        let cookie_packet = build_cookie(&responder, &mut minute_keys, &parsed, &rng);
+        let cookie = parse_cookie(short.secret(), &responder.public(), &cookie_packet).unwrap();
+
+        let message = [0u8; 32];
+        let initiate = build_initiate(&initiator, &short, &responder.public(), &cookie, &message, &rng);
+        let validated = parse_initiate(&responder, &mut minute_keys, &initiate, &rng).unwrap();
+        assert_eq!(validated.initiator_short, short.public());
+        assert_eq!(&validated.initiator_long, initiator.public().as_bytes());
+        assert_eq!(validated.message, message);
+        assert_eq!(
+            validated.responder_short.public_key().as_bytes(),
+            &cookie.responder_short
+        );
+    }
+
+    #[test]
+    fn hello_from_wrong_responder_key_rejected() {
+        let rng = HostRng::os();
+        let initiator = Identity::generate();
+        let responder = Identity::generate();
+        let short = ShortTermKey::generate(&rng);
+        let hello = build_hello(&initiator, &short, &Identity::generate().public(), &rng);
+        assert!(parse_hello(&responder, &hello).is_err());
+    }
+
+    #[test]
+    fn tampered_initiate_rejected() {
+        let rng = HostRng::os();
+        let initiator = Identity::generate();
+        let responder = Identity::generate();
+        let mut minute_keys = MinuteKeys::new(&rng);
+        let short = ShortTermKey::generate(&rng);
+        let hello = build_hello(&initiator, &short, &responder.public(), &rng);
+        let parsed = parse_hello(&responder, &hello).unwrap();
+        let cookie_packet = build_cookie(&responder, &mut minute_keys, &parsed, &rng);
+        let cookie = parse_cookie(short.secret(), &responder.public(), &cookie_packet).unwrap();
+        let mut initiate =
+            build_initiate(&initiator, &short, &responder.public(), &cookie, &[0u8; 16], &rng);
+        let last = initiate.len() - 1;
+        initiate[last] ^= 0xff;
+        assert!(parse_initiate(&responder, &mut minute_keys, &initiate, &rng).is_err());
+    }
+}
@@ -0,0 +1,288 @@
+//! Channel packet header encoding (spec section 4.1.1).
+//!
+//! Every decrypted message box starts with a packet header carrying the
+//! packet sequence number and, optionally, a protocol version and FEC group.
+
+use crate::error::{Error, Result};
+use crate::frame::Frame;
+
+/// Magic values distinguishing packet types on the wire (spec section 3.3).
+pub(crate) const MAGIC_HELLO: &[u8; 8] = b"qVNq5xLh";
+pub(crate) const MAGIC_COOKIE: &[u8; 8] = b"rl3Anmxk";
+pub(crate) const MAGIC_INITIATE: &[u8; 8] = b"qVNq5xLi";
+pub(crate) const MAGIC_MESSAGE: &[u8; 8] = b"rl3q5xLm";
+
+const FLAG_VERSION: u8 = 0x01;
+const FLAG_FEC_GROUP: u8 = 0x02;
+const FLAG_FEC_LAST: u8 = 0x10;
+const SEQ_SIZE_SHIFT: u8 = 2;
+const SEQ_SIZE_MASK: u8 = 0x0c;
+
+/// Decrypted packet header: flags, optional version/FEC group, sequence number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PacketHeader {
+    /// Optional protocol version, sent until acknowledged by the peer.
+    pub version: Option<u16>,
+    /// FEC group this packet belongs to, if FEC is in use.
+    pub fec_group: Option<u8>,
+    /// Set on the final packet of a FEC group (the XOR packet).
+    pub fec_last: bool,
+    /// Packet sequence number within this direction of the channel.
+    pub seq: u64,
+}
+
+impl PacketHeader {
+    pub(crate) fn new(seq: u64) -> Self {
+        PacketHeader {
+            version: None,
+            fec_group: None,
+            fec_last: false,
+            seq,
+        }
+    }
+
+    /// Largest sequence number the 6-byte width can carry (48 bits).
+    const SEQ_48_MAX: u64 = 0xFFFF_FFFF_FFFF;
+
+    /// Smallest number of bytes that can carry `seq` (2, 4, 6 or 8).
+    fn seq_width(seq: u64) -> usize {
+        if seq <= u64::from(u16::MAX) {
+            2
+        } else if seq <= u64::from(u32::MAX) {
+            4
+        } else if seq <= Self::SEQ_48_MAX {
+            6
+        } else {
+            8
+        }
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        let width = Self::seq_width(self.seq);
+        let ss = match width {
+            2 => 0u8,
+            4 => 1,
+            6 => 2,
+            _ => 3,
+        };
+        let mut flags = ss << SEQ_SIZE_SHIFT;
+        if self.version.is_some() {
+            flags |= FLAG_VERSION;
+        }
+        if self.fec_group.is_some() {
+            flags |= FLAG_FEC_GROUP;
+            if self.fec_last {
+                flags |= FLAG_FEC_LAST;
+            }
+        }
+        buf.push(flags);
+        if let Some(v) = self.version {
+            buf.extend_from_slice(&v.to_be_bytes());
+        }
+        if let Some(g) = self.fec_group {
+            buf.push(g);
+        }
+        // Only the low `width` bytes go out; at width 6 this masks the
+        // value to 48 bits and never touches the high 16.
+        buf.extend_from_slice(&self.seq.to_be_bytes()[8 - width..]);
+    }
+
+    /// Decode a header, advancing `buf` past it.
+    pub(crate) fn decode(buf: &mut &[u8]) -> Result<Self> {
+        let flags = take(buf, 1)?[0];
+        let version = if flags & FLAG_VERSION != 0 {
+            let v = take(buf, 2)?;
+            Some(u16::from_be_bytes([v[0], v[1]]))
+        } else {
+            None
+        };
+        let fec_group = if flags & FLAG_FEC_GROUP != 0 {
+            Some(take(buf, 1)?[0])
+        } else {
+            None
+        };
+        let fec_last = flags & FLAG_FEC_LAST != 0;
+        if fec_last && fec_group.is_none() {
+            return Err(Error::protocol("FEC-last flag without FEC group"));
+        }
+        let width = match (flags & SEQ_SIZE_MASK) >> SEQ_SIZE_SHIFT {
+            0 => 2,
+            1 => 4,
+            2 => 6,
+            _ => 8,
+        };
+        let seq = decode_be_uint(take(buf, width)?);
+        Ok(PacketHeader {
+            version,
+            fec_group,
+            fec_last,
+            seq,
+        })
+    }
+}
+
+/// A complete decrypted channel packet: a header followed by frames.
+///
+/// This is the boundary between the transport layer, which moves opaque
+/// encrypted payloads, and the framing layer, which interprets them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Packet {
+    pub header: PacketHeader,
+    pub frames: Vec<Frame>,
+}
+
+impl Packet {
+    pub(crate) fn new(header: PacketHeader, frames: Vec<Frame>) -> Self {
+        Packet { header, frames }
+    }
+
+    /// Encode the header and every frame into `buf`, returning the number
+    /// of bytes written, or `None` if the packet does not fit.
+    pub(crate) fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut out = Vec::with_capacity(buf.len());
+        self.header.encode(&mut out);
+        for frame in &self.frames {
+            frame.encode(&mut out);
+        }
+        if out.len() > buf.len() {
+            return None;
+        }
+        buf[..out.len()].copy_from_slice(&out);
+        Some(out.len())
+    }
+
+    /// Decode a full packet payload: a header, then frames to the end.
+    pub(crate) fn decode(mut buf: &[u8]) -> Result<Packet> {
+        let header = PacketHeader::decode(&mut buf)?;
+        let frames = Frame::decode_all(buf)?;
+        Ok(Packet { header, frames })
+    }
+}
+
+/// Split `len` bytes off the front of `buf`, or fail with a protocol error.
+pub(crate) fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(Error::protocol("truncated packet"));
+    }
+    let (head, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(head)
+}
+
+/// Decode a big-endian unsigned integer of up to 8 bytes.
+pub(crate) fn decode_be_uint(bytes: &[u8]) -> u64 {
+    let mut out = 0u64;
+    for &b in bytes {
+        out = out << 8 | u64::from(b);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(header: PacketHeader) -> PacketHeader {
+        let mut buf = Vec::new();
+        header.encode(&mut buf);
+        let mut slice = buf.as_slice();
+        let decoded = PacketHeader::decode(&mut slice).unwrap();
+        assert!(slice.is_empty());
+        decoded
+    }
+
+    #[test]
+    fn minimal_header_is_three_bytes() {
+        let mut buf = Vec::new();
+        PacketHeader::new(7).encode(&mut buf);
+        assert_eq!(buf.len(), 3);
+    }
+
+    #[test]
+    fn roundtrip_plain() {
+        let h = PacketHeader::new(0x1234);
+        assert_eq!(roundtrip(h), h);
+    }
+
+    #[test]
+    fn roundtrip_wide_sequence() {
+        let h = PacketHeader::new(u64::MAX - 1);
+        assert_eq!(roundtrip(h), h);
+    }
+
+    #[test]
+    fn six_byte_width_spans_the_48_bit_range() {
+        // Everything from just past the 4-byte range up to the 48-bit
+        // ceiling takes exactly 6 bytes on the wire; one more bit does not.
+        for seq in [0x1_0000_0000u64, 0x1234_5678_9abc, 0xFFFF_FFFF_FFFF] {
+            let h = PacketHeader::new(seq);
+            let mut buf = Vec::new();
+            h.encode(&mut buf);
+            assert_eq!(buf.len(), 1 + 6, "seq {seq:#x} not 6 bytes wide");
+            assert_eq!(roundtrip(h), h);
+        }
+        let mut buf = Vec::new();
+        PacketHeader::new(0x1_0000_0000_0000).encode(&mut buf);
+        assert_eq!(buf.len(), 1 + 8);
+    }
+
+    #[test]
+    fn six_byte_packets_round_trip_whole() {
+        for seq in [0x1_0000_0000u64, 0xFFFF_FFFF_FFFF] {
+            let packet = Packet::new(
+                PacketHeader::new(seq),
+                vec![Frame::Detach { lsid: 1 }, Frame::Empty],
+            );
+            let mut buf = [0u8; 64];
+            let len = packet.encode(&mut buf).unwrap();
+            let decoded = Packet::decode(&buf[..len]).unwrap();
+            assert_eq!(decoded, packet);
+            assert_eq!(decoded.header.seq, seq, "high bits leaked into seq");
+        }
+    }
+
+    #[test]
+    fn roundtrip_version_and_fec() {
+        let h = PacketHeader {
+            version: Some(1),
+            fec_group: Some(3),
+            fec_last: true,
+            seq: 0x1_0000_0000,
+        };
+        assert_eq!(roundtrip(h), h);
+    }
+
+    #[test]
+    fn packet_roundtrip() {
+        let packet = Packet::new(
+            PacketHeader::new(42),
+            vec![
+                Frame::Priority {
+                    lsid: 3,
+                    priority: 9,
+                },
+                Frame::StopSending {
+                    lsid: 5,
+                    error_code: 0,
+                },
+            ],
+        );
+        let mut buf = [0u8; 64];
+        let len = packet.encode(&mut buf).unwrap();
+        assert_eq!(Packet::decode(&buf[..len]).unwrap(), packet);
+    }
+
+    #[test]
+    fn packet_encode_reports_a_short_buffer() {
+        let packet = Packet::new(PacketHeader::new(42), vec![Frame::Empty]);
+        assert!(packet.encode(&mut [0u8; 3]).is_none());
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let mut buf = Vec::new();
+        PacketHeader::new(0x12345).encode(&mut buf);
+        let mut slice = &buf[..buf.len() - 1];
+        assert!(PacketHeader::decode(&mut slice).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+//! Packet assembly: fitting frames into a channel packet's payload budget.
+//!
+//! The packetizer owns the arithmetic tying the UDP payload size to the
+//! message box budget (spec section 4.1) and the padding rules that round
+//! every message box up to a multiple of 16 bytes.
+
+use crate::crypto::BOX_OVERHEAD;
+use crate::frame::Frame;
+
+/// Channel header: 8-byte magic, 32-byte short-term key, 8-byte nonce.
+pub(crate) const CHANNEL_HEADER_SIZE: usize = 48;
+
+/// Bytes of a UDP payload not available to the message plaintext.
+pub(crate) const PACKET_OVERHEAD: usize = CHANNEL_HEADER_SIZE + BOX_OVERHEAD;
+
+/// Assembles frames into packet payloads within a size budget.
+pub(crate) struct Packetizer {
+    /// Largest UDP payload this channel may emit.
+    packet_size: usize,
+    /// Permitted UDP payload sizes, ascending; empty means unrestricted.
+    pad_sizes: Vec<usize>,
+}
+
+impl Packetizer {
+    pub(crate) fn new(packet_size: usize) -> Self {
+        Packetizer {
+            packet_size,
+            pad_sizes: Vec::new(),
+        }
+    }
+
+    /// Restrict emitted packets to the given UDP payload sizes (ascending),
+    /// padding every packet up to the next one.
+    pub(crate) fn set_pad_sizes(&mut self, sizes: Vec<usize>) {
+        self.pad_sizes = sizes;
+    }
+
+    pub(crate) fn set_packet_size(&mut self, packet_size: usize) {
+        self.packet_size = packet_size;
+    }
+
+    /// Message plaintext budget for a packet of the configured size.
+    pub(crate) fn payload_budget(&self) -> usize {
+        self.packet_size - PACKET_OVERHEAD
+    }
+
+    /// Pad a message payload with filler frames: up to the next configured
+    /// size class when padding classes are set (so observers cannot infer
+    /// message sizes), otherwise to a multiple of 16 bytes.
+    ///
+    /// Returns the (PADDING, EMPTY) frame counts appended, for accounting.
+    pub(crate) fn pad_payload(&self, payload: &mut Vec<u8>) -> (u64, u64) {
+        let target = self
+            .pad_sizes
+            .iter()
+            .map(|size| size - PACKET_OVERHEAD)
+            .find(|&budget| budget >= payload.len());
+        match target {
+            Some(budget) => Self::pad_to(payload, budget),
+            None => Self::pad(payload),
+        }
+    }
+
+    /// Pad a message payload to a multiple of 16 bytes (at least 16).
+    ///
+    /// Uses a PADDING frame where three or more bytes remain and EMPTY
+    /// frames for one- and two-byte remainders.
+    pub(crate) fn pad(payload: &mut Vec<u8>) -> (u64, u64) {
+        let target = std::cmp::max(16, payload.len().div_ceil(16) * 16);
+        Self::pad_to(payload, target)
+    }
+
+    /// Pad a message payload with filler frames to exactly `target` bytes.
+    pub(crate) fn pad_to(payload: &mut Vec<u8>, target: usize) -> (u64, u64) {
+        let mut remaining = target - payload.len();
+        let mut padding = 0;
+        if remaining >= 3 {
+            Frame::Padding((remaining - 3) as u16).encode(payload);
+            padding = 1;
+            remaining = 0;
+        }
+        for _ in 0..remaining {
+            Frame::Empty.encode(payload);
+        }
+        (padding, remaining as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pads_to_sixteen_byte_multiples() {
+        for len in 0..64 {
+            let mut payload = vec![0xaa; len];
+            if len > 0 {
+                payload[0] = crate::frame::FRAME_EMPTY;
+            }
+            Packetizer::pad(&mut payload);
+            assert!(payload.len() >= 16);
+            assert_eq!(payload.len() % 16, 0, "len {len} padded badly");
+        }
+    }
+
+    #[test]
+    fn padding_decodes_as_filler_frames() {
+        let mut payload = Vec::new();
+        Frame::Detach { lsid: 1 }.encode(&mut payload);
+        Packetizer::pad(&mut payload);
+        let frames = Frame::decode_all(&payload).unwrap();
+        assert_eq!(frames[0], Frame::Detach { lsid: 1 });
+        assert!(frames[1..]
+            .iter()
+            .all(|f| matches!(f, Frame::Padding(_) | Frame::Empty)));
+    }
+
+    #[test]
+    fn size_classes_pad_to_the_next_class() {
+        let mut p = Packetizer::new(1232);
+        p.set_pad_sizes(vec![768, 1232]);
+        let mut small = vec![crate::frame::FRAME_EMPTY; 10];
+        p.pad_payload(&mut small);
+        assert_eq!(small.len() + PACKET_OVERHEAD, 768);
+        let mut large = vec![crate::frame::FRAME_EMPTY; 800];
+        p.pad_payload(&mut large);
+        assert_eq!(large.len() + PACKET_OVERHEAD, 1232);
+    }
+
+    #[test]
+    fn budget_accounts_for_header_and_box() {
+        let p = Packetizer::new(1232);
+        assert_eq!(p.payload_budget(), 1168);
+    }
+}
@@ -0,0 +1,86 @@
+//! Host-wide buffer pool bounding stream memory.
+//!
+//! Every stream on a host charges its send queues and readable reassembly
+//! bytes against one shared pool. When the pool runs dry, writers block
+//! instead of allocating, and receive windows shrink so peers slow down.
+
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+
+/// Default pool capacity shared by all streams of a host.
+pub(crate) const DEFAULT_POOL_SIZE: usize = 16 * 1024 * 1024;
+
+/// A shared byte budget with writer wakeups on release.
+pub(crate) struct BufferPool {
+    capacity: usize,
+    inner: Mutex<PoolInner>,
+}
+
+struct PoolInner {
+    used: usize,
+    /// Writers blocked on pool space, woken on any release.
+    wakers: Vec<Waker>,
+}
+
+impl BufferPool {
+    pub(crate) fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(BufferPool {
+            capacity,
+            inner: Mutex::new(PoolInner {
+                used: 0,
+                wakers: Vec::new(),
+            }),
+        })
+    }
+
+    /// Bytes still unclaimed. Zero when inbound data pushed usage past the
+    /// capacity; the pool never refuses received data.
+    pub(crate) fn available(&self) -> usize {
+        self.capacity
+            .saturating_sub(self.inner.lock().unwrap().used)
+    }
+
+    /// Claim `n` bytes. Callers are expected to respect [`available`]
+    /// except for inbound data, which must be buffered regardless.
+    ///
+    /// [`available`]: BufferPool::available
+    pub(crate) fn charge(&self, n: usize) {
+        self.inner.lock().unwrap().used += n;
+    }
+
+    /// Return `n` bytes and wake writers blocked on pool space.
+    pub(crate) fn discharge(&self, n: usize) {
+        let wakers = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.used = inner.used.saturating_sub(n);
+            std::mem::take(&mut inner.wakers)
+        };
+        for w in wakers {
+            w.wake();
+        }
+    }
+
+    /// Register for a wakeup on the next release of pool space.
+    pub(crate) fn register(&self, waker: &Waker) {
+        self.inner.lock().unwrap().wakers.push(waker.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accounting_saturates() {
+        let pool = BufferPool::new(100);
+        assert_eq!(pool.available(), 100);
+        pool.charge(60);
+        assert_eq!(pool.available(), 40);
+        pool.charge(60); // inbound overshoot
+        assert_eq!(pool.available(), 0);
+        pool.discharge(120);
+        assert_eq!(pool.available(), 100);
+        pool.discharge(50);
+        assert_eq!(pool.available(), 100);
+    }
+}
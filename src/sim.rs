@@ -0,0 +1,322 @@
+//! In-memory simulated network for deterministic tests.
+//!
+//! A [`SimNetwork`] hands out [`SimSocket`]s with synthetic addresses in
+//! `10.77.0.0/16`. Datagrams are delivered directly between sockets without
+//! touching the operating system, so tests composed with Tokio's paused
+//! clock run instantly and deterministically.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
+/// Datagrams queued for one endpoint, with their source address.
+type Inbox = mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>;
+
+/// A simulated network segment connecting [`SimSocket`]s.
+#[derive(Clone, Default)]
+pub struct SimNetwork {
+    inner: Arc<Mutex<SimInner>>,
+}
+
+#[derive(Default)]
+struct SimInner {
+    next_host: u16,
+    endpoints: HashMap<SocketAddr, mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>>,
+    trace: Vec<TracedPacket>,
+    /// Scripted one-shot fault rules, consumed as their packets pass.
+    faults: Vec<Fault>,
+    /// A packet held back by [`Fault::Reorder`], delivered after its
+    /// successor.
+    held: Option<(Vec<u8>, SocketAddr, SocketAddr)>,
+    /// One-way delivery delays per (from, to) link.
+    latency: HashMap<(SocketAddr, SocketAddr), Duration>,
+    /// Upper bounds on extra random per-packet delay per (from, to) link.
+    jitter: HashMap<(SocketAddr, SocketAddr), Duration>,
+    /// State of the deterministic generator drawing jitter delays.
+    jitter_rng: u64,
+    /// Links that drop everything after the nth network-wide packet.
+    down: HashMap<(SocketAddr, SocketAddr), u64>,
+    /// Source-address rewrites, as a NAT rebinding would apply.
+    masquerade: HashMap<SocketAddr, SocketAddr>,
+    /// Shared rate limits in front of destinations, modelling a
+    /// bottleneck link all of a host's inbound traffic squeezes through.
+    bottleneck: HashMap<SocketAddr, Bottleneck>,
+}
+
+/// A rate-limited queue in front of one destination.
+struct Bottleneck {
+    /// Drain rate, in bytes per second.
+    rate: u64,
+    /// Queue capacity in bytes; a datagram that does not fit is dropped.
+    queue: usize,
+    /// When the queue as of the last arrival will have drained.
+    busy_until: tokio::time::Instant,
+}
+
+/// A scripted fault applied to one datagram, counted in delivery order
+/// across the whole network starting at 1.
+#[derive(Debug, Clone)]
+pub enum Fault {
+    /// Silently drop packet number `nth`.
+    Drop { nth: u64 },
+    /// Flip the byte at `offset` of packet number `nth`.
+    Corrupt { nth: u64, offset: usize },
+    /// Hold packet number `nth` back until the next packet has been
+    /// delivered, swapping the pair.
+    Reorder { nth: u64 },
+    /// Deliver packet number `nth` after a fixed delay.
+    Delay { nth: u64, by: Duration },
+    /// Deliver packet number `nth` twice, as a duplicating network would.
+    Duplicate { nth: u64 },
+}
+
+impl Fault {
+    fn nth(&self) -> u64 {
+        match self {
+            Fault::Drop { nth }
+            | Fault::Corrupt { nth, .. }
+            | Fault::Reorder { nth }
+            | Fault::Delay { nth, .. }
+            | Fault::Duplicate { nth } => *nth,
+        }
+    }
+}
+
+/// One datagram observed on a [`SimNetwork`].
+#[derive(Debug, Clone)]
+pub struct TracedPacket {
+    pub from: SocketAddr,
+    pub to: SocketAddr,
+    pub len: usize,
+}
+
+impl SimNetwork {
+    pub fn new() -> Self {
+        SimNetwork::default()
+    }
+
+    /// Create a socket on this network with a fresh synthetic address.
+    pub fn socket(&self) -> SimSocket {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_host += 1;
+        let addr = SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(10, 77, (inner.next_host >> 8) as u8, inner.next_host as u8)),
+            9,
+        );
+        let (tx, rx) = mpsc::unbounded_channel();
+        inner.endpoints.insert(addr, tx);
+        SimSocket {
+            net: self.clone(),
+            addr,
+            rx: Arc::new(tokio::sync::Mutex::new(rx)),
+        }
+    }
+
+    /// All datagrams carried so far, in delivery order.
+    pub fn trace(&self) -> Vec<TracedPacket> {
+        self.inner.lock().unwrap().trace.clone()
+    }
+
+    /// Queue a scripted [`Fault`]. Rules are one-shot and may be queued in
+    /// any order; each fires when its packet number passes.
+    pub fn inject(&self, fault: Fault) {
+        self.inner.lock().unwrap().faults.push(fault);
+    }
+
+    /// Silently drop every datagram sent from `from` to `to` after the
+    /// first `after` packets, counted network-wide in delivery order like
+    /// fault rules. The reverse direction is unaffected; dropped packets
+    /// still appear in the trace, like [`Fault::Drop`].
+    pub fn set_link_down_after(&self, from: SocketAddr, to: SocketAddr, after: u64) {
+        self.inner.lock().unwrap().down.insert((from, to), after);
+    }
+
+    /// Rewrite the source address of every datagram sent by `from` to
+    /// `seen_as`, as a NAT rebinding would. Delivery itself is unchanged;
+    /// only the address receivers observe moves.
+    pub fn set_source_rewrite(&self, from: SocketAddr, seen_as: SocketAddr) {
+        self.inner.lock().unwrap().masquerade.insert(from, seen_as);
+    }
+
+    /// Put a shared bottleneck in front of `to`: every datagram addressed
+    /// to it, from any sender, drains through one `bytes_per_sec` link
+    /// with a `queue`-byte tail-drop buffer. Combines with per-link
+    /// latency, which models propagation after the queue.
+    pub fn set_bottleneck(&self, to: SocketAddr, bytes_per_sec: u64, queue: usize) {
+        assert!(bytes_per_sec > 0, "a bottleneck needs a drain rate");
+        self.inner.lock().unwrap().bottleneck.insert(
+            to,
+            Bottleneck {
+                rate: bytes_per_sec,
+                queue,
+                busy_until: tokio::time::Instant::now(),
+            },
+        );
+    }
+
+    /// Delay every datagram sent from `from` to `to` by `latency`.
+    /// Directions are independent; unset links deliver immediately.
+    pub fn set_link_latency(&self, from: SocketAddr, to: SocketAddr, latency: Duration) {
+        self.inner
+            .lock()
+            .unwrap()
+            .latency
+            .insert((from, to), latency);
+    }
+
+    /// Add a uniformly distributed extra delay of up to `jitter` to every
+    /// datagram sent from `from` to `to`, on top of the link latency. The
+    /// draws come from a fixed-seed generator, so a given delivery order
+    /// reproduces the same delays run after run.
+    pub fn set_link_jitter(&self, from: SocketAddr, to: SocketAddr, jitter: Duration) {
+        self.inner.lock().unwrap().jitter.insert((from, to), jitter);
+    }
+
+    fn deliver(&self, buf: &[u8], from: SocketAddr, to: SocketAddr) {
+        let mut inner = self.inner.lock().unwrap();
+        let from = inner.masquerade.get(&from).copied().unwrap_or(from);
+        inner.trace.push(TracedPacket {
+            from,
+            to,
+            len: buf.len(),
+        });
+        let nth = inner.trace.len() as u64;
+        if inner.down.get(&(from, to)).is_some_and(|&after| nth > after) {
+            return;
+        }
+        let mut datagram = buf.to_vec();
+        let fault = inner
+            .faults
+            .iter()
+            .position(|f| f.nth() == nth)
+            .map(|i| inner.faults.remove(i));
+        let mut stashed = false;
+        match fault {
+            Some(Fault::Drop { .. }) => {}
+            Some(Fault::Corrupt { offset, .. }) => {
+                if let Some(byte) = datagram.get_mut(offset) {
+                    *byte ^= 0xff;
+                }
+                inner.send(&datagram, from, to);
+            }
+            Some(Fault::Reorder { .. }) => {
+                inner.held = Some((datagram, from, to));
+                stashed = true;
+            }
+            Some(Fault::Duplicate { .. }) => {
+                inner.send(&datagram, from, to);
+                inner.send(&datagram, from, to);
+            }
+            Some(Fault::Delay { by, .. }) => {
+                if let Some(tx) = inner.endpoints.get(&to).cloned() {
+                    tokio::spawn(async move {
+                        tokio::time::sleep(by).await;
+                        let _ = tx.send((datagram, from));
+                    });
+                }
+            }
+            None => {
+                let mut delay = inner.latency.get(&(from, to)).copied().unwrap_or(Duration::ZERO);
+                if let Some(&jitter) = inner.jitter.get(&(from, to)) {
+                    delay += inner.draw_jitter(jitter);
+                }
+                let mut dropped = false;
+                if let Some(neck) = inner.bottleneck.get_mut(&to) {
+                    let now = tokio::time::Instant::now();
+                    let backlog = neck.busy_until.saturating_duration_since(now);
+                    let queued =
+                        (backlog.as_nanos() * u128::from(neck.rate) / 1_000_000_000) as usize;
+                    if queued + datagram.len() > neck.queue {
+                        dropped = true; // tail drop at the full queue
+                    } else {
+                        let transmit = Duration::from_nanos(
+                            datagram.len() as u64 * 1_000_000_000 / neck.rate,
+                        );
+                        neck.busy_until = now.max(neck.busy_until) + transmit;
+                        delay += backlog + transmit;
+                    }
+                }
+                if dropped {
+                    // Fell off the bottleneck queue; stays in the trace
+                    // like a faulted packet.
+                } else if delay.is_zero() {
+                    inner.send(&datagram, from, to);
+                } else if let Some(tx) = inner.endpoints.get(&to).cloned() {
+                    tokio::spawn(async move {
+                        tokio::time::sleep(delay).await;
+                        let _ = tx.send((datagram, from));
+                    });
+                }
+            }
+        }
+        // A packet held by a Reorder rule goes out right behind its
+        // successor.
+        if !stashed {
+            if let Some((held, held_from, held_to)) = inner.held.take() {
+                inner.send(&held, held_from, held_to);
+            }
+        }
+    }
+}
+
+impl SimInner {
+    /// Draw a delay in `[0, bound]` from a splitmix-style generator.
+    fn draw_jitter(&mut self, bound: Duration) -> Duration {
+        self.jitter_rng = self.jitter_rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.jitter_rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        Duration::from_nanos(z % (bound.as_nanos() as u64 + 1))
+    }
+
+    fn send(&self, datagram: &[u8], from: SocketAddr, to: SocketAddr) {
+        if let Some(tx) = self.endpoints.get(&to) {
+            // A full or closed receiver behaves like any lossy network.
+            let _ = tx.send((datagram.to_vec(), from));
+        }
+    }
+}
+
+/// One endpoint on a [`SimNetwork`].
+#[derive(Clone)]
+pub struct SimSocket {
+    net: SimNetwork,
+    addr: SocketAddr,
+    rx: Arc<tokio::sync::Mutex<Inbox>>,
+}
+
+impl SimSocket {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub(crate) async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.net.deliver(buf, self.addr, addr);
+        Ok(buf.len())
+    }
+
+    pub(crate) fn try_send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.net.deliver(buf, self.addr, addr);
+        Ok(buf.len())
+    }
+
+    pub(crate) async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut rx = self.rx.lock().await;
+        match rx.recv().await {
+            Some((datagram, from)) => {
+                let len = datagram.len().min(buf.len());
+                buf[..len].copy_from_slice(&datagram[..len]);
+                Ok((len, from))
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "simulated network shut down",
+            )),
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Socket protocol layer: datagram transports a host can run over.
+//!
+//! SSS normally runs over UDP, but tests run hosts over an in-memory
+//! simulated network (see [`crate::sim`]) for determinism.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::net::UdpSocket;
+
+use crate::sim::SimSocket;
+
+/// A datagram transport bound to one local endpoint.
+pub(crate) enum Socket {
+    Udp(Arc<UdpSocket>),
+    Sim(SimSocket),
+}
+
+impl Socket {
+    pub(crate) async fn bind_udp(addr: SocketAddr) -> io::Result<Socket> {
+        Ok(Socket::Udp(Arc::new(UdpSocket::bind(addr).await?)))
+    }
+
+    /// Bind a UDP socket with explicit socket options.
+    ///
+    /// `dual_stack` binds IPv6 with `IPV6_V6ONLY` cleared, so IPv4 traffic
+    /// arrives too (with v4-mapped source addresses `::ffff:a.b.c.d`);
+    /// plain [`bind_udp`](Self::bind_udp) leaves the OS default in place.
+    /// `reuse_port` sets `SO_REUSEPORT` (unix only) so another process --
+    /// or a second host in the same one -- can share the port during a
+    /// graceful restart.
+    pub(crate) fn bind_udp_opts(
+        addr: SocketAddr,
+        dual_stack: bool,
+        reuse_port: bool,
+    ) -> io::Result<Socket> {
+        let domain = if addr.is_ipv4() {
+            socket2::Domain::IPV4
+        } else {
+            socket2::Domain::IPV6
+        };
+        let raw = socket2::Socket::new(domain, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+        if dual_stack {
+            raw.set_only_v6(false)?;
+        }
+        if reuse_port {
+            #[cfg(unix)]
+            raw.set_reuse_port(true)?;
+            #[cfg(not(unix))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "SO_REUSEPORT is not available on this platform",
+            ));
+        }
+        raw.set_nonblocking(true)?;
+        raw.bind(&addr.into())?;
+        let socket = UdpSocket::from_std(raw.into())?;
+        Ok(Socket::Udp(Arc::new(socket)))
+    }
+
+    /// The raw file descriptor of the underlying UDP socket, if any.
+    #[cfg(unix)]
+    pub(crate) fn as_raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        match self {
+            Socket::Udp(s) => Some(std::os::fd::AsRawFd::as_raw_fd(&**s)),
+            Socket::Sim(_) => None,
+        }
+    }
+
+    /// Set the DSCP on outgoing packets: `IP_TOS` for IPv4 sockets and
+    /// `IPV6_TCLASS` (unix only) for IPv6 ones, with a best-effort `IP_TOS`
+    /// too on the latter for dual-stack v4-mapped traffic. A no-op over a
+    /// simulated network.
+    pub(crate) fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        let Socket::Udp(udp) = self else {
+            return Ok(());
+        };
+        // DSCP occupies the upper six bits of the TOS / traffic-class byte.
+        let tos = u32::from(dscp) << 2;
+        if self.local_addr()?.is_ipv4() {
+            return socket2::SockRef::from(&**udp).set_tos(tos);
+        }
+        #[cfg(unix)]
+        {
+            let value = tos as libc::c_int;
+            let rc = unsafe {
+                libc::setsockopt(
+                    std::os::fd::AsRawFd::as_raw_fd(&**udp),
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_TCLASS,
+                    std::ptr::from_ref(&value).cast(),
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            };
+            if rc != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let _ = socket2::SockRef::from(&**udp).set_tos(tos);
+            Ok(())
+        }
+        #[cfg(not(unix))]
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "IPV6_TCLASS is not available on this platform",
+        ))
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Socket::Udp(s) => s.local_addr(),
+            Socket::Sim(s) => Ok(s.local_addr()),
+        }
+    }
+
+    pub(crate) async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        match self {
+            Socket::Udp(s) => s.send_to(buf, addr).await,
+            Socket::Sim(s) => s.send_to(buf, addr).await,
+        }
+    }
+
+    /// Best-effort synchronous send, for paths that cannot await (a Drop
+    /// or signal handler). The datagram is dropped when the socket would
+    /// block.
+    pub(crate) fn try_send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        match self {
+            Socket::Udp(s) => s.try_send_to(buf, addr),
+            Socket::Sim(s) => s.try_send_to(buf, addr),
+        }
+    }
+
+    pub(crate) async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self {
+            Socket::Udp(s) => s.recv_from(buf).await,
+            Socket::Sim(s) => s.recv_from(buf).await,
+        }
+    }
+}
@@ -0,0 +1,2588 @@
+//! Stream layer: ordered, reliable, prioritized byte streams (spec section 5).
+//!
+//! A [`Stream`] is a lightweight bidirectional flow multiplexed with others
+//! onto a channel. Streams form a hierarchy: any stream can spawn substreams,
+//! and top-level application streams are substreams of the channel root.
+
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::future::poll_fn;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::channel::{ChannelShared, Role};
+use crate::decongestion::{self, CongestionAlgorithm};
+use crate::error::{Error, Result};
+use crate::packet::{decode_be_uint, take};
+use crate::pool::BufferPool;
+
+/// The channel root stream is always implicitly attached with LSID 0.
+pub(crate) const ROOT_LSID: u32 = 0;
+
+/// Default cap on bytes buffered and in flight per stream.
+pub(crate) const DEFAULT_SEND_WINDOW: usize = 256 * 1024;
+
+/// Default receive window a stream can advertise when memory is plentiful.
+pub(crate) const DEFAULT_RECV_WINDOW: usize = 256 * 1024;
+
+/// Cap on out-of-order bytes buffered beyond the contiguous frontier.
+/// Fragments further ahead are dropped and left to retransmission, so a
+/// single lost packet cannot make the receiver buffer unbounded future data.
+pub(crate) const MAX_REORDER_GAP: u64 = 256 * 1024;
+
+/// Permanent Unique Stream Identifier (spec section 5, "Stream IDs - USID").
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Usid {
+    /// Half-channel identifier for the originating direction.
+    pub half_channel: [u8; 16],
+    /// Stream counter within that half-channel.
+    pub counter: u64,
+}
+
+impl Usid {
+    /// Encoded size on the wire: half-channel id plus counter.
+    pub(crate) const WIRE_SIZE: usize = 24;
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.half_channel);
+        buf.extend_from_slice(&self.counter.to_be_bytes());
+    }
+
+    pub(crate) fn decode(buf: &mut &[u8]) -> Result<Self> {
+        let mut half_channel = [0u8; 16];
+        half_channel.copy_from_slice(take(buf, 16)?);
+        let counter = decode_be_uint(take(buf, 8)?);
+        Ok(Usid {
+            half_channel,
+            counter,
+        })
+    }
+}
+
+/// Allocates local stream IDs (LSIDs) with role parity (spec section 5):
+/// the initiator opens streams on the odd side, the responder on the even
+/// side, so simultaneously-active streams on the two sides can never share
+/// an ID and anyone can tell who opened a stream from its ID alone.
+pub(crate) struct StreamIdAllocator {
+    next: u32,
+}
+
+impl StreamIdAllocator {
+    pub(crate) fn new(role: Role) -> Self {
+        StreamIdAllocator {
+            next: match role {
+                Role::Initiator => 1,
+                Role::Responder => 2,
+            },
+        }
+    }
+
+    /// Hand out the next free ID on this side's parity, skipping any still
+    /// held by a long-lived stream.
+    pub(crate) fn alloc(&mut self, in_use: &HashMap<u32, Arc<StreamShared>>) -> u32 {
+        while in_use.contains_key(&self.next) {
+            self.next += 2;
+        }
+        let lsid = self.next;
+        self.next += 2;
+        lsid
+    }
+
+    /// Keep `lsid` and everything below it from being handed out: inbound
+    /// stream pairs land on our parity without going through [`alloc`](Self::alloc).
+    pub(crate) fn reserve(&mut self, lsid: u32) {
+        self.next = self.next.max(lsid + 2);
+    }
+
+    /// Whether `lsid` was opened by the channel initiator. The root stream
+    /// (LSID 0) belongs to the channel itself rather than either side.
+    pub(crate) fn initiator(lsid: u32) -> bool {
+        lsid != ROOT_LSID && lsid % 2 == 1
+    }
+}
+
+/// A queued span of outgoing stream data.
+///
+/// The `acked` flag is shared between the send queues and the channel's
+/// sent-packet log so that a spuriously retransmitted chunk is only
+/// accounted once.
+#[derive(Clone)]
+pub(crate) struct Chunk {
+    pub(crate) offset: u64,
+    pub(crate) data: Bytes,
+    pub(crate) fin: bool,
+    pub(crate) record: bool,
+    pub(crate) acked: Arc<AtomicBool>,
+}
+
+impl Chunk {
+    fn new(offset: u64, data: Bytes, fin: bool, record: bool) -> Self {
+        Chunk {
+            offset,
+            data,
+            fin,
+            record,
+            acked: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub(crate) fn is_acked(&self) -> bool {
+        self.acked.load(Ordering::Relaxed)
+    }
+}
+
+/// Reassembly of received stream data into an ordered byte sequence.
+pub(crate) struct Reassembly {
+    /// Next in-order offset we have not yet buffered.
+    next: u64,
+    /// Out-of-order segments beyond `next`.
+    segments: BTreeMap<u64, Bytes>,
+    /// In-order bytes ready for the application, starting at `read_pos`.
+    readable: VecDeque<u8>,
+    /// Absolute stream offset of the front of `readable`.
+    read_pos: u64,
+    /// Absolute end offsets of received records.
+    record_ends: BTreeSet<u64>,
+    /// Total stream length, once the FIN offset is known.
+    fin_offset: Option<u64>,
+    /// Keep everything received instead of draining on read, so
+    /// [`Stream::read_at`] can address consumed bytes by offset.
+    retain: bool,
+}
+
+impl Reassembly {
+    pub(crate) fn new() -> Self {
+        Reassembly {
+            next: 0,
+            segments: BTreeMap::new(),
+            readable: VecDeque::new(),
+            read_pos: 0,
+            record_ends: BTreeSet::new(),
+            fin_offset: None,
+            retain: false,
+        }
+    }
+
+    /// Switch to retain mode: reads copy without draining, and the whole
+    /// stream stays buffered (and pool-charged) until the stream goes away.
+    pub(crate) fn retain_all(&mut self) {
+        self.retain = true;
+    }
+
+    pub(crate) fn retains(&self) -> bool {
+        self.retain
+    }
+
+    /// Bytes available to an in-order read. In retain mode consumed bytes
+    /// stay in `readable`, so this subtracts the cursor.
+    pub(crate) fn unread(&self) -> usize {
+        let consumed = if self.retain { self.read_pos as usize } else { 0 };
+        self.readable.len() - consumed
+    }
+
+    /// Insert received data; returns true if anything became readable or the
+    /// end of stream was reached.
+    pub(crate) fn insert(&mut self, offset: u64, data: Bytes, fin: bool, record: bool) -> bool {
+        let end = offset + data.len() as u64;
+        // Enforce the reorder gap: fragments wholly beyond the frontier that
+        // would extend the buffered range past the cap are not accepted.
+        if offset > self.next && end > self.next + MAX_REORDER_GAP {
+            return false;
+        }
+        if record {
+            // A zero-length record frame carries a bare boundary at `offset`.
+            self.record_ends.insert(end);
+        }
+        if fin {
+            self.fin_offset = Some(end);
+        }
+        let before = self.readable.len();
+        if end > self.next {
+            // Keep only the part we have not already buffered.
+            let data = if offset < self.next {
+                data.slice((self.next - offset) as usize..)
+            } else {
+                data
+            };
+            let offset = offset.max(self.next);
+            self.segments.entry(offset).or_insert(data);
+            self.drain_contiguous();
+        }
+        self.readable.len() > before || self.at_end()
+    }
+
+    /// Contiguous received byte ranges: the in-order prefix, then any
+    /// out-of-order segments, with touching or overlapping entries merged.
+    /// The holes between entries are exactly the gaps blocking reassembly.
+    pub(crate) fn received_ranges(&self) -> Vec<Range<u64>> {
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        if self.next > 0 {
+            ranges.push(0..self.next);
+        }
+        for (&offset, data) in &self.segments {
+            let end = offset + data.len() as u64;
+            match ranges.last_mut() {
+                Some(last) if offset <= last.end => last.end = last.end.max(end),
+                _ => ranges.push(offset..end),
+            }
+        }
+        ranges
+    }
+
+    fn drain_contiguous(&mut self) {
+        while let Some((&offset, _)) = self.segments.first_key_value() {
+            if offset > self.next {
+                break;
+            }
+            let (offset, data) = self.segments.pop_first().unwrap();
+            let end = offset + data.len() as u64;
+            if end > self.next {
+                let skip = (self.next - offset) as usize;
+                self.readable.extend(&data[skip..]);
+                self.next = end;
+            }
+        }
+    }
+
+    /// Whether all data up to the FIN has been consumed.
+    pub(crate) fn at_end(&self) -> bool {
+        self.fin_offset == Some(self.next) && self.unread() == 0
+    }
+
+    pub(crate) fn is_readable(&self) -> bool {
+        self.unread() > 0
+    }
+
+    pub(crate) fn readable_len(&self) -> usize {
+        self.readable.len()
+    }
+
+    /// Out-of-order bytes currently buffered beyond the contiguous frontier.
+    pub(crate) fn reorder_depth(&self) -> usize {
+        self.segments.values().map(Bytes::len).sum()
+    }
+
+    /// Pull up to `buf.len()` in-order bytes.
+    pub(crate) fn read(&mut self, buf: &mut [u8]) -> usize {
+        if self.retain {
+            let n = self.read_at(self.read_pos, buf);
+            self.read_pos += n as u64;
+            return n;
+        }
+        let n = buf.len().min(self.readable.len());
+        for (dst, src) in buf.iter_mut().zip(self.readable.drain(..n)) {
+            *dst = src;
+        }
+        self.read_pos += n as u64;
+        n
+    }
+
+    /// Absolute end offset of the first record boundary past the read
+    /// cursor, as soon as its frame has arrived.
+    pub(crate) fn next_boundary(&self) -> Option<u64> {
+        self.record_ends.range(self.read_pos + 1..).next().copied()
+    }
+
+    /// Pull one complete record, if fully received.
+    pub(crate) fn read_record(&mut self) -> Option<Vec<u8>> {
+        let end = self.next_boundary()?;
+        if end > self.next {
+            return None; // record not fully received yet
+        }
+        let len = (end - self.read_pos) as usize;
+        if self.retain {
+            let start = self.read_pos as usize;
+            let record: Vec<u8> = self.readable.iter().skip(start).take(len).copied().collect();
+            self.read_pos = end;
+            return Some(record);
+        }
+        if len > self.readable.len() {
+            return None; // earlier bytes were consumed by plain reads
+        }
+        let record: Vec<u8> = self.readable.drain(..len).collect();
+        self.read_pos = end;
+        self.record_ends.remove(&end);
+        Some(record)
+    }
+
+    /// Copy up to `buf.len()` bytes at absolute `offset` without consuming
+    /// anything; retain mode only. Stops at the contiguous frontier, so
+    /// the count is short when `offset` reaches into a gap or the tail.
+    pub(crate) fn read_at(&self, offset: u64, buf: &mut [u8]) -> usize {
+        debug_assert!(self.retain, "read_at outside retain mode");
+        let start = (offset.min(self.next)) as usize;
+        let n = buf.len().min(self.readable.len().saturating_sub(start));
+        for (i, dst) in buf[..n].iter_mut().enumerate() {
+            *dst = self.readable[start + i];
+        }
+        n
+    }
+}
+
+/// Shared state backing one stream.
+pub(crate) struct StreamShared {
+    /// LSID in our own ID space.
+    pub(crate) lsid: u32,
+    pub(crate) usid: Usid,
+    /// The stream's primary channel. Replaced when a hibernated stream
+    /// reattaches after its channel was torn down.
+    channel: Mutex<Weak<ChannelShared>>,
+    /// Wakes the host's reattach watcher when a detached stream sees
+    /// activity.
+    pub(crate) detach_wake: Mutex<Option<Arc<tokio::sync::Notify>>>,
+    /// The live sender group, so repeated [`Stream::sender`] calls join it.
+    sender: Mutex<Weak<SenderInner>>,
+    pub(crate) core: Mutex<StreamCore>,
+    /// Multipath send state, behind its own lock so channel pumps can
+    /// consult it without taking the stream core.
+    multipath: Mutex<Option<Multipath>>,
+}
+
+/// Send-side multipath state: the channels this stream's packets may use.
+struct Multipath {
+    policy: PathPolicy,
+    paths: Vec<Weak<ChannelShared>>,
+    /// Round-robin cursor over `paths`.
+    next: usize,
+}
+
+/// Path-selection policy for [`Stream::enable_multipath`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathPolicy {
+    /// Alternate packets over the paths in turn.
+    RoundRobin,
+    /// Send on the path with the lowest smoothed RTT estimate.
+    LowestRtt,
+}
+
+pub(crate) struct StreamCore {
+    /// Host-wide pool all buffered bytes are charged against.
+    pub(crate) pool: Arc<BufferPool>,
+    /// Parent LSID in our ID space, sent while the INIT flag is still needed.
+    pub(crate) parent_lsid: u32,
+    /// Set once any frame of this stream has been acknowledged; INIT is
+    /// repeated on every frame until then so a lost attach cannot strand
+    /// the stream.
+    pub(crate) init_acked: bool,
+    /// This stream holds a slot against the channel's substream cap.
+    pub(crate) open_slot: bool,
+    /// Close behavior set by [`Stream::set_linger`]; `None` until configured,
+    /// in which case [`Stream::close`] waits for the FIN acknowledgement
+    /// without a deadline.
+    linger: Option<Option<Duration>>,
+    /// Metadata to ride on the opening STREAM frame, on the initiating side.
+    pub(crate) metadata_out: Option<Bytes>,
+    /// Metadata carried by the peer's opening STREAM frame.
+    pub(crate) open_metadata: Option<Bytes>,
+    /// The (service, protocol) pair this top-level stream was connected or
+    /// accepted under; `None` for substreams.
+    pub(crate) service: Option<(String, String)>,
+    /// Fresh data awaiting first transmission.
+    pub(crate) out: VecDeque<Chunk>,
+    /// Data declared lost, awaiting retransmission.
+    pub(crate) rtx: VecDeque<Chunk>,
+    /// Next offset to assign to written data.
+    pub(crate) next_offset: u64,
+    /// Bytes queued in `out` and `rtx`.
+    pub(crate) buffered: usize,
+    /// Bytes handed to the channel and not yet acknowledged.
+    pub(crate) outstanding: usize,
+    /// Application payload bytes sent in first transmissions.
+    pub(crate) payload_bytes: u64,
+    /// Protocol bytes spent on this stream: frame headers, retransmitted
+    /// payload, and its share of packet-level costs (see
+    /// [`Stream::total_overhead_bytes`]).
+    pub(crate) overhead_bytes: u64,
+    pub(crate) send_window: usize,
+    /// Base receive window advertised when the pool is not under pressure.
+    pub(crate) recv_window: usize,
+    /// Explicit receive window set by [`Stream::set_advertised_window`],
+    /// overriding the automatic pool-derived value.
+    pub(crate) window_override: Option<usize>,
+    /// Application cap on outstanding unacknowledged bytes, tighter than
+    /// whatever the congestion and flow-control windows would allow.
+    pub(crate) max_in_flight: Option<usize>,
+    /// Bytes the application consumed since the channel pump last
+    /// collected them, feeding connection-level flow-control grants.
+    pub(crate) conn_consumed_pending: u64,
+    /// Absolute offset cap from the peer's WINDOW_UPDATE frames, raised
+    /// monotonically; `None` until the peer imposes one.
+    pub(crate) send_limit: Option<u64>,
+    /// When reassembly last advanced: the eviction order under the
+    /// channel's reassembly memory cap.
+    pub(crate) last_progress: Instant,
+    /// Opt-in rolling SHA-256 over the payload bytes this stream carries:
+    /// written bytes and in-order reads both feed it. `None` until
+    /// [`Stream::enable_content_digest`].
+    content_digest: Option<Sha256>,
+    /// Application send-rate cap in bytes per second; 0 means unlimited.
+    pub(crate) rate_limit: u64,
+    /// Current token bucket balance for the rate limiter, in bytes.
+    rate_tokens: u64,
+    /// When the bucket last earned tokens.
+    rate_refilled: Instant,
+    pub(crate) fin_sent: bool,
+    pub(crate) fin_acked: bool,
+    /// Writes are no longer accepted.
+    pub(crate) send_closed: bool,
+    /// Delay sub-packet-size sends while earlier data is unacknowledged.
+    pub(crate) nagle: bool,
+    pub(crate) priority: u32,
+    /// Proportional bandwidth share among streams of equal priority.
+    pub(crate) weight: u16,
+    /// Normalized service received, in bytes scaled down by the weight;
+    /// the channel serves the backlogged stream with the lowest debt.
+    pub(crate) sched_debt: u64,
+    /// Whether the stream had sendable data in the last scheduling pass.
+    pub(crate) sched_backlogged: bool,
+    pub(crate) recv: Reassembly,
+    /// Reads were shut down locally; inbound data is discarded.
+    pub(crate) read_shutdown: bool,
+    /// The peer shut its read side down (with this application code);
+    /// our writes are pointless.
+    pub(crate) peer_stopped: Option<u32>,
+    /// Set when the stream was reset locally or by the peer.
+    pub(crate) reset: Option<(u32, String)>,
+    /// Final byte offset the peer said its send side reached, when its
+    /// RESET carried one; bookkeeping for interrupted transfers.
+    pub(crate) peer_final_offset: Option<u64>,
+    /// Set by [`Stream::abort`]: every held byte was discharged in bulk, so
+    /// per-chunk ack and loss accounting must not touch the pool again.
+    pub(crate) aborted: bool,
+    /// Set when the channel carrying this stream went away.
+    pub(crate) conn_closed: bool,
+    /// Why the channel went away, when it was aborted rather than closed.
+    pub(crate) conn_reset: Option<(u32, String)>,
+    /// Why the most recent write attempt pended, for diagnostics.
+    pub(crate) blocked: Option<BlockReason>,
+    /// Inbound top-level stream still awaiting its service request record.
+    pub(crate) pending_service: bool,
+    /// Substreams spawned by the peer, awaiting accept.
+    pub(crate) incoming_subs: VecDeque<Stream>,
+    read_wakers: Vec<Waker>,
+    write_wakers: Vec<Waker>,
+    /// Wakers parked in [`Stream::poll_progress`], woken only when the
+    /// acked offset advances (or the stream tears down).
+    progress_wakers: Vec<Waker>,
+    /// Largest acked offset already delivered through
+    /// [`Stream::poll_progress`].
+    progress_reported: u64,
+}
+
+impl StreamShared {
+    pub(crate) fn new(
+        lsid: u32,
+        parent_lsid: u32,
+        usid: Usid,
+        channel: Weak<ChannelShared>,
+        pool: Arc<BufferPool>,
+    ) -> Arc<Self> {
+        Arc::new(StreamShared {
+            lsid,
+            usid,
+            channel: Mutex::new(channel),
+            detach_wake: Mutex::new(None),
+            sender: Mutex::new(Weak::new()),
+            multipath: Mutex::new(None),
+            core: Mutex::new(StreamCore {
+                pool,
+                parent_lsid,
+                init_acked: false,
+                open_slot: false,
+                linger: None,
+                metadata_out: None,
+                open_metadata: None,
+                service: None,
+                out: VecDeque::new(),
+                rtx: VecDeque::new(),
+                next_offset: 0,
+                buffered: 0,
+                outstanding: 0,
+                send_window: DEFAULT_SEND_WINDOW,
+                recv_window: DEFAULT_RECV_WINDOW,
+                window_override: None,
+                max_in_flight: None,
+                conn_consumed_pending: 0,
+                send_limit: None,
+                last_progress: Instant::now(),
+                content_digest: None,
+                rate_limit: 0,
+                rate_tokens: 0,
+                rate_refilled: Instant::now(),
+                payload_bytes: 0,
+                overhead_bytes: 0,
+                fin_sent: false,
+                fin_acked: false,
+                send_closed: false,
+                nagle: true,
+                priority: 0,
+                weight: 1,
+                sched_debt: 0,
+                sched_backlogged: false,
+                recv: Reassembly::new(),
+                read_shutdown: false,
+                peer_stopped: None,
+                reset: None,
+                peer_final_offset: None,
+                aborted: false,
+                conn_closed: false,
+                conn_reset: None,
+                blocked: None,
+                pending_service: false,
+                incoming_subs: VecDeque::new(),
+                read_wakers: Vec::new(),
+                write_wakers: Vec::new(),
+                progress_wakers: Vec::new(),
+                progress_reported: 0,
+            }),
+        })
+    }
+
+    pub(crate) fn lock(&self) -> std::sync::MutexGuard<'_, StreamCore> {
+        self.core.lock().unwrap()
+    }
+
+    pub(crate) fn channel(&self) -> Option<Arc<ChannelShared>> {
+        self.channel.lock().unwrap().upgrade()
+    }
+
+    /// Whether `chan` is this stream's primary channel.
+    pub(crate) fn is_primary(&self, chan: &ChannelShared) -> bool {
+        std::ptr::eq(self.channel.lock().unwrap().as_ptr(), chan as *const _)
+    }
+
+    /// Point the stream at a new primary channel (reattach).
+    pub(crate) fn set_channel(&self, chan: &Arc<ChannelShared>) {
+        *self.channel.lock().unwrap() = Arc::downgrade(chan);
+    }
+
+    /// Drop the primary channel reference (detach).
+    pub(crate) fn clear_channel(&self) {
+        *self.channel.lock().unwrap() = Weak::new();
+    }
+
+    /// Give the substream slot back to the channel, at most once.
+    pub(crate) fn release_open_slot(&self) {
+        if !std::mem::take(&mut self.lock().open_slot) {
+            return;
+        }
+        if let Some(channel) = self.channel() {
+            channel.release_open_slot();
+        }
+    }
+
+    fn nudge(&self) {
+        let mp = self.multipath.lock().unwrap();
+        if let Some(mp) = &*mp {
+            for path in &mp.paths {
+                if let Some(channel) = path.upgrade() {
+                    channel.notify.notify_one();
+                }
+            }
+            return;
+        }
+        drop(mp);
+        if let Some(channel) = self.channel() {
+            channel.notify.notify_one();
+        } else if let Some(wake) = &*self.detach_wake.lock().unwrap() {
+            wake.notify_one();
+        }
+    }
+
+    /// Whether the path policy lets `chan` carry this stream's next packet.
+    /// Always true for single-path streams.
+    pub(crate) fn path_allows(&self, chan: &ChannelShared) -> bool {
+        let mp = self.multipath.lock().unwrap();
+        let Some(mp) = &*mp else { return true };
+        match mp.policy {
+            PathPolicy::RoundRobin => {
+                let turn = &mp.paths[mp.next % mp.paths.len()];
+                // A torn-down path must not hold the turn forever.
+                if turn.upgrade().is_none() {
+                    return true;
+                }
+                std::ptr::eq(turn.as_ptr(), chan as *const _)
+            }
+            PathPolicy::LowestRtt => {
+                let best = mp
+                    .paths
+                    .iter()
+                    .filter_map(Weak::upgrade)
+                    .min_by_key(|c| match c.srtt_hint() {
+                        0 => u64::MAX,
+                        rtt => rtt,
+                    });
+                best.is_some_and(|c| std::ptr::eq(Arc::as_ptr(&c), chan as *const _))
+            }
+        }
+    }
+
+    /// Advance the round-robin cursor after a chunk was pulled, and hand the
+    /// turn to the next path's pump: it may be asleep with nothing else due.
+    pub(crate) fn note_path_used(&self) {
+        if let Some(mp) = &mut *self.multipath.lock().unwrap() {
+            mp.next = mp.next.wrapping_add(1);
+            if let Some(channel) = mp.paths[mp.next % mp.paths.len()].upgrade() {
+                channel.notify.notify_one();
+            }
+        }
+    }
+
+    /// Account an acknowledged chunk; idempotent per chunk.
+    pub(crate) fn chunk_acked(&self, chunk: &Chunk) {
+        if chunk.acked.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let mut core = self.lock();
+        if core.aborted {
+            return;
+        }
+        core.outstanding = core.outstanding.saturating_sub(chunk.data.len());
+        core.pool.discharge(chunk.data.len());
+        core.init_acked = true;
+        if chunk.fin {
+            core.fin_acked = true;
+        }
+        core.wake_writers();
+        if !chunk.data.is_empty() {
+            core.wake_progress();
+        }
+    }
+
+    /// Requeue a lost chunk for retransmission.
+    pub(crate) fn chunk_lost(&self, chunk: Chunk) {
+        if chunk.is_acked() {
+            return;
+        }
+        let mut core = self.lock();
+        if core.aborted {
+            return;
+        }
+        core.outstanding = core.outstanding.saturating_sub(chunk.data.len());
+        core.buffered += chunk.data.len();
+        core.rtx.push_back(chunk);
+        drop(core);
+        self.nudge();
+    }
+
+    /// Tear the stream down because its channel went away.
+    pub(crate) fn connection_closed(&self) {
+        let mut core = self.lock();
+        core.conn_closed = true;
+        let held = core.buffered + core.outstanding + core.recv.readable_len();
+        core.buffered = 0;
+        core.outstanding = 0;
+        core.pool.discharge(held);
+        core.wake_all();
+    }
+
+    /// Like [`connection_closed`](Self::connection_closed), but records the
+    /// abort code so readers and writers see the connection-reset reason.
+    pub(crate) fn connection_reset(&self, code: u32, reason: &str) {
+        {
+            let mut core = self.lock();
+            core.conn_reset = Some((code, reason.to_string()));
+        }
+        self.connection_closed();
+    }
+}
+
+impl StreamCore {
+    pub(crate) fn wake_readers(&mut self) {
+        for w in self.read_wakers.drain(..) {
+            w.wake();
+        }
+    }
+
+    pub(crate) fn wake_writers(&mut self) {
+        for w in self.write_wakers.drain(..) {
+            w.wake();
+        }
+    }
+
+    pub(crate) fn wake_all(&mut self) {
+        self.wake_readers();
+        self.wake_writers();
+        self.wake_progress();
+    }
+
+    fn wake_progress(&mut self) {
+        for w in self.progress_wakers.drain(..) {
+            w.wake();
+        }
+    }
+
+    fn send_space(&self) -> usize {
+        self.send_window
+            .min(self.max_in_flight.unwrap_or(usize::MAX))
+            .saturating_sub(self.buffered + self.outstanding)
+            .min(self.pool.available())
+    }
+
+    /// Record what a write just stalled on: exhausted window credit means
+    /// flow control; otherwise the buffer pool dried up under a backlog
+    /// the congestion controller is still draining.
+    fn note_blocked(&mut self) {
+        let window = self.send_window.min(self.max_in_flight.unwrap_or(usize::MAX));
+        self.blocked = Some(
+            if window <= self.buffered + self.outstanding {
+                BlockReason::FlowControl
+            } else {
+                BlockReason::Congestion
+            },
+        );
+    }
+
+    fn queue_chunk(&mut self, data: Bytes, fin: bool, record: bool) {
+        if let Some(digest) = &mut self.content_digest {
+            digest.update(&data);
+        }
+        let chunk = Chunk::new(self.next_offset, data, fin, record);
+        self.next_offset += chunk.data.len() as u64;
+        self.buffered += chunk.data.len();
+        self.pool.charge(chunk.data.len());
+        self.out.push_back(chunk);
+    }
+
+    pub(crate) fn queue_record(&mut self, data: &[u8]) {
+        self.queue_chunk(Bytes::copy_from_slice(data), false, true);
+    }
+
+    /// Mark a message boundary at the current write offset: the last
+    /// queued chunk carries the record flag, or a zero-length record chunk
+    /// does when nothing is buffered to carry it.
+    pub(crate) fn queue_boundary(&mut self) {
+        match self.out.back_mut() {
+            Some(last) if !last.fin => last.record = true,
+            _ => self.queue_chunk(Bytes::new(), false, true),
+        }
+    }
+
+    pub(crate) fn queue_fin(&mut self) {
+        if self.send_closed {
+            return;
+        }
+        self.send_closed = true;
+        self.queue_chunk(Bytes::new(), true, false);
+    }
+
+    /// Whether the channel pump should visit this stream.
+    ///
+    /// `full_frame` is the packet's worth of payload used for the Nagle
+    /// check: small trailing data is held back while earlier data is in
+    /// flight unless a record boundary or FIN forces it out.
+    pub(crate) fn sendable(&self, full_frame: usize) -> bool {
+        if self.reset.is_some() || self.conn_closed {
+            return false;
+        }
+        if self.max_in_flight.is_some_and(|cap| self.outstanding >= cap) {
+            return false;
+        }
+        if self.rtx.iter().any(|c| !c.is_acked()) {
+            return true;
+        }
+        if self.out.is_empty() {
+            return false;
+        }
+        if !self.nagle || self.outstanding == 0 || self.buffered >= full_frame {
+            return true;
+        }
+        self.out.iter().any(|c| c.record || c.fin)
+    }
+
+    /// Pull the next chunk to transmit, splitting it to fit `max_bytes`.
+    pub(crate) fn next_chunk(&mut self, max_bytes: usize) -> Option<Chunk> {
+        let mut max_bytes = match self.max_in_flight {
+            Some(cap) => max_bytes.min(cap.saturating_sub(self.outstanding)),
+            None => max_bytes,
+        };
+        // The peer's WINDOW_UPDATE limit binds fresh data only; queued
+        // retransmissions re-cover offsets below the monotonic limit.
+        if self.rtx.is_empty() {
+            if let Some(limit) = self.send_limit {
+                let head = self.next_offset - self.buffered as u64;
+                max_bytes = max_bytes
+                    .min(usize::try_from(limit.saturating_sub(head)).unwrap_or(usize::MAX));
+            }
+        }
+        if max_bytes == 0 {
+            return None;
+        }
+        loop {
+            let from_rtx = !self.rtx.is_empty();
+            let queue = if from_rtx { &mut self.rtx } else { &mut self.out };
+            let mut chunk = queue.pop_front()?;
+            if chunk.is_acked() {
+                self.buffered = self.buffered.saturating_sub(chunk.data.len());
+                continue;
+            }
+            if chunk.data.len() > max_bytes {
+                if max_bytes == 0 {
+                    queue.push_front(chunk);
+                    return None;
+                }
+                let rest = Chunk::new(
+                    chunk.offset + max_bytes as u64,
+                    chunk.data.split_off(max_bytes),
+                    chunk.fin,
+                    chunk.record,
+                );
+                chunk.fin = false;
+                chunk.record = false;
+                chunk.acked = Arc::new(AtomicBool::new(false));
+                let queue = if from_rtx { &mut self.rtx } else { &mut self.out };
+                queue.push_front(rest);
+            }
+            self.buffered = self.buffered.saturating_sub(chunk.data.len());
+            self.outstanding += chunk.data.len();
+            if from_rtx {
+                self.overhead_bytes += chunk.data.len() as u64;
+            } else {
+                self.payload_bytes += chunk.data.len() as u64;
+            }
+            if chunk.fin {
+                self.fin_sent = true;
+            }
+            return Some(chunk);
+        }
+    }
+
+    /// Raise the absolute offset the peer permits us to reach, from a
+    /// WINDOW_UPDATE frame. Stale (lower) updates are ignored, so
+    /// reordered frames cannot shrink the limit.
+    pub(crate) fn apply_window_update(&mut self, max_offset: u64) {
+        if self.send_limit.is_some_and(|cur| cur >= max_offset) {
+            return;
+        }
+        self.send_limit = Some(max_offset);
+        self.wake_writers();
+    }
+
+    /// Whether unacknowledged retransmissions are queued.
+    pub(crate) fn has_rtx(&self) -> bool {
+        self.rtx.iter().any(|c| !c.is_acked())
+    }
+
+    /// Bytes the send rate limiter allows right now, refilling the token
+    /// bucket from the time elapsed first. Unlimited when no limit is set.
+    pub(crate) fn rate_allowance(&mut self, now: Instant) -> usize {
+        if self.rate_limit == 0 {
+            return usize::MAX;
+        }
+        let elapsed = now.saturating_duration_since(self.rate_refilled);
+        let earned = (elapsed.as_nanos() * u128::from(self.rate_limit) / 1_000_000_000) as u64;
+        if earned > 0 {
+            self.rate_tokens = (self.rate_tokens + earned).min(self.rate_burst());
+            self.rate_refilled = now;
+        }
+        self.rate_tokens as usize
+    }
+
+    /// Debit the bucket for bytes handed to the packetizer.
+    pub(crate) fn rate_consume(&mut self, bytes: usize) {
+        if self.rate_limit > 0 {
+            self.rate_tokens = self.rate_tokens.saturating_sub(bytes as u64);
+        }
+    }
+
+    /// Bucket capacity: 50ms at the configured rate, at least one packet's
+    /// payload so the limiter can never wedge a stream entirely.
+    fn rate_burst(&self) -> u64 {
+        (self.rate_limit / 20).max(decongestion::MSS as u64)
+    }
+
+    /// When the limiter will have a packet's worth of tokens again, for a
+    /// stream with data pending but held back by its rate limit. `None`
+    /// when no limit binds.
+    pub(crate) fn rate_ready_at(&self, full_frame: usize) -> Option<Instant> {
+        if self.rate_limit == 0 || !self.sendable(full_frame) {
+            return None;
+        }
+        let want = (full_frame as u64).min(self.rate_burst());
+        let need = want.saturating_sub(self.rate_tokens);
+        if need == 0 {
+            return None;
+        }
+        let wait = Duration::from_nanos(need.saturating_mul(1_000_000_000) / self.rate_limit);
+        Some(self.rate_refilled + wait)
+    }
+
+    /// Record received stream data and wake any blocked readers.
+    ///
+    /// Inbound bytes are charged to the pool unconditionally: refusing them
+    /// would break reliability, so pool pressure instead shrinks the window
+    /// we advertise.
+    pub(crate) fn receive(&mut self, offset: u64, data: Bytes, fin: bool, record: bool) {
+        if self.reset.is_some() || self.read_shutdown {
+            return;
+        }
+        let before = self.recv.readable_len();
+        let grew = self.recv.insert(offset, data, fin, record);
+        self.pool.charge(self.recv.readable_len() - before);
+        if grew {
+            self.last_progress = Instant::now();
+            self.wake_readers();
+        }
+    }
+
+    /// The peer asked us to stop sending: drop queued data and fail writers.
+    pub(crate) fn apply_stop_sending(&mut self, error_code: u32) {
+        self.peer_stopped = Some(error_code);
+        self.out.clear();
+        self.rtx.clear();
+        self.pool.discharge(self.buffered);
+        self.buffered = 0;
+        self.send_closed = true;
+        self.wake_writers();
+    }
+
+    /// Return consumed readable bytes to the pool.
+    ///
+    /// After teardown the stream's held bytes were discharged in bulk, so
+    /// late reads of already-buffered data must not discharge again.
+    pub(crate) fn release_read(&mut self, n: usize) {
+        if !self.conn_closed {
+            self.pool.discharge(n);
+            self.conn_consumed_pending += n as u64;
+        }
+    }
+
+    pub(crate) fn apply_reset(&mut self, code: u32, reason: String) {
+        if self.reset.is_none() {
+            self.reset = Some((code, reason));
+        }
+        self.out.clear();
+        self.rtx.clear();
+        self.pool.discharge(self.buffered);
+        self.buffered = 0;
+        self.send_closed = true;
+        self.wake_all();
+    }
+}
+
+/// Why a write attempt could not make progress, from
+/// [`Stream::write_block_reason`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockReason {
+    /// The peer's receive window is full: every byte of credit is already
+    /// buffered or in flight.
+    FlowControl,
+    /// Buffer credit ran out while the congestion controller holds the
+    /// backlog back; the window itself still has room.
+    Congestion,
+}
+
+/// Options for [`Stream::open_substream_with`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SubstreamOptions {
+    /// What to do when the concurrent substream cap is reached.
+    pub on_limit: OnLimit,
+    /// Retain everything received so [`Stream::read_at`] can address it by
+    /// offset, instead of reclaiming consumed bytes. The whole stream
+    /// stays in memory, charged to the buffer pool, until the stream is
+    /// dropped.
+    pub retain_all: bool,
+}
+
+/// Behavior of a substream open at the concurrency cap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnLimit {
+    /// Fail the open with [`Error::SubstreamLimit`].
+    #[default]
+    Reject,
+    /// Wait for a slot freed by an earlier substream closing.
+    Queue,
+}
+
+/// The feature set in effect on a channel after SETTINGS negotiation:
+/// the intersection of what the two ends advertised, from
+/// [`Stream::features`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    /// Unreliable datagrams ([`Stream::send_datagram`]) are available.
+    pub datagrams: bool,
+    /// Stream compression was agreed; see [`crate::HostBuilder::compression`].
+    pub compression: bool,
+}
+
+/// A read-only snapshot of the parameters governing a channel once the
+/// connect handshake and its SETTINGS exchange have completed, from
+/// [`Stream::connection_params`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionParams {
+    /// Wire protocol version in use. This implementation speaks only
+    /// version 1, so that is what every channel reports.
+    pub version: u16,
+    /// Cap on concurrently open locally-initiated substreams, from
+    /// [`crate::HostBuilder::max_substreams`].
+    pub max_substreams: usize,
+    /// Receive window each new stream on this end starts with.
+    pub initial_recv_window: usize,
+    /// Send window each new stream assumes until the peer advertises one.
+    pub initial_send_window: usize,
+    /// Unreliable datagrams were agreed in SETTINGS.
+    pub datagrams: bool,
+    /// Stream compression was agreed in SETTINGS.
+    pub compression: bool,
+    /// ECN congestion signals are in use; always `false` until the
+    /// reserved ECN hook is wired up.
+    pub ecn: bool,
+    /// The peer's advertised idle timeout; `None` until its SETTINGS
+    /// arrive (or forever, against a peer that predates the setting).
+    pub peer_idle_timeout: Option<Duration>,
+}
+
+/// A bidirectional SSS stream.
+///
+/// Obtained from [`crate::Host::connect`], [`crate::Listener::accept`] or
+/// [`Stream::open_substream`]. Dropping a stream half-closes its send side
+/// after any buffered data has been delivered.
+pub struct Stream {
+    pub(crate) shared: Arc<StreamShared>,
+    /// Application context attached to this handle; dropped with it.
+    context: Option<Box<dyn Any + Send + Sync>>,
+}
+
+impl Stream {
+    pub(crate) fn new(shared: Arc<StreamShared>) -> Self {
+        Stream {
+            shared,
+            context: None,
+        }
+    }
+
+    fn check_open(core: &StreamCore) -> Result<()> {
+        if let Some((code, reason)) = &core.reset {
+            return Err(Error::StreamReset {
+                code: *code,
+                reason: reason.clone(),
+            });
+        }
+        if let Some((code, reason)) = &core.conn_reset {
+            return Err(Error::ConnectionReset {
+                code: *code,
+                reason: reason.clone(),
+            });
+        }
+        if core.conn_closed {
+            return Err(Error::ConnectionClosed);
+        }
+        Ok(())
+    }
+
+    /// Queue `buf` for sending, returning how many bytes the send window
+    /// and buffer pool accepted. Waits only while no byte fits at all, so a
+    /// short count means the window filled: loop on the remainder.
+    pub async fn write(&self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write_some(&self.shared, buf).await
+    }
+
+    /// Queue all of `buf` as a complete record: the receiver can read it
+    /// back as a unit with [`Stream::read_record`], and it is pushed out
+    /// immediately.
+    pub async fn write_record(&self, buf: &[u8]) -> Result<()> {
+        self.write_inner(buf, true).await
+    }
+
+    /// Mark a message boundary at the current write offset, so the
+    /// receiver can split the byte sequence at the sender's intended
+    /// points without a length prefix. Boundaries ride the record flag of
+    /// the frames already carrying the data: they add no bytes to the
+    /// stream, and the receiver observes them via
+    /// [`Stream::next_boundary_offset`] or pulls whole messages with
+    /// [`Stream::read_record`].
+    pub fn write_message_boundary(&self) -> Result<()> {
+        let mut core = self.shared.lock();
+        Self::check_open(&core)?;
+        if let Some(code) = core.peer_stopped {
+            return Err(Error::PeerStoppedReading { code });
+        }
+        if core.send_closed {
+            return Err(Error::StreamClosed);
+        }
+        core.queue_boundary();
+        drop(core);
+        self.shared.nudge();
+        Ok(())
+    }
+
+    /// Queue an owned, refcounted buffer for sending without copying it:
+    /// the send queue holds slices of `data` until they are acknowledged,
+    /// and retransmissions reuse the same backing allocation. Queues the
+    /// whole buffer, waiting for window space as needed; for demand-driven
+    /// sending use [`Stream::write`] and its partial counts instead.
+    pub async fn write_bytes(&self, data: Bytes) -> Result<()> {
+        let mut offset = 0;
+        poll_fn(|cx| {
+            let mut core = self.shared.lock();
+            Self::check_open(&core)?;
+            if let Some(code) = core.peer_stopped {
+                return Poll::Ready(Err(Error::PeerStoppedReading { code }));
+            }
+            if core.send_closed {
+                return Poll::Ready(Err(Error::StreamClosed));
+            }
+            while offset < data.len() {
+                let space = core.send_space();
+                if space == 0 {
+                    core.write_wakers.push(cx.waker().clone());
+                    core.pool.register(cx.waker());
+                    return Poll::Pending;
+                }
+                let n = space.min(data.len() - offset);
+                core.queue_chunk(data.slice(offset..offset + n), false, false);
+                offset += n;
+                drop(core);
+                self.shared.nudge();
+                core = self.shared.lock();
+            }
+            Poll::Ready(Ok(()))
+        })
+        .await
+    }
+
+    /// Queue the same refcounted buffer on several streams at once: the
+    /// broadcast counterpart of [`Stream::write_bytes`]. Every send queue
+    /// holds slices of the one allocation, so a server pushing a payload
+    /// to many peers keeps a single copy in memory until the last
+    /// acknowledgement releases it. Each stream's flow control and
+    /// retransmission proceed independently -- a slow receiver delays its
+    /// own queueing, not the others'. Fails on the first stream error;
+    /// data already queued elsewhere stays queued.
+    pub async fn broadcast(streams: &[Stream], data: Bytes) -> Result<()> {
+        let mut offsets = vec![0usize; streams.len()];
+        poll_fn(|cx| {
+            let mut pending = false;
+            for (stream, offset) in streams.iter().zip(offsets.iter_mut()) {
+                if *offset == data.len() {
+                    continue;
+                }
+                let mut core = stream.shared.lock();
+                Self::check_open(&core)?;
+                if let Some(code) = core.peer_stopped {
+                    return Poll::Ready(Err(Error::PeerStoppedReading { code }));
+                }
+                if core.send_closed {
+                    return Poll::Ready(Err(Error::StreamClosed));
+                }
+                while *offset < data.len() {
+                    let space = core.send_space();
+                    if space == 0 {
+                        core.write_wakers.push(cx.waker().clone());
+                        core.pool.register(cx.waker());
+                        pending = true;
+                        break;
+                    }
+                    let n = space.min(data.len() - *offset);
+                    core.queue_chunk(data.slice(*offset..*offset + n), false, false);
+                    *offset += n;
+                }
+                drop(core);
+                stream.shared.nudge();
+            }
+            if pending {
+                Poll::Pending
+            } else {
+                Poll::Ready(Ok(()))
+            }
+        })
+        .await
+    }
+
+    /// Pump a reader into this stream until its EOF, returning the total
+    /// bytes forwarded. Each read is sized to the window space currently
+    /// available, so a slow receiver backpressures the source through
+    /// flow control and nothing accumulates beyond one read's worth.
+    /// With `fin` set the send side half-closes after the last byte, so
+    /// the peer sees end-of-stream exactly where the source ended. Built
+    /// for proxying: the caller hands over the reader and never touches
+    /// an intermediate buffer.
+    pub async fn send_from<R>(&self, mut src: R, fin: bool) -> Result<u64>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        use tokio::io::AsyncReadExt;
+
+        let mut buf = vec![0u8; 16 * 1024];
+        let mut total = 0u64;
+        loop {
+            // Wait until the window admits something, and learn how much.
+            let space = poll_fn(|cx| {
+                let mut core = self.shared.lock();
+                Self::check_open(&core)?;
+                if let Some(code) = core.peer_stopped {
+                    return Poll::Ready(Err(Error::PeerStoppedReading { code }));
+                }
+                if core.send_closed {
+                    return Poll::Ready(Err(Error::StreamClosed));
+                }
+                let space = core.send_space();
+                if space == 0 {
+                    core.write_wakers.push(cx.waker().clone());
+                    core.pool.register(cx.waker());
+                    return Poll::Pending;
+                }
+                Poll::Ready(Ok(space))
+            })
+            .await?;
+            let want = space.min(buf.len());
+            let n = src.read(&mut buf[..want]).await?;
+            if n == 0 {
+                if fin {
+                    self.close_send();
+                }
+                return Ok(total);
+            }
+            let mut core = self.shared.lock();
+            Self::check_open(&core)?;
+            core.queue_chunk(Bytes::copy_from_slice(&buf[..n]), false, false);
+            drop(core);
+            self.shared.nudge();
+            total += n as u64;
+        }
+    }
+
+    async fn write_inner(&self, buf: &[u8], record: bool) -> Result<()> {
+        let mut written = 0;
+        poll_fn(|cx| {
+            let mut core = self.shared.lock();
+            Self::check_open(&core)?;
+            if let Some(code) = core.peer_stopped {
+                return Poll::Ready(Err(Error::PeerStoppedReading { code }));
+            }
+            if core.send_closed {
+                return Poll::Ready(Err(Error::StreamClosed));
+            }
+            while written < buf.len() {
+                let space = core.send_space();
+                if space == 0 {
+                    core.write_wakers.push(cx.waker().clone());
+                    core.pool.register(cx.waker());
+                    return Poll::Pending;
+                }
+                let n = space.min(buf.len() - written);
+                let last = written + n == buf.len();
+                core.queue_chunk(
+                    Bytes::copy_from_slice(&buf[written..written + n]),
+                    false,
+                    record && last,
+                );
+                written += n;
+                drop(core);
+                self.shared.nudge();
+                core = self.shared.lock();
+            }
+            Poll::Ready(Ok(()))
+        })
+        .await
+    }
+
+    /// Resolve once data (or the end of stream) can be read without
+    /// waiting. Consumes nothing; composes with [`Stream::try_read`].
+    pub async fn readable(&self) -> Result<()> {
+        poll_fn(|cx| {
+            let mut core = self.shared.lock();
+            if core.read_shutdown || core.recv.is_readable() || core.recv.at_end() {
+                return Poll::Ready(Ok(()));
+            }
+            Self::check_open(&core)?;
+            core.read_wakers.push(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Resolve once the send window has space for at least one byte.
+    /// Transfers nothing; composes with [`Stream::try_write`].
+    pub async fn writable(&self) -> Result<()> {
+        poll_fn(|cx| {
+            let mut core = self.shared.lock();
+            Self::check_open(&core)?;
+            if let Some(code) = core.peer_stopped {
+                return Poll::Ready(Err(Error::PeerStoppedReading { code }));
+            }
+            if core.send_closed {
+                return Poll::Ready(Err(Error::StreamClosed));
+            }
+            if core.send_space() > 0 {
+                core.blocked = None;
+                return Poll::Ready(Ok(()));
+            }
+            core.note_blocked();
+            core.write_wakers.push(cx.waker().clone());
+            core.pool.register(cx.waker());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Read without waiting; `None` when no data is available right now,
+    /// `Some(0)` at end of stream.
+    pub fn try_read(&self, buf: &mut [u8]) -> Result<Option<usize>> {
+        let mut core = self.shared.lock();
+        if core.read_shutdown {
+            return Ok(Some(0));
+        }
+        if core.recv.is_readable() {
+            let n = core.recv.read(buf);
+            if let Some(digest) = &mut core.content_digest {
+                digest.update(&buf[..n]);
+            }
+            if !core.recv.retains() {
+                core.release_read(n);
+                drop(core);
+                // Freed credit may unblock the peer; let the pump grant it.
+                self.shared.nudge();
+            }
+            return Ok(Some(n));
+        }
+        if core.recv.at_end() {
+            return Ok(Some(0));
+        }
+        Self::check_open(&core)?;
+        Ok(None)
+    }
+
+    /// Read up to `buf.len()` bytes at absolute stream `offset` without
+    /// consuming anything, treating the fully buffered stream as a
+    /// seekable blob. Short counts stop at the contiguous received
+    /// prefix; a gap or unreceived tail reads as zero bytes copied.
+    ///
+    /// # Panics
+    ///
+    /// The stream must have been opened with
+    /// [`SubstreamOptions::retain_all`]; without it consumed bytes are
+    /// reclaimed and offsets would dangle.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let core = self.shared.lock();
+        assert!(
+            core.recv.retains(),
+            "read_at needs SubstreamOptions::retain_all"
+        );
+        Self::check_open(&core)?;
+        Ok(core.recv.read_at(offset, buf))
+    }
+
+    /// Abort the entire channel carrying this stream. The peer gets a
+    /// connection CLOSE with `error_code` right away, and every stream on
+    /// the channel -- both here and there -- fails with
+    /// [`Error::ConnectionReset`] rather than draining. For unrecoverable
+    /// errors; prefer [`reset`](Self::reset) to abandon just this stream.
+    pub fn reset_connection(&self, error_code: u32, reason: &str) -> Result<()> {
+        let channel = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        channel.reset(error_code, reason);
+        Ok(())
+    }
+
+    /// Congestion window of the channel carrying this stream, in bytes.
+    /// Primarily for tests and diagnostics.
+    pub fn congestion_window(&self) -> Result<usize> {
+        let channel = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        let window = channel.lock().cc.window();
+        Ok(window)
+    }
+
+    /// Slow-start threshold of the channel's congestion controller, in
+    /// bytes; `None` until the strategy establishes one.
+    pub fn ssthresh(&self) -> Result<Option<usize>> {
+        let channel = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        let ssthresh = channel.lock().cc.ssthresh();
+        Ok(ssthresh)
+    }
+
+    /// Smoothed RTT estimate of the channel carrying this stream, `None`
+    /// before the first sample. With the simulated transport and a
+    /// configured link latency this converges to the round-trip delay,
+    /// making the crate usable as a network testbed.
+    pub fn smoothed_rtt(&self) -> Result<Option<Duration>> {
+        let channel = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        let (srtt, _, _) = channel.lock().rtt_estimates();
+        Ok(srtt)
+    }
+
+    /// Most recent raw RTT sample on the channel, before smoothing.
+    pub fn latest_rtt(&self) -> Result<Option<Duration>> {
+        let channel = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        let (_, latest, _) = channel.lock().rtt_estimates();
+        Ok(latest)
+    }
+
+    /// Smallest RTT sample seen on the channel, approximating the path's
+    /// propagation delay.
+    pub fn min_rtt(&self) -> Result<Option<Duration>> {
+        let channel = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        let (_, _, min) = channel.lock().rtt_estimates();
+        Ok(min)
+    }
+
+    /// The congestion algorithm of the channel carrying this stream, when
+    /// it is one of the application-selectable ones.
+    pub fn congestion_algorithm(&self) -> Result<Option<CongestionAlgorithm>> {
+        let channel = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        let algorithm = channel.lock().cc.algorithm();
+        Ok(algorithm)
+    }
+
+    /// Replace the congestion controller of the channel carrying this
+    /// stream. Only the channel's sole stream may do this: other streams
+    /// share the controller, so swapping it under them errors with
+    /// [`Error::ChannelShared`]. Bytes already in flight carry over.
+    pub fn set_congestion_algorithm(&self, algo: CongestionAlgorithm) -> Result<()> {
+        let channel = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        let mut core = channel.lock();
+        if core.streams.len() > 1 {
+            return Err(Error::ChannelShared);
+        }
+        let in_flight = core.cc.in_flight();
+        let mut cc = decongestion::make_selected(algo);
+        cc.on_sent(in_flight);
+        core.cc = cc;
+        Ok(())
+    }
+
+    /// The receive window this stream can currently advertise to its
+    /// peer: the base window clamped to what the host buffer pool has left,
+    /// unless overridden by [`set_advertised_window`](Self::set_advertised_window).
+    pub fn advertised_window(&self) -> usize {
+        let core = self.shared.lock();
+        core.window_override
+            .unwrap_or_else(|| core.recv_window.min(core.pool.available()))
+    }
+
+    /// Pin the advertised receive window to `window` bytes, bypassing the
+    /// automatic pool-derived value, and tell the peer right away; it caps
+    /// its unacknowledged data toward us accordingly. Meant for testing and
+    /// advanced tuning -- the automatic value suits most applications.
+    pub fn set_advertised_window(&self, window: usize) {
+        let mut core = self.shared.lock();
+        if core.window_override == Some(window) {
+            return;
+        }
+        core.window_override = Some(window);
+        drop(core);
+        if let Some(channel) = self.shared.channel() {
+            channel.queue_window_advert(self.shared.lsid, window as u32);
+        }
+    }
+
+    /// Why the most recent write attempt on this stream pended, or `None`
+    /// if it made progress: full flow-control window versus a congestion
+    /// backlog. Diagnoses what is limiting throughput.
+    pub fn write_block_reason(&self) -> Option<BlockReason> {
+        self.shared.lock().blocked
+    }
+
+    /// Queue as much of `buf` as the send window allows without waiting;
+    /// `None` when there is no space right now.
+    pub fn try_write(&self, buf: &[u8]) -> Result<Option<usize>> {
+        let mut core = self.shared.lock();
+        Self::check_open(&core)?;
+        if let Some(code) = core.peer_stopped {
+            return Err(Error::PeerStoppedReading { code });
+        }
+        if core.send_closed {
+            return Err(Error::StreamClosed);
+        }
+        let space = core.send_space();
+        if space == 0 {
+            core.note_blocked();
+            return Ok(None);
+        }
+        core.blocked = None;
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        let n = space.min(buf.len());
+        core.queue_chunk(Bytes::copy_from_slice(&buf[..n]), false, false);
+        drop(core);
+        self.shared.nudge();
+        Ok(Some(n))
+    }
+
+    /// Read up to `buf.len()` bytes; resolves with 0 at end of stream.
+    pub async fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        poll_fn(|cx| {
+            let mut core = self.shared.lock();
+            if core.read_shutdown {
+                return Poll::Ready(Ok(0));
+            }
+            if core.recv.is_readable() {
+                let n = core.recv.read(buf);
+                if let Some(digest) = &mut core.content_digest {
+                    digest.update(&buf[..n]);
+                }
+                if !core.recv.retains() {
+                    core.release_read(n);
+                    drop(core);
+                    self.shared.nudge();
+                }
+                return Poll::Ready(Ok(n));
+            }
+            if core.recv.at_end() {
+                return Poll::Ready(Ok(0));
+            }
+            Self::check_open(&core)?;
+            core.read_wakers.push(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Application payload bytes this stream has put on the wire in first
+    /// transmissions; once everything written has been sent, this matches
+    /// the bytes written.
+    pub fn payload_bytes(&self) -> u64 {
+        self.shared.lock().payload_bytes
+    }
+
+    /// Protocol bytes spent moving this stream's payload: retransmitted
+    /// data, STREAM frame headers, and a share -- proportional to its
+    /// payload bytes in each packet -- of the packet header, crypto
+    /// envelope, acknowledgements, control frames and padding riding
+    /// along. Maintained incrementally as packets are assembled, so
+    /// efficiency is `payload / (payload + overhead)` at any point.
+    /// Packets carrying no stream data, like bare acknowledgements and
+    /// MTU probes, are channel overhead and attributed to no stream.
+    pub fn total_overhead_bytes(&self) -> u64 {
+        self.shared.lock().overhead_bytes
+    }
+
+    /// Bytes written on this stream that the peer has acknowledged: the
+    /// write offset minus whatever is still queued or in flight.
+    pub fn acked_offset(&self) -> u64 {
+        let core = self.shared.lock();
+        core.next_offset - (core.buffered + core.outstanding) as u64
+    }
+
+    /// Poll for transfer progress: resolves with the new
+    /// [`acked_offset`](Self::acked_offset) each time it advances past the
+    /// last value this method delivered, waking only on acknowledgements
+    /// that move it. Errors once the stream or its connection is torn
+    /// down, which doubles as stall detection.
+    pub fn poll_progress(&self, cx: &mut std::task::Context<'_>) -> Poll<Result<u64>> {
+        let mut core = self.shared.lock();
+        let acked = core.next_offset - (core.buffered + core.outstanding) as u64;
+        if acked > core.progress_reported {
+            core.progress_reported = acked;
+            return Poll::Ready(Ok(acked));
+        }
+        Self::check_open(&core)?;
+        core.progress_wakers.push(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Await the next advance of the acked offset; see
+    /// [`poll_progress`](Self::poll_progress).
+    pub async fn progress(&self) -> Result<u64> {
+        poll_fn(|cx| self.poll_progress(cx)).await
+    }
+
+    /// Read exactly `buf.len()` bytes, accumulating across packets.
+    ///
+    /// If the stream ends before the buffer fills, the call fails with
+    /// [`Error::UnexpectedEof`] reporting how many bytes were read; those
+    /// bytes are not lost -- they sit in the front of `buf`.
+    pub async fn read_exact(&self, buf: &mut [u8]) -> Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.read(&mut buf[filled..]).await? {
+                0 => {
+                    return Err(Error::UnexpectedEof {
+                        read: filled,
+                        expected: buf.len(),
+                    })
+                }
+                n => filled += n,
+            }
+        }
+        Ok(())
+    }
+
+    /// Absolute stream offset of the next boundary the sender marked past
+    /// the current read position, as soon as its frame has arrived; `None`
+    /// while no boundary is pending. The bytes leading up to it may still
+    /// be in flight, so pair this with
+    /// [`Stream::contiguous_bytes_available`] before splitting.
+    pub fn next_boundary_offset(&self) -> Option<u64> {
+        self.shared.lock().recv.next_boundary()
+    }
+
+    /// Read one complete record; `None` at end of stream.
+    pub async fn read_record(&self) -> Result<Option<Vec<u8>>> {
+        poll_fn(|cx| {
+            let mut core = self.shared.lock();
+            if core.read_shutdown {
+                return Poll::Ready(Ok(None));
+            }
+            if let Some(record) = core.recv.read_record() {
+                if let Some(digest) = &mut core.content_digest {
+                    digest.update(&record);
+                }
+                if !core.recv.retains() {
+                    core.release_read(record.len());
+                    drop(core);
+                    self.shared.nudge();
+                }
+                return Poll::Ready(Ok(Some(record)));
+            }
+            if core.recv.at_end() {
+                return Poll::Ready(Ok(None));
+            }
+            Self::check_open(&core)?;
+            core.read_wakers.push(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Shut the read side down: tell the peer to stop sending, discard any
+    /// buffered and further inbound data, and make subsequent reads return
+    /// end of stream immediately.
+    pub fn shutdown_read(&self) {
+        self.stop_sending(0);
+    }
+
+    /// Ask the peer to stop sending, with an application reason code: its
+    /// pending writes on this stream fail with
+    /// [`Error::PeerStoppedReading`] carrying `error_code`, and queued
+    /// unsent data is dropped. Only the peer's write direction is touched
+    /// -- data flowing the other way is unaffected, unlike
+    /// [`reset`](Self::reset). Locally this shuts the read side down like
+    /// [`shutdown_read`](Self::shutdown_read).
+    pub fn stop_sending(&self, error_code: u32) {
+        let mut core = self.shared.lock();
+        if core.read_shutdown || core.conn_closed {
+            return;
+        }
+        core.read_shutdown = true;
+        let buffered = core.recv.readable_len();
+        core.recv = Reassembly::new();
+        core.release_read(buffered);
+        core.wake_readers();
+        drop(core);
+        if let Some(channel) = self.shared.channel() {
+            channel.queue_stop_sending(self.shared.lsid, error_code);
+        }
+    }
+
+    /// A cloneable write handle for fan-in: any number of clones can queue
+    /// data to this stream from different tasks, each `send` going in as
+    /// one unserialized-with-others unit. Repeated calls join the same
+    /// sender group; when the group's last clone drops, the write side
+    /// half-closes as if by [`Stream::close_send`].
+    pub fn sender(&self) -> StreamSender {
+        let mut slot = self.shared.sender.lock().unwrap();
+        if let Some(inner) = slot.upgrade() {
+            return StreamSender { inner };
+        }
+        let inner = Arc::new(SenderInner {
+            shared: self.shared.clone(),
+            write_lock: tokio::sync::Mutex::new(()),
+        });
+        *slot = Arc::downgrade(&inner);
+        StreamSender { inner }
+    }
+
+    /// Half-close the send side: queue a FIN after all buffered data.
+    pub fn close_send(&self) {
+        let mut core = self.shared.lock();
+        if core.reset.is_some() || core.conn_closed {
+            return;
+        }
+        core.queue_fin();
+        drop(core);
+        self.shared.release_open_slot();
+        self.shared.nudge();
+    }
+
+    /// Gracefully close the send side and wait until the peer acknowledged
+    /// all data including the FIN. [`Stream::set_linger`] changes how long
+    /// the wait lasts.
+    pub async fn close(&self) -> Result<()> {
+        let linger = self.shared.lock().linger;
+        match linger {
+            Some(Some(d)) if d.is_zero() => {
+                // Zero linger: abortive close, discarding buffered data.
+                self.reset(0, "closed with zero linger");
+                return Ok(());
+            }
+            Some(None) => {
+                // No linger: hand off to the channel and return at once.
+                self.close_send();
+                return Ok(());
+            }
+            Some(Some(d)) => {
+                self.close_send();
+                return match tokio::time::timeout(d, self.fin_acked()).await {
+                    Ok(result) => result,
+                    Err(_) => Err(Error::Timeout),
+                };
+            }
+            None => {}
+        }
+        self.close_send();
+        self.fin_acked().await
+    }
+
+    /// Flush everything accepted by [`write`](Stream::write) and gracefully
+    /// half-close the send side, resolving once the peer has acknowledged
+    /// the final offset. The FIN is ordered after all buffered data, so a
+    /// resolved call guarantees the peer received every byte written; the
+    /// read side stays open. The safe "done writing" path -- equivalent to
+    /// [`close`](Stream::close), and subject to the same
+    /// [`set_linger`](Stream::set_linger) policy.
+    pub async fn flush_and_close(&self) -> Result<()> {
+        self.close().await
+    }
+
+    /// How [`Stream::close`] treats unacknowledged data: `Some(d)` waits at
+    /// most `d` for the FIN acknowledgement, failing with [`Error::Timeout`]
+    /// when it elapses; `None` returns immediately while the channel drains
+    /// in the background; `Some(Duration::ZERO)` resets the stream instead.
+    pub fn set_linger(&self, linger: Option<Duration>) {
+        self.shared.lock().linger = Some(linger);
+    }
+
+    async fn fin_acked(&self) -> Result<()> {
+        poll_fn(|cx| {
+            let mut core = self.shared.lock();
+            Self::check_open(&core)?;
+            if core.fin_acked {
+                return Poll::Ready(Ok(()));
+            }
+            core.write_wakers.push(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Spread this stream's packets over every established channel to the
+    /// same peer, selecting among them with `policy`. Returns the number of
+    /// paths in use (at least one: the stream's own channel). Channels
+    /// opened later are not picked up; call again to refresh the set.
+    pub fn enable_multipath(&self, policy: PathPolicy) -> Result<usize> {
+        let primary = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        let host = primary.host.upgrade().ok_or(Error::ConnectionClosed)?;
+        let peer = *primary.remote_identity.lock().unwrap();
+        let mut paths = vec![Arc::downgrade(&primary)];
+        let channels: Vec<_> = host.channels.lock().unwrap().values().cloned().collect();
+        for chan in channels {
+            if std::ptr::eq(Arc::as_ptr(&chan), Arc::as_ptr(&primary))
+                || peer.is_none()
+                || *chan.remote_identity.lock().unwrap() != peer
+            {
+                continue;
+            }
+            chan.attach_path(&self.shared);
+            paths.push(Arc::downgrade(&chan));
+        }
+        let count = paths.len();
+        *self.shared.multipath.lock().unwrap() = Some(Multipath { policy, paths, next: 0 });
+        Ok(count)
+    }
+
+    /// Abort the stream, discarding buffered data on both ends.
+    pub fn reset(&self, error_code: u32, reason: &str) {
+        self.reset_inner(error_code, reason, None);
+    }
+
+    /// Like [`reset`](Self::reset), additionally telling the peer the
+    /// absolute byte offset this send side had reached. The receiver reads
+    /// it from [`Stream::peer_final_offset`] and can record how much of an
+    /// interrupted transfer is missing before resuming it elsewhere.
+    pub fn reset_with_final_offset(&self, error_code: u32, reason: &str, final_offset: u64) {
+        self.reset_inner(error_code, reason, Some(final_offset));
+    }
+
+    fn reset_inner(&self, error_code: u32, reason: &str, final_offset: Option<u64>) {
+        let mut core = self.shared.lock();
+        if core.reset.is_some() || core.conn_closed {
+            return;
+        }
+        core.apply_reset(error_code, reason.to_string());
+        drop(core);
+        self.shared.release_open_slot();
+        if let Some(channel) = self.shared.channel() {
+            channel.queue_reset(self.shared.lsid, error_code, reason, final_offset);
+        }
+    }
+
+    /// The final offset the peer reported when it reset this stream, if
+    /// its RESET carried one; `None` otherwise.
+    pub fn peer_final_offset(&self) -> Option<u64> {
+        self.shared.lock().peer_final_offset
+    }
+
+    /// Abort the stream, returning every byte it holds to the buffer pool
+    /// immediately.
+    ///
+    /// Unlike [`reset`](Self::reset), which keeps in-flight data charged
+    /// until the peer acknowledges it, `abort` drops send queues, in-flight
+    /// accounting and the reassembly buffer on the spot and removes the
+    /// stream from the channel's scheduler -- for memory-pressure
+    /// situations. The peer is sent a RESET; afterwards every handle fails
+    /// with [`Error::StreamReset`] and the reason `"aborted"`.
+    pub fn abort(&self) {
+        let mut core = self.shared.lock();
+        if core.reset.is_some() || core.conn_closed {
+            return;
+        }
+        core.apply_reset(0, "aborted".to_string());
+        core.aborted = true;
+        let held = core.outstanding + core.recv.readable_len();
+        core.outstanding = 0;
+        core.recv = Reassembly::new();
+        core.pool.discharge(held);
+        drop(core);
+        self.shared.release_open_slot();
+        if let Some(channel) = self.shared.channel() {
+            channel.queue_reset(self.shared.lsid, 0, "aborted", None);
+            channel.forget_stream(self.shared.lsid);
+        }
+    }
+
+    /// Spawn a substream of this stream. Fails with
+    /// [`Error::SubstreamLimit`] when the channel's concurrent substream cap
+    /// is reached; see [`Stream::open_substream_with`] to queue instead.
+    pub fn open_substream(&self) -> Result<Stream> {
+        let channel = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        Self::check_open(&self.shared.lock())?;
+        Ok(Stream::new(channel.open_substream(self.shared.lsid)?))
+    }
+
+    /// Spawn a substream with explicit behavior at the substream cap.
+    /// With [`OnLimit::Queue`] the open waits for a slot freed by an earlier
+    /// substream closing; queued opens complete in order.
+    pub async fn open_substream_with(&self, opts: SubstreamOptions) -> Result<Stream> {
+        let channel = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        Self::check_open(&self.shared.lock())?;
+        let shared = match opts.on_limit {
+            OnLimit::Reject => channel.open_substream(self.shared.lsid)?,
+            OnLimit::Queue => channel.open_substream_queued(self.shared.lsid).await?,
+        };
+        if opts.retain_all {
+            shared.lock().recv.retain_all();
+        }
+        Ok(Stream::new(shared))
+    }
+
+    /// Spawn a substream with application metadata riding on the opening
+    /// STREAM frame. The peer can read it with [`Stream::open_metadata`]
+    /// before any stream data. Metadata must fit in a single packet.
+    pub fn open_substream_with_metadata(&self, meta: &[u8]) -> Result<Stream> {
+        let channel = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        Self::check_open(&self.shared.lock())?;
+        Ok(Stream::new(channel.open_stream_with_metadata(
+            self.shared.lsid,
+            meta,
+        )?))
+    }
+
+    /// Out-of-order bytes buffered beyond the receive frontier, for
+    /// diagnostics. Bounded by the reorder gap cap.
+    pub fn reorder_depth(&self) -> usize {
+        self.shared.lock().recv.reorder_depth()
+    }
+
+    /// Metadata the peer attached when opening this stream, if any.
+    pub fn open_metadata(&self) -> Option<Bytes> {
+        self.shared.lock().open_metadata.clone()
+    }
+
+    /// Accept the next substream spawned by the peer on this stream.
+    pub async fn accept_substream(&self) -> Result<Stream> {
+        poll_fn(|cx| {
+            let mut core = self.shared.lock();
+            if let Some(sub) = core.incoming_subs.pop_front() {
+                return Poll::Ready(Ok(sub));
+            }
+            Self::check_open(&core)?;
+            core.read_wakers.push(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Set this stream's scheduling priority; 0 is highest.
+    ///
+    /// The packet scheduler's order is deterministic: streams are serviced
+    /// by priority, then by the weighted round-robin debt (see
+    /// [`set_weight`](Stream::set_weight)), then by stream id -- which
+    /// follows creation order. Ties cannot depend on hash-map iteration,
+    /// so identical inputs yield byte-for-byte identical wire output.
+    pub fn set_priority(&self, priority: u32) {
+        let mut core = self.shared.lock();
+        if core.priority == priority {
+            return;
+        }
+        core.priority = priority;
+        drop(core);
+        if let Some(channel) = self.shared.channel() {
+            channel.queue_priority(self.shared.lsid, priority);
+        }
+    }
+
+    /// Current scheduling priority.
+    pub fn priority(&self) -> u32 {
+        self.shared.lock().priority
+    }
+
+    /// This stream's channel-local identifier, stable for the stream's
+    /// life. The two ends may number the same stream differently; the id
+    /// is only meaningful alongside ids from the same end.
+    pub fn id(&self) -> u32 {
+        self.shared.lsid
+    }
+
+    /// Whether this stream was opened under a parent stream rather than
+    /// by a connect.
+    pub fn is_substream(&self) -> bool {
+        self.shared.lock().parent_lsid != ROOT_LSID
+    }
+
+    /// The [`id`](Self::id) of this stream's parent, `None` for a
+    /// top-level stream. The link is set when the stream is opened or
+    /// accepted and never changes, so the substream hierarchy can be
+    /// rebuilt for logging or routing.
+    pub fn parent_id(&self) -> Option<u32> {
+        let parent = self.shared.lock().parent_lsid;
+        (parent != ROOT_LSID).then_some(parent)
+    }
+
+    /// Set this stream's bandwidth weight; defaults to 1, and 0 is treated
+    /// as 1. The channel divides its send capacity among backlogged streams
+    /// of equal priority in proportion to their weights, so a weight-3
+    /// stream gets about three times the bytes of a weight-1 sibling
+    /// instead of starving it. Priority still ranks first: weights only
+    /// share capacity within one priority level. Unlike priority, the
+    /// weight shapes this end's sending only and is not sent to the peer.
+    pub fn set_weight(&self, weight: u16) {
+        self.shared.lock().weight = weight.max(1);
+    }
+
+    /// Current bandwidth weight.
+    pub fn weight(&self) -> u16 {
+        self.shared.lock().weight
+    }
+
+    /// Cap this stream's outstanding unacknowledged bytes at `bytes`,
+    /// regardless of how much the congestion and flow-control windows
+    /// would permit; the most restrictive of the three wins. Writes pend
+    /// once the cap is reached and resume as acknowledgements drain it.
+    /// Useful to bound buffering latency or per-stream memory.
+    pub fn set_max_in_flight(&self, bytes: usize) {
+        self.shared.lock().max_in_flight = Some(bytes);
+        self.shared.nudge();
+    }
+
+    /// Current in-flight cap, if one was set.
+    pub fn max_in_flight(&self) -> Option<usize> {
+        self.shared.lock().max_in_flight
+    }
+
+    /// Cap this stream's transmit rate at `bytes_per_sec`, independent of
+    /// congestion control -- the tighter of the two governs. A token
+    /// bucket read against the host clock smooths sends into bursts of at
+    /// most 50ms; zero removes the limit. Useful to keep background
+    /// transfers from crowding out interactive traffic.
+    pub fn set_rate_limit(&self, bytes_per_sec: u64) {
+        let mut core = self.shared.lock();
+        core.rate_limit = bytes_per_sec;
+        // Start with a full bucket so a fresh limit allows one burst.
+        core.rate_tokens = core.rate_burst();
+        core.rate_refilled = Instant::now();
+        drop(core);
+        self.shared.nudge();
+    }
+
+    /// Current send rate cap in bytes per second, if one is set.
+    pub fn rate_limit(&self) -> Option<u64> {
+        let limit = self.shared.lock().rate_limit;
+        (limit > 0).then_some(limit)
+    }
+
+    /// Send an unreliable datagram over the channel carrying this
+    /// stream. It travels encrypted in a single packet but is never
+    /// retransmitted: loss or a backed-up queue silently drops it.
+    /// Fails with [`Error::DatagramTooLarge`] when the payload cannot
+    /// fit one packet.
+    pub fn send_datagram(&self, data: &[u8]) -> Result<()> {
+        let channel = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        channel.send_datagram(Bytes::copy_from_slice(data))
+    }
+
+    /// The feature set negotiated on the channel carrying this stream: a
+    /// feature is on only when both ends advertised it in SETTINGS, so a
+    /// peer lacking one degrades the channel gracefully instead of failing
+    /// the connection.
+    pub fn features(&self) -> Result<Features> {
+        let channel = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        let core = channel.lock();
+        Ok(Features {
+            datagrams: core.datagrams,
+            compression: core.compression,
+        })
+    }
+
+    /// The full parameter set in effect on the channel carrying this
+    /// stream: negotiated feature flags plus the limits, windows and
+    /// timeouts each end declared during connect. Peer-advertised values
+    /// need a round trip to settle, so immediately after connect some may
+    /// still read as absent.
+    pub fn connection_params(&self) -> Result<ConnectionParams> {
+        let channel = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        let core = channel.lock();
+        Ok(ConnectionParams {
+            version: 1,
+            max_substreams: channel.max_substreams,
+            initial_recv_window: DEFAULT_RECV_WINDOW,
+            initial_send_window: DEFAULT_SEND_WINDOW,
+            datagrams: core.datagrams,
+            compression: core.compression,
+            ecn: false,
+            peer_idle_timeout: core.peer_idle_timeout,
+        })
+    }
+
+    /// Receive the next datagram from the channel carrying this stream.
+    /// Recent network duplicates are suppressed; delivery follows
+    /// arrival order, which loss and reordering may differ from send
+    /// order.
+    pub async fn recv_datagram(&self) -> Result<Vec<u8>> {
+        let channel = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        let data = poll_fn(|cx| channel.poll_recv_datagram(cx)).await?;
+        Ok(data.to_vec())
+    }
+
+    /// Permit the peer to send this stream data up to absolute offset
+    /// `max_offset`, via a dedicated WINDOW_UPDATE frame. The peer treats
+    /// the limit as monotonic, so reordered or repeated grants are safe;
+    /// a grant below what was already allowed is ignored there.
+    pub fn grant_window(&self, max_offset: u64) -> Result<()> {
+        let channel = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        channel.queue_window_update(self.shared.lsid, max_offset);
+        Ok(())
+    }
+
+    /// Bound the total unconsumed inbound payload buffered across every
+    /// stream on the channel carrying this stream. The channel grants the
+    /// peer connection-level credit as the application consumes data from
+    /// any stream, so one channel cannot buffer unboundedly by fanning
+    /// out over many substreams; a sender blocks when either this or a
+    /// per-stream window is exhausted.
+    pub fn set_connection_window(&self, bytes: usize) -> Result<()> {
+        let channel = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        channel.set_conn_window(bytes);
+        Ok(())
+    }
+
+    /// Permit the peer to send up to `max_offset` total fresh payload
+    /// bytes across every stream on the channel carrying this stream,
+    /// via a connection-level WINDOW_UPDATE frame. Monotonic like
+    /// [`grant_window`](Self::grant_window).
+    pub fn grant_connection_window(&self, max_offset: u64) -> Result<()> {
+        let channel = self.shared.channel().ok_or(Error::ConnectionClosed)?;
+        channel.queue_window_update(ROOT_LSID, max_offset);
+        Ok(())
+    }
+
+    /// Attach application context to this stream handle -- a session
+    /// object, say -- replacing any previous context. It travels with the
+    /// handle and is dropped with it, sparing the application an external
+    /// map keyed by stream.
+    pub fn set_context<T: Any + Send + Sync>(&mut self, ctx: T) {
+        self.context = Some(Box::new(ctx));
+    }
+
+    /// The attached context, when one of type `T` is present.
+    pub fn context<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.context.as_ref()?.downcast_ref()
+    }
+
+    /// Mutable access to the attached context of type `T`.
+    pub fn context_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        self.context.as_mut()?.downcast_mut()
+    }
+
+    /// The contiguous byte ranges received so far, including what has
+    /// already been read. The holes between them are the gaps reassembly
+    /// is waiting on -- a quick answer to "what is this stalled stream
+    /// missing?" in a debugger.
+    pub fn received_ranges(&self) -> Vec<Range<u64>> {
+        self.shared.lock().recv.received_ranges()
+    }
+
+    /// In-order bytes buffered and ready for an immediate read, however
+    /// many packets (and whatever interleaving with other streams) carried
+    /// them. Out-of-order segments past the first gap do not count; see
+    /// [Stream::received_ranges] for those.
+    pub fn contiguous_bytes_available(&self) -> usize {
+        self.shared.lock().recv.unread()
+    }
+
+    /// Start the opt-in rolling content digest: a SHA-256 over the
+    /// payload bytes this stream carries from here on, written bytes and
+    /// in-order reads alike. Enable it before the payload flows on both
+    /// ends of a one-way transfer and the two digests come out equal --
+    /// an end-to-end integrity check above the wire encryption. Bytes
+    /// fetched by the random-access [`Stream::read_at`] are not counted.
+    pub fn enable_content_digest(&self) {
+        let mut core = self.shared.lock();
+        if core.content_digest.is_none() {
+            core.content_digest = Some(Sha256::new());
+        }
+    }
+
+    /// The rolling content digest over the bytes carried so far, or
+    /// `None` unless [`Stream::enable_content_digest`] was called.
+    /// Compare once the transfer is complete.
+    pub fn content_digest(&self) -> Option<[u8; 32]> {
+        self.shared
+            .lock()
+            .content_digest
+            .as_ref()
+            .map(|digest| digest.clone().finalize().into())
+    }
+
+    /// Disable or re-enable Nagle-style coalescing of small writes.
+    pub fn set_nagle(&self, enabled: bool) {
+        self.shared.lock().nagle = enabled;
+        if !enabled {
+            self.shared.nudge();
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let mut core = self.shared.lock();
+        if core.read_shutdown {
+            return Poll::Ready(Ok(()));
+        }
+        if core.recv.is_readable() {
+            let unfilled = buf.initialize_unfilled();
+            let n = core.recv.read(unfilled);
+            if let Some(digest) = &mut core.content_digest {
+                digest.update(&unfilled[..n]);
+            }
+            buf.advance(n);
+            if !core.recv.retains() {
+                core.release_read(n);
+                drop(core);
+                // Freed credit may unblock the peer; let the pump grant it.
+                self.shared.nudge();
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if core.recv.at_end() {
+            return Poll::Ready(Ok(()));
+        }
+        if let Err(e) = Self::check_open(&core) {
+            return Poll::Ready(Err(e.into()));
+        }
+        core.read_wakers.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut core = self.shared.lock();
+        if let Err(e) = Self::check_open(&core) {
+            return Poll::Ready(Err(e.into()));
+        }
+        if let Some(code) = core.peer_stopped {
+            return Poll::Ready(Err(Error::PeerStoppedReading { code }.into()));
+        }
+        if core.send_closed {
+            return Poll::Ready(Err(Error::StreamClosed.into()));
+        }
+        let space = core.send_space();
+        if space == 0 {
+            core.write_wakers.push(cx.waker().clone());
+            core.pool.register(cx.waker());
+            return Poll::Pending;
+        }
+        let n = space.min(buf.len());
+        core.queue_chunk(Bytes::copy_from_slice(&buf[..n]), false, false);
+        drop(core);
+        self.shared.nudge();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut core = self.shared.lock();
+        if let Err(e) = Self::check_open(&core) {
+            return Poll::Ready(Err(e.into()));
+        }
+        if core.buffered == 0 && core.outstanding == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        core.write_wakers.push(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.close_send();
+        let mut core = self.shared.lock();
+        if core.fin_acked || core.reset.is_some() || core.conn_closed {
+            return Poll::Ready(Ok(()));
+        }
+        core.write_wakers.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// One partial write against a stream's shared state: the body behind
+/// [`Stream::write`] and [`StreamSender::send`].
+async fn write_some(shared: &Arc<StreamShared>, buf: &[u8]) -> Result<usize> {
+    poll_fn(|cx| {
+        let mut core = shared.lock();
+        Stream::check_open(&core)?;
+        if let Some(code) = core.peer_stopped {
+            return Poll::Ready(Err(Error::PeerStoppedReading { code }));
+        }
+        if core.send_closed {
+            return Poll::Ready(Err(Error::StreamClosed));
+        }
+        let space = core.send_space();
+        if space == 0 {
+            core.note_blocked();
+            core.write_wakers.push(cx.waker().clone());
+            core.pool.register(cx.waker());
+            return Poll::Pending;
+        }
+        core.blocked = None;
+        let n = space.min(buf.len());
+        core.queue_chunk(Bytes::copy_from_slice(&buf[..n]), false, false);
+        drop(core);
+        shared.nudge();
+        Poll::Ready(Ok(n))
+    })
+    .await
+}
+
+/// What one sender group shares: the stream, and a lock serializing whole
+/// `send` calls so no sender's buffer is interleaved with another's.
+struct SenderInner {
+    shared: Arc<StreamShared>,
+    write_lock: tokio::sync::Mutex<()>,
+}
+
+impl Drop for SenderInner {
+    /// The last sender is gone: half-close the write side, as
+    /// [`Stream::close_send`] would.
+    fn drop(&mut self) {
+        let mut core = self.shared.lock();
+        if core.reset.is_some() || core.conn_closed {
+            return;
+        }
+        core.queue_fin();
+        drop(core);
+        self.shared.release_open_slot();
+        self.shared.nudge();
+    }
+}
+
+/// A cloneable write handle for fan-in, created by [`Stream::sender`]:
+/// clones queue data to the same stream from different tasks.
+pub struct StreamSender {
+    inner: Arc<SenderInner>,
+}
+
+impl StreamSender {
+    /// Queue all of `data`, waiting for window space as needed. The buffer
+    /// goes in as one unit: concurrent sends from other clones are
+    /// serialized around it, so each sender's writes arrive whole and in
+    /// that sender's order.
+    pub async fn send(&self, data: &[u8]) -> Result<()> {
+        let _exclusive = self.inner.write_lock.lock().await;
+        let mut written = 0;
+        while written < data.len() {
+            written += write_some(&self.inner.shared, &data[written..]).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Clone for StreamSender {
+    fn clone(&self) -> Self {
+        StreamSender {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl std::fmt::Debug for StreamSender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamSender")
+            .field("lsid", &self.inner.shared.lsid)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Dropping an open stream closes it best-effort in the background: data
+/// already accepted by [`write`](Stream::write) is flushed and a FIN queued
+/// behind it, and the peer is told to stop sending, since nothing will read
+/// here again. The channel carries the close out on its own; prefer an
+/// explicit [`close`](Stream::close) to observe delivery.
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let mut core = self.shared.lock();
+        let open = core.reset.is_none() && !core.conn_closed;
+        let fin = open && !core.send_closed;
+        if fin {
+            core.queue_fin();
+        }
+        let stop = open && !core.read_shutdown;
+        if stop {
+            core.read_shutdown = true;
+            let buffered = core.recv.readable_len();
+            core.recv = Reassembly::new();
+            core.release_read(buffered);
+        }
+        drop(core);
+        if fin {
+            self.shared.nudge();
+        }
+        if stop {
+            if let Some(channel) = self.shared.channel() {
+                channel.queue_stop_sending(self.shared.lsid, 0);
+            }
+        }
+        self.shared.release_open_slot();
+    }
+}
+
+impl std::fmt::Debug for Stream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Stream")
+            .field("lsid", &self.shared.lsid)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Service request/reply records exchanged on new top-level streams
+/// (spec section 5, "Service request streams").
+pub(crate) mod service {
+    use crate::error::{Error, Result};
+    use crate::packet::{decode_be_uint, take};
+
+    pub(crate) const TYPE_REQUEST: u8 = 1;
+    pub(crate) const TYPE_REPLY: u8 = 2;
+    pub(crate) const KIND_CONNECT: u8 = 1;
+
+    pub(crate) const STATUS_OK: u32 = 0;
+    pub(crate) const STATUS_NOT_FOUND: u32 = 1;
+
+    /// Encode a "request connection to given service and protocol" record.
+    pub(crate) fn encode_connect_request(service: &str, protocol: &str) -> Vec<u8> {
+        let mut buf = vec![TYPE_REQUEST, KIND_CONNECT];
+        buf.extend_from_slice(&(service.len() as u16).to_be_bytes());
+        buf.extend_from_slice(service.as_bytes());
+        buf.extend_from_slice(&(protocol.len() as u16).to_be_bytes());
+        buf.extend_from_slice(protocol.as_bytes());
+        buf
+    }
+
+    pub(crate) fn decode_connect_request(mut buf: &[u8]) -> Result<(String, String)> {
+        let buf = &mut buf;
+        let header = take(buf, 2)?;
+        if header != [TYPE_REQUEST, KIND_CONNECT] {
+            return Err(Error::protocol("malformed service request"));
+        }
+        let slen = decode_be_uint(take(buf, 2)?) as usize;
+        let service = String::from_utf8_lossy(take(buf, slen)?).into_owned();
+        let plen = decode_be_uint(take(buf, 2)?) as usize;
+        let protocol = String::from_utf8_lossy(take(buf, plen)?).into_owned();
+        Ok((service, protocol))
+    }
+
+    /// Encode a "connection results" reply record.
+    pub(crate) fn encode_connect_reply(status: u32, reply: &str) -> Vec<u8> {
+        let mut buf = vec![TYPE_REPLY, KIND_CONNECT];
+        buf.extend_from_slice(&status.to_be_bytes());
+        buf.extend_from_slice(&(reply.len() as u16).to_be_bytes());
+        buf.extend_from_slice(reply.as_bytes());
+        buf
+    }
+
+    pub(crate) fn decode_connect_reply(mut buf: &[u8]) -> Result<(u32, String)> {
+        let buf = &mut buf;
+        let header = take(buf, 2)?;
+        if header != [TYPE_REPLY, KIND_CONNECT] {
+            return Err(Error::protocol("malformed service reply"));
+        }
+        let status = decode_be_uint(take(buf, 4)?) as u32;
+        let rlen = decode_be_uint(take(buf, 2)?) as usize;
+        let reply = String::from_utf8_lossy(take(buf, rlen)?).into_owned();
+        Ok((status, reply))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_allocators_never_overlap_across_roles() {
+        let mut client = StreamIdAllocator::new(Role::Initiator);
+        let mut server = StreamIdAllocator::new(Role::Responder);
+        let empty = HashMap::new();
+        let ours: Vec<u32> = (0..100).map(|_| client.alloc(&empty)).collect();
+        let theirs: Vec<u32> = (0..100).map(|_| server.alloc(&empty)).collect();
+        for lsid in &ours {
+            assert!(!theirs.contains(lsid), "LSID {lsid} allocated by both sides");
+        }
+    }
+
+    #[test]
+    fn lsid_parity_reports_the_opening_role() {
+        let mut client = StreamIdAllocator::new(Role::Initiator);
+        let mut server = StreamIdAllocator::new(Role::Responder);
+        let empty = HashMap::new();
+        for _ in 0..10 {
+            assert!(StreamIdAllocator::initiator(client.alloc(&empty)));
+            assert!(!StreamIdAllocator::initiator(server.alloc(&empty)));
+        }
+        // The root stream belongs to the channel, not either side.
+        assert!(!StreamIdAllocator::initiator(ROOT_LSID));
+    }
+
+    #[test]
+    fn reassembly_orders_segments() {
+        let mut r = Reassembly::new();
+        r.insert(5, Bytes::from_static(b"world"), false, false);
+        assert!(!r.is_readable());
+        r.insert(0, Bytes::from_static(b"hello"), false, false);
+        let mut buf = [0u8; 16];
+        assert_eq!(r.read(&mut buf), 10);
+        assert_eq!(&buf[..10], b"helloworld");
+    }
+
+    #[test]
+    fn reassembly_discards_duplicates() {
+        let mut r = Reassembly::new();
+        r.insert(0, Bytes::from_static(b"abcd"), false, false);
+        r.insert(0, Bytes::from_static(b"abcd"), false, false);
+        r.insert(2, Bytes::from_static(b"cdef"), false, false);
+        let mut buf = [0u8; 16];
+        assert_eq!(r.read(&mut buf), 6);
+        assert_eq!(&buf[..6], b"abcdef");
+    }
+
+    #[test]
+    fn reorder_gap_limit_drops_far_fragments() {
+        let mut r = Reassembly::new();
+        // Just inside the gap is buffered.
+        r.insert(MAX_REORDER_GAP - 4, Bytes::from_static(b"near"), false, false);
+        assert_eq!(r.reorder_depth(), 4);
+        // Wholly beyond the cap is dropped.
+        assert!(!r.insert(MAX_REORDER_GAP + 1000, Bytes::from_static(b"far"), false, false));
+        assert_eq!(r.reorder_depth(), 4);
+        // A fragment at the frontier always advances it, shifting the cap.
+        r.insert(0, Bytes::from_static(b"go"), false, false);
+        assert!(!r.insert(MAX_REORDER_GAP + 1000, Bytes::from_static(b"fa"), false, false));
+        r.insert(MAX_REORDER_GAP, Bytes::from_static(b"ok"), false, false);
+        assert_eq!(r.reorder_depth(), 6);
+    }
+
+    #[test]
+    fn received_ranges_reflect_the_gaps() {
+        let mut r = Reassembly::new();
+        r.insert(0, Bytes::from_static(&[1; 10]), false, false);
+        r.insert(20, Bytes::from_static(&[2; 10]), false, false);
+        assert_eq!(r.received_ranges(), vec![0..10, 20..30]);
+        // Filling the hole merges everything into one range.
+        r.insert(10, Bytes::from_static(&[3; 10]), false, false);
+        assert_eq!(r.received_ranges(), vec![0..30]);
+    }
+
+    #[test]
+    fn reassembly_end_of_stream() {
+        let mut r = Reassembly::new();
+        r.insert(0, Bytes::from_static(b"bye"), true, false);
+        let mut buf = [0u8; 8];
+        assert_eq!(r.read(&mut buf), 3);
+        assert!(r.at_end());
+    }
+
+    #[test]
+    fn zero_length_fin_ends_the_stream_at_the_current_offset() {
+        // FIN with no final bytes, at the frontier.
+        let mut r = Reassembly::new();
+        r.insert(0, Bytes::from_static(b"hello"), false, false);
+        let mut buf = [0u8; 8];
+        assert_eq!(r.read(&mut buf), 5);
+        assert!(!r.at_end());
+        assert!(r.insert(5, Bytes::new(), true, false));
+        assert!(r.at_end());
+
+        // A bare FIN may also outrun the data it trails.
+        let mut r = Reassembly::new();
+        r.insert(10, Bytes::new(), true, false);
+        assert!(!r.at_end());
+        r.insert(0, Bytes::from_static(b"ten bytes."), false, false);
+        assert_eq!(r.read(&mut buf[..2]), 2);
+        assert_eq!(r.read(&mut buf), 8);
+        assert!(r.at_end());
+    }
+
+    #[test]
+    fn zero_length_fin_on_an_empty_stream() {
+        let mut r = Reassembly::new();
+        assert!(r.insert(0, Bytes::new(), true, false));
+        assert!(r.at_end());
+        assert!(!r.is_readable());
+    }
+
+    #[test]
+    fn records_read_back_as_units() {
+        let mut r = Reassembly::new();
+        r.insert(0, Bytes::from_static(b"one"), false, true);
+        r.insert(3, Bytes::from_static(b"four"), false, true);
+        assert_eq!(r.read_record().unwrap(), b"one");
+        assert_eq!(r.read_record().unwrap(), b"four");
+        assert!(r.read_record().is_none());
+    }
+
+    #[test]
+    fn partial_record_not_readable() {
+        let mut r = Reassembly::new();
+        r.insert(3, Bytes::from_static(b"df"), false, true);
+        assert!(r.read_record().is_none());
+        r.insert(0, Bytes::from_static(b"asd"), false, false);
+        assert_eq!(r.read_record().unwrap(), b"asddf");
+    }
+
+    #[test]
+    fn service_codec_roundtrip() {
+        let req = service::encode_connect_request("metta", "chat");
+        let (s, p) = service::decode_connect_request(&req).unwrap();
+        assert_eq!((s.as_str(), p.as_str()), ("metta", "chat"));
+
+        let reply = service::encode_connect_reply(service::STATUS_OK, "ok");
+        let (status, text) = service::decode_connect_reply(&reply).unwrap();
+        assert_eq!(status, service::STATUS_OK);
+        assert_eq!(text, "ok");
+    }
+}
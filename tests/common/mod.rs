@@ -0,0 +1,86 @@
+//! Shared helpers for integration tests.
+
+use sss::sim::SimNetwork;
+use sss::{Host, Listener, Stream};
+
+/// Two hosts on a fresh simulated network.
+#[allow(dead_code)]
+pub async fn sim_hosts() -> (Host, Host, SimNetwork) {
+    let net = SimNetwork::new();
+    let a = Host::builder()
+        .sim_socket(net.socket())
+        .build()
+        .await
+        .unwrap();
+    let b = Host::builder()
+        .sim_socket(net.socket())
+        .build()
+        .await
+        .unwrap();
+    (a, b, net)
+}
+
+/// A connected client/server stream pair over a simulated network.
+///
+/// Returns the hosts too: dropping them tears the connection down.
+#[allow(dead_code)]
+pub async fn connected_pair() -> (Host, Host, Stream, Stream, Listener) {
+    let (client, server, _net) = sim_hosts().await;
+    let mut listener = server.listen("test", "v1");
+    let outbound = client
+        .connect(
+            server.local_addr().unwrap(),
+            server.public_key(),
+            "test",
+            "v1",
+        )
+        .await
+        .unwrap();
+    let inbound = listener.accept().await.unwrap();
+    (client, server, outbound, inbound, listener)
+}
+
+/// Two hosts on a fresh simulated network with customized builders.
+#[allow(dead_code)]
+pub async fn sim_hosts_with(
+    a: impl FnOnce(sss::HostBuilder) -> sss::HostBuilder,
+    b: impl FnOnce(sss::HostBuilder) -> sss::HostBuilder,
+) -> (Host, Host, SimNetwork) {
+    let net = SimNetwork::new();
+    let ha = a(Host::builder().sim_socket(net.socket()))
+        .build()
+        .await
+        .unwrap();
+    let hb = b(Host::builder().sim_socket(net.socket()))
+        .build()
+        .await
+        .unwrap();
+    (ha, hb, net)
+}
+
+/// Write all of `data`, looping on the partial counts [`Stream::write`]
+/// returns while the send window fills.
+#[allow(dead_code)]
+pub async fn write_all(stream: &Stream, data: &[u8]) {
+    let mut written = 0;
+    while written < data.len() {
+        written += stream.write(&data[written..]).await.unwrap();
+    }
+}
+
+/// Connect a client/server stream pair over already-built hosts.
+#[allow(dead_code)]
+pub async fn connect_pair(client: &Host, server: &Host) -> (Stream, Stream, Listener) {
+    let mut listener = server.listen("test", "v1");
+    let outbound = client
+        .connect(
+            server.local_addr().unwrap(),
+            server.public_key(),
+            "test",
+            "v1",
+        )
+        .await
+        .unwrap();
+    let inbound = listener.accept().await.unwrap();
+    (outbound, inbound, listener)
+}
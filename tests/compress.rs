@@ -0,0 +1,52 @@
+//! Negotiated stream compression tests.
+
+mod common;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[tokio::test(start_paused = true)]
+async fn compressed_transfer_shrinks_on_the_wire_and_round_trips() {
+    let (client, server, net) =
+        common::sim_hosts_with(|b| b.compression(), |b| b.compression()).await;
+    let (outbound, inbound, _l) = common::connect_pair(&client, &server).await;
+
+    let payload: Vec<u8> = b"all work and no play makes a dull protocol. "
+        .iter()
+        .copied()
+        .cycle()
+        .take(64 * 1024)
+        .collect();
+
+    let before: usize = net.trace().iter().map(|p| p.len).sum();
+    let mut writer = outbound.into_compressed().unwrap();
+    let mut reader = inbound.into_compressed().unwrap();
+    let expected = payload.clone();
+    let sender = tokio::spawn(async move {
+        writer.write_all(&payload).await.unwrap();
+        writer.shutdown().await.unwrap();
+    });
+    let mut got = vec![0u8; expected.len()];
+    reader.read_exact(&mut got).await.unwrap();
+    sender.await.unwrap();
+    assert_eq!(got, expected);
+
+    // 64 KiB of repetitive text must move far fewer bytes than raw, even
+    // counting packet overhead and padding.
+    let wire: usize = net.trace().iter().map(|p| p.len).sum::<usize>() - before;
+    assert!(
+        wire < expected.len() / 4,
+        "{wire} wire bytes for a {} byte compressible payload",
+        expected.len()
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn compression_requires_agreement_from_both_hosts() {
+    // Only the client opts in; the server declines in its SETTINGS reply.
+    let (client, server, _net) = common::sim_hosts_with(|b| b.compression(), |b| b).await;
+    let (outbound, _inbound, _l) = common::connect_pair(&client, &server).await;
+    let Err(err) = outbound.into_compressed() else {
+        panic!("compression wrapped without the server's agreement");
+    };
+    assert!(matches!(err, sss::Error::Protocol(_)), "unexpected: {err:?}");
+}
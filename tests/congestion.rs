@@ -0,0 +1,321 @@
+//! Congestion control behavior tests.
+
+mod common;
+
+use common::{connect_pair, sim_hosts, write_all};
+use sss::sim::Fault;
+
+async fn transfer(outbound: &sss::Stream, inbound: &sss::Stream, len: usize) {
+    let data = vec![0x42u8; len];
+    let receive = async {
+        let mut got = 0;
+        let mut buf = vec![0u8; 64 * 1024];
+        while got < len {
+            got += inbound.read(&mut buf).await.unwrap();
+        }
+    };
+    let ((), ()) = tokio::join!(receive, write_all(outbound, &data));
+}
+
+#[tokio::test(start_paused = true)]
+async fn loss_halves_the_congestion_window() {
+    let (client, server, net) = sim_hosts().await;
+    let (outbound, inbound, _l) = connect_pair(&client, &server).await;
+
+    // Grow the window with a bulk transfer; no loss yet.
+    transfer(&outbound, &inbound, 512 * 1024).await;
+    let prior = outbound.congestion_window().unwrap();
+    assert_eq!(outbound.ssthresh().unwrap(), None, "no loss seen yet");
+
+    // Let the last acks drain so the network is quiet, then drop exactly
+    // the next packet: the first data packet of the following transfer.
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    net.inject(Fault::Drop {
+        nth: net.trace().len() as u64 + 1,
+    });
+    transfer(&outbound, &inbound, 128 * 1024).await;
+
+    let ssthresh = outbound.ssthresh().unwrap().expect("loss was detected");
+    assert!(
+        ssthresh >= prior / 2 && ssthresh <= prior * 3 / 4,
+        "ssthresh {ssthresh} not roughly half of the prior window {prior}"
+    );
+    // The window was pulled back to the threshold and has barely regrown.
+    let window = outbound.congestion_window().unwrap();
+    assert!(window >= ssthresh && window < prior, "window {window}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn cwnd_events_trace_slow_start_and_loss() {
+    use std::sync::{Arc, Mutex};
+
+    use common::sim_hosts_with;
+    use sss::{CwndCause, CwndEvent};
+
+    let events: Arc<Mutex<Vec<CwndEvent>>> = Arc::new(Mutex::new(Vec::new()));
+    let sink = events.clone();
+    let (client, server, net) = sim_hosts_with(
+        move |b| b.on_cwnd_change(move |e| sink.lock().unwrap().push(e)),
+        |b| b,
+    )
+    .await;
+    let (outbound, inbound, _l) = connect_pair(&client, &server).await;
+
+    // Grow the window loss-free, then drop the first packet of a second
+    // transfer to force a pullback.
+    transfer(&outbound, &inbound, 256 * 1024).await;
+    let growth = events.lock().unwrap().len();
+    assert!(growth > 0, "slow start produced no events");
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    net.inject(Fault::Drop {
+        nth: net.trace().len() as u64 + 1,
+    });
+    transfer(&outbound, &inbound, 64 * 1024).await;
+
+    let events = events.lock().unwrap();
+    // Slow start: every early event is ack-driven growth, and consecutive
+    // events chain old -> new.
+    for pair in events[..growth].windows(2) {
+        assert_eq!(pair[1].old, pair[0].new, "events do not chain");
+        assert!(pair[1].at >= pair[0].at, "timestamps go backwards");
+    }
+    for event in &events[..growth] {
+        assert_eq!(event.cause, CwndCause::Ack);
+        assert!(event.new > event.old, "slow start must grow the window");
+    }
+    // The loss shows up as a single shrinking event with a loss cause.
+    let losses: Vec<&CwndEvent> = events
+        .iter()
+        .filter(|e| matches!(e.cause, CwndCause::Loss | CwndCause::Timeout))
+        .collect();
+    assert_eq!(losses.len(), 1, "expected exactly one loss event");
+    assert!(losses[0].new < losses[0].old);
+}
+
+#[tokio::test(start_paused = true)]
+async fn tail_loss_is_recovered_by_a_probe_before_the_rto() {
+    let (client, server, net) = sim_hosts().await;
+    let (outbound, inbound, _l) = connect_pair(&client, &server).await;
+
+    // Establish an RTT estimate, then let the network go quiet.
+    transfer(&outbound, &inbound, 64 * 1024).await;
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    // Drop the next packet. With nothing sent after it there are no later
+    // acks to report it missing, so only a timer can recover it.
+    net.inject(Fault::Drop {
+        nth: net.trace().len() as u64 + 1,
+    });
+    outbound.write(b"tail").await.unwrap();
+    let mut buf = [0u8; 16];
+    let n = inbound.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"tail");
+
+    // The probe retransmission recovered the tail without declaring a loss,
+    // so the congestion controller never pulled the window back.
+    assert_eq!(outbound.ssthresh().unwrap(), None);
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_sole_stream_may_pick_the_congestion_algorithm() {
+    use sss::{CongestionAlgorithm, Error};
+
+    let (client, server, _net) = sim_hosts().await;
+    let (outbound, inbound, _l) = connect_pair(&client, &server).await;
+    assert_eq!(
+        outbound.congestion_algorithm().unwrap(),
+        Some(CongestionAlgorithm::NewReno)
+    );
+    outbound
+        .set_congestion_algorithm(CongestionAlgorithm::Vegas)
+        .unwrap();
+    assert_eq!(
+        outbound.congestion_algorithm().unwrap(),
+        Some(CongestionAlgorithm::Vegas)
+    );
+    // The swapped-in controller carries data just the same.
+    transfer(&outbound, &inbound, 64 * 1024).await;
+
+    // A substream makes the channel shared; the swap is refused.
+    let sub = outbound.open_substream().unwrap();
+    assert!(matches!(
+        sub.set_congestion_algorithm(CongestionAlgorithm::NewReno),
+        Err(Error::ChannelShared)
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn two_bbr_flows_share_a_bottleneck_fairly() {
+    use std::time::Duration;
+
+    use sss::CongestionAlgorithm;
+
+    // BBR's bandwidth filter samples the real clock, so this test runs in
+    // real time against a real-rate bottleneck.
+    let net = sss::sim::SimNetwork::new();
+    let server = sss::Host::builder()
+        .sim_socket(net.socket())
+        .build()
+        .await
+        .unwrap();
+    let mut listener = server.listen("test", "v1");
+    let addr = server.local_addr().unwrap();
+    let key = server.public_key();
+    // 500 KB/s shared by both flows, a 32 KB queue and 10ms propagation
+    // each way: a BDP of about ten kilobytes.
+    net.set_bottleneck(addr, 500_000, 32 * 1024);
+
+    let mut flows = Vec::new();
+    for _ in 0..2 {
+        let client = sss::Host::builder()
+            .sim_socket(net.socket())
+            .build()
+            .await
+            .unwrap();
+        net.set_link_latency(client.local_addr().unwrap(), addr, Duration::from_millis(10));
+        net.set_link_latency(addr, client.local_addr().unwrap(), Duration::from_millis(10));
+        let stream = client.connect(addr, key, "test", "v1").await.unwrap();
+        stream.set_congestion_algorithm(CongestionAlgorithm::Bbr).unwrap();
+        let inbound = listener.accept().await.unwrap();
+        // Discard everything server-side so flow control never binds.
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 64 * 1024];
+            while inbound.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+        });
+        flows.push((client, stream));
+    }
+
+    // Saturate both flows for three seconds.
+    let until = tokio::time::Instant::now() + Duration::from_secs(3);
+    let chunk = vec![0u8; 16 * 1024];
+    let pump = |stream: &sss::Stream| {
+        let stream = stream.sender();
+        let chunk = chunk.clone();
+        async move {
+            while tokio::time::Instant::now() < until {
+                tokio::select! {
+                    r = stream.send(&chunk) => {
+                        if r.is_err() {
+                            break;
+                        }
+                    }
+                    _ = tokio::time::sleep_until(until) => break,
+                }
+            }
+        }
+    };
+    tokio::join!(pump(&flows[0].1), pump(&flows[1].1));
+
+    let first = flows[0].1.acked_offset();
+    let second = flows[1].1.acked_offset();
+    let total = first + second;
+    // The link carried a meaningful share of its 1.5 MB capacity...
+    assert!(total > 400_000, "only {total} bytes crossed the bottleneck");
+    assert!(total < 2_000_000, "{total} bytes exceed the link capacity");
+    // ...and neither flow starved the other: both hold a rough half.
+    let min = first.min(second);
+    assert!(
+        min * 4 >= total,
+        "unfair split: {first} vs {second} bytes delivered"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_rate_limited_stream_stays_under_its_cap() {
+    use std::time::Duration;
+
+    // The token bucket refills against the real clock, so this test runs
+    // in real time: a second of saturating writes through a 100 KB/s cap.
+    let (_client, _server, outbound, inbound, _listener) = common::connected_pair().await;
+    outbound.set_rate_limit(100_000);
+    assert_eq!(outbound.rate_limit(), Some(100_000));
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 64 * 1024];
+        while inbound.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+    });
+
+    let start = std::time::Instant::now();
+    let until = tokio::time::Instant::now() + Duration::from_secs(1);
+    let chunk = vec![0u8; 16 * 1024];
+    loop {
+        tokio::select! {
+            r = outbound.write(&chunk) => r.map(|_| ()).unwrap(),
+            _ = tokio::time::sleep_until(until) => break,
+        }
+    }
+    let elapsed = start.elapsed();
+    let sent = outbound.acked_offset();
+    // At most the cap for the elapsed time plus one 50ms burst...
+    let budget = 100_000.0 * elapsed.as_secs_f64() * 1.1 + 5_000.0;
+    assert!(
+        (sent as f64) <= budget,
+        "{sent} bytes through in {elapsed:?} beats the 100 KB/s cap"
+    );
+    // ...while the limiter's own wakeups keep the stream moving.
+    assert!(sent > 40_000, "only {sent} bytes in {elapsed:?}: stalled");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_rtt_estimator_converges_to_the_link_latency() {
+    use std::time::Duration;
+
+    // RTT samples read the real clock, so this runs in real time over a
+    // 25ms link latency each way: a 50ms round trip, with a little
+    // forward jitter on top.
+    let (client, server, net) = sim_hosts().await;
+    let ca = client.local_addr().unwrap();
+    let sa = server.local_addr().unwrap();
+    net.set_link_latency(ca, sa, Duration::from_millis(25));
+    net.set_link_latency(sa, ca, Duration::from_millis(25));
+    net.set_link_jitter(ca, sa, Duration::from_millis(2));
+    let (outbound, inbound, _l) = connect_pair(&client, &server).await;
+
+    transfer(&outbound, &inbound, 256 * 1024).await;
+
+    let srtt = outbound.smoothed_rtt().unwrap().expect("samples taken");
+    assert!(
+        srtt >= Duration::from_millis(45) && srtt <= Duration::from_millis(120),
+        "SRTT {srtt:?} is far from the 50ms round trip"
+    );
+    let min = outbound.min_rtt().unwrap().expect("samples taken");
+    assert!(
+        min >= Duration::from_millis(45) && min <= srtt.max(min),
+        "min RTT {min:?} undershoots the propagation delay"
+    );
+    assert!(outbound.latest_rtt().unwrap().is_some());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reported_ack_delay_is_subtracted_from_rtt_samples() {
+    use std::time::Duration;
+
+    // The server sits on acks for up to 80ms and scales its delay field
+    // by 2^3 microseconds; the client learns both from SETTINGS. RTT
+    // samples read the real clock, so this runs in real time.
+    let (client, server, net) = common::sim_hosts_with(
+        |b| b,
+        |b| {
+            b.ack_delay_exponent(3)
+                .max_ack_delay(Duration::from_millis(80))
+        },
+    )
+    .await;
+    let ca = client.local_addr().unwrap();
+    let sa = server.local_addr().unwrap();
+    net.set_link_latency(ca, sa, Duration::from_millis(10));
+    net.set_link_latency(sa, ca, Duration::from_millis(10));
+    let (outbound, _inbound, _l) = connect_pair(&client, &server).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // A lone small write gives the server no reason to ack early: its
+    // acknowledgement arrives a whole delayed-ack timer after the 20ms
+    // round trip. The reported delay must cancel back out of the sample.
+    outbound.write(b"ping").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let latest = outbound.latest_rtt().unwrap().expect("the ack was taken");
+    assert!(
+        latest < Duration::from_millis(60),
+        "RTT {latest:?} still contains the peer's 80ms ack delay"
+    );
+}
@@ -0,0 +1,1153 @@
+//! Connection establishment tests.
+
+mod common;
+
+use common::{connected_pair, sim_hosts};
+use sss::{Error, Host};
+
+#[tokio::test(start_paused = true)]
+async fn connect_and_accept() {
+    let (_client, _server, outbound, inbound, _listener) = connected_pair().await;
+    outbound.write(b"ping").await.unwrap();
+    let mut buf = [0u8; 16];
+    let n = inbound.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"ping");
+}
+
+#[tokio::test(start_paused = true)]
+async fn connect_to_unknown_service_refused() {
+    let (client, server, _net) = sim_hosts().await;
+    let err = client
+        .connect(
+            server.local_addr().unwrap(),
+            server.public_key(),
+            "nonexistent",
+            "v1",
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::ServiceRefused { .. }), "got {err:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn connect_to_silent_peer_times_out() {
+    let (client, server, net) = sim_hosts().await;
+    let peer_key = server.public_key();
+    // A socket nobody reads from: handshake packets vanish.
+    let dead = net.socket();
+    drop(server);
+    let err = client
+        .connect(dead.local_addr(), peer_key, "test", "v1")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::Timeout), "got {err:?}");
+}
+
+#[tokio::test]
+async fn connect_over_udp_loopback() {
+    let server = Host::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .build()
+        .await
+        .unwrap();
+    let client = Host::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .build()
+        .await
+        .unwrap();
+    let mut listener = server.listen("echo", "v1");
+    let outbound = client
+        .connect(
+            server.local_addr().unwrap(),
+            server.public_key(),
+            "echo",
+            "v1",
+        )
+        .await
+        .unwrap();
+    let inbound = listener.accept().await.unwrap();
+    outbound.write(b"over real udp").await.unwrap();
+    let mut buf = [0u8; 32];
+    let n = inbound.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"over real udp");
+}
+
+#[tokio::test]
+async fn connect_over_ipv6_loopback() {
+    let server = Host::builder()
+        .bind("[::1]:0".parse().unwrap())
+        .build()
+        .await
+        .unwrap();
+    let client = Host::builder()
+        .bind("[::1]:0".parse().unwrap())
+        .build()
+        .await
+        .unwrap();
+    assert!(server.local_addr().unwrap().is_ipv6());
+    let mut listener = server.listen("echo", "v1");
+    let outbound = client
+        .connect(
+            server.local_addr().unwrap(),
+            server.public_key(),
+            "echo",
+            "v1",
+        )
+        .await
+        .unwrap();
+    let inbound = listener.accept().await.unwrap();
+    outbound.write(b"over v6 loopback").await.unwrap();
+    let mut buf = [0u8; 32];
+    let n = inbound.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"over v6 loopback");
+}
+
+#[tokio::test]
+async fn a_dual_stack_host_serves_both_address_families() {
+    let server = Host::builder().dual_stack().build().await.unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut listener = server.listen("echo", "v1");
+
+    let v4_client = Host::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .build()
+        .await
+        .unwrap();
+    let v6_client = Host::builder()
+        .bind("[::1]:0".parse().unwrap())
+        .build()
+        .await
+        .unwrap();
+
+    let over_v4 = v4_client
+        .connect(
+            format!("127.0.0.1:{port}").parse().unwrap(),
+            server.public_key(),
+            "echo",
+            "v1",
+        )
+        .await
+        .unwrap();
+    let over_v6 = v6_client
+        .connect(
+            format!("[::1]:{port}").parse().unwrap(),
+            server.public_key(),
+            "echo",
+            "v1",
+        )
+        .await
+        .unwrap();
+    let first = listener.accept().await.unwrap();
+    let second = listener.accept().await.unwrap();
+
+    over_v4.write(b"from v4").await.unwrap();
+    over_v6.write(b"from v6").await.unwrap();
+    let mut seen = Vec::new();
+    for inbound in [&first, &second] {
+        let mut buf = [0u8; 16];
+        let n = inbound.read(&mut buf).await.unwrap();
+        seen.push(buf[..n].to_vec());
+    }
+    seen.sort();
+    assert_eq!(seen, [b"from v4".to_vec(), b"from v6".to_vec()]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn configured_connect_timeout_is_honored() {
+    use std::time::Duration;
+
+    let net = sss::sim::SimNetwork::new();
+    let client = Host::builder()
+        .sim_socket(net.socket())
+        .connect_timeout(Duration::from_millis(500))
+        .build()
+        .await
+        .unwrap();
+    let server = Host::builder().sim_socket(net.socket()).build().await.unwrap();
+    let peer_key = server.public_key();
+    // A socket nobody reads from: handshake packets vanish.
+    let dead = net.socket();
+    drop(server);
+
+    let started = tokio::time::Instant::now();
+    let err = client
+        .connect(dead.local_addr(), peer_key, "test", "v1")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::Timeout), "got {err:?}");
+    let elapsed = started.elapsed();
+    assert!(
+        elapsed >= Duration::from_millis(500) && elapsed < Duration::from_secs(2),
+        "timed out after {elapsed:?}, expected about 500ms"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn per_call_timeout_overrides_the_default() {
+    use std::time::Duration;
+
+    let (client, server, net) = sim_hosts().await;
+    let peer_key = server.public_key();
+    let dead = net.socket();
+    drop(server);
+
+    let started = tokio::time::Instant::now();
+    let err = client
+        .connect_with_timeout(
+            dead.local_addr(),
+            peer_key,
+            "test",
+            "v1",
+            Duration::from_millis(200),
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::Timeout), "got {err:?}");
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn local_endpoints_resolve_ephemeral_ports() {
+    let host = Host::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .build()
+        .await
+        .unwrap();
+    let endpoints = host.local_endpoints();
+    assert_eq!(endpoints.len(), 1);
+    assert_ne!(endpoints[0].port(), 0, "ephemeral port was not resolved");
+    assert_eq!(endpoints[0].ip(), "127.0.0.1".parse::<std::net::IpAddr>().unwrap());
+}
+
+#[tokio::test(start_paused = true)]
+async fn single_policy_reuses_the_channel() {
+    let (client, server, _net) = sim_hosts().await;
+    let mut listener = server.listen("test", "v1");
+    let addr = server.local_addr().unwrap();
+    let key = server.public_key();
+    let first = client.connect(addr, key, "test", "v1").await.unwrap();
+    let second = client.connect(addr, key, "test", "v1").await.unwrap();
+    assert_eq!(client.channels().len(), 1, "expected one shared channel");
+
+    // Both streams are usable independently.
+    first.write(b"one").await.unwrap();
+    second.write(b"two").await.unwrap();
+    let in_first = listener.accept().await.unwrap();
+    let in_second = listener.accept().await.unwrap();
+    let mut buf = [0u8; 8];
+    let n = in_first.read(&mut buf).await.unwrap();
+    assert_eq!(n, 3);
+    let n = in_second.read(&mut buf).await.unwrap();
+    assert_eq!(n, 3);
+}
+
+#[tokio::test(start_paused = true)]
+async fn multi_policy_creates_distinct_channels() {
+    use sss::ChannelPolicy;
+
+    let net = sss::sim::SimNetwork::new();
+    let client = Host::builder()
+        .sim_socket(net.socket())
+        .channel_policy(ChannelPolicy::Multi)
+        .build()
+        .await
+        .unwrap();
+    let server = Host::builder().sim_socket(net.socket()).build().await.unwrap();
+    let mut listener = server.listen("test", "v1");
+    let addr = server.local_addr().unwrap();
+    let key = server.public_key();
+    let _first = client.connect(addr, key, "test", "v1").await.unwrap();
+    let _second = client.connect(addr, key, "test", "v1").await.unwrap();
+    let _in1 = listener.accept().await.unwrap();
+    let _in2 = listener.accept().await.unwrap();
+
+    let channels = client.channels();
+    assert_eq!(channels.len(), 2, "expected two distinct channels");
+    assert_ne!(channels[0].id, channels[1].id);
+    assert!(channels.iter().all(|c| c.peer == Some(key)));
+    assert_eq!(server.channels().len(), 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn go_away_refuses_new_substreams_but_drains_existing() {
+    let (_client, server, outbound, inbound, _listener) = connected_pair().await;
+    let sub = outbound.open_substream().unwrap();
+    sub.write(b"in flight").await.unwrap();
+    let sub_in = inbound.accept_substream().await.unwrap();
+
+    // The server announces its shutdown; wait for the client to hear it.
+    server.go_away();
+    for _ in 0..1_000 {
+        if matches!(outbound.open_substream(), Err(Error::GoingAway)) {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+    }
+    assert!(matches!(outbound.open_substream(), Err(Error::GoingAway)));
+
+    // The substream opened before the announcement still completes.
+    sub.write(b" and done").await.unwrap();
+    sub.close().await.unwrap();
+    let mut buf = [0u8; 32];
+    let mut got = Vec::new();
+    loop {
+        let n = sub_in.read(&mut buf).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        got.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(got, b"in flight and done");
+}
+
+#[tokio::test(start_paused = true)]
+async fn resetting_the_connection_fails_every_stream_with_the_code() {
+    let (_client, _server, outbound, inbound, _listener) = connected_pair().await;
+    let sub = outbound.open_substream().unwrap();
+    sub.write(b"doomed").await.unwrap();
+    let sub_in = inbound.accept_substream().await.unwrap();
+
+    outbound.reset_connection(42, "unrecoverable").unwrap();
+
+    // Both local streams fail with the reset reason once the channel is
+    // gone, and both remote ones once the CLOSE arrives.
+    let reset = |e: &Error| matches!(e, Error::ConnectionReset { code: 42, .. });
+    for stream in [&outbound, &sub, &inbound, &sub_in] {
+        let mut seen = None;
+        for _ in 0..1_000 {
+            match stream.try_write(b"x") {
+                Err(e) if reset(&e) => {
+                    seen = Some(e);
+                    break;
+                }
+                _ => tokio::time::sleep(std::time::Duration::from_millis(1)).await,
+            }
+        }
+        let seen = seen.expect("stream never saw the connection reset");
+        assert_eq!(seen.to_string(), "connection reset (code 42): unrecoverable");
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn seeded_hosts_produce_identical_connection_ids() {
+    use rand::SeedableRng;
+
+    // Two isolated worlds, the clients seeded identically: the handshakes
+    // replay byte for byte, down to the connection id the server indexes.
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let net = sss::sim::SimNetwork::new();
+        let client = sss::Host::builder()
+            .sim_socket(net.socket())
+            .rng(rand::rngs::StdRng::seed_from_u64(7))
+            .build()
+            .await
+            .unwrap();
+        let server = sss::Host::builder()
+            .sim_socket(net.socket())
+            .build()
+            .await
+            .unwrap();
+        let _listener = server.listen("test", "v1");
+        let outbound = client
+            .connect(
+                server.local_addr().unwrap(),
+                server.public_key(),
+                "test",
+                "v1",
+            )
+            .await
+            .unwrap();
+        outbound.write(b"hi").await.unwrap();
+        let info = server.channels();
+        ids.push((client.public_key(), info[0].id));
+    }
+    assert_eq!(ids[0].0, ids[1].0, "identities diverged across seeds");
+    assert_eq!(ids[0].1, ids[1].1, "connection ids diverged across seeds");
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_reordered_handshake_still_completes_promptly() {
+    use std::time::Duration;
+
+    let (client, server, net) = sim_hosts().await;
+    // Hold the INITIATE (network-wide packet 3, after HELLO and COOKIE)
+    // back until the client's first MESSAGE has been delivered, so the
+    // server sees a MESSAGE for a channel it does not know yet.
+    net.inject(sss::sim::Fault::Reorder { nth: 3 });
+    let started = tokio::time::Instant::now();
+    let mut listener = server.listen("test", "v1");
+    let outbound = client
+        .connect(
+            server.local_addr().unwrap(),
+            server.public_key(),
+            "test",
+            "v1",
+        )
+        .await
+        .unwrap();
+    let inbound = listener.accept().await.unwrap();
+    outbound.write(b"out of order").await.unwrap();
+    let mut buf = [0u8; 16];
+    let n = inbound.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"out of order");
+    // The stashed MESSAGE is replayed when the INITIATE lands; nothing
+    // waits out a retransmission timer.
+    assert!(
+        started.elapsed() < Duration::from_millis(300),
+        "handshake took {:?}",
+        started.elapsed()
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_accept_rate_limit_refuses_a_burst_from_one_address() {
+    use std::time::Duration;
+
+    let net = sss::sim::SimNetwork::new();
+    let server = Host::builder()
+        .sim_socket(net.socket())
+        .accept_rate_limit(2, Duration::from_secs(60))
+        .build()
+        .await
+        .unwrap();
+    // Every connect dials its own channel, so one host can burst.
+    let client = Host::builder()
+        .sim_socket(net.socket())
+        .channel_policy(sss::ChannelPolicy::Multi)
+        .connect_timeout(Duration::from_millis(500))
+        .build()
+        .await
+        .unwrap();
+    let _listener = server.listen("test", "v1");
+    let addr = server.local_addr().unwrap();
+    let key = server.public_key();
+
+    let first = client.connect(addr, key, "test", "v1").await.unwrap();
+    let second = client.connect(addr, key, "test", "v1").await.unwrap();
+    let err = client.connect(addr, key, "test", "v1").await.unwrap_err();
+    assert!(matches!(err, Error::Timeout), "got {err:?}");
+    drop((first, second));
+
+    // Another address is not affected by the busy one's burst.
+    let other = Host::builder()
+        .sim_socket(net.socket())
+        .connect_timeout(Duration::from_millis(500))
+        .build()
+        .await
+        .unwrap();
+    other.connect(addr, key, "test", "v1").await.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn reuseport_hosts_share_a_port_and_both_accept() {
+    use std::time::Duration;
+
+    let identity = sss::Identity::generate();
+    let first = Host::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .reuse_port()
+        .identity(identity.clone())
+        .build()
+        .await
+        .unwrap();
+    let addr = first.local_addr().unwrap();
+    // The replacement binds the very same port while the first still runs.
+    let second = Host::builder()
+        .bind(addr)
+        .reuse_port()
+        .identity(identity)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(second.local_addr().unwrap(), addr);
+
+    let mut first_listener = first.listen("echo", "v1");
+    let mut second_listener = second.listen("echo", "v1");
+    // The kernel spreads flows across the sharing sockets by source port;
+    // every connect must land on a working host either way.
+    let mut clients = Vec::new();
+    for _ in 0..16 {
+        let client = Host::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .build()
+            .await
+            .unwrap();
+        let stream = client
+            .connect(addr, first.public_key(), "echo", "v1")
+            .await
+            .unwrap();
+        clients.push((client, stream));
+    }
+    let mut accepted = [0usize; 2];
+    for (i, listener) in [&mut first_listener, &mut second_listener].into_iter().enumerate() {
+        while let Ok(Some(_)) =
+            tokio::time::timeout(Duration::from_millis(100), listener.accept()).await
+        {
+            accepted[i] += 1;
+        }
+    }
+    assert_eq!(accepted[0] + accepted[1], 16);
+    assert!(
+        accepted[0] > 0 && accepted[1] > 0,
+        "flows did not spread across the sharing sockets: {accepted:?}"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn channel_choice_controls_reuse_and_isolation() {
+    let (client, server, _net) = common::sim_hosts().await;
+    let server_addr = server.local_addr().unwrap();
+    let mut listener = server.listen("test", "v1");
+
+    // No channel exists yet: requiring one fails without negotiating.
+    let err = client
+        .connect_with_channel(
+            server_addr,
+            server.public_key(),
+            "test",
+            "v1",
+            sss::ChannelChoice::Existing,
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(err, sss::Error::ConnectionClosed), "got {err:?}");
+    assert!(client.channels().is_empty());
+
+    // A first connect negotiates; a reusing connect rides the same channel.
+    let _first = client
+        .connect(server_addr, server.public_key(), "test", "v1")
+        .await
+        .unwrap();
+    let _first_in = listener.accept().await.unwrap();
+    let _second = client
+        .connect_with_channel(
+            server_addr,
+            server.public_key(),
+            "test",
+            "v1",
+            sss::ChannelChoice::Reuse,
+        )
+        .await
+        .unwrap();
+    let _second_in = listener.accept().await.unwrap();
+    assert_eq!(client.channels().len(), 1);
+
+    // Forcing a new channel isolates the third stream on a second one.
+    let _third = client
+        .connect_with_channel(
+            server_addr,
+            server.public_key(),
+            "test",
+            "v1",
+            sss::ChannelChoice::New,
+        )
+        .await
+        .unwrap();
+    let _third_in = listener.accept().await.unwrap();
+    assert_eq!(client.channels().len(), 2);
+
+    // And with a channel up, requiring one now succeeds on it.
+    let _fourth = client
+        .connect_with_channel(
+            server_addr,
+            server.public_key(),
+            "test",
+            "v1",
+            sss::ChannelChoice::Existing,
+        )
+        .await
+        .unwrap();
+    assert_eq!(client.channels().len(), 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_third_handshake_queues_until_a_slot_frees() {
+    let net = sss::sim::SimNetwork::new();
+    let server = Host::builder()
+        .sim_socket(net.socket())
+        .handshake_limit(2, 4)
+        .build()
+        .await
+        .unwrap();
+    let mut listener = server.listen("test", "v1");
+    let addr = server.local_addr().unwrap();
+    let key = server.public_key();
+
+    // Two peers behind a slow return path occupy both handshake slots:
+    // their COOKIEs take a second to come back, so their INITIATEs -- and
+    // the slots they release -- do too.
+    let mut slow = Vec::new();
+    for _ in 0..2 {
+        let peer = Host::builder()
+            .sim_socket(net.socket())
+            .build()
+            .await
+            .unwrap();
+        net.set_link_latency(
+            addr,
+            peer.local_addr().unwrap(),
+            std::time::Duration::from_secs(1),
+        );
+        slow.push(peer);
+    }
+    let client = Host::builder()
+        .sim_socket(net.socket())
+        .build()
+        .await
+        .unwrap();
+
+    let (first, second, (third, waited)) = tokio::join!(
+        slow[0].connect(addr, key, "test", "v1"),
+        slow[1].connect(addr, key, "test", "v1"),
+        async {
+            // Arrive after both slots are held; the HELLO is queued until
+            // a slow peer's INITIATE frees a slot at the one-second mark.
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            let started = tokio::time::Instant::now();
+            let stream = client.connect(addr, key, "test", "v1").await;
+            (stream, started.elapsed())
+        }
+    );
+    first.unwrap();
+    second.unwrap();
+    third.unwrap();
+    assert!(
+        waited >= std::time::Duration::from_millis(800),
+        "third handshake was not held back: {waited:?}"
+    );
+    assert!(
+        waited < std::time::Duration::from_millis(1900),
+        "third handshake missed the freed slot: {waited:?}"
+    );
+    for _ in 0..3 {
+        listener.accept().await.unwrap();
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn wakeup_fd_becomes_readable_on_an_incoming_packet() {
+    use std::io::Read;
+    use std::os::fd::{AsRawFd, BorrowedFd};
+    use std::time::Duration;
+
+    let host = Host::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .build()
+        .await
+        .unwrap();
+    assert!(host.as_raw_fd().is_some(), "UDP hosts export the socket fd");
+    let wakeup = tokio::io::unix::AsyncFd::with_interest(
+        host.wakeup_fd(),
+        tokio::io::Interest::READABLE,
+    )
+    .unwrap();
+
+    // A quiet host signals nothing.
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), wakeup.readable())
+            .await
+            .is_err(),
+        "wakeup fd readable before any packet arrived"
+    );
+
+    let sender = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    sender
+        .send_to(b"hello there, sss", host.local_addr().unwrap())
+        .await
+        .unwrap();
+
+    let mut guard = tokio::time::timeout(Duration::from_secs(2), wakeup.readable())
+        .await
+        .expect("wakeup fd never became readable")
+        .unwrap();
+    let n = guard
+        .try_io(|fd| {
+            let owned = unsafe { BorrowedFd::borrow_raw(fd.as_raw_fd()) }.try_clone_to_owned()?;
+            let mut buf = [0u8; 8];
+            std::fs::File::from(owned).read(&mut buf)
+        })
+        .unwrap()
+        .unwrap();
+    assert!(n >= 1, "expected a signal byte");
+}
+
+#[tokio::test(start_paused = true)]
+async fn sim_hosts_export_no_socket_fd() {
+    let (client, _server, _net) = sim_hosts().await;
+    #[cfg(unix)]
+    assert!(client.as_raw_fd().is_none());
+    let _ = client;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn dscp_is_applied_to_the_udp_socket() {
+    use std::os::fd::BorrowedFd;
+
+    let host = Host::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .dscp(46) // expedited forwarding
+        .build()
+        .await
+        .unwrap();
+    let fd = unsafe { BorrowedFd::borrow_raw(host.as_raw_fd().unwrap()) };
+    let tos = socket2::SockRef::from(&fd).tos().unwrap();
+    assert_eq!(tos, 46 << 2, "DSCP sits in the upper six TOS bits");
+}
+
+#[test]
+#[should_panic(expected = "six-bit")]
+fn dscp_above_six_bits_is_rejected() {
+    Host::builder().dscp(64);
+}
+
+#[tokio::test(start_paused = true)]
+async fn established_callback_fires_once_per_channel() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    let server_seen = Arc::new(Mutex::new(Vec::new()));
+    let client_fires = Arc::new(AtomicUsize::new(0));
+    let (client, server, _net) = common::sim_hosts_with(
+        |b| {
+            let fires = client_fires.clone();
+            b.on_channel_established(move |_, _| {
+                fires.fetch_add(1, Ordering::SeqCst);
+            })
+        },
+        |b| {
+            let seen = server_seen.clone();
+            b.on_channel_established(move |peer, info| {
+                seen.lock().unwrap().push((*peer, info.remote_addr));
+            })
+        },
+    )
+    .await;
+    let mut listener = server.listen("test", "v1");
+    let addr = server.local_addr().unwrap();
+    let key = server.public_key();
+
+    let first = client.connect(addr, key, "test", "v1").await.unwrap();
+    listener.accept().await.unwrap();
+    // A second connect rides the established channel under the default
+    // single-channel policy, so neither side's callback fires again.
+    let second = client.connect(addr, key, "test", "v1").await.unwrap();
+    listener.accept().await.unwrap();
+    drop((first, second));
+
+    assert_eq!(client_fires.load(Ordering::SeqCst), 1);
+    let seen = server_seen.lock().unwrap();
+    assert_eq!(seen.len(), 1, "server saw {} channels", seen.len());
+    assert_eq!(seen[0].0, client.public_key());
+    assert_eq!(seen[0].1, client.local_addr().unwrap());
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_channel_over_the_cap_is_refused_until_one_closes() {
+    use std::time::Duration;
+
+    let net = sss::sim::SimNetwork::new();
+    let server = Host::builder()
+        .sim_socket(net.socket())
+        .max_channels(2)
+        .build()
+        .await
+        .unwrap();
+    let mut listener = server.listen("test", "v1");
+    let addr = server.local_addr().unwrap();
+    let key = server.public_key();
+
+    let mut clients = Vec::new();
+    for _ in 0..3 {
+        clients.push(
+            Host::builder()
+                .sim_socket(net.socket())
+                .build()
+                .await
+                .unwrap(),
+        );
+    }
+    let _first = clients[0].connect(addr, key, "test", "v1").await.unwrap();
+    let _second = clients[1].connect(addr, key, "test", "v1").await.unwrap();
+    listener.accept().await.unwrap();
+    listener.accept().await.unwrap();
+
+    // Both slots taken: the third client's handshake packets are dropped
+    // until its deadline passes.
+    let err = clients[2]
+        .connect_with_timeout(addr, key, "test", "v1", Duration::from_secs(2))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::Timeout), "got {err:?}");
+
+    // Closing one channel frees its slot for the retry.
+    clients[0].close();
+    let freed = server
+        .run_until(Duration::from_secs(2), || server.channels().len() < 2)
+        .await;
+    assert!(freed, "closed channel did not free its slot");
+    clients[2].connect(addr, key, "test", "v1").await.unwrap();
+    listener.accept().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn connect_beyond_the_channel_cap_fails_locally() {
+    let net = sss::sim::SimNetwork::new();
+    let client = Host::builder()
+        .sim_socket(net.socket())
+        .max_channels(1)
+        .build()
+        .await
+        .unwrap();
+    let mut servers = Vec::new();
+    let mut listeners = Vec::new();
+    for _ in 0..2 {
+        let server = Host::builder()
+            .sim_socket(net.socket())
+            .build()
+            .await
+            .unwrap();
+        listeners.push(server.listen("test", "v1"));
+        servers.push(server);
+    }
+    let _first = client
+        .connect(
+            servers[0].local_addr().unwrap(),
+            servers[0].public_key(),
+            "test",
+            "v1",
+        )
+        .await
+        .unwrap();
+    let err = client
+        .connect(
+            servers[1].local_addr().unwrap(),
+            servers[1].public_key(),
+            "test",
+            "v1",
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::ChannelLimit), "got {err:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_dropped_host_notifies_its_peers_promptly() {
+    use std::time::Duration;
+
+    let (client, _server, outbound, inbound, _l) = connected_pair().await;
+    drop(outbound);
+    drop(client);
+
+    // The final CLOSE reaches the peer at once; without it the stream
+    // would sit until the half-hour idle timeout.
+    let mut buf = [0u8; 8];
+    let read = tokio::time::timeout(Duration::from_secs(1), inbound.read(&mut buf)).await;
+    assert!(
+        read.expect("peer did not observe the close promptly").is_err(),
+        "stream should report the closed connection"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_pinned_connect_sends_from_the_extra_bind() {
+    use sss::sim::SimNetwork;
+
+    let net = SimNetwork::new();
+    let extra = net.socket();
+    let pinned = extra.local_addr();
+    let client = Host::builder()
+        .sim_socket(net.socket())
+        .sim_socket_also(extra)
+        .build()
+        .await
+        .unwrap();
+    let server = Host::builder().sim_socket(net.socket()).build().await.unwrap();
+    let mut listener = server.listen("test", "v1");
+    let server_addr = server.local_addr().unwrap();
+    let primary = client.local_addr().unwrap();
+    assert_eq!(client.local_endpoints(), vec![primary, pinned]);
+
+    let outbound = client
+        .connect_from(pinned, server_addr, server.public_key(), "test", "v1")
+        .await
+        .unwrap();
+    let inbound = listener.accept().await.unwrap();
+    outbound.write(b"from over here").await.unwrap();
+    let mut buf = [0u8; 32];
+    let n = inbound.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"from over here");
+
+    // Every packet of the pinned channel -- handshake included -- left
+    // from the extra bind, and the server answered it there; the primary
+    // socket never spoke.
+    for packet in net.trace() {
+        assert_ne!(packet.from, primary, "primary socket sent a packet");
+        assert_ne!(packet.to, primary, "server answered the primary bind");
+        if packet.to == server_addr {
+            assert_eq!(packet.from, pinned);
+        }
+    }
+
+    // An unknown local address is refused outright.
+    let err = client
+        .connect_from(
+            "10.9.9.9:9".parse().unwrap(),
+            server_addr,
+            server.public_key(),
+            "test",
+            "v1",
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::Io(_)), "got {err:?}");
+}
+
+/// With the peer unreachable, each handshake attempt gives up at the
+/// per-attempt handshake timeout (instead of backing off without bound)
+/// and the connect as a whole fails at the connect timeout.
+#[tokio::test(start_paused = true)]
+async fn handshake_and_connect_timeouts_bound_a_dead_connect() {
+    use std::time::Duration;
+
+    use common::sim_hosts_with;
+
+    let (client, server, net) = sim_hosts_with(
+        |b| {
+            b.handshake_timeout(Duration::from_millis(300))
+                .connect_timeout(Duration::from_secs(2))
+        },
+        |b| b,
+    )
+    .await;
+    let client_addr = client.local_addr().unwrap();
+    let server_addr = server.local_addr().unwrap();
+    net.set_link_down_after(client_addr, server_addr, 0);
+
+    let start = tokio::time::Instant::now();
+    let err = client
+        .connect(server_addr, server.public_key(), "test", "v1")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::Timeout), "got {err:?}");
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed >= Duration::from_secs(2) && elapsed < Duration::from_millis(2200),
+        "connect gave up after {elapsed:?}, not at the 2s connect timeout"
+    );
+    // The 300ms per-attempt cap kept the HELLOs coming: at 0, 250, 550,
+    // 850... ms, where unbounded doubling would have managed only four.
+    let hellos = net.trace().len();
+    assert!(hellos >= 6, "only {hellos} HELLOs went out in two seconds");
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_peer_without_datagram_support_degrades_gracefully() {
+    let (client, server, _net) =
+        common::sim_hosts_with(|b| b, |b| b.disable_datagrams()).await;
+    let mut listener = server.listen("test", "v1");
+    let outbound = client
+        .connect(
+            server.local_addr().unwrap(),
+            server.public_key(),
+            "test",
+            "v1",
+        )
+        .await
+        .unwrap();
+    let inbound = listener.accept().await.unwrap();
+    // Let the responder's SETTINGS confirmation reach the initiator.
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    // Both ends settle on the intersection: no datagrams.
+    assert!(!outbound.features().unwrap().datagrams);
+    assert!(!inbound.features().unwrap().datagrams);
+    assert!(matches!(
+        outbound.send_datagram(b"nope"),
+        Err(Error::DatagramUnsupported)
+    ));
+    assert!(matches!(
+        inbound.send_datagram(b"nope"),
+        Err(Error::DatagramUnsupported)
+    ));
+
+    // Reliable streams are unaffected by the missing feature.
+    outbound.write(b"still reliable").await.unwrap();
+    let mut buf = [0u8; 16];
+    let n = inbound.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"still reliable");
+}
+
+#[tokio::test(start_paused = true)]
+async fn closing_one_service_leaves_the_other_running() {
+    let (client, server, _net) = sim_hosts().await;
+    let mut alpha = server.listen("alpha", "v1");
+    let mut beta = server.listen("beta", "v1");
+    let addr = server.local_addr().unwrap();
+    let key = server.public_key();
+    let a1 = client.connect(addr, key, "alpha", "v1").await.unwrap();
+    let a2 = client.connect(addr, key, "alpha", "v1").await.unwrap();
+    let b = client.connect(addr, key, "beta", "v1").await.unwrap();
+    let a1_in = alpha.accept().await.unwrap();
+    let _a2_in = alpha.accept().await.unwrap();
+    let b_in = beta.accept().await.unwrap();
+
+    assert_eq!(server.close_streams_matching(Some("alpha"), None, 9), 2);
+    // A second pass finds nothing left to close.
+    assert_eq!(server.close_streams_matching(Some("alpha"), None, 9), 0);
+
+    // Both alpha streams surface the reset on the far end.
+    for stream in [&a1, &a2] {
+        let mut buf = [0u8; 8];
+        match stream.read(&mut buf).await {
+            Err(Error::StreamReset { code: 9, .. }) => {}
+            other => panic!("expected a code-9 reset, got {other:?}"),
+        }
+    }
+    assert!(matches!(a1_in.try_write(b"x"), Err(Error::StreamReset { .. })));
+
+    // The beta stream is untouched in both directions.
+    b.write(b"ping").await.unwrap();
+    let mut buf = [0u8; 8];
+    let n = b_in.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"ping");
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_filling_accept_queue_lowers_the_advertised_stream_limit() {
+    let (client, server, _net) = sim_hosts().await;
+    let mut listener = server.listen("test", "v1");
+    let addr = server.local_addr().unwrap();
+    let key = server.public_key();
+
+    // Nobody accepts, so each connect eats one accept-queue slot and the
+    // server's advertised headroom shrinks one step at a time.
+    let mut streams = Vec::new();
+    let mut seen = Vec::new();
+    for _ in 0..3 {
+        streams.push(client.connect(addr, key, "test", "v1").await.unwrap());
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        seen.push(client.channels()[0].stream_limit.expect("limit advertised"));
+    }
+    assert_eq!(seen[1], seen[0] - 1);
+    assert_eq!(seen[2], seen[0] - 2);
+
+    // Fill the queue completely: the advertised limit reaches zero and the
+    // client refuses further connects locally, before they hit the wire.
+    for _ in 0..seen[2] {
+        streams.push(client.connect(addr, key, "test", "v1").await.unwrap());
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(client.channels()[0].stream_limit, Some(0));
+    assert!(matches!(
+        client.connect(addr, key, "test", "v1").await,
+        Err(Error::SubstreamLimit)
+    ));
+
+    // Draining the queue raises the limit again and connects resume.
+    listener.accept().await.unwrap();
+    listener.accept().await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(client.channels()[0].stream_limit, Some(2));
+    streams.push(client.connect(addr, key, "test", "v1").await.unwrap());
+}
+
+#[tokio::test(start_paused = true)]
+async fn authenticated_headers_interoperate_when_both_ends_enable() {
+    let (client, server, _net) = common::sim_hosts_with(
+        |b| b.authenticate_headers(),
+        |b| b.authenticate_headers(),
+    )
+    .await;
+    let mut listener = server.listen("test", "v1");
+    let outbound = client
+        .connect(
+            server.local_addr().unwrap(),
+            server.public_key(),
+            "test",
+            "v1",
+        )
+        .await
+        .unwrap();
+    let inbound = listener.accept().await.unwrap();
+    outbound.write(b"tagged headers").await.unwrap();
+    let mut buf = [0u8; 16];
+    let n = inbound.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"tagged headers");
+}
+
+#[tokio::test(start_paused = true)]
+async fn connection_params_reflect_the_peer_advertisements() {
+    use std::time::Duration;
+
+    let (client, server, _net) = common::sim_hosts_with(
+        |b| b.idle_timeout(Duration::from_secs(7)).compression(),
+        |b| {
+            b.idle_timeout(Duration::from_secs(9))
+                .compression()
+                .disable_datagrams()
+                .max_substreams(5)
+        },
+    )
+    .await;
+    let mut listener = server.listen("test", "v1");
+    let outbound = client
+        .connect(
+            server.local_addr().unwrap(),
+            server.public_key(),
+            "test",
+            "v1",
+        )
+        .await
+        .unwrap();
+    let inbound = listener.accept().await.unwrap();
+    // Let the SETTINGS exchange complete in both directions.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let at_client = outbound.connection_params().unwrap();
+    let at_server = inbound.connection_params().unwrap();
+
+    // Each end reports the idle timeout the *other* end advertised.
+    assert_eq!(at_client.peer_idle_timeout, Some(Duration::from_secs(9)));
+    assert_eq!(at_server.peer_idle_timeout, Some(Duration::from_secs(7)));
+
+    // Feature flags settle on the intersection, seen identically from
+    // both sides.
+    for params in [&at_client, &at_server] {
+        assert_eq!(params.version, 1);
+        assert!(params.compression);
+        assert!(!params.datagrams);
+        assert!(!params.ecn);
+        assert!(params.initial_recv_window > 0);
+        assert!(params.initial_send_window > 0);
+    }
+
+    // The substream cap is a local limit, not a negotiated one.
+    assert_eq!(at_server.max_substreams, 5);
+    assert_ne!(at_client.max_substreams, 5);
+}
+
+#[tokio::test(start_paused = true)]
+async fn listeners_enumerate_the_dispatch_table() {
+    let (_client, server, _net) = sim_hosts().await;
+    assert!(server.listeners().is_empty());
+
+    let _a = server.listen("chat", "v1");
+    let _b = server.listen("chat", "v2");
+    let _c = server.listen("files", "v1");
+    // A wildcard registration is just another literal key, listed as-is.
+    let _d = server.listen("*", "v1");
+
+    assert_eq!(
+        server.listeners(),
+        vec![
+            ("*".to_string(), "v1".to_string()),
+            ("chat".to_string(), "v1".to_string()),
+            ("chat".to_string(), "v2".to_string()),
+            ("files".to_string(), "v1".to_string()),
+        ]
+    );
+}
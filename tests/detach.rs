@@ -0,0 +1,117 @@
+//! Detach-on-idle tests: streams hibernate across channel teardown and
+//! revive on a fresh channel with their offsets intact.
+
+mod common;
+
+use std::time::Duration;
+
+use common::sim_hosts_with;
+use sss::HostBuilder;
+
+fn tune(b: HostBuilder) -> HostBuilder {
+    b.detach_on_idle(true)
+        .idle_timeout(Duration::from_millis(200))
+}
+
+/// Poll until the host has no live channels, or fail after a few seconds.
+async fn wait_for_teardown(host: &sss::Host) {
+    for _ in 0..5_000 {
+        if host.channels().is_empty() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    panic!("channel did not idle out");
+}
+
+#[tokio::test(start_paused = true)]
+async fn streams_survive_an_idle_channel_teardown() {
+    let (client, server, _net) = sim_hosts_with(tune, tune).await;
+    let (outbound, inbound, _listener) = common::connect_pair(&client, &server).await;
+    outbound.write(b"before").await.unwrap();
+    let mut buf = [0u8; 64];
+    assert_eq!(inbound.read(&mut buf).await.unwrap(), 6);
+
+    wait_for_teardown(&client).await;
+
+    // Writing wakes the hibernated stream, which dials a fresh channel and
+    // resumes at the old offset.
+    outbound.write(b" and after").await.unwrap();
+    let n = inbound.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b" and after");
+    assert_eq!(client.channels().len(), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_responder_side_revives_too() {
+    let (client, server, _net) = sim_hosts_with(tune, tune).await;
+    let (outbound, inbound, _listener) = common::connect_pair(&client, &server).await;
+    outbound.write(b"ping").await.unwrap();
+    let mut buf = [0u8; 64];
+    assert_eq!(inbound.read(&mut buf).await.unwrap(), 4);
+
+    wait_for_teardown(&server).await;
+
+    // Only the initiator redials; the responder's hibernated stream is
+    // merged back in when the attach INIT carries the old stream's USID.
+    outbound.write(b"again").await.unwrap();
+    assert_eq!(inbound.read(&mut buf).await.unwrap(), 5);
+    inbound.write(b"pong").await.unwrap();
+    let n = outbound.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"pong");
+}
+
+#[tokio::test(start_paused = true)]
+async fn idle_teardown_still_closes_streams_by_default() {
+    let tune = |b: HostBuilder| b.idle_timeout(Duration::from_millis(200));
+    let (client, server, _net) = sim_hosts_with(tune, tune).await;
+    let (outbound, inbound, _listener) = common::connect_pair(&client, &server).await;
+    outbound.write(b"hello").await.unwrap();
+    let mut buf = [0u8; 64];
+    assert_eq!(inbound.read(&mut buf).await.unwrap(), 5);
+
+    wait_for_teardown(&client).await;
+    assert!(outbound.write(b"too late").await.is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_reattached_channel_warm_starts_congestion_control() {
+    use std::sync::{Arc, Mutex};
+
+    let events: Arc<Mutex<Vec<sss::CwndEvent>>> = Arc::new(Mutex::new(Vec::new()));
+    let sink = events.clone();
+    let tune_client = move |b: HostBuilder| {
+        tune(b).on_cwnd_change(move |e| sink.lock().unwrap().push(e))
+    };
+    let (client, server, _net) = sim_hosts_with(tune_client, tune).await;
+    let (outbound, inbound, _listener) = common::connect_pair(&client, &server).await;
+
+    // Grow the window well past cold start before letting the channel idle.
+    let payload = vec![0xcd; 200 * 1024];
+    common::write_all(&outbound, &payload).await;
+    let mut buf = [0u8; 16 * 1024];
+    let mut total = 0;
+    while total < payload.len() {
+        total += inbound.read(&mut buf).await.unwrap();
+    }
+    wait_for_teardown(&client).await;
+    events.lock().unwrap().clear();
+
+    // Reattaching dials a fresh channel, which starts from the old one's
+    // estimates rather than the initial window.
+    outbound.write(b"after migration").await.unwrap();
+    let n = inbound.read(&mut buf).await.unwrap();
+    assert_eq!(n, 15);
+    for _ in 0..5_000 {
+        if !events.lock().unwrap().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    let first = events.lock().unwrap().first().copied().unwrap();
+    assert!(
+        first.old > 50 * 1024,
+        "expected a warm-started window, got {} bytes",
+        first.old
+    );
+}
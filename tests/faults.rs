@@ -0,0 +1,345 @@
+//! Scripted fault injection tests.
+
+mod common;
+
+use common::sim_hosts;
+use sss::sim::Fault;
+
+/// Packets one and two are HELLO and COOKIE; number three is the INITIATE
+/// carrying the first channel packet.
+#[tokio::test(start_paused = true)]
+async fn dropped_packet_is_retransmitted() {
+    let (client, server, net) = sim_hosts().await;
+    net.inject(Fault::Drop { nth: 3 });
+    let mut listener = server.listen("test", "v1");
+    let outbound = client
+        .connect(
+            server.local_addr().unwrap(),
+            server.public_key(),
+            "test",
+            "v1",
+        )
+        .await
+        .unwrap();
+    let inbound = listener.accept().await.unwrap();
+    outbound.write(b"survived the drop").await.unwrap();
+    let mut buf = [0u8; 32];
+    let n = inbound.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"survived the drop");
+}
+
+#[tokio::test(start_paused = true)]
+async fn corrupted_packet_is_rejected_and_recovered() {
+    let (client, server, net) = sim_hosts().await;
+    let (outbound, inbound, _l) = common::connect_pair(&client, &server).await;
+    // Corrupt a byte inside the next packet's message box.
+    net.inject(Fault::Corrupt {
+        nth: net.trace().len() as u64 + 1,
+        offset: 60,
+    });
+    outbound.write(b"integrity matters").await.unwrap();
+    let mut buf = [0u8; 32];
+    let n = inbound.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"integrity matters");
+}
+
+#[tokio::test(start_paused = true)]
+async fn reordered_pair_is_reassembled() {
+    let (client, server, net) = sim_hosts().await;
+    let (outbound, inbound, _l) = common::connect_pair(&client, &server).await;
+    net.inject(Fault::Reorder {
+        nth: net.trace().len() as u64 + 1,
+    });
+    outbound.write(b"first half, ").await.unwrap();
+    outbound.write(b"second half").await.unwrap();
+    let mut got = Vec::new();
+    let mut buf = [0u8; 64];
+    while got.len() < 23 {
+        let n = inbound.read(&mut buf).await.unwrap();
+        got.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(got, b"first half, second half");
+}
+
+/// A server must not amplify traffic toward an address that has never
+/// proven it can receive our packets: after the INITIATE, everything from
+/// the "client" is dropped, so the server may send at most three times the
+/// bytes it received from that (potentially spoofed) address.
+#[tokio::test(start_paused = true)]
+async fn unvalidated_address_is_not_amplified() {
+    let (client, server, net) = sim_hosts().await;
+    let client_addr = client.local_addr().unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let _listener = server.listen("test", "v1");
+
+    // Let the HELLO and the INITIATE through, then cut the return path so
+    // the server never hears from the address again.
+    net.set_link_down_after(client_addr, server_addr, 3);
+    let _ = client
+        .connect(server_addr, server.public_key(), "test", "v1")
+        .await;
+
+    // Give retransmission timers ample time to fire.
+    tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+    let received: usize = net
+        .trace()
+        .iter()
+        .filter(|p| p.from == client_addr && p.to == server_addr)
+        .take(2) // only the HELLO and the INITIATE reached the server
+        .map(|p| p.len)
+        .sum();
+    let sent: usize = net
+        .trace()
+        .iter()
+        .filter(|p| p.from == server_addr && p.to == client_addr)
+        .map(|p| p.len)
+        .sum();
+    assert!(
+        sent <= 3 * received,
+        "server sent {sent} bytes against {received} received"
+    );
+}
+
+/// With every packet lost, a channel configured with a retransmit limit
+/// declares the peer unresponsive after the configured number of timeouts
+/// instead of backing off forever. Real time: retransmission deadlines run
+/// on the monotonic clock.
+#[tokio::test(flavor = "multi_thread")]
+async fn total_loss_gives_up_after_the_retransmit_limit() {
+    let (client, server, net) = common::sim_hosts_with(|b| b.max_retransmits(2), |b| b).await;
+    let client_addr = client.local_addr().unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let (outbound, _inbound, _l) = common::connect_pair(&client, &server).await;
+
+    // Cut both directions, then send into the void: nothing will ever be
+    // acknowledged again.
+    let sent_so_far = net.trace().len() as u64;
+    net.set_link_down_after(client_addr, server_addr, sent_so_far);
+    net.set_link_down_after(server_addr, client_addr, sent_so_far);
+    common::write_all(&outbound, &vec![0x5a; 64 * 1024]).await;
+
+    // After the first timeout the lost chunks sit requeued, visible as
+    // retransmission queue depth (the collapsed window resends only a
+    // couple of them at a time).
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    let channels = client.channels();
+    assert_eq!(channels.len(), 1);
+    assert!(
+        channels[0].rtx_queued > 0,
+        "expected a retransmission backlog under total loss"
+    );
+
+    // Two strikes are allowed; the third retransmission timeout kills the
+    // channel (about 200 + 400 + 800 ms of backoff) and pending reads fail.
+    let err = tokio::time::timeout(std::time::Duration::from_secs(10), async {
+        let mut buf = [0u8; 64];
+        outbound.read(&mut buf).await.unwrap_err()
+    })
+    .await
+    .expect("channel kept retransmitting past its limit");
+    assert!(
+        matches!(&err, sss::Error::ConnectionReset { reason, .. } if reason.contains("retransmit")),
+        "unexpected teardown error: {err:?}"
+    );
+    assert!(client.channels().is_empty());
+}
+
+/// HELLO packets one and two never arrive; the third attempt (after 250 ms
+/// and 500 ms of backoff) completes the handshake.
+#[tokio::test(start_paused = true)]
+async fn the_third_handshake_attempt_succeeds_after_two_drops() {
+    let (client, server, net) =
+        common::sim_hosts_with(|b| b.handshake_attempts(3), |b| b).await;
+    net.inject(Fault::Drop { nth: 1 });
+    net.inject(Fault::Drop { nth: 2 });
+    let (outbound, inbound, _l) = common::connect_pair(&client, &server).await;
+    outbound.write(b"third time lucky").await.unwrap();
+    let mut buf = [0u8; 32];
+    let n = inbound.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"third time lucky");
+}
+
+/// With the server unreachable, a bounded handshake gives up with a
+/// timeout after its last backoff interval instead of retrying until the
+/// connect deadline.
+#[tokio::test(start_paused = true)]
+async fn a_bounded_handshake_times_out_against_a_dead_server() {
+    let (client, server, net) =
+        common::sim_hosts_with(|b| b.handshake_attempts(2), |b| b).await;
+    let client_addr = client.local_addr().unwrap();
+    let server_addr = server.local_addr().unwrap();
+    net.set_link_down_after(client_addr, server_addr, 0);
+    let _listener = server.listen("test", "v1");
+
+    let started = tokio::time::Instant::now();
+    let err = client
+        .connect(server_addr, server.public_key(), "test", "v1")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, sss::Error::Timeout), "unexpected: {err:?}");
+    // Two attempts: 250 ms + 500 ms of backoff, far short of the 10 s
+    // connect deadline.
+    assert!(started.elapsed() < std::time::Duration::from_secs(2));
+}
+
+#[tokio::test(start_paused = true)]
+async fn datagrams_survive_loss_without_redelivery() {
+    use std::time::Duration;
+
+    let (client, server, net) = sim_hosts().await;
+    let mut listener = server.listen("test", "v1");
+    let outbound = client
+        .connect(
+            server.local_addr().unwrap(),
+            server.public_key(),
+            "test",
+            "v1",
+        )
+        .await
+        .unwrap();
+    let inbound = listener.accept().await.unwrap();
+    // Let the handshake traffic drain so each datagram below is the very
+    // next packet on the network.
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    for i in 0u8..5 {
+        match i {
+            // The third datagram's packet is dropped outright; the
+            // fifth's is duplicated in flight.
+            2 => net.inject(Fault::Drop {
+                nth: net.trace().len() as u64 + 1,
+            }),
+            4 => net.inject(Fault::Duplicate {
+                nth: net.trace().len() as u64 + 1,
+            }),
+            _ => {}
+        }
+        outbound.send_datagram(&[i; 64]).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    // The survivors arrive whole and exactly once; the lost one is
+    // gone for good -- nothing retransmits it.
+    let mut got = Vec::new();
+    while let Ok(Ok(data)) =
+        tokio::time::timeout(Duration::from_secs(2), inbound.recv_datagram()).await
+    {
+        got.push(data);
+    }
+    let expect: Vec<Vec<u8>> = [0u8, 1, 3, 4].iter().map(|&i| vec![i; 64]).collect();
+    assert_eq!(got, expect);
+}
+
+/// A peer that vanishes mid-transfer (power loss, not a close) leaves the
+/// local side with packets outstanding and nothing coming back. With an
+/// unresponsive timeout configured, the channel is declared dead after
+/// that window instead of retransmitting until the idle timeout.
+#[tokio::test(start_paused = true)]
+async fn a_silent_peer_trips_the_unresponsive_timeout() {
+    use std::time::Duration;
+
+    let (client, server, net) = common::sim_hosts_with(
+        |b| b.unresponsive_timeout(Duration::from_secs(3)),
+        |b| b,
+    )
+    .await;
+    let client_addr = client.local_addr().unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let (outbound, _inbound, _l) = common::connect_pair(&client, &server).await;
+
+    // The peer goes dark in both directions, then we send into the void so
+    // the channel has unacknowledged data outstanding.
+    let sent_so_far = net.trace().len() as u64;
+    net.set_link_down_after(client_addr, server_addr, sent_so_far);
+    net.set_link_down_after(server_addr, client_addr, sent_so_far);
+    common::write_all(&outbound, b"anyone there?").await;
+
+    // Well before the timeout the channel is still trying.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(client.channels().len(), 1);
+
+    // Three seconds of silence with data in flight kill the channel.
+    let err = tokio::time::timeout(Duration::from_secs(5), async {
+        let mut buf = [0u8; 64];
+        outbound.read(&mut buf).await.unwrap_err()
+    })
+    .await
+    .expect("channel outlived the unresponsive timeout");
+    assert!(
+        matches!(&err, sss::Error::ConnectionReset { reason, .. } if reason.contains("unresponsive")),
+        "unexpected teardown error: {err:?}"
+    );
+    assert!(client.channels().is_empty());
+}
+
+/// Several streams with reassembly gaps push a channel past its
+/// configured reassembly memory cap; the least-recently-progressed
+/// stream is reset to reclaim its buffers while the others ride out
+/// retransmission.
+#[tokio::test(start_paused = true)]
+async fn the_reassembly_cap_resets_the_stalest_stream() {
+    use std::time::Duration;
+
+    let (client, server, net) =
+        common::sim_hosts_with(|b| b, |b| b.reassembly_limit(4 * 1024)).await;
+    let ca = client.local_addr().unwrap();
+    let sa = server.local_addr().unwrap();
+    // Enough latency that retransmissions cannot heal a gap while the
+    // second stream's backlog is still arriving.
+    net.set_link_latency(ca, sa, Duration::from_millis(100));
+    net.set_link_latency(sa, ca, Duration::from_millis(100));
+    let (outbound, inbound, _l) = common::connect_pair(&client, &server).await;
+
+    let a = outbound.open_substream().unwrap();
+    a.set_nagle(false);
+    a.write(b"x").await.unwrap();
+    let peer_a = inbound.accept_substream().await.unwrap();
+    let b = outbound.open_substream().unwrap();
+    b.set_nagle(false);
+    b.write(b"x").await.unwrap();
+    let peer_b = inbound.accept_substream().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // Open a gap on each stream: the head of its burst is dropped, the
+    // rest arrives as out-of-order reassembly memory. Stream a gapped
+    // first, so it is the stalest when the cap is hit.
+    net.inject(Fault::Drop {
+        nth: net.trace().len() as u64 + 1,
+    });
+    a.write(&[0xaa; 1000]).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    a.write(&[0xaa; 3000]).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    net.inject(Fault::Drop {
+        nth: net.trace().len() as u64 + 1,
+    });
+    b.write(&[0xbb; 1000]).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    b.write(&[0xbb; 3000]).await.unwrap();
+
+    // Stream a is evicted with the documented reason; its peer handle
+    // fails rather than hanging on the gap forever.
+    let err = tokio::time::timeout(Duration::from_secs(5), async {
+        let mut buf = [0u8; 16];
+        loop {
+            if let Err(e) = peer_a.read(&mut buf).await {
+                break e;
+            }
+        }
+    })
+    .await
+    .expect("the gapped stream was never reset");
+    assert!(
+        matches!(&err, sss::Error::StreamReset { reason, .. } if reason.contains("reassembly")),
+        "unexpected error: {err:?}"
+    );
+
+    // Stream b survives: retransmission fills its gap and every byte
+    // arrives.
+    let mut got = 0;
+    let mut buf = vec![0u8; 4096];
+    while got < 4001 {
+        got += peer_b.read(&mut buf).await.unwrap();
+    }
+    assert_eq!(got, 4001);
+}
@@ -0,0 +1,75 @@
+//! Message framing over a stream via the length-delimited adaptor.
+
+mod common;
+
+use bytes::Bytes;
+use common::connected_pair;
+use futures_util::{SinkExt, StreamExt};
+use sss::LengthDelimitedCodec;
+
+#[tokio::test(start_paused = true)]
+async fn framed_messages_arrive_whole_and_in_order() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    let mut tx = outbound.into_framed(LengthDelimitedCodec::new());
+    let mut rx = inbound.into_framed(LengthDelimitedCodec::new());
+
+    // Three messages of very different sizes: the large one spans several
+    // packets, the empty one is nothing but its prefix.
+    let messages = [
+        Bytes::from_static(b"short"),
+        Bytes::from(vec![b'x'; 100_000]),
+        Bytes::new(),
+    ];
+    for m in &messages {
+        tx.send(m.clone()).await.unwrap();
+    }
+    tx.close().await.unwrap();
+
+    for expected in &messages {
+        let got = rx.next().await.expect("stream ended early").unwrap();
+        assert_eq!(&got, expected);
+    }
+    assert!(rx.next().await.is_none(), "expected end of stream");
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_truncated_frame_surfaces_an_error() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    let mut rx = inbound.into_framed(LengthDelimitedCodec::new());
+
+    // A prefix promising ten bytes, followed by only three and a FIN.
+    outbound.write(&10u32.to_be_bytes()).await.unwrap();
+    outbound.write(b"cut").await.unwrap();
+    outbound.close().await.unwrap();
+
+    assert!(rx.next().await.expect("expected an item").is_err());
+    assert!(rx.next().await.is_none());
+}
+
+#[tokio::test(start_paused = true)]
+async fn sender_boundaries_surface_at_the_right_offsets() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+
+    // One message written in pieces, then a second, then a bare boundary
+    // marked after everything queued has already been flushed.
+    outbound.write(b"first").await.unwrap();
+    outbound.write(b" message").await.unwrap();
+    outbound.write_message_boundary().unwrap();
+    outbound.write(b"second").await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    outbound.write_message_boundary().unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    // The boundaries added no bytes; splitting at them recovers the
+    // sender's messages. Offsets are absolute, so the connect exchange
+    // that already crossed this stream shifts them by a constant.
+    assert_eq!(inbound.contiguous_bytes_available(), 19);
+    let first = inbound.next_boundary_offset().expect("first boundary");
+    let mut buf = [0u8; 16];
+    inbound.read_exact(&mut buf[..13]).await.unwrap();
+    assert_eq!(&buf[..13], b"first message");
+    assert_eq!(inbound.next_boundary_offset(), Some(first + 6));
+    inbound.read_exact(&mut buf[..6]).await.unwrap();
+    assert_eq!(&buf[..6], b"second");
+    assert_eq!(inbound.next_boundary_offset(), None);
+}
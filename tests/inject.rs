@@ -0,0 +1,103 @@
+//! Raw packet injection tests (feature `packet-injection`).
+#![cfg(feature = "packet-injection")]
+
+mod common;
+
+use std::time::Duration;
+
+use sss::Error;
+
+use common::connected_pair;
+
+/// Packet sequence number for crafted packets, far enough ahead of the
+/// handful the live connection sends that nothing collides with it.
+const CRAFTED_SEQ: u64 = 64;
+
+#[tokio::test(start_paused = true)]
+async fn an_injected_hand_crafted_stream_packet_reaches_the_stream() {
+    let (client, server, outbound, inbound, _listener) = connected_pair().await;
+    // Let the connect exchange drain so the stream's next offset is known.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let offset = outbound.acked_offset();
+    assert!(offset > 0 && offset < 0x1_0000, "offset should fit two bytes");
+
+    // Build the plaintext packet by hand: a minimal header, then one STREAM
+    // frame continuing the connect stream at its current offset.
+    let data = b"hand-crafted";
+    let mut plain = Vec::new();
+    plain.push(0); // header flags: no version, no FEC group, 2-byte seq
+    plain.extend_from_slice(&(CRAFTED_SEQ as u16).to_be_bytes());
+    plain.push(2); // STREAM frame type
+    plain.extend_from_slice(&(0x0200u16 | 2 << 10).to_be_bytes()); // data length, 2-byte offset
+    plain.extend_from_slice(&outbound.id().to_be_bytes());
+    plain.extend_from_slice(&(offset as u16).to_be_bytes());
+    plain.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    plain.extend_from_slice(data);
+
+    let datagram = client
+        .craft_message(&server.public_key(), CRAFTED_SEQ, &plain)
+        .expect("an established channel to the server");
+    server
+        .inject_packet(client.local_addr().unwrap(), &datagram)
+        .await
+        .unwrap();
+
+    let mut buf = [0u8; 32];
+    let n = inbound.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], data);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_tampered_injected_packet_fails_to_decrypt() {
+    let (client, server, _outbound, _inbound, _listener) = connected_pair().await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // An EMPTY frame behind a minimal header is the smallest valid packet.
+    let plain = [0, 0, CRAFTED_SEQ as u8, 0];
+    let mut datagram = client
+        .craft_message(&server.public_key(), CRAFTED_SEQ, &plain)
+        .unwrap();
+    let last = datagram.len() - 1;
+    datagram[last] ^= 0xff;
+    let err = server
+        .inject_packet(client.local_addr().unwrap(), &datagram)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::Crypto), "got {err:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_flipped_header_bit_fails_header_authentication() {
+    let (client, server, _net) = common::sim_hosts_with(
+        |b| b.authenticate_headers(),
+        |b| b.authenticate_headers(),
+    )
+    .await;
+    let mut listener = server.listen("test", "v1");
+    let _outbound = client
+        .connect(
+            server.local_addr().unwrap(),
+            server.public_key(),
+            "test",
+            "v1",
+        )
+        .await
+        .unwrap();
+    let _inbound = listener.accept().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let plain = [0, 0, CRAFTED_SEQ as u8, 0];
+    let datagram = client
+        .craft_message(&server.public_key(), CRAFTED_SEQ, &plain)
+        .unwrap();
+    let from = client.local_addr().unwrap();
+
+    // Untampered, the crafted packet clears the header check.
+    server.inject_packet(from, &datagram).await.unwrap();
+
+    // A single bit flipped in the masked sequence field trips the MAC.
+    let mut tampered = datagram.clone();
+    tampered[41] ^= 0x01;
+    let err = server.inject_packet(from, &tampered).await.unwrap_err();
+    assert!(matches!(err, Error::Crypto), "got {err:?}");
+}
@@ -0,0 +1,65 @@
+//! Checksum-only loopback mode tests (feature `insecure-loopback`).
+#![cfg(feature = "insecure-loopback")]
+
+use std::time::Duration;
+
+use sss::sim::SimNetwork;
+use sss::Host;
+
+#[tokio::test(start_paused = true)]
+async fn insecure_loopback_hosts_exchange_data() {
+    let net = SimNetwork::new();
+    let client = Host::builder()
+        .sim_socket(net.socket())
+        .insecure_loopback()
+        .build()
+        .await
+        .unwrap();
+    let server = Host::builder()
+        .sim_socket(net.socket())
+        .insecure_loopback()
+        .build()
+        .await
+        .unwrap();
+    let mut listener = server.listen("test", "v1");
+    let outbound = client
+        .connect(
+            server.local_addr().unwrap(),
+            server.public_key(),
+            "test",
+            "v1",
+        )
+        .await
+        .unwrap();
+    let inbound = listener.accept().await.unwrap();
+
+    outbound.write(b"over the checksum channel").await.unwrap();
+    let mut buf = [0u8; 32];
+    let n = inbound.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"over the checksum channel");
+}
+
+#[tokio::test(start_paused = true)]
+async fn insecure_to_secure_connect_is_refused() {
+    let net = SimNetwork::new();
+    let client = Host::builder()
+        .sim_socket(net.socket())
+        .insecure_loopback()
+        .connect_timeout(Duration::from_secs(2))
+        .build()
+        .await
+        .unwrap();
+    let server = Host::builder().sim_socket(net.socket()).build().await.unwrap();
+    let _listener = server.listen("test", "v1");
+    // The secure side cannot open checksummed message packets (and vice
+    // versa), so the connection never establishes.
+    assert!(client
+        .connect(
+            server.local_addr().unwrap(),
+            server.public_key(),
+            "test",
+            "v1",
+        )
+        .await
+        .is_err());
+}
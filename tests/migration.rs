@@ -0,0 +1,20 @@
+//! Connection-id demultiplexing across source address changes.
+
+mod common;
+
+use common::{connect_pair, sim_hosts};
+
+#[tokio::test(start_paused = true)]
+async fn known_connection_id_routes_from_a_new_source_address() {
+    let (client, server, net) = sim_hosts().await;
+    let (outbound, inbound, _l) = connect_pair(&client, &server).await;
+    outbound.write(b"before").await.unwrap();
+    let mut buf = [0u8; 32];
+    assert_eq!(inbound.read(&mut buf).await.unwrap(), 6);
+
+    // The client's NAT rebinds: same channel id, brand-new source address.
+    net.set_source_rewrite(client.local_addr().unwrap(), "10.77.9.9:9".parse().unwrap());
+    outbound.write(b"after the move").await.unwrap();
+    let n = inbound.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"after the move");
+}
@@ -0,0 +1,107 @@
+//! Multipath packet scheduling tests.
+
+mod common;
+
+use std::time::Duration;
+
+use common::sim_hosts_with;
+use sss::{ChannelPolicy, PathPolicy};
+
+async fn read_exactly(stream: &sss::Stream, len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut buf = vec![0u8; 64 * 1024];
+    while out.len() < len {
+        let n = stream.read(&mut buf).await.unwrap();
+        assert_ne!(n, 0, "unexpected end of stream at {} bytes", out.len());
+        out.extend_from_slice(&buf[..n]);
+    }
+    out
+}
+
+#[tokio::test(start_paused = true)]
+async fn round_robin_spreads_a_transfer_over_both_channels() {
+    let (client, server, net) =
+        sim_hosts_with(|b| b.channel_policy(ChannelPolicy::Multi), |b| b).await;
+    let mut listener = server.listen("test", "v1");
+    let addr = server.local_addr().unwrap();
+    let outbound = client
+        .connect(addr, server.public_key(), "test", "v1")
+        .await
+        .unwrap();
+    let inbound = listener.accept().await.unwrap();
+    // A second connect under the multi policy establishes a second channel.
+    let _second = client
+        .connect(addr, server.public_key(), "test", "v1")
+        .await
+        .unwrap();
+    let _second_in = listener.accept().await.unwrap();
+    assert_eq!(client.channels().len(), 2);
+    net.set_link_latency(
+        client.local_addr().unwrap(),
+        addr,
+        Duration::from_millis(10),
+    );
+
+    let baseline: u64 = client.channels().iter().map(|c| c.bytes_sent).sum();
+    assert_eq!(outbound.enable_multipath(PathPolicy::RoundRobin).unwrap(), 2);
+    let data: Vec<u8> = (0..500_000u32).map(|i| (i * 37 % 249) as u8).collect();
+    let send = {
+        let data = data.clone();
+        async move {
+            common::write_all(&outbound, &data).await;
+            outbound.close().await.unwrap();
+        }
+    };
+    let (received, ()) = tokio::join!(read_exactly(&inbound, data.len()), send);
+    assert_eq!(received, data, "delivery stays ordered across paths");
+
+    // Both channels must have carried a real share of the payload.
+    let shares: Vec<u64> = client.channels().iter().map(|c| c.bytes_sent).collect();
+    let carried: u64 = shares.iter().sum::<u64>() - baseline;
+    assert!(carried >= data.len() as u64);
+    for share in shares {
+        assert!(
+            share > data.len() as u64 / 4,
+            "one path carried almost nothing: {share} bytes"
+        );
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn lowest_rtt_still_delivers_in_order() {
+    let (client, server, _net) =
+        sim_hosts_with(|b| b.channel_policy(ChannelPolicy::Multi), |b| b).await;
+    let mut listener = server.listen("test", "v1");
+    let addr = server.local_addr().unwrap();
+    let outbound = client
+        .connect(addr, server.public_key(), "test", "v1")
+        .await
+        .unwrap();
+    let inbound = listener.accept().await.unwrap();
+    let _second = client
+        .connect(addr, server.public_key(), "test", "v1")
+        .await
+        .unwrap();
+    let _second_in = listener.accept().await.unwrap();
+
+    assert_eq!(outbound.enable_multipath(PathPolicy::LowestRtt).unwrap(), 2);
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i * 13 % 240) as u8).collect();
+    let send = {
+        let data = data.clone();
+        async move {
+            common::write_all(&outbound, &data).await;
+            outbound.close().await.unwrap();
+        }
+    };
+    let (received, ()) = tokio::join!(read_exactly(&inbound, data.len()), send);
+    assert_eq!(received, data);
+}
+
+#[tokio::test(start_paused = true)]
+async fn single_path_streams_are_unaffected() {
+    let (client, server, _net) = sim_hosts_with(|b| b, |b| b).await;
+    let (outbound, inbound, _listener) = common::connect_pair(&client, &server).await;
+    assert_eq!(outbound.enable_multipath(PathPolicy::RoundRobin).unwrap(), 1);
+    outbound.write(b"just the one channel").await.unwrap();
+    assert_eq!(read_exactly(&inbound, 20).await, b"just the one channel");
+}
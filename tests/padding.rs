@@ -0,0 +1,60 @@
+//! Packet padding (traffic-analysis resistance) tests.
+
+mod common;
+
+use common::sim_hosts_with;
+
+#[tokio::test(start_paused = true)]
+async fn padded_packets_use_only_the_configured_sizes() {
+    let sizes = [768usize, 1232];
+    let tune = |b: sss::HostBuilder| b.pad_to(sizes.to_vec());
+    let (client, server, net) = sim_hosts_with(tune, tune).await;
+    let (outbound, inbound, _l) = common::connect_pair(&client, &server).await;
+
+    // Mix tiny writes with a bulk transfer so both size classes come up.
+    outbound.write(b"hi").await.unwrap();
+    let mut buf = vec![0u8; 64 * 1024];
+    assert_eq!(inbound.read(&mut buf).await.unwrap(), 2);
+    let data = vec![0x5au8; 200 * 1024];
+    let receive = async {
+        let mut got = 0;
+        while got < data.len() {
+            got += inbound.read(&mut buf).await.unwrap();
+        }
+    };
+    let ((), ()) = tokio::join!(receive, common::write_all(&outbound, &data));
+
+    // Everything after the three-packet handshake is a channel packet and
+    // must land exactly on a configured size.
+    let trace = net.trace();
+    assert!(trace.len() > 10, "transfer left almost no trace");
+    for packet in &trace[3..] {
+        assert!(
+            sizes.contains(&packet.len),
+            "{} -> {}: {} bytes is not a configured size",
+            packet.from,
+            packet.to,
+            packet.len
+        );
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn padding_is_invisible_to_the_byte_stream() {
+    let tune = |b: sss::HostBuilder| b.pad_to(vec![768]);
+    let (client, server, _net) = sim_hosts_with(tune, tune).await;
+    let (outbound, inbound, _l) = common::connect_pair(&client, &server).await;
+    let data: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+    let receive = async {
+        let mut out = Vec::new();
+        let mut buf = vec![0u8; 16 * 1024];
+        while out.len() < data.len() {
+            let n = inbound.read(&mut buf).await.unwrap();
+            assert_ne!(n, 0);
+            out.extend_from_slice(&buf[..n]);
+        }
+        out
+    };
+    let (received, ()) = tokio::join!(receive, common::write_all(&outbound, &data));
+    assert_eq!(received, data);
+}
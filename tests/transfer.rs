@@ -0,0 +1,1390 @@
+//! Stream data transfer tests.
+
+mod common;
+
+use common::connected_pair;
+use sss::Error;
+
+async fn read_exactly(stream: &sss::Stream, len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut buf = vec![0u8; 64 * 1024];
+    while out.len() < len {
+        let n = stream.read(&mut buf).await.unwrap();
+        assert_ne!(n, 0, "unexpected end of stream at {} bytes", out.len());
+        out.extend_from_slice(&buf[..n]);
+    }
+    out
+}
+
+#[tokio::test(start_paused = true)]
+async fn echo_both_directions() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    outbound.write(b"marco").await.unwrap();
+    assert_eq!(read_exactly(&inbound, 5).await, b"marco");
+    inbound.write(b"polo").await.unwrap();
+    assert_eq!(read_exactly(&outbound, 4).await, b"polo");
+}
+
+#[tokio::test(start_paused = true)]
+async fn large_transfer() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    let data: Vec<u8> = (0..1_000_000u32).map(|i| (i * 31 % 251) as u8).collect();
+    let send = {
+        let data = data.clone();
+        async move {
+            common::write_all(&outbound, &data).await;
+            outbound.close().await.unwrap();
+        }
+    };
+    let (received, ()) = tokio::join!(read_exactly(&inbound, data.len()), send);
+    assert_eq!(received, data);
+    let mut buf = [0u8; 8];
+    assert_eq!(inbound.read(&mut buf).await.unwrap(), 0, "clean EOF");
+}
+
+#[tokio::test(start_paused = true)]
+async fn records_arrive_as_units() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    outbound.write_record(b"first").await.unwrap();
+    outbound.write_record(b"second, longer").await.unwrap();
+    assert_eq!(inbound.read_record().await.unwrap().unwrap(), b"first");
+    assert_eq!(
+        inbound.read_record().await.unwrap().unwrap(),
+        b"second, longer"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn close_delivers_eof_after_data() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    outbound.write(b"last words").await.unwrap();
+    outbound.close().await.unwrap();
+    assert_eq!(read_exactly(&inbound, 10).await, b"last words");
+    let mut buf = [0u8; 8];
+    assert_eq!(inbound.read(&mut buf).await.unwrap(), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn reset_aborts_peer_reads() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    outbound.write(b"doomed").await.unwrap();
+    // Let the data drain before resetting so the race stays deterministic.
+    assert_eq!(read_exactly(&inbound, 6).await, b"doomed");
+    outbound.reset(42, "test abort");
+    let mut buf = [0u8; 8];
+    let err = loop {
+        match inbound.read(&mut buf).await {
+            Ok(_) => continue,
+            Err(e) => break e,
+        }
+    };
+    match err {
+        Error::StreamReset { code, reason } => {
+            assert_eq!(code, 42);
+            assert_eq!(reason, "test abort");
+        }
+        other => panic!("expected reset, got {other:?}"),
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn substreams_inherit_the_channel() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    let sub_out = outbound.open_substream().unwrap();
+    sub_out.write(b"from the substream").await.unwrap();
+    let sub_in = inbound.accept_substream().await.unwrap();
+    assert_eq!(read_exactly(&sub_in, 18).await, b"from the substream");
+    // The parent stream still works.
+    outbound.write(b"parent").await.unwrap();
+    assert_eq!(read_exactly(&inbound, 6).await, b"parent");
+}
+
+#[tokio::test(start_paused = true)]
+async fn async_io_traits() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let (_c, _s, mut outbound, mut inbound, _l) = connected_pair().await;
+    outbound.write_all(b"via AsyncWrite").await.unwrap();
+    outbound.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    inbound.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"via AsyncWrite");
+}
+
+#[tokio::test(start_paused = true)]
+async fn readable_pends_until_data_arrives() {
+    use std::time::Duration;
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    let mut readable = std::pin::pin!(inbound.readable());
+    tokio::select! {
+        _ = &mut readable => panic!("readable resolved on an empty stream"),
+        _ = tokio::time::sleep(Duration::from_millis(50)) => {}
+    }
+    outbound.write(b"x").await.unwrap();
+    readable.await.unwrap();
+    let mut buf = [0u8; 4];
+    assert_eq!(inbound.try_read(&mut buf).unwrap(), Some(1));
+    assert_eq!(buf[0], b'x');
+    // Drained again: try_read reports not-ready rather than blocking.
+    assert_eq!(inbound.try_read(&mut buf).unwrap(), None);
+}
+
+#[tokio::test(start_paused = true)]
+async fn writable_reflects_send_window_space() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    outbound.writable().await.unwrap();
+    let n = outbound.try_write(b"immediate").unwrap().unwrap();
+    assert_eq!(read_exactly(&inbound, n).await, b"immediate"[..n]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn buffer_pool_caps_buffered_bytes_across_streams() {
+    use common::sim_hosts_with;
+    let pool_cap = 8 * 1024;
+    let (client, server, _net) =
+        sim_hosts_with(|b| b.buffer_pool_size(pool_cap), |b| b).await;
+    let mut listener = server.listen("test", "v1");
+    let outbound = client
+        .connect(
+            server.local_addr().unwrap(),
+            server.public_key(),
+            "test",
+            "v1",
+        )
+        .await
+        .unwrap();
+    let _inbound = listener.accept().await.unwrap();
+
+    let full_window = outbound.advertised_window();
+    let subs: Vec<_> = (0..4).map(|_| outbound.open_substream().unwrap()).collect();
+    // No await between writes: nothing can drain, so accepted bytes are
+    // bounded by the pool alone.
+    let mut accepted = 0;
+    for sub in &subs {
+        if let Some(n) = sub.try_write(&vec![0u8; pool_cap]).unwrap() {
+            accepted += n;
+        }
+    }
+    assert!(accepted <= pool_cap, "accepted {accepted} of a {pool_cap} pool");
+    assert!(accepted > 0);
+    // Window advertisements shrink as the pool fills up.
+    assert!(outbound.advertised_window() < full_window);
+    assert_eq!(outbound.advertised_window(), pool_cap - accepted);
+}
+
+#[tokio::test(start_paused = true)]
+async fn shutdown_read_discards_data_and_stops_peer() {
+    use std::time::Duration;
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    outbound.write(b"already sent").await.unwrap();
+    inbound.shutdown_read();
+    // Reads return end of stream immediately, even for data in flight.
+    let mut buf = [0u8; 16];
+    assert_eq!(inbound.read(&mut buf).await.unwrap(), 0);
+    // The peer observes the stop once the frame arrives: writes fail.
+    let err = loop {
+        match outbound.write(b"more data").await {
+            Ok(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            Err(e) => break e,
+        }
+    };
+    assert!(matches!(err, Error::PeerStoppedReading { code: 0 }), "got {err:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn open_metadata_arrives_before_the_first_read() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    let sub_out = outbound
+        .open_substream_with_metadata(b"GET /index")
+        .unwrap();
+    sub_out.write(b"body").await.unwrap();
+    let sub_in = inbound.accept_substream().await.unwrap();
+    // The metadata is readable before any stream data has been consumed.
+    assert_eq!(sub_in.open_metadata().as_deref(), Some(&b"GET /index"[..]));
+    assert_eq!(read_exactly(&sub_in, 4).await, b"body");
+    // Streams opened without metadata report none.
+    assert_eq!(outbound.open_metadata(), None);
+}
+
+#[tokio::test(start_paused = true)]
+async fn oversized_open_metadata_is_rejected() {
+    let (_c, _s, outbound, _inbound, _l) = connected_pair().await;
+    let huge = vec![0u8; 4096];
+    assert!(outbound.open_substream_with_metadata(&huge).is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn substream_opens_queue_at_the_cap() {
+    use common::{connect_pair, sim_hosts_with};
+    use sss::{OnLimit, SubstreamOptions};
+
+    let (client, server, _net) = sim_hosts_with(|b| b.max_substreams(2), |b| b).await;
+    let (outbound, inbound, _l) = connect_pair(&client, &server).await;
+
+    let first = outbound.open_substream().unwrap();
+    let _second = outbound.open_substream().unwrap();
+    // The cap is reached: a plain open is rejected outright.
+    assert!(matches!(
+        outbound.open_substream(),
+        Err(Error::SubstreamLimit)
+    ));
+
+    // A queued open waits for a slot instead.
+    let queued = outbound.open_substream_with(SubstreamOptions {
+        on_limit: OnLimit::Queue,
+        ..Default::default()
+    });
+    tokio::pin!(queued);
+    tokio::select! {
+        _ = &mut queued => panic!("open completed past the cap"),
+        _ = tokio::time::sleep(std::time::Duration::from_millis(50)) => {}
+    }
+
+    drop(first);
+    let third = queued.await.unwrap();
+    third.write(b"queued open").await.unwrap();
+    // Skip the empty stream left behind by the dropped substream.
+    let payload = loop {
+        let sub_in = inbound.accept_substream().await.unwrap();
+        let mut buf = [0u8; 16];
+        match sub_in.read(&mut buf).await.unwrap() {
+            0 => continue,
+            n => break buf[..n].to_vec(),
+        }
+    };
+    assert_eq!(payload, b"queued open");
+}
+
+#[tokio::test(start_paused = true)]
+async fn linger_none_returns_before_the_ack() {
+    let (_c, server, outbound, _inbound, _l) = connected_pair().await;
+    // Silence the peer: nothing will be acknowledged anymore.
+    drop(server);
+    outbound.write(b"going nowhere").await.unwrap();
+    outbound.set_linger(None);
+    // Returns at once even though the FIN can never be acknowledged.
+    outbound.close().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn linger_deadline_waits_for_the_ack() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    outbound.set_linger(Some(std::time::Duration::from_secs(5)));
+    outbound.write(b"flushed").await.unwrap();
+    let close = async { outbound.close().await.unwrap() };
+    let ((), received) = tokio::join!(close, read_exactly(&inbound, 7));
+    assert_eq!(received, b"flushed");
+}
+
+#[tokio::test(start_paused = true)]
+async fn linger_deadline_expires_unacked() {
+    let (client, server, net) = common::sim_hosts().await;
+    let (outbound, _inbound, _l) = common::connect_pair(&client, &server).await;
+    // Sever the return path: nothing the peer sends -- acknowledgements
+    // included -- arrives anymore.
+    net.set_link_down_after(
+        server.local_addr().unwrap(),
+        client.local_addr().unwrap(),
+        net.trace().len() as u64,
+    );
+    outbound.write(b"stuck").await.unwrap();
+    outbound.set_linger(Some(std::time::Duration::from_millis(200)));
+    let started = tokio::time::Instant::now();
+    let err = outbound.close().await.unwrap_err();
+    assert!(matches!(err, Error::Timeout), "got {err:?}");
+    assert!(started.elapsed() >= std::time::Duration::from_millis(200));
+}
+
+#[tokio::test(start_paused = true)]
+async fn linger_zero_resets_the_stream() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    outbound.write(b"discarded").await.unwrap();
+    outbound.set_linger(Some(std::time::Duration::ZERO));
+    outbound.close().await.unwrap();
+    let mut buf = [0u8; 16];
+    let err = loop {
+        match inbound.read(&mut buf).await {
+            Ok(_) => continue,
+            Err(err) => break err,
+        }
+    };
+    assert!(matches!(err, Error::StreamReset { code: 0, .. }), "got {err:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn write_returns_a_partial_count_when_the_window_fills() {
+    let (client, server, _net) =
+        common::sim_hosts_with(|b| b.buffer_pool_size(4096), |b| b).await;
+    let (outbound, inbound, _l) = common::connect_pair(&client, &server).await;
+    let data = vec![0x7fu8; 16 * 1024];
+    let first = outbound.write(&data).await.unwrap();
+    assert!(
+        first > 0 && first < data.len(),
+        "expected a short count, got {first}"
+    );
+    // ACKs drain the buffer pool and reopen the window for the remainder.
+    let send = async {
+        let mut written = first;
+        while written < data.len() {
+            written += outbound.write(&data[written..]).await.unwrap();
+        }
+    };
+    let (received, ()) = tokio::join!(read_exactly(&inbound, data.len()), send);
+    assert_eq!(received, data);
+}
+
+#[tokio::test(start_paused = true)]
+async fn dropping_a_stream_flushes_and_closes_cleanly() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    outbound.write(b"parting words").await.unwrap();
+    drop(outbound);
+
+    // The buffered data still arrives, followed by a clean end of stream.
+    assert_eq!(read_exactly(&inbound, 13).await, b"parting words");
+    let mut buf = [0u8; 8];
+    assert_eq!(inbound.read(&mut buf).await.unwrap(), 0);
+
+    // And the dropped side said it will not read: writes toward it fail
+    // once the STOP_SENDING arrives, rather than stalling silently.
+    let stopped = async {
+        loop {
+            match inbound.write(b"anyone there?").await {
+                Err(Error::PeerStoppedReading { .. }) => break,
+                Err(e) => panic!("unexpected error: {e}"),
+                Ok(_) => tokio::time::sleep(std::time::Duration::from_millis(1)).await,
+            }
+        }
+    };
+    tokio::time::timeout(std::time::Duration::from_secs(5), stopped)
+        .await
+        .expect("peer was never told to stop sending");
+}
+
+#[tokio::test(start_paused = true)]
+async fn lowering_the_advertised_window_throttles_the_sender() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    outbound.write(b"warmup").await.unwrap();
+    assert_eq!(read_exactly(&inbound, 6).await, b"warmup");
+
+    // Pin the receiver's window well below the default; the override is
+    // what the query reports and what the peer is told.
+    inbound.set_advertised_window(2048);
+    assert_eq!(inbound.advertised_window(), 2048);
+
+    // Once the advertisement lands, a large write is cut off at the new
+    // window instead of the 256 KiB default.
+    let chunk = vec![b'w'; 4096];
+    let mut throttled = false;
+    for _ in 0..5_000 {
+        let n = outbound.write(&chunk).await.unwrap();
+        assert_eq!(read_exactly(&inbound, n).await, chunk[..n]);
+        if n < chunk.len() {
+            assert!(n <= 2048, "short write of {n} exceeds the window");
+            throttled = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+    }
+    assert!(throttled, "the lowered window never limited a write");
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_bare_fin_frame_ends_the_read_side_cleanly() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    outbound.write(b"final five").await.unwrap();
+    assert_eq!(read_exactly(&inbound, 10).await, b"final five");
+
+    // With the data long since delivered, the FIN travels in a STREAM
+    // frame of its own, carrying no bytes at offset ten.
+    outbound.close_send();
+    let mut buf = [0u8; 8];
+    assert_eq!(inbound.read(&mut buf).await.unwrap(), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_blocked_write_reports_what_it_stalled_on() {
+    use std::time::Duration;
+
+    use sss::BlockReason;
+
+    // Flow control: a tiny advertised window fills while acks crawl back
+    // over a slow link.
+    let (client, server, net) = common::sim_hosts().await;
+    let (outbound, inbound, _l) = common::connect_pair(&client, &server).await;
+    assert_eq!(outbound.write_block_reason(), None);
+    inbound.set_advertised_window(1024);
+    let chunk = vec![b'b'; 4096];
+    loop {
+        let n = outbound.write(&chunk).await.unwrap();
+        assert_eq!(read_exactly(&inbound, n).await.len(), n);
+        if n < chunk.len() {
+            break; // the 1 KiB advertisement has taken effect
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    let (ca, sa) = (client.local_addr().unwrap(), server.local_addr().unwrap());
+    net.set_link_latency(ca, sa, Duration::from_millis(50));
+    net.set_link_latency(sa, ca, Duration::from_millis(50));
+    while outbound.try_write(&chunk).unwrap().is_some() {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    assert_eq!(outbound.write_block_reason(), Some(BlockReason::FlowControl));
+
+    // Congestion: the window is wide open, but buffer credit runs out
+    // under the backlog the congestion controller is still draining.
+    let tune = |b: sss::HostBuilder| b.buffer_pool_size(64 * 1024);
+    let (client, server, net) = common::sim_hosts_with(tune, |b| b).await;
+    let (ca, sa) = (client.local_addr().unwrap(), server.local_addr().unwrap());
+    net.set_link_latency(ca, sa, Duration::from_millis(50));
+    net.set_link_latency(sa, ca, Duration::from_millis(50));
+    let (outbound, inbound, _l) = common::connect_pair(&client, &server).await;
+    inbound.set_advertised_window(1 << 20);
+    while outbound.try_write(&chunk).unwrap().is_some() {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    assert_eq!(outbound.write_block_reason(), Some(BlockReason::Congestion));
+}
+
+#[tokio::test(start_paused = true)]
+async fn weights_split_bandwidth_proportionally() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let (client, server, net) = common::sim_hosts().await;
+    let ca = client.local_addr().unwrap();
+    let sa = server.local_addr().unwrap();
+    // Enough latency that slow start keeps capacity scarce while we sample.
+    net.set_link_latency(ca, sa, Duration::from_millis(50));
+    net.set_link_latency(sa, ca, Duration::from_millis(50));
+    let (outbound, inbound, _l) = common::connect_pair(&client, &server).await;
+    let sub_out = outbound.open_substream().unwrap();
+    sub_out.write(b"open").await.unwrap();
+    let sub_in = inbound.accept_substream().await.unwrap();
+
+    outbound.set_weight(3);
+    assert_eq!(outbound.weight(), 3);
+    assert_eq!(sub_out.weight(), 1);
+
+    // Keep both streams backlogged while the receivers count arrivals.
+    for stream in [outbound, sub_out] {
+        tokio::spawn(async move {
+            let chunk = vec![0xbb; 8 * 1024];
+            loop {
+                if stream.write(&chunk).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    let heavy = Arc::new(AtomicUsize::new(0));
+    let light = Arc::new(AtomicUsize::new(0));
+    for (stream, total) in [(inbound, heavy.clone()), (sub_in, light.clone())] {
+        tokio::spawn(async move {
+            let mut buf = [0u8; 16 * 1024];
+            while let Ok(n) = stream.read(&mut buf).await {
+                total.fetch_add(n, Ordering::Relaxed);
+            }
+        });
+    }
+    tokio::time::sleep(Duration::from_millis(400)).await;
+
+    let heavy = heavy.load(Ordering::Relaxed);
+    let light = light.load(Ordering::Relaxed);
+    assert!(light > 0, "the light stream must not starve");
+    assert!(
+        heavy >= light * 2 && heavy <= light * 5,
+        "expected roughly a 3:1 split, got {heavy}:{light}"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn an_in_flight_cap_holds_outstanding_bytes_below_the_cwnd() {
+    use std::time::Duration;
+
+    let (client, server, net) = common::sim_hosts().await;
+    let ca = client.local_addr().unwrap();
+    let sa = server.local_addr().unwrap();
+    net.set_link_latency(ca, sa, Duration::from_millis(50));
+    net.set_link_latency(sa, ca, Duration::from_millis(50));
+    let (outbound, inbound, _l) = common::connect_pair(&client, &server).await;
+
+    // Far below the initial congestion window (ten full packets).
+    outbound.set_max_in_flight(4096);
+    assert_eq!(outbound.max_in_flight(), Some(4096));
+    let payload = vec![0xee; 64 * 1024];
+    let writer = tokio::spawn(async move {
+        let mut written = 0;
+        while written < payload.len() {
+            written += outbound.write(&payload[written..]).await.unwrap();
+        }
+    });
+
+    // Before the first acknowledgements return (one RTT = 100ms), every
+    // delivered byte was in flight at once; the cap bounds them.
+    tokio::time::sleep(Duration::from_millis(80)).await;
+    let mut buf = [0u8; 16 * 1024];
+    let mut early = 0;
+    while let Ok(Some(n)) = inbound.try_read(&mut buf) {
+        early += n;
+    }
+    assert!(
+        0 < early && early <= 4096,
+        "{early} bytes arrived inside one RTT, expected at most the cap"
+    );
+
+    // The transfer still completes once acks start draining the cap.
+    let mut total = early;
+    while total < 64 * 1024 {
+        total += inbound.read(&mut buf).await.unwrap();
+    }
+    writer.await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn application_context_rides_along_with_the_stream() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    struct Session {
+        user: String,
+        dropped: Arc<AtomicBool>,
+    }
+    impl Drop for Session {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let (_client, _server, outbound, mut inbound, _l) = connected_pair().await;
+    let dropped = Arc::new(AtomicBool::new(false));
+    inbound.set_context(Session {
+        user: "alice".into(),
+        dropped: dropped.clone(),
+    });
+
+    assert_eq!(inbound.context::<Session>().unwrap().user, "alice");
+    assert!(inbound.context::<u32>().is_none());
+    inbound.context_mut::<Session>().unwrap().user.push_str("@example");
+    assert_eq!(inbound.context::<Session>().unwrap().user, "alice@example");
+
+    outbound.write(b"still a stream").await.unwrap();
+    let mut buf = [0u8; 16];
+    let n = inbound.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"still a stream");
+
+    drop(inbound);
+    assert!(dropped.load(Ordering::SeqCst), "context must drop with the stream");
+}
+
+#[tokio::test(start_paused = true)]
+async fn cloned_senders_interleave_without_tearing_writes() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    let sender_a = outbound.sender();
+    let sender_b = sender_a.clone();
+
+    // Each message is [tag, len, seq, filler...]: variable lengths make
+    // any torn or interleaved buffer unparseable.
+    async fn produce(sender: sss::StreamSender, tag: u8) {
+        for seq in 0..50u8 {
+            let len = 3 + (seq as usize % 29);
+            let mut msg = vec![tag; len];
+            msg[1] = len as u8;
+            msg[2] = seq;
+            sender.send(&msg).await.unwrap();
+            if seq % 7 == 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            }
+        }
+    }
+    let a = tokio::spawn(produce(sender_a, 0xaa));
+    let b = tokio::spawn(produce(sender_b, 0xbb));
+    a.await.unwrap();
+    b.await.unwrap();
+    // Both producers are done; the group's last drop half-closes.
+
+    let mut received = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = inbound.read(&mut buf).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        received.extend_from_slice(&buf[..n]);
+    }
+    let mut next_seq = [0u8; 2];
+    let mut pos = 0;
+    while pos < received.len() {
+        let tag = received[pos];
+        let which = match tag {
+            0xaa => 0,
+            0xbb => 1,
+            other => panic!("message boundary lost at {pos}: tag {other:#x}"),
+        };
+        let len = received[pos + 1] as usize;
+        let seq = received[pos + 2];
+        assert_eq!(seq, next_seq[which], "sender {tag:#x} out of order");
+        next_seq[which] += 1;
+        assert!(received[pos + 3..pos + len].iter().all(|&b| b == tag));
+        pos += len;
+    }
+    assert_eq!(next_seq, [50, 50], "missing messages");
+}
+
+#[tokio::test(start_paused = true)]
+async fn abort_returns_held_bytes_to_the_pool_and_resets_the_peer() {
+    use std::time::Duration;
+
+    let (client, server, net) =
+        common::sim_hosts_with(|b| b.buffer_pool_size(64 * 1024), |b| b).await;
+    let ca = client.local_addr().unwrap();
+    let sa = server.local_addr().unwrap();
+    net.set_link_latency(ca, sa, Duration::from_millis(50));
+    net.set_link_latency(sa, ca, Duration::from_millis(50));
+    let (outbound, inbound, _l) = common::connect_pair(&client, &server).await;
+
+    // A measurement substream: its advertised window tracks the client's
+    // pool, and its unread ballast stays held across the main stream abort.
+    let probe = outbound.open_substream().unwrap();
+    probe.write(b"hi").await.unwrap();
+    let probe_in = inbound.accept_substream().await.unwrap();
+    let mut buf = vec![0u8; 16 * 1024];
+    probe_in.read(&mut buf).await.unwrap();
+    common::write_all(&probe_in, &[0xbb; 4 * 1024]).await;
+    common::write_all(&inbound, &[0xcc; 8 * 1024]).await;
+
+    // Both unread ballasts arrived and the probe's own bytes were acked:
+    // the pool holds exactly the 12 KiB of unread data.
+    while probe.advertised_window() != 52 * 1024 {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    // Delay the return path so acknowledgements stop draining the pool,
+    // then fill the main stream's send side: 40 KiB stays charged, partly
+    // in flight and partly queued.
+    net.set_link_latency(sa, ca, Duration::from_secs(5));
+    common::write_all(&outbound, &vec![0xaa; 40 * 1024]).await;
+    assert_eq!(probe.advertised_window(), 12 * 1024);
+
+    // Abort frees the send queues, in-flight accounting and reassembly at
+    // once; only the probe's 4 KiB ballast remains held.
+    outbound.abort();
+    assert_eq!(probe.advertised_window(), 60 * 1024);
+    let err = outbound.write(b"x").await.unwrap_err();
+    assert!(
+        matches!(&err, sss::Error::StreamReset { code: 0, reason } if reason == "aborted"),
+        "write after abort: {err:?}"
+    );
+    let err = outbound.read(&mut buf).await.unwrap_err();
+    assert!(matches!(err, sss::Error::StreamReset { .. }));
+
+    // The peer drains what was delivered, then observes the RESET.
+    let reset = loop {
+        match inbound.read(&mut buf).await {
+            Ok(0) => panic!("peer saw a clean end of stream, expected a reset"),
+            Ok(_) => continue,
+            Err(e) => break e,
+        }
+    };
+    assert!(
+        matches!(&reset, sss::Error::StreamReset { reason, .. } if reason == "aborted"),
+        "peer error: {reset:?}"
+    );
+
+    // Acks for the aborted in-flight data eventually arrive over the slow
+    // return path; they must not discharge the pool a second time.
+    tokio::time::sleep(Duration::from_secs(12)).await;
+    assert_eq!(probe.advertised_window(), 60 * 1024);
+}
+
+#[tokio::test(start_paused = true)]
+async fn read_exact_accumulates_a_header_spanning_two_packets() {
+    let (_client, _server, outbound, inbound, _l) = common::connected_pair().await;
+
+    // Two separate writes with a pause in between leave the 8-byte header
+    // split across two packets on the wire.
+    outbound.write(&[1, 2, 3, 4]).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    outbound.write(&[5, 6, 7, 8]).await.unwrap();
+
+    let mut header = [0u8; 8];
+    inbound.read_exact(&mut header).await.unwrap();
+    assert_eq!(header, [1, 2, 3, 4, 5, 6, 7, 8]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn read_exact_reports_a_partial_fill_at_end_of_stream() {
+    let (_client, _server, outbound, inbound, _l) = common::connected_pair().await;
+
+    outbound.write(&[0xab; 5]).await.unwrap();
+    drop(outbound); // half-closes: five bytes, then end of stream
+
+    let mut header = [0u8; 8];
+    let err = inbound.read_exact(&mut header).await.unwrap_err();
+    assert!(
+        matches!(err, sss::Error::UnexpectedEof { read: 5, expected: 8 }),
+        "unexpected error: {err:?}"
+    );
+    // The partial prefix is still there for the caller.
+    assert_eq!(&header[..5], &[0xab; 5]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn run_until_drives_a_transfer_to_full_acknowledgement() {
+    let (client, _server, outbound, _inbound, _l) = common::connected_pair().await;
+
+    // A substream's offsets start at zero (the parent's include the
+    // service request record), so full acknowledgement is exact equality.
+    let sub = outbound.open_substream().unwrap();
+    let len = 32 * 1024;
+    common::write_all(&sub, &vec![0x42; len]).await;
+    let met = client
+        .run_until(std::time::Duration::from_secs(5), || {
+            sub.acked_offset() == len as u64
+        })
+        .await;
+    assert!(met, "transfer was not fully acknowledged in time");
+
+    // And the negative form: a condition that never holds reports failure
+    // once the deadline passes.
+    let met = client
+        .run_until(std::time::Duration::from_millis(50), || false)
+        .await;
+    assert!(!met);
+}
+
+#[tokio::test(start_paused = true)]
+async fn stop_sending_fails_peer_writes_with_the_reason_code() {
+    let (_client, _server, outbound, inbound, _l) = common::connected_pair().await;
+
+    outbound.write(b"unwanted").await.unwrap();
+    inbound.stop_sending(42);
+
+    // The requester's writes keep the reason code until they observe it.
+    let err = loop {
+        match outbound.write(b"more").await {
+            Ok(_) => tokio::time::sleep(std::time::Duration::from_millis(1)).await,
+            Err(e) => break e,
+        }
+    };
+    assert!(
+        matches!(err, Error::PeerStoppedReading { code: 42 }),
+        "got {err:?}"
+    );
+
+    // Only that direction died: the stopping side can still write and the
+    // peer can still read.
+    inbound.write(b"still flowing").await.unwrap();
+    let mut buf = [0u8; 32];
+    let n = outbound.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"still flowing");
+}
+
+#[tokio::test(start_paused = true)]
+async fn overhead_accounting_tracks_a_loopback_transfer() {
+    let (client, _server, outbound, _inbound, _l) = common::connected_pair().await;
+
+    let sub = outbound.open_substream().unwrap();
+    let len = 48 * 1024;
+    common::write_all(&sub, &vec![0x5a; len]).await;
+    let met = client
+        .run_until(std::time::Duration::from_secs(5), || {
+            sub.acked_offset() == len as u64
+        })
+        .await;
+    assert!(met, "transfer was not fully acknowledged in time");
+
+    assert_eq!(sub.payload_bytes(), len as u64, "payload must match writes");
+    let overhead = sub.total_overhead_bytes();
+    assert!(overhead > 0, "headers and envelopes cost something");
+    // Sanity bound: a lossless bulk transfer should be far more payload
+    // than protocol.
+    assert!(
+        overhead < len as u64 / 2,
+        "implausible overhead {overhead} for {len} payload bytes"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn flush_and_close_waits_for_the_final_ack() {
+    let (_client, _server, outbound, _inbound, _l) = connected_pair().await;
+
+    let sub = outbound.open_substream().unwrap();
+    let len = 32 * 1024;
+    common::write_all(&sub, &vec![0x3c; len]).await;
+    sub.flush_and_close().await.unwrap();
+    // Resolution implies the peer acknowledged everything written.
+    assert_eq!(sub.acked_offset(), len as u64);
+}
+
+#[tokio::test(start_paused = true)]
+async fn equal_priority_streams_are_serviced_in_creation_order() {
+    let (_client, _server, outbound, inbound, _l) = connected_pair().await;
+
+    // Four equal-priority, equal-weight substreams with a byte each: the
+    // scheduler's documented order (priority, debt, then stream id) must
+    // put them on the wire in creation order.
+    let mut subs = Vec::new();
+    for tag in 0..4u8 {
+        let sub = outbound.open_substream().unwrap();
+        sub.write(&[tag]).await.unwrap();
+        subs.push(sub);
+    }
+    for expected in 0..4u8 {
+        let sub = inbound.accept_substream().await.unwrap();
+        let mut tag = [0u8; 1];
+        sub.read_exact(&mut tag).await.unwrap();
+        assert_eq!(tag[0], expected, "substreams serviced out of order");
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn retain_mode_allows_random_access_reads() {
+    let (_client, _server, outbound, inbound, _l) = connected_pair().await;
+
+    let blob = outbound
+        .open_substream_with(sss::SubstreamOptions {
+            retain_all: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    // A request byte puts the substream's INIT on the wire so the peer
+    // can accept it and answer with the blob.
+    blob.write(b"?").await.unwrap();
+    let peer = inbound.accept_substream().await.unwrap();
+    let data: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+    common::write_all(&peer, &data).await;
+    peer.close().await.unwrap();
+
+    // Drain the stream normally; retained bytes stay addressable.
+    let mut all = vec![0u8; data.len()];
+    blob.read_exact(&mut all).await.unwrap();
+    assert_eq!(all, data);
+
+    let mut window = [0u8; 8];
+    let n = blob.read_at(100, &mut window).unwrap();
+    assert_eq!(n, 8);
+    assert_eq!(window, data[100..108]);
+    // Past the end of the received data nothing is copied.
+    assert_eq!(blob.read_at(data.len() as u64, &mut window).unwrap(), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_window_update_caps_and_raises_the_stream_send_limit() {
+    use std::time::Duration;
+
+    let (_client, _server, outbound, inbound, _listener) = common::connected_pair().await;
+    let sub = outbound.open_substream().unwrap();
+    sub.write(b"ab").await.unwrap();
+    let peer = inbound.accept_substream().await.unwrap();
+    let mut buf = [0u8; 64];
+    assert_eq!(peer.read(&mut buf).await.unwrap(), 2);
+
+    // Cap the substream at absolute offset 4, then write well past it:
+    // exactly two more bytes may cross.
+    peer.grant_window(4).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    sub.write(b"0123456789").await.unwrap();
+    let mut got = 0;
+    while got < 2 {
+        got += peer.read(&mut buf[got..]).await.unwrap();
+    }
+    assert_eq!(&buf[..2], b"01");
+    assert!(
+        tokio::time::timeout(Duration::from_millis(500), peer.read(&mut buf))
+            .await
+            .is_err(),
+        "bytes crossed beyond the granted limit"
+    );
+
+    // A stale (lower) grant changes nothing; a higher one releases the
+    // rest.
+    peer.grant_window(3).unwrap();
+    assert!(
+        tokio::time::timeout(Duration::from_millis(500), peer.read(&mut buf))
+            .await
+            .is_err(),
+        "a stale grant moved the limit"
+    );
+    peer.grant_window(20).unwrap();
+    let mut got = 0;
+    while got < 8 {
+        got += peer.read(&mut buf[got..]).await.unwrap();
+    }
+    assert_eq!(&buf[..8], b"23456789");
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_connection_window_update_caps_total_fresh_payload() {
+    use std::time::Duration;
+
+    let (_client, _server, outbound, inbound, _listener) = common::connected_pair().await;
+    // Quiesce with known traffic so the connect stream's acked offset
+    // equals the channel's fresh payload total.
+    common::write_all(&outbound, &[0x55; 64]).await;
+    let mut buf = [0u8; 64];
+    let mut got = 0;
+    while got < 64 {
+        got += inbound.read(&mut buf[got..]).await.unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let base = outbound.acked_offset();
+
+    // Grant four more bytes at the connection level and write ten.
+    inbound.grant_connection_window(base + 4).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    outbound.write(b"0123456789").await.unwrap();
+    let mut got = 0;
+    while got < 4 {
+        got += inbound.read(&mut buf[got..]).await.unwrap();
+    }
+    assert_eq!(&buf[..4], b"0123");
+    assert!(
+        tokio::time::timeout(Duration::from_millis(500), inbound.read(&mut buf))
+            .await
+            .is_err(),
+        "bytes crossed beyond the connection-level limit"
+    );
+
+    // Raising the connection limit releases the remainder.
+    inbound.grant_connection_window(base + 14).unwrap();
+    let mut got = 0;
+    while got < 6 {
+        got += inbound.read(&mut buf[got..]).await.unwrap();
+    }
+    assert_eq!(&buf[..6], b"456789");
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_connection_window_blocks_all_substreams_when_full() {
+    use std::time::Duration;
+
+    let (_client, _server, outbound, inbound, _listener) = connected_pair().await;
+    inbound.set_connection_window(8 * 1024).unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // Two substreams, each with a 256 KB per-stream window to itself.
+    let sub_a = outbound.open_substream().unwrap();
+    sub_a.write(b"a").await.unwrap();
+    let peer_a = inbound.accept_substream().await.unwrap();
+    let sub_b = outbound.open_substream().unwrap();
+    sub_b.write(b"b").await.unwrap();
+    let peer_b = inbound.accept_substream().await.unwrap();
+    let mut buf = [0u8; 8];
+    assert_eq!(peer_a.read(&mut buf).await.unwrap(), 1);
+    assert_eq!(peer_b.read(&mut buf).await.unwrap(), 1);
+
+    // 24 KB queued against an 8 KB connection window: the channel stops
+    // after roughly a window's worth no matter how it splits the credit.
+    common::write_all(&sub_a, &vec![0xaa; 12 * 1024]).await;
+    common::write_all(&sub_b, &vec![0xbb; 12 * 1024]).await;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let arrived = |s: &sss::Stream| -> u64 {
+        s.received_ranges().iter().map(|r| r.end - r.start).sum()
+    };
+    let held = arrived(&peer_a) + arrived(&peer_b);
+    assert!(
+        held <= 9 * 1024,
+        "{held} bytes buffered past the 8 KB connection window"
+    );
+    assert!(held >= 4 * 1024, "{held} bytes: the window went unused");
+
+    // Consuming grants credit back and drains the rest. Both readers
+    // must run: unread data on either stream pins connection credit.
+    async fn drain(peer: &sss::Stream) {
+        let mut buf = vec![0u8; 32 * 1024];
+        let mut got = 1usize;
+        while got < 12 * 1024 + 1 {
+            got += peer.read(&mut buf).await.unwrap();
+        }
+    }
+    tokio::join!(drain(&peer_a), drain(&peer_b));
+}
+
+#[tokio::test(start_paused = true)]
+async fn substream_introspection_links_child_to_parent() {
+    let (_client, _server, outbound, inbound, _listener) = connected_pair().await;
+    assert!(!outbound.is_substream());
+    assert_eq!(outbound.parent_id(), None);
+
+    let sub = outbound.open_substream().unwrap();
+    // A substream announces itself with its first frame.
+    sub.write(b"x").await.unwrap();
+    let peer = inbound.accept_substream().await.unwrap();
+
+    assert!(sub.is_substream());
+    assert_eq!(sub.parent_id(), Some(outbound.id()));
+    assert!(peer.is_substream());
+    assert_eq!(peer.parent_id(), Some(inbound.id()));
+    assert!(!inbound.is_substream());
+}
+
+/// A host built with `default_nodelay(true)` starts every stream with
+/// coalescing off: a tiny write flies while earlier data is still in
+/// flight, without per-stream `set_nagle` calls. Host-wide `default_priority`
+/// seeds the scheduling priority the same way.
+#[tokio::test(start_paused = true)]
+async fn host_defaults_seed_nodelay_and_priority() {
+    use std::time::Duration;
+
+    use common::sim_hosts_with;
+
+    let (client, server, net) =
+        sim_hosts_with(|b| b.default_nodelay(true).default_priority(7), |b| b).await;
+    let ca = client.local_addr().unwrap();
+    let sa = server.local_addr().unwrap();
+    net.set_link_latency(ca, sa, Duration::from_millis(50));
+    net.set_link_latency(sa, ca, Duration::from_millis(50));
+    let (outbound, inbound, _l) = common::connect_pair(&client, &server).await;
+    assert_eq!(outbound.priority(), 7);
+    // The server host keeps the stock defaults.
+    assert_eq!(inbound.priority(), 0);
+
+    // Put one byte in flight, then follow with another before any ack can
+    // return. Nagle would hold the second byte for a full round trip; the
+    // host default sends it at once.
+    let start = tokio::time::Instant::now();
+    outbound.write(b"a").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    outbound.write(b"b").await.unwrap();
+    let mut got = 0;
+    let mut buf = [0u8; 8];
+    while got < 2 {
+        got += inbound.read(&mut buf).await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed < Duration::from_millis(120),
+        "second write was delayed {elapsed:?}: Nagle still on"
+    );
+}
+
+/// One logical write split across STREAM frames in different packets is
+/// stitched back together by offset, even with another stream's frames
+/// interleaved between the carrying packets.
+#[tokio::test(start_paused = true)]
+async fn frames_coalesce_across_packets_despite_interleaving() {
+    use std::time::Duration;
+
+    let (_client, _server, outbound, inbound, _listener) = connected_pair().await;
+    let a = outbound.open_substream().unwrap();
+    let b = outbound.open_substream().unwrap();
+    a.set_nagle(false);
+    b.set_nagle(false);
+    a.write(b"first half / ").await.unwrap();
+    let peer_a = inbound.accept_substream().await.unwrap();
+    b.write(b"noise").await.unwrap();
+    let peer_b = inbound.accept_substream().await.unwrap();
+
+    // Spacing the writes out puts each in its own packet: a's second
+    // fragment travels two packets after its first, with b's frame in
+    // between.
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(peer_a.contiguous_bytes_available(), 13);
+    b.write(b"more noise").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    a.write(b"second half").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // Both fragments landed in order on a, undisturbed by b's traffic.
+    assert_eq!(peer_a.contiguous_bytes_available(), 24);
+    let mut buf = [0u8; 64];
+    let n = peer_a.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"first half / second half");
+    assert_eq!(peer_a.contiguous_bytes_available(), 0);
+    let n = peer_b.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"noisemore noise");
+}
+
+/// Both ends of a transfer compute the opt-in content digest over the
+/// payload and arrive at the same value: an end-to-end integrity check
+/// above the wire encryption.
+#[tokio::test(start_paused = true)]
+async fn content_digests_match_across_a_transfer() {
+    let (_client, _server, outbound, inbound, _listener) = connected_pair().await;
+    assert_eq!(outbound.content_digest(), None, "digest is opt-in");
+    outbound.enable_content_digest();
+    inbound.enable_content_digest();
+
+    let payload: Vec<u8> = (0..64 * 1024).map(|i| (i * 31 % 251) as u8).collect();
+    let receive = async {
+        let mut got = Vec::new();
+        let mut buf = vec![0u8; 8 * 1024];
+        loop {
+            let n = inbound.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            got.extend_from_slice(&buf[..n]);
+        }
+        got
+    };
+    let send = async {
+        common::write_all(&outbound, &payload).await;
+        outbound.close().await.unwrap();
+    };
+    let (got, ()) = tokio::join!(receive, send);
+    assert_eq!(got, payload);
+
+    let sent = outbound.content_digest().expect("enabled on the writer");
+    let received = inbound.content_digest().expect("enabled on the reader");
+    assert_eq!(sent, received, "the two ends hashed different payloads");
+}
+
+/// `write_bytes` hands the send queue slices of the caller's buffer
+/// instead of copying it: while the data sits queued, the caller's own
+/// handle is not the only reference to the allocation.
+#[tokio::test(start_paused = true)]
+async fn write_bytes_enqueues_without_copying() {
+    use bytes::Bytes;
+
+    let (_client, _server, outbound, inbound, _listener) = connected_pair().await;
+    let payload: Vec<u8> = (0..16 * 1024).map(|i| (i % 241) as u8).collect();
+    let data = Bytes::from(payload.clone());
+    let probe = data.clone();
+    outbound.write_bytes(data).await.unwrap();
+
+    // Nothing has been acknowledged yet, so the queue still holds slices
+    // of the buffer; reclaiming it as uniquely owned must fail. A copying
+    // implementation would have let go already.
+    let probe = match probe.try_into_mut() {
+        Ok(_) => panic!("the send queue copied the buffer instead of slicing it"),
+        Err(still_shared) => still_shared,
+    };
+
+    let mut got = Vec::new();
+    let mut buf = vec![0u8; 8 * 1024];
+    while got.len() < payload.len() {
+        let n = inbound.read(&mut buf).await.unwrap();
+        got.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(got, probe.to_vec());
+}
+
+#[tokio::test(start_paused = true)]
+async fn progress_reports_a_monotone_sequence_of_acked_offsets() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    // The connect exchange is acked by now; progress starts from there.
+    let base = outbound.acked_offset();
+    let total = 32 * 1024;
+
+    let writer = async {
+        for _ in 0..4 {
+            common::write_all(&outbound, &vec![7u8; total / 4]).await;
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    };
+    let reader = async {
+        let mut buf = vec![0u8; 4096];
+        let mut got = 0;
+        while got < total {
+            got += inbound.read(&mut buf).await.unwrap();
+        }
+    };
+    let watcher = async {
+        // The first event replays the baseline; collect from there on.
+        let mut offsets = vec![outbound.progress().await.unwrap()];
+        while *offsets.last().unwrap() < base + total as u64 {
+            offsets.push(outbound.progress().await.unwrap());
+        }
+        offsets
+    };
+    let ((), (), offsets) = tokio::join!(writer, reader, watcher);
+
+    // Chunked sends force several distinct acks, each moving the offset.
+    assert!(offsets.len() > 3, "expected several progress events");
+    assert!(offsets.windows(2).all(|w| w[0] < w[1]), "not monotone: {offsets:?}");
+    assert_eq!(*offsets.last().unwrap(), base + total as u64);
+}
+
+#[tokio::test(start_paused = true)]
+async fn raising_priority_mid_transfer_shifts_share_without_reordering() {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let (client, server, net) = common::sim_hosts().await;
+    let ca = client.local_addr().unwrap();
+    let sa = server.local_addr().unwrap();
+    // Enough latency that slow start keeps capacity scarce while we sample.
+    net.set_link_latency(ca, sa, Duration::from_millis(50));
+    net.set_link_latency(sa, ca, Duration::from_millis(50));
+    let (outbound, inbound, _l) = common::connect_pair(&client, &server).await;
+    let sub_out = Arc::new(outbound.open_substream().unwrap());
+    sub_out.write(b"open").await.unwrap();
+    let sub_in = inbound.accept_substream().await.unwrap();
+
+    // Start both below the top priority so one can be raised later.
+    outbound.set_priority(1);
+    sub_out.set_priority(1);
+
+    // Keep both streams backlogged with per-stream byte counters, so the
+    // receivers can verify in-stream order across the priority change.
+    for stream in [Arc::new(outbound), sub_out.clone()] {
+        tokio::spawn(async move {
+            let mut sent = 0usize;
+            loop {
+                let chunk: Vec<u8> = (sent..sent + 8 * 1024).map(|i| i as u8).collect();
+                match stream.write(&chunk).await {
+                    Ok(n) => sent += n,
+                    Err(_) => break,
+                }
+            }
+        });
+    }
+    let main_total = Arc::new(AtomicUsize::new(0));
+    let sub_total = Arc::new(AtomicUsize::new(0));
+    let misordered = Arc::new(AtomicBool::new(false));
+    // The substream's counter starts after the 4-byte "open" greeting.
+    for (stream, total, skip) in [(inbound, main_total.clone(), 0), (sub_in, sub_total.clone(), 4)]
+    {
+        let misordered = misordered.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 16 * 1024];
+            let mut seen = 0usize;
+            while let Ok(n) = stream.read(&mut buf).await {
+                for &b in &buf[..n] {
+                    if seen >= skip && b != (seen - skip) as u8 {
+                        misordered.store(true, Ordering::Relaxed);
+                    }
+                    seen += 1;
+                }
+                total.fetch_add(n, Ordering::Relaxed);
+            }
+        });
+    }
+
+    // Phase one: equal priority, the debt scheduler splits roughly evenly.
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let main_before = main_total.load(Ordering::Relaxed);
+    let sub_before = sub_total.load(Ordering::Relaxed);
+    assert!(main_before > 0 && sub_before > 0);
+    let (hi, lo) = (main_before.max(sub_before), main_before.min(sub_before));
+    assert!(hi <= lo * 3, "expected a near-even split, got {main_before}:{sub_before}");
+
+    // Raise the substream's priority mid-flight (0 is highest); only
+    // scheduling decisions from here on should favor it.
+    sub_out.set_priority(0);
+    tokio::time::sleep(Duration::from_millis(400)).await;
+
+    let main_delta = main_total.load(Ordering::Relaxed) - main_before;
+    let sub_delta = sub_total.load(Ordering::Relaxed) - sub_before;
+    assert!(sub_delta > 0, "the promoted stream must make progress");
+    assert!(
+        sub_delta > main_delta * 4,
+        "expected the promoted stream to dominate, got {sub_delta}:{main_delta}"
+    );
+    assert!(
+        !misordered.load(Ordering::Relaxed),
+        "bytes must stay ordered within each stream across the change"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn one_buffer_broadcasts_to_three_streams_without_copies() {
+    use bytes::Bytes;
+
+    use sss::Stream;
+
+    let (client, server, _net) = common::sim_hosts().await;
+    let mut listener = server.listen("push", "v1");
+    let mut receivers = Vec::new();
+    let mut senders = Vec::new();
+    for _ in 0..3 {
+        receivers.push(
+            client
+                .connect(
+                    server.local_addr().unwrap(),
+                    server.public_key(),
+                    "push",
+                    "v1",
+                )
+                .await
+                .unwrap(),
+        );
+        senders.push(listener.accept().await.unwrap());
+    }
+
+    let payload: Vec<u8> = (0..32 * 1024).map(|i| (i % 239) as u8).collect();
+    let data = Bytes::from(payload.clone());
+    let probe = data.clone();
+    Stream::broadcast(&senders, data).await.unwrap();
+
+    // All three queues share the probe's allocation: while anything is
+    // still unacknowledged, the buffer cannot be reclaimed as unique.
+    assert!(
+        probe.try_into_mut().is_err(),
+        "the broadcast copied the buffer instead of slicing it"
+    );
+
+    // Delivery is per stream: each receiver gets its own complete,
+    // ordered copy.
+    for stream in &receivers {
+        let mut got = Vec::new();
+        let mut buf = vec![0u8; 8 * 1024];
+        while got.len() < payload.len() {
+            let n = stream.read(&mut buf).await.unwrap();
+            got.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(got, payload);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn send_from_proxies_a_reader_to_eof_with_fin() {
+    let (_client, _server, outbound, inbound, _listener) = connected_pair().await;
+    let payload: Vec<u8> = (0..48 * 1024).map(|i| (i % 233) as u8).collect();
+
+    // A fixed-size in-memory reader stands in for the proxied source;
+    // `&[u8]` implements `AsyncRead` and EOFs at its end.
+    let source = std::io::Cursor::new(payload.clone());
+    let sender = {
+        let payload_len = payload.len() as u64;
+        tokio::spawn(async move {
+            let sent = outbound.send_from(source, true).await.unwrap();
+            assert_eq!(sent, payload_len);
+        })
+    };
+
+    let mut got = Vec::new();
+    let mut buf = vec![0u8; 8 * 1024];
+    loop {
+        let n = inbound.read(&mut buf).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        got.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(got, payload, "the peer must see the exact source bytes");
+    sender.await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_reset_can_report_the_senders_final_offset() {
+    let (_c, _s, outbound, inbound, _l) = connected_pair().await;
+    outbound.write(b"partial delivery").await.unwrap();
+    assert_eq!(read_exactly(&inbound, 16).await, b"partial delivery");
+    assert_eq!(inbound.peer_final_offset(), None);
+
+    // The sender knows how far its data actually reached; the reset
+    // carries that offset so the receiver can bookmark the interruption.
+    let final_offset = outbound.acked_offset() + 16;
+    outbound.reset_with_final_offset(17, "interrupted", final_offset);
+
+    let mut buf = [0u8; 8];
+    let err = loop {
+        match inbound.read(&mut buf).await {
+            Ok(_) => continue,
+            Err(e) => break e,
+        }
+    };
+    match err {
+        Error::StreamReset { code, reason } => {
+            assert_eq!(code, 17);
+            assert_eq!(reason, "interrupted");
+        }
+        other => panic!("expected reset, got {other:?}"),
+    }
+    assert_eq!(inbound.peer_final_offset(), Some(final_offset));
+}